@@ -0,0 +1,87 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Scrolls through a generated Rust file the way the editor does, highlighting each
+//! viewport on the worker pool, and prints the resulting metrics.
+//!
+//! ```sh
+//! cargo run --release --example threaded_highlighting_demo [lines]
+//! ```
+
+use std::time::{Duration, Instant};
+
+use edit::syntax::{HighlightingMetrics, HighlightingService};
+
+const VIEWPORT_HEIGHT: usize = 50;
+
+fn main() {
+    let line_count = std::env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(20_000);
+
+    let mut lines = generate_source(line_count);
+    let mut service = HighlightingService::new();
+    let mut state = service.create_highlighting_state("demo.rs");
+
+    let start = Instant::now();
+    let mut provisional = 0;
+    let mut top = 0;
+
+    while top < lines.len() {
+        let bottom = (top + VIEWPORT_HEIGHT).min(lines.len());
+        state.update_viewport(top, bottom);
+
+        // Like a frame: nothing here waits for the workers.
+        for line_number in top..bottom {
+            let get_line = |n: usize| lines.get(n).cloned();
+            service
+                .highlight_line_async(&mut state, &lines[line_number], line_number, get_line)
+                .unwrap();
+            if !service.is_line_cached(&state, &lines[line_number], line_number) {
+                provisional += 1;
+            }
+        }
+
+        // Every tenth page, type into the viewport while its request may still be running.
+        if (top / VIEWPORT_HEIGHT) % 10 == 9 {
+            lines[top].push_str(" // edited");
+            state.mark_line_dirty(top);
+        }
+
+        if !service.wait_for_async_results(Duration::from_secs(10)) {
+            eprintln!("The workers did not answer within 10s");
+        }
+        service.apply_async_results(&mut state);
+        top = bottom;
+    }
+
+    println!("Highlighted {} lines in {:?}", lines.len(), start.elapsed());
+    println!("Lines shown before their tokens arrived: {provisional}");
+    print_metrics(&state.metrics);
+}
+
+fn print_metrics(metrics: &HighlightingMetrics) {
+    println!("Requests completed:  {}", metrics.requests_completed);
+    println!("Requests cancelled:  {}", metrics.requests_cancelled);
+    println!("Avg request time:    {:?}", metrics.avg_request_time());
+    println!("Max request time:    {:?}", metrics.max_request_time);
+    println!("Lines highlighted:   {}", metrics.lines_highlighted);
+    println!("Avg time per line:   {:?}", metrics.avg_time_per_line);
+    println!("Max line time:       {:?}", metrics.max_line_time);
+    println!("Cache hits / misses: {} / {}", metrics.cache_hits, metrics.cache_misses);
+}
+
+/// Generates a Rust file with a mix of comments, strings and code.
+fn generate_source(line_count: usize) -> Vec<String> {
+    let snippets = [
+        "/// Returns the answer.",
+        "pub fn answer(x: u32) -> u32 {",
+        "    let message = \"Hello,\\tworld!\";",
+        "    /* a block comment",
+        "       spanning two lines */",
+        "    if x > 42 { return x * 2; }",
+        "    println!(\"{message} {}\", x);",
+        "    x + 0x2a",
+        "}",
+        "",
+    ];
+    (0..line_count).map(|i| snippets[i % snippets.len()].to_string()).collect()
+}
@@ -1,140 +1,838 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-//! Performance baseline establishment script for Phase 0.
+//! Measures the performance of syntax highlighting on a synthetic corpus.
 //!
-//! This script runs comprehensive performance tests to establish baseline
-//! measurements for syntax highlighting operations, file loading, and memory usage.
-
-use std::time::Instant;
-use edit::syntax::{run_baseline_test, global_highlighting_service};
-
-fn main() {
-    println!("=== Edit Syntax Highlighting Performance Baseline ===");
-    println!("Phase 0 - Establishing performance baseline\n");
-
-    // Test file set representing typical usage
-    let test_files = vec![
-        "test_small.rs",       // Small Rust file
-        "test_medium.js",      // Medium JavaScript file
-        "test_large.py",       // Large Python file
-        "test_json.json",      // JSON configuration
-        "test_markdown.md",    // Markdown documentation
-        "test_typescript.ts",  // TypeScript code
-        "test_css.css",        // CSS styles
-        "test_html.html",      // HTML markup
-        "test_yaml.yml",       // YAML configuration
-        "test_toml.toml",      // TOML configuration
-    ];
-
-    println!("Running baseline tests with {} test files...\n", test_files.len());
-
-    // Run the comprehensive baseline test
-    let start_time = Instant::now();
-    let measurement = run_baseline_test(&test_files);
-    let total_time = start_time.elapsed();
-
-    println!("Baseline test completed in {:.2}s\n", total_time.as_secs_f64());
-
-    // Generate and display the performance report
-    let report = measurement.generate_report();
-    println!("{}", report);
-
-    // Check if performance meets Phase 0 requirements
-    let (meets_requirements, issues) = measurement.meets_requirements();
-    
-    if meets_requirements {
-        println!("✅ All performance requirements met!");
-        println!("Phase 0 baseline successfully established.");
+//! For every language and [`FileSizeCategory`] the corpus contains typical code as well as
+//! pathological files: minified ones with very long lines, deeply nested ones, and ones that
+//! open a block comment (or the closest thing the language has) and never close it. The
+//! corpus depends only on the seed, so that results are comparable across machines.
+//!
+//! Each file is loaded the way the editor loads it and then scrolled through page by page.
+//! The report can be saved as JSON and passed to `--compare` on a later run, which then
+//! fails if any metric regressed.
+//!
+//! ```sh
+//! cargo run --release --bin baseline -- --size small,medium --format json --output base.json
+//! cargo run --release --bin baseline -- --size small,medium --compare base.json
+//! ```
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use std::{env, process};
+
+use edit::arena;
+use edit::buffer::TextBuffer;
+use edit::helpers::{KIBI, MEBI};
+use edit::syntax::{
+    FileSizeCategory, HighlightingPolicy, HighlightingService, Language, PerformanceBaseline,
+    PerformanceMeasurement,
+};
+
+const SCRATCH_ARENA_CAPACITY: usize = 128 * MEBI;
+
+/// The lines the editor shows at once.
+const VIEWPORT_HEIGHT: usize = 50;
+/// The pages of each file that are scrolled to, spread evenly from the top to the bottom.
+const PAGES: usize = 16;
+/// How often each page is drawn, e.g. as the cursor moves. Only the first frame is timed.
+const FRAMES_PER_PAGE: usize = 4;
+/// The length the lines of minified files grow to, just short of where the highlighter
+/// gives up on a line.
+const LONG_LINE_LENGTH: usize = 8 * KIBI;
+/// The depth that deeply nested files reach, if their size allows it.
+const DEEP_NESTING_DEPTH: usize = 100;
+/// The depth that typical files don't go beyond.
+const TYPICAL_NESTING_DEPTH: usize = 4;
+
+const USAGE: &str = "\
+Usage: baseline [OPTIONS]
+Options:
+    --size <SIZES>           The file sizes to generate: small, medium, large, extra_large or all
+                             (default: all)
+    --seed <SEED>            The seed of the corpus (default: 1)
+    --languages <NAMES>      The languages to generate (default: all that have a generator)
+    --format <FORMAT>        The report format: text or json (default: text)
+    --output <FILE>          Write the report to FILE instead of stdout
+    --compare <FILE>         Fail if the results regressed compared to a previous JSON report
+    --threshold <PERCENT>    The change that counts as a regression (default: 10)
+    -h, --help               Print this help message
+Lists are comma separated.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug)]
+struct Options {
+    sizes: Vec<FileSizeCategory>,
+    seed: u64,
+    languages: Vec<Language>,
+    format: Format,
+    output: Option<PathBuf>,
+    compare: Option<PathBuf>,
+    threshold: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            sizes: FileSizeCategory::ALL.to_vec(),
+            seed: 1,
+            languages: DIALECTS.iter().map(|d| d.language).collect(),
+            format: Format::Text,
+            output: None,
+            compare: None,
+            threshold: 10.0,
+        }
+    }
+}
+
+impl Options {
+    /// Parses the command line. Returns `None` if only the usage should be printed.
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Ok(None);
+            }
+
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => {
+                    let value = args.next().ok_or_else(|| format!("{arg} requires a value"))?;
+                    (arg, value)
+                }
+            };
+
+            match name.as_str() {
+                "--size" => {
+                    options.sizes = if value == "all" {
+                        FileSizeCategory::ALL.to_vec()
+                    } else {
+                        parse_list(&value, |key| {
+                            FileSizeCategory::from_key(key)
+                                .ok_or_else(|| format!("Unknown file size \"{key}\""))
+                        })?
+                    };
+                }
+                "--seed" => {
+                    options.seed =
+                        value.parse().map_err(|_| format!("Invalid seed \"{value}\""))?;
+                }
+                "--languages" => {
+                    options.languages = parse_list(&value, |name| {
+                        let language = Language::from_name(name)
+                            .ok_or_else(|| format!("Unknown language \"{name}\""))?;
+                        if Dialect::of(language).is_none() {
+                            return Err(format!(
+                                "There's no corpus generator for {}",
+                                language.display_name()
+                            ));
+                        }
+                        Ok(language)
+                    })?;
+                }
+                "--format" => {
+                    options.format = match value.as_str() {
+                        "text" => Format::Text,
+                        "json" => Format::Json,
+                        _ => return Err(format!("Unknown format \"{value}\"")),
+                    };
+                }
+                "--output" => options.output = Some(value.into()),
+                "--compare" => options.compare = Some(value.into()),
+                "--threshold" => {
+                    options.threshold = value
+                        .parse()
+                        .ok()
+                        .filter(|&t: &f64| t >= 0.0)
+                        .ok_or_else(|| format!("Invalid threshold \"{value}\""))?;
+                }
+                _ => return Err(format!("Unknown option \"{name}\"")),
+            }
+        }
+
+        Ok(Some(options))
+    }
+}
+
+fn parse_list<T: PartialEq>(
+    list: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Vec<T>, String> {
+    let mut items = Vec::new();
+    for item in list.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let item = parse(item)?;
+        if !items.contains(&item) {
+            items.push(item);
+        }
+    }
+    if items.is_empty() {
+        return Err(format!("Expected a list, found \"{list}\""));
+    }
+    Ok(items)
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Runs the baseline and prints its report. Returns whether all requirements were met.
+fn run(options: &Options) -> Result<bool, String> {
+    arena::init(SCRATCH_ARENA_CAPACITY).map_err(|err| format!("{err:?}"))?;
+
+    let previous = options.compare.as_deref().map(PerformanceBaseline::load).transpose()?;
+    let dir = env::temp_dir().join(format!("edit-baseline-{}", process::id()));
+    fs::create_dir_all(&dir).map_err(|err| format!("Failed to create {}: {err}", dir.display()))?;
+
+    let start = Instant::now();
+    let results = measure_corpus(options, &dir);
+    let total_time = start.elapsed();
+    _ = fs::remove_dir_all(&dir);
+    let (mut measurement, files) = results?;
+
+    if let Some(previous) = previous {
+        measurement.compare_against(previous, options.threshold / 100.0);
+    }
+    let (passes, issues) = measurement.meets_requirements();
+
+    let report = match options.format {
+        Format::Json => measurement.generate_json_report(),
+        Format::Text => {
+            let mut report = format!(
+                "=== Edit Syntax Highlighting Performance Baseline ===\n\
+                 Seed {}, {} files measured in {:.2}s\n\n",
+                options.seed,
+                files.len(),
+                total_time.as_secs_f64()
+            );
+            for file in &files {
+                report.push_str(&file.to_string());
+                report.push('\n');
+            }
+            report.push('\n');
+            report.push_str(&measurement.generate_report());
+            report
+        }
+    };
+
+    match &options.output {
+        Some(path) => fs::write(path, report + "\n")
+            .map_err(|err| format!("Failed to write {}: {err}", path.display()))?,
+        None => println!("{report}"),
+    }
+
+    // The JSON report may be piped elsewhere, so the verdict goes to stderr.
+    if passes {
+        eprintln!("✅ All performance requirements met!");
     } else {
-        println!("⚠️  Performance issues detected:");
+        eprintln!("⚠️  Performance issues detected:");
         for issue in &issues {
-            println!("  - {}", issue);
-        }
-        println!("\nRecommendations:");
-        println!("  - Review highlighting algorithm efficiency");
-        println!("  - Optimize token generation and caching");
-        println!("  - Consider reducing memory overhead");
-    }
-
-    // Test syntax highlighting service integration
-    println!("\n=== Testing Syntax Highlighting Service Integration ===");
-    test_service_integration();
-
-    println!("\n=== Performance Baseline Complete ===");
-}
-
-fn test_service_integration() {
-    let mut service = global_highlighting_service();
-    
-    // Test creating highlighting states for different languages
-    let test_cases = vec![
-        ("main.rs", "Rust"),
-        ("app.js", "JavaScript"),
-        ("script.py", "Python"),
-        ("config.json", "JSON"),
-        ("README.md", "Markdown"),
-        ("style.css", "CSS"),
-        ("index.html", "HTML"),
-        ("data.yaml", "YAML"),
-        ("config.toml", "TOML"),
-        ("app.ts", "TypeScript"),
-        ("unknown.xyz", "PlainText"),
-    ];
-
-    println!("Testing language detection and state creation:");
-    
-    for (filename, expected_lang) in test_cases {
-        let mut state = service.create_highlighting_state(filename);
-        println!("  {} -> {} (enabled: {})", filename, expected_lang, state.enabled);
-        
-        // Test highlighting a sample line
-        let sample_line = match expected_lang {
-            "Rust" => "fn main() { println!(\"Hello, world!\"); }",
-            "JavaScript" => "console.log('Hello, world!');",
-            "Python" => "print('Hello, world!')",
-            "JSON" => "{ \"message\": \"Hello, world!\" }",
-            _ => "Hello, world!",
+            eprintln!("  - {issue}");
+        }
+    }
+    Ok(passes)
+}
+
+/// How one file of the corpus fared.
+struct FileResult {
+    name: String,
+    bytes: usize,
+    lines: usize,
+    policy: HighlightingPolicy,
+    load_time: Duration,
+    highlight_time: Duration,
+}
+
+impl std::fmt::Display for FileResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<28} {:>8.1}KB {:>7} lines  {:<12} loaded in {:>8.2}ms, highlighted in {:>8.2}ms",
+            self.name,
+            self.bytes as f64 / 1024.0,
+            self.lines,
+            format!("{:?}", self.policy),
+            self.load_time.as_secs_f64() * 1000.0,
+            self.highlight_time.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+fn measure_corpus(
+    options: &Options,
+    dir: &Path,
+) -> Result<(PerformanceMeasurement, Vec<FileResult>), String> {
+    let mut service = HighlightingService::new();
+    let mut measurement = PerformanceMeasurement::new();
+    measurement.start_measurement();
+
+    let mut files = Vec::new();
+    let (mut cache_hits, mut cache_misses) = (0, 0);
+
+    for &language in &options.languages {
+        let dialect = Dialect::of(language).unwrap();
+        for &size in &options.sizes {
+            for case in Case::ALL {
+                let name =
+                    format!("{}_{}.{}", case.key(), size.key(), language.primary_extension());
+                let text = dialect.generate(case, size, options.seed);
+                let path = dir.join(&name);
+                fs::write(&path, &text)
+                    .map_err(|err| format!("Failed to write {}: {err}", path.display()))?;
+
+                let result = measure_file(&mut service, &mut measurement, &path, &text)?;
+                cache_hits += result.1;
+                cache_misses += result.2;
+                files.push(FileResult { name, ..result.0 });
+            }
+        }
+    }
+
+    measurement.record_cache_performance(cache_hits, cache_misses);
+    Ok((measurement, files))
+}
+
+/// Loads and highlights a file of the corpus. Also returns the cache hits and misses.
+fn measure_file(
+    service: &mut HighlightingService,
+    measurement: &mut PerformanceMeasurement,
+    path: &Path,
+    text: &str,
+) -> Result<(FileResult, usize, usize), String> {
+    let fail = |err| format!("Failed to load {}: {err:?}", path.display());
+
+    // Like opening a document: read it into a buffer and pick its language and policy.
+    let start = Instant::now();
+    let mut tb = TextBuffer::new(false).map_err(fail)?;
+    let mut file = File::open(path).map_err(|err| fail(err.into()))?;
+    tb.read_file(&mut file, None).map_err(fail)?;
+    let mut state = service.create_highlighting_state(path);
+    let size = tb.text_length();
+    let average_line_length = size / tb.logical_line_count().max(1) as usize;
+    state.set_policy(HighlightingPolicy::for_document(size as u64, average_line_length));
+    let load_time = start.elapsed();
+    measurement.record_file_load(text.len() as u64, load_time);
+
+    let lines: Vec<&str> = text.lines().collect();
+    let last_top = lines.len().saturating_sub(VIEWPORT_HEIGHT);
+    let mut tops: Vec<_> = (0..PAGES).map(|page| last_top * page / (PAGES - 1)).collect();
+    tops.dedup();
+
+    let start = Instant::now();
+    for top in tops {
+        let bottom = (top + VIEWPORT_HEIGHT).min(lines.len());
+        service.update_viewport(&mut state, top, bottom);
+
+        for frame in 0..FRAMES_PER_PAGE {
+            for (line_number, &line) in lines.iter().enumerate().take(bottom).skip(top) {
+                let line_start = Instant::now();
+                let tokens =
+                    service.highlight_line_with_context(&mut state, line, line_number, |n| {
+                        lines.get(n).map(|l| l.to_string())
+                    })?;
+                if frame == 0 && state.is_highlighting() {
+                    measurement.record_line_highlight(
+                        line.len(),
+                        line_start.elapsed(),
+                        tokens.len(),
+                    );
+                }
+            }
+        }
+    }
+    let highlight_time = start.elapsed();
+
+    measurement.measure_highlighting_memory();
+    if state.token_cache().memory_usage() as u64
+        > measurement.get_baseline().memory_usage.token_cache_bytes
+    {
+        measurement.record_token_cache(state.token_cache());
+    }
+
+    let result = FileResult {
+        name: String::new(),
+        bytes: text.len(),
+        lines: lines.len(),
+        policy: state.policy(),
+        load_time,
+        highlight_time,
+    };
+    Ok((result, state.metrics.cache_hits, state.metrics.cache_misses))
+}
+
+/// The kinds of files generated for each language and size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Case {
+    /// Code with comments, strings and a few levels of nesting.
+    Typical,
+    /// Minified code, with statements joined into very long lines.
+    LongLines,
+    /// Blocks nested [`DEEP_NESTING_DEPTH`] levels deep.
+    DeepNesting,
+    /// Typical code inside a block comment that never ends.
+    Unterminated,
+}
+
+impl Case {
+    const ALL: [Self; 4] = [Self::Typical, Self::LongLines, Self::DeepNesting, Self::Unterminated];
+
+    fn key(self) -> &'static str {
+        match self {
+            Case::Typical => "typical",
+            Case::LongLines => "long_lines",
+            Case::DeepNesting => "deep_nesting",
+            Case::Unterminated => "unterminated",
+        }
+    }
+}
+
+/// A SplitMix64 generator, so that a seed produces the same corpus on every machine.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+const WORDS: &[&str] = &[
+    "alpha", "buffer", "cache", "delta", "event", "frame", "glyph", "handle", "index", "json",
+    "key", "line", "margin", "node", "offset", "parser", "query", "range", "scope", "token",
+    "undo", "value", "width", "cursor", "yield", "zone",
+];
+
+/// Describes how code in a language looks, to generate files that resemble it.
+/// They're not necessarily valid, but exercise the same paths in the lexer.
+struct Dialect {
+    language: Language,
+    /// The lines that make up a block. `$id`, `$num` and `$text` stand for a random
+    /// identifier, number and a few words.
+    statements: &'static [&'static str],
+    /// Lines that open a nested block, and the line that closes it. Blocks that end
+    /// by dedenting have an empty closing line.
+    blocks: &'static [(&'static str, &'static str)],
+    indent: &'static str,
+    /// Joins the statements on the lines of minified files.
+    separator: &'static str,
+    /// Starts a construct that spans lines and is never terminated.
+    unterminated: &'static str,
+}
+
+const DIALECTS: &[Dialect] = &[
+    Dialect {
+        language: Language::Rust,
+        statements: &[
+            "let $id = $num;",
+            "let $id = \"$text\";",
+            "$id.$id(&$id, $num)?;",
+            "// $text",
+            "/* $text */",
+            "println!(\"{} $text\", $id);",
+            "return Some($id + $num);",
+        ],
+        blocks: &[
+            ("fn $id($id: &str) -> Result<u32, Error> {", "}"),
+            ("if $id > $num {", "}"),
+            ("for $id in 0..$num {", "}"),
+            ("impl $id {", "}"),
+        ],
+        indent: "    ",
+        separator: " ",
+        unterminated: "/*",
+    },
+    Dialect {
+        language: Language::JavaScript,
+        statements: &[
+            "const $id = $num;",
+            "let $id = '$text';",
+            "$id.$id($id, $num);",
+            "// $text",
+            "/* $text */",
+            "console.log(`$text ${$id}`);",
+            "return $id ?? null;",
+        ],
+        blocks: &[
+            ("function $id($id, $id) {", "}"),
+            ("if ($id > $num) {", "}"),
+            ("for (const $id of $id) {", "}"),
+            ("const $id = () => {", "};"),
+        ],
+        indent: "    ",
+        separator: " ",
+        unterminated: "/*",
+    },
+    Dialect {
+        language: Language::TypeScript,
+        statements: &[
+            "const $id: number = $num;",
+            "let $id: string = '$text';",
+            "$id.$id<$id>($id);",
+            "// $text",
+            "type $id = { $id: string; $id?: number };",
+            "return `$text ${$id}`;",
+        ],
+        blocks: &[
+            ("function $id($id: string): number {", "}"),
+            ("if ($id !== undefined) {", "}"),
+            ("for (const $id of $id) {", "}"),
+            ("interface $id {", "}"),
+        ],
+        indent: "    ",
+        separator: " ",
+        unterminated: "/*",
+    },
+    Dialect {
+        language: Language::Python,
+        statements: &[
+            "$id = $num",
+            "$id = \"$text\"",
+            "$id.$id($id, $num)",
+            "# $text",
+            "print(f\"$text {$id}\")",
+            "return $id or None",
+            "\"\"\"$text\"\"\"",
+        ],
+        blocks: &[
+            ("def $id($id, $id=$num):", ""),
+            ("if $id > $num:", ""),
+            ("for $id in range($num):", ""),
+            ("class $id:", ""),
+        ],
+        indent: "    ",
+        separator: "; ",
+        unterminated: "\"\"\"",
+    },
+    Dialect {
+        language: Language::Json,
+        statements: &[
+            "\"$id\": $num,",
+            "\"$id\": \"$text\",",
+            "\"$id\": [$num, $num, $num],",
+            "\"$id\": true,",
+            "\"$id\": null,",
+        ],
+        blocks: &[("\"$id\": {", "},"), ("\"$id\": [", "],")],
+        indent: "  ",
+        separator: " ",
+        unterminated: "\"",
+    },
+    Dialect {
+        language: Language::Markdown,
+        statements: &[
+            "$text.",
+            "- $text",
+            "1. $text",
+            "**$text** and *$text*",
+            "`$id` $text",
+            "[$text](https://example.com/$id)",
+            "## $text",
+        ],
+        blocks: &[("- $text", ""), ("> $text", "")],
+        indent: "  ",
+        separator: " ",
+        unterminated: "```",
+    },
+    Dialect {
+        language: Language::Css,
+        statements: &[
+            "color: #$num;",
+            "margin: $numpx $numpx;",
+            "font-family: \"$text\", sans-serif;",
+            "/* $text */",
+            "background: url(\"$id.png\");",
+            "width: calc(100% - $numpx);",
+        ],
+        blocks: &[
+            (".$id {", "}"),
+            ("#$id > .$id:hover {", "}"),
+            ("@media (max-width: $numpx) {", "}"),
+        ],
+        indent: "    ",
+        separator: " ",
+        unterminated: "/*",
+    },
+    Dialect {
+        language: Language::Html,
+        statements: &[
+            "<p>$text</p>",
+            "<a href=\"/$id\">$text</a>",
+            "<img src=\"$id.png\" alt=\"$text\">",
+            "<!-- $text -->",
+            "<input type=\"text\" name=\"$id\" value=\"$num\">",
+            "$text",
+        ],
+        blocks: &[
+            ("<div class=\"$id\">", "</div>"),
+            ("<ul id=\"$id\">", "</ul>"),
+            ("<section>", "</section>"),
+        ],
+        indent: "  ",
+        separator: "",
+        unterminated: "<!--",
+    },
+    Dialect {
+        language: Language::Yaml,
+        statements: &[
+            "$id: $num",
+            "$id: \"$text\"",
+            "- $text",
+            "# $text",
+            "$id: true",
+            "$id: [$num, $num]",
+        ],
+        blocks: &[("$id:", ""), ("- $id:", "")],
+        indent: "  ",
+        separator: " ",
+        unterminated: "\"",
+    },
+    Dialect {
+        language: Language::Toml,
+        statements: &[
+            "$id = $num",
+            "$id = \"$text\"",
+            "$id = [$num, $num, $num]",
+            "# $text",
+            "$id = true",
+            "$id = { $id = $num }",
+        ],
+        blocks: &[("[$id.$id]", ""), ("[[$id]]", "")],
+        indent: "",
+        separator: " ",
+        unterminated: "\"\"\"",
+    },
+];
+
+impl Dialect {
+    fn of(language: Language) -> Option<&'static Dialect> {
+        DIALECTS.iter().find(|d| d.language == language)
+    }
+
+    /// Generates a file of the given size category, the same for the same seed.
+    fn generate(&self, case: Case, size: FileSizeCategory, seed: u64) -> String {
+        // Comfortably within each category, as the last line may overshoot.
+        let target = match size {
+            FileSizeCategory::Small => 8 * KIBI,
+            FileSizeCategory::Medium => 64 * KIBI,
+            FileSizeCategory::Large => 512 * KIBI,
+            FileSizeCategory::ExtraLarge => 2 * MEBI,
         };
-        
-        let start = Instant::now();
-        match service.highlight_line(&mut state, sample_line, 0) {
-            Ok(tokens) => {
-                let duration = start.elapsed();
-                println!("    Highlighted {} chars -> {} tokens in {}μs", 
-                    sample_line.len(), tokens.len(), duration.as_micros());
+        let max_depth = match case {
+            Case::DeepNesting => DEEP_NESTING_DEPTH,
+            _ => TYPICAL_NESTING_DEPTH,
+        };
+
+        // Each file gets its own generator, so that the other options don't change it.
+        let key = format!("{}_{}.{}", case.key(), size.key(), self.language.primary_extension());
+        let mut rng =
+            Rng(key.bytes().fold(seed, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3)));
+
+        let mut text = String::with_capacity(target + LONG_LINE_LENGTH);
+        let mut line = String::new();
+        // The closing lines of the open blocks, and the bytes that writing them will take.
+        let mut open: Vec<&str> = Vec::new();
+        let mut reserved = 0;
+        let mut climbing = true;
+
+        if case == Case::Unterminated {
+            text.push_str(self.unterminated);
+            text.push('\n');
+        }
+
+        while text.len() + reserved < target {
+            let depth = open.len();
+            line.clear();
+            line.push_str(&self.indent.repeat(depth));
+
+            let opens = match case {
+                Case::DeepNesting => {
+                    climbing = (climbing && depth < max_depth) || depth == 0;
+                    climbing || rng.below(4) == 0
+                }
+                _ => depth < max_depth && rng.below(8) == 0,
+            };
+            let closes = !opens && depth > 0 && rng.below(if climbing { 8 } else { 2 }) == 0;
+
+            let &(opening, closing) = rng.pick(self.blocks);
+            let close_cost = self.close_cost(closing, depth);
+
+            if closes {
+                let closing = open.pop().unwrap();
+                reserved -= self.close_cost(closing, depth - 1);
+                if closing.is_empty() {
+                    continue;
+                }
+                line.truncate(line.len() - self.indent.len());
+                line.push_str(closing);
+            } else if opens
+                && depth < max_depth
+                && text.len() + reserved + close_cost + 200 < target
+            {
+                self.expand(opening, &mut rng, &mut line);
+                open.push(closing);
+                reserved += close_cost;
+            } else if case == Case::LongLines {
+                let budget = target.saturating_sub(text.len() + reserved);
+                while line.len() < LONG_LINE_LENGTH.min(budget) {
+                    self.expand(rng.pick(self.statements), &mut rng, &mut line);
+                    line.push_str(self.separator);
+                }
+            } else {
+                self.expand(rng.pick(self.statements), &mut rng, &mut line);
             }
-            Err(e) => {
-                println!("    Error highlighting: {}", e);
+
+            text.push_str(&line);
+            text.push('\n');
+        }
+
+        while let Some(closing) = open.pop() {
+            if !closing.is_empty() {
+                text.push_str(&self.indent.repeat(open.len()));
+                text.push_str(closing);
+                text.push('\n');
             }
         }
+
+        text
+    }
+
+    /// The bytes taken by the closing line of a block at the given depth.
+    fn close_cost(&self, closing: &str, depth: usize) -> usize {
+        if closing.is_empty() { 0 } else { self.indent.len() * depth + closing.len() + 1 }
     }
-    
-    // Test performance with various line lengths
-    println!("\nTesting line length performance:");
-    let mut rust_state = service.create_highlighting_state("test.rs");
-    
-    let extra_long_line = "x".repeat(1000);
-    let test_lines = vec![
-        "fn main() {",  // Short line
-        "pub fn complex_function(param1: &str, param2: Option<i32>, param3: Vec<String>) -> Result<(), Error> {",  // Normal line
-        "let very_long_variable_name_that_demonstrates_long_line_handling = some_complex_expression_with_many_method_calls().and_then(|result| result.map(|x| x.to_string())).unwrap_or_else(|| default_value.clone());",  // Long line
-        &extra_long_line,  // Extra long line
-    ];
-    
-    for (i, line) in test_lines.iter().enumerate() {
-        let start = Instant::now();
-        match service.highlight_line(&mut rust_state, line, i) {
-            Ok(tokens) => {
-                let duration = start.elapsed();
-                println!("  Line {} chars: {} tokens in {}μs", 
-                    line.len(), tokens.len(), duration.as_micros());
+
+    /// Appends `template` to `out` with its placeholders filled in.
+    fn expand(&self, template: &str, rng: &mut Rng, out: &mut String) {
+        let mut rest = template;
+        while let Some(i) = rest.find('$') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            if let Some(r) = rest.strip_prefix("$id") {
+                out.push_str(rng.pick(WORDS));
+                if rng.below(2) == 0 {
+                    out.push('_');
+                    out.push_str(rng.pick(WORDS));
+                }
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("$num") {
+                out.push_str(&rng.below(10_000).to_string());
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("$text") {
+                for i in 0..2 + rng.below(6) {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    out.push_str(rng.pick(WORDS));
+                }
+                rest = r;
+            } else {
+                out.push('$');
+                rest = &rest[1..];
             }
-            Err(e) => {
-                println!("  Error with {}-char line: {}", line.len(), e);
+        }
+        out.push_str(rest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corpus_is_deterministic() {
+        let rust = Dialect::of(Language::Rust).unwrap();
+        let a = rust.generate(Case::Typical, FileSizeCategory::Small, 1);
+        assert_eq!(a, rust.generate(Case::Typical, FileSizeCategory::Small, 1));
+        assert_ne!(a, rust.generate(Case::Typical, FileSizeCategory::Small, 2));
+    }
+
+    #[test]
+    fn test_corpus_sizes_and_shapes() {
+        for dialect in DIALECTS {
+            for size in [FileSizeCategory::Small, FileSizeCategory::Medium] {
+                for case in Case::ALL {
+                    let text = dialect.generate(case, size, 1);
+                    let name = format!("{:?} {}", dialect.language, text.len());
+                    assert_eq!(FileSizeCategory::from_bytes(text.len() as u64), size, "{name}");
+
+                    let longest = text.lines().map(str::len).max().unwrap();
+                    assert_eq!(case == Case::LongLines, longest > 4 * KIBI, "{name}");
+                    if case == Case::Unterminated {
+                        assert!(text.starts_with(dialect.unterminated), "{name}");
+                    }
+                }
             }
         }
+
+        // Only the bigger files have room for the deepest nesting.
+        let rust = Dialect::of(Language::Rust).unwrap();
+        let text = rust.generate(Case::DeepNesting, FileSizeCategory::Medium, 1);
+        let indent = " ".repeat(4 * (DEEP_NESTING_DEPTH - 1));
+        assert!(text.lines().any(|line| line.starts_with(&indent)));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let parse = |args: &[&str]| Options::parse(args.iter().map(|a| a.to_string()));
+
+        let options = parse(&[
+            "--size",
+            "small,large",
+            "--seed=7",
+            "--languages",
+            "rs,python",
+            "--format",
+            "json",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.sizes, [FileSizeCategory::Small, FileSizeCategory::Large]);
+        assert_eq!(options.seed, 7);
+        assert_eq!(options.languages, [Language::Rust, Language::Python]);
+        assert_eq!(options.format, Format::Json);
+
+        assert!(parse(&["--help"]).unwrap().is_none());
+        assert!(parse(&["--size", "huge"]).is_err());
+        assert!(parse(&["--languages", "go"]).is_err());
+        assert!(parse(&["--seed"]).is_err());
     }
-    
-    println!("\nService integration test completed.");
 }
@@ -11,7 +11,11 @@ use std::rc::Rc;
 use edit::buffer::{RcTextBuffer, TextBuffer};
 use edit::helpers::{CoordType, Point};
 use edit::simd::memrchr2;
-use edit::syntax::{HighlightingState, global_highlighting_service, register_buffer_highlighting};
+use edit::syntax::language::MODELINE_SEARCH_LINES;
+use edit::syntax::{
+    HighlightingPolicy, HighlightingState, Language, global_highlighting_service,
+    register_buffer_highlighting,
+};
 use edit::{apperr, path, sys};
 
 use crate::state::DisplayablePathBuf;
@@ -25,6 +29,8 @@ pub struct Document {
     pub new_file_counter: usize,
     /// Syntax highlighting state for this document
     pub highlighting_state: Rc<RefCell<HighlightingState>>,
+    /// The language picked by the user, if any. Takes precedence over detection.
+    pub language_override: Option<Language>,
 }
 
 impl Document {
@@ -61,6 +67,10 @@ impl Document {
             self.file_id = Some(id);
         }
 
+        // The new contents may call for a different language or highlighting policy.
+        self.update_language();
+        self.update_file_mode();
+
         Ok(())
     }
 
@@ -72,21 +82,74 @@ impl Document {
         self.path = Some(path.clone());
         
         // Update highlighting state for the new file type
-        {
-            let mut service = global_highlighting_service();
-            let new_state = service.create_highlighting_state(&path);
-            self.highlighting_state = Rc::new(RefCell::new(new_state));
-            
-            // Re-register with the new highlighting state
-            register_buffer_highlighting(&*self.buffer.borrow(), self.highlighting_state.clone());
-        }
+        self.update_language();
         
         self.update_file_mode();
     }
 
+    /// Forces the document to use the given language, or goes back to detecting it if `None`.
+    /// The choice is kept when the document is saved under a new name.
+    pub fn set_language_override(&mut self, language: Option<Language>) {
+        self.language_override = language;
+        self.update_language();
+        self.update_file_mode();
+    }
+
+    /// Detects the language from the path and, if that fails, the first and
+    /// last few lines of the buffer, and rebuilds the highlighting state.
+    pub fn update_language(&mut self) {
+        if let Some(language) = self.language_override {
+            let new_state =
+                global_highlighting_service().create_highlighting_state_for_language(language);
+            self.set_highlighting_state(new_state);
+            return;
+        }
+
+        let (head, tail) = {
+            let tb = self.buffer.borrow();
+            let line_count = tb.logical_line_count();
+            let search = MODELINE_SEARCH_LINES as CoordType;
+            let head_end = line_count.min(search);
+            let tail_beg = line_count.saturating_sub(search).max(head_end);
+            let lines = |range: std::ops::Range<CoordType>| -> Vec<String> {
+                range.filter_map(|y| tb.logical_line_text(y)).collect()
+            };
+            (lines(0..head_end), lines(tail_beg..line_count))
+        };
+        let head: Vec<&str> = head.iter().map(String::as_str).collect();
+        let tail: Vec<&str> = tail.iter().map(String::as_str).collect();
+
+        let new_state = global_highlighting_service().create_highlighting_state_with_content(
+            self.path.as_deref(),
+            &head,
+            &tail,
+        );
+        self.set_highlighting_state(new_state);
+    }
+
+    fn set_highlighting_state(&mut self, mut state: HighlightingState) {
+        // Huge and minified files get less highlighting, unless the user asked for all of it.
+        if self.highlighting_state.borrow().is_policy_forced() {
+            state.force_full_highlighting();
+        } else {
+            let tb = self.buffer.borrow();
+            let size = tb.text_length();
+            let average_line_length = size / tb.logical_line_count().max(1) as usize;
+            state.set_policy(HighlightingPolicy::for_document(size as u64, average_line_length));
+        }
+
+        self.highlighting_state = Rc::new(RefCell::new(state));
+
+        // Re-register with the new highlighting state
+        register_buffer_highlighting(&self.buffer.borrow(), self.highlighting_state.clone());
+    }
+
     fn update_file_mode(&mut self) {
+        // Git commit, merge and tag messages are wrapped at 72 columns.
+        let is_commit_message = self.highlighting_state.borrow().language == Language::GitCommit
+            || self.filename == "COMMIT_EDITMSG";
         let mut tb = self.buffer.borrow_mut();
-        tb.set_ruler(if self.filename == "COMMIT_EDITMSG" { 72 } else { 0 });
+        tb.set_ruler(if is_commit_message { 72 } else { 0 });
     }
 }
 
@@ -139,10 +202,11 @@ impl DocumentManager {
             file_id: None,
             new_file_counter: 0,
             highlighting_state: {
-                let mut service = global_highlighting_service();
-                let state = service.create_highlighting_state("Untitled.txt");
+                let service = global_highlighting_service();
+                let state = service.create_highlighting_state_for_language(Language::PlainText);
                 Rc::new(RefCell::new(state))
             },
+            language_override: None,
         };
         self.gen_untitled_name(&mut doc);
         
@@ -211,6 +275,7 @@ impl DocumentManager {
                 let state = service.create_highlighting_state(&path);
                 Rc::new(RefCell::new(state))
             },
+            language_override: None,
         };
         doc.set_path(path);
         
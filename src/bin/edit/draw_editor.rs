@@ -1,13 +1,16 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use std::fmt::Write as _;
 use std::num::ParseIntError;
 
-use edit::framebuffer::IndexedColor;
+use edit::buffer::TokenInspection;
+use edit::framebuffer::{Attributes, INDEXED_COLORS_COUNT, IndexedColor};
 use edit::helpers::*;
-use edit::icu;
 use edit::input::{kbmod, vk};
+use edit::syntax::{Language, ThemeColor, TokenInfo, TokenSource, global_color_mapper};
 use edit::tui::*;
+use edit::{arena_format, icu};
 
 use crate::localization::*;
 use crate::state::*;
@@ -330,6 +333,145 @@ pub fn draw_goto_menu(ctx: &mut Context, state: &mut State) {
     }
 }
 
+/// Shows what the highlighter produced for the line with the cursor:
+/// its tokens, where they came from and how the theme styles them.
+pub fn draw_token_inspector(ctx: &mut Context, state: &mut State) {
+    ctx.modal_begin("inspect-token", loc(LocId::ViewInspectToken));
+    {
+        ctx.block_begin("content");
+        ctx.inherit_focus();
+        ctx.attr_padding(Rect::three(1, 2, 1));
+
+        if let Some(inspection) = &state.token_inspection {
+            draw_token_inspection(ctx, inspection);
+        } else {
+            ctx.label("unavailable", loc(LocId::InspectTokenUnavailable));
+        }
+
+        ctx.block_end();
+    }
+    if ctx.modal_end() {
+        state.wants_token_inspector = false;
+        state.token_inspection = None;
+    }
+}
+
+fn draw_token_inspection(ctx: &mut Context, inspection: &TokenInspection) {
+    let language = inspection.language;
+    let source = match inspection.source {
+        TokenSource::Cache => loc(LocId::InspectTokenCached),
+        TokenSource::Highlighted => loc(LocId::InspectTokenHighlighted),
+        TokenSource::Pending => loc(LocId::InspectTokenPending),
+    };
+    ctx.label("summary", &arena_format!(ctx.arena(), "{} · {}", language.display_name(), source));
+    ctx.attr_overflow(Overflow::TruncateTail);
+
+    let current = inspection.token_at_cursor();
+    let (kind, style) = match current {
+        Some(token) => (
+            describe_token(token),
+            describe_style(language, token.kind.as_deref(), ctx.indexed_colors()),
+        ),
+        None => ("-".to_string(), describe_style(language, None, ctx.indexed_colors())),
+    };
+    ctx.label("token", &arena_format!(ctx.arena(), "@{}  {}", inspection.offset, kind));
+    ctx.attr_overflow(Overflow::TruncateTail);
+    ctx.label("style", &style);
+    ctx.attr_overflow(Overflow::TruncateTail);
+
+    let width = (ctx.size().width - 20).max(10);
+    let height = (inspection.tokens.len() as CoordType).clamp(1, (ctx.size().height - 14).max(3));
+
+    ctx.scrollarea_begin("tokens", Size { width, height });
+    ctx.attr_background_rgba(ctx.indexed_alpha(IndexedColor::Black, 1, 4));
+    ctx.attr_padding(Rect::three(0, 1, 0));
+    ctx.inherit_focus();
+    {
+        let color_mapper = global_color_mapper();
+
+        for (i, token) in inspection.tokens.iter().enumerate() {
+            let marker =
+                if current.is_some_and(|c| std::ptr::eq(c, token)) { "▶ " } else { "  " };
+
+            ctx.next_block_id_mixin(i as u64);
+            ctx.styled_label_begin("token");
+            ctx.styled_label_add_text(&arena_format!(
+                ctx.arena(),
+                "{marker}{:>4}..{:<4} {:<20} ",
+                token.start_offset,
+                token.end_offset,
+                token.kind.as_deref().unwrap_or("-"),
+            ));
+
+            // Show the text in the color it's drawn with.
+            if let Some(style) = token
+                .kind
+                .as_deref()
+                .and_then(|kind| color_mapper.resolve_in(language, kind, ctx.indexed_colors()))
+            {
+                if let Some(fg) = style.foreground {
+                    ctx.styled_label_set_foreground(fg);
+                }
+                ctx.styled_label_set_attributes(style.attributes);
+            }
+            ctx.styled_label_add_text(&arena_format!(ctx.arena(), "{:?}", token.text));
+            ctx.styled_label_end();
+            ctx.attr_overflow(Overflow::TruncateTail);
+        }
+    }
+    ctx.scrollarea_end();
+}
+
+/// Describes a token as `kind "text" start..end`.
+fn describe_token(token: &TokenInfo) -> String {
+    format!(
+        "{} {:?} {}..{}",
+        token.kind.as_deref().unwrap_or("-"),
+        token.text,
+        token.start_offset,
+        token.end_offset
+    )
+}
+
+/// Describes how the current theme styles `kind` in `language`: the theme key that
+/// applies, its colors as written in the theme, the colors they resolve to, and the attributes.
+fn describe_style(
+    language: Language,
+    kind: Option<&str>,
+    palette: &[u32; INDEXED_COLORS_COUNT],
+) -> String {
+    let color_mapper = global_color_mapper();
+    let theme = color_mapper.theme();
+    let Some((key, style)) = kind.and_then(|kind| theme.lookup(Some(language), kind)) else {
+        return format!("{}: -", theme.name);
+    };
+    let resolved = color_mapper.resolve_in(language, kind.unwrap_or_default(), palette).unwrap();
+
+    let mut out = format!("{}: {key}", theme.name);
+    for (label, color, resolved) in [
+        ("fg", style.foreground, resolved.foreground),
+        ("bg", style.background, resolved.background),
+    ] {
+        if let (Some(color), Some(resolved)) = (color, resolved) {
+            _ = write!(out, "  {label} {color}");
+            let resolved = ThemeColor::Rgba(resolved).to_string();
+            if resolved != color.to_string() {
+                _ = write!(out, " = {resolved}");
+            }
+        }
+    }
+    for (name, attr) in [
+        ("bold", Attributes::Bold),
+        ("italic", Attributes::Italic),
+        ("underline", Attributes::Underlined),
+    ] {
+        if style.attributes.is(attr) {
+            _ = write!(out, "  {name}");
+        }
+    }
+    out
+}
+
 fn validate_goto_point(line: &str) -> Result<Point, ParseIntError> {
     let mut coords = [0; 2];
     let (y, x) = line.split_once(':').unwrap_or((line, "0"));
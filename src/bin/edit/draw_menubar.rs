@@ -4,6 +4,7 @@
 use edit::arena_format;
 use edit::helpers::*;
 use edit::input::{kbmod, vk};
+use edit::syntax::{global_color_mapper, global_color_mapper_mut};
 use edit::tui::*;
 
 use crate::localization::*;
@@ -117,6 +118,23 @@ fn draw_menu_view(ctx: &mut Context, state: &mut State) {
             tb.set_word_wrap(!word_wrap);
             ctx.needs_rerender();
         }
+        if ctx.menubar_menu_button(loc(LocId::ViewInspectToken), 'I', kbmod::ALT | vk::I) {
+            state.token_inspection = tb.inspect_syntax_at_cursor();
+            state.wants_token_inspector = true;
+        }
+    }
+
+    // Cycles through the syntax themes. Highlighting colors are resolved while rendering,
+    // so the rerender is all it takes for every document to pick up the new theme.
+    let theme_label = arena_format!(
+        ctx.arena(),
+        "{}{}",
+        loc(LocId::ViewSyntaxTheme),
+        global_color_mapper().theme().name
+    );
+    if ctx.menubar_menu_button(&theme_label, 'T', vk::NULL) {
+        global_color_mapper_mut().select_next_theme();
+        ctx.needs_rerender();
     }
 
     ctx.menubar_menu_end();
@@ -1,9 +1,12 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use edit::arena::scratch_arena;
 use edit::framebuffer::{Attributes, IndexedColor};
+use edit::fuzzy::score_fuzzy;
 use edit::helpers::*;
 use edit::input::vk;
+use edit::syntax::{HighlightingPolicy, Language};
 use edit::tui::*;
 use edit::{arena_format, icu};
 
@@ -19,6 +22,8 @@ pub fn draw_statusbar(ctx: &mut Context, state: &mut State) {
     ctx.attr_intrinsic_size(Size { width: COORD_TYPE_SAFE_MAX, height: 1 });
     ctx.attr_padding(Rect::two(0, 1));
 
+    let mut language_change = None;
+
     if let Some(doc) = state.documents.active() {
         let mut tb = doc.buffer.borrow_mut();
 
@@ -145,6 +150,83 @@ pub fn draw_statusbar(ctx: &mut Context, state: &mut State) {
             }
         }
 
+        let language = doc.highlighting_state.borrow().language;
+        state.wants_language_picker |=
+            ctx.button("language", language.display_name(), ButtonStyle::default());
+        if state.wants_language_picker {
+            ctx.block_begin("language-picker");
+            ctx.attr_float(FloatSpec {
+                anchor: Anchor::Last,
+                gravity_x: 0.0,
+                gravity_y: 1.0,
+                offset_x: 0.0,
+                offset_y: 0.0,
+            });
+            ctx.attr_border();
+            ctx.attr_padding(Rect::two(0, 1));
+            {
+                ctx.editline("filter", &mut state.language_picker_filter);
+                ctx.attr_intrinsic_size(Size { width: 24, height: 1 });
+                ctx.focus_on_first_present();
+                let filter_focused = ctx.is_focused();
+
+                let auto_detect = loc(LocId::LanguageAutoDetect);
+                let choices = filter_languages(&state.language_picker_filter, auto_detect);
+
+                // Enter in the filter picks the best match.
+                if filter_focused
+                    && ctx.consume_shortcut(vk::RETURN)
+                    && let Some(&choice) = choices.first()
+                {
+                    language_change = Some(choice);
+                }
+
+                ctx.scrollarea_begin(
+                    "scrollarea",
+                    Size { width: 24, height: (choices.len() as CoordType).clamp(1, 10) },
+                );
+                {
+                    ctx.list_begin("languages");
+                    ctx.attr_padding(Rect::two(0, 1));
+                    for &choice in &choices {
+                        let (selected, label) = match choice {
+                            Some(l) => (doc.language_override == Some(l), l.display_name()),
+                            None => (doc.language_override.is_none(), auto_detect),
+                        };
+                        if ctx.list_item(selected, label) == ListSelection::Activated {
+                            language_change = Some(choice);
+                        }
+                    }
+                    ctx.list_end();
+                }
+                ctx.scrollarea_end();
+            }
+            ctx.block_end();
+
+            if language_change.is_some() || !ctx.contains_focus() {
+                state.wants_language_picker = false;
+                state.language_picker_filter.clear();
+                ctx.needs_rerender();
+            }
+        }
+
+        // Huge and minified files get less highlighting. Clicking this turns all of it back on.
+        let policy = {
+            let highlighting = doc.highlighting_state.borrow();
+            if highlighting.enabled { highlighting.policy() } else { HighlightingPolicy::Full }
+        };
+        let reduced = match policy {
+            HighlightingPolicy::Full => None,
+            HighlightingPolicy::ViewportOnly => Some(LocId::HighlightingViewportOnly),
+            HighlightingPolicy::Off => Some(LocId::HighlightingOff),
+        };
+        if let Some(label) = reduced
+            && ctx.button("highlighting", loc(label), ButtonStyle::default())
+        {
+            doc.highlighting_state.borrow_mut().force_full_highlighting();
+            ctx.needs_rerender();
+        }
+
         ctx.label(
             "location",
             &arena_format!(
@@ -191,6 +273,34 @@ pub fn draw_statusbar(ctx: &mut Context, state: &mut State) {
     }
 
     ctx.table_end();
+
+    if let Some(language) = language_change
+        && let Some(doc) = state.documents.active_mut()
+    {
+        doc.set_language_override(language);
+    }
+}
+
+/// Returns the language picker entries matching `filter`, best match first.
+/// `None` stands for the "auto detect" entry labeled `auto_detect`.
+fn filter_languages(filter: &str, auto_detect: &str) -> Vec<Option<Language>> {
+    let entries = std::iter::once((None, auto_detect))
+        .chain(Language::all().map(|l| (Some(l), l.display_name())));
+
+    if filter.is_empty() {
+        return entries.map(|(language, _)| language).collect();
+    }
+
+    let scratch = scratch_arena(None);
+    let mut matches: Vec<_> = entries
+        .filter_map(|(language, label)| {
+            let (score, _) = score_fuzzy(&scratch, label, filter, true);
+            (score > 0).then_some((score, language))
+        })
+        .collect();
+    // Stable, so that equally good matches keep their order.
+    matches.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+    matches.into_iter().map(|(_, language)| language).collect()
 }
 
 pub fn draw_dialog_encoding_change(ctx: &mut Context, state: &mut State) {
@@ -291,3 +401,21 @@ pub fn draw_document_picker(ctx: &mut Context, state: &mut State) {
         state.wants_document_picker = false;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_languages() {
+        edit::arena::init(16 * 1024 * 1024).unwrap();
+
+        assert_eq!(filter_languages("", "Auto").len(), Language::ALL.len() + 1);
+        assert_eq!(filter_languages("py", "Auto"), vec![Some(Language::Python)]);
+        assert_eq!(
+            filter_languages("script", "Auto")[..2],
+            [Some(Language::JavaScript), Some(Language::TypeScript)]
+        );
+        assert_eq!(filter_languages("auto", "Auto"), vec![None]);
+    }
+}
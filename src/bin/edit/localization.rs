@@ -43,6 +43,8 @@ pub enum LocId {
     ViewFocusStatusbar,
     ViewWordWrap,
     ViewDocumentPicker,
+    ViewSyntaxTheme,
+    ViewInspectToken,
 
     // Help menu
     Help,
@@ -54,6 +56,12 @@ pub enum LocId {
     UnsavedChangesDialogYes,
     UnsavedChangesDialogNo,
 
+    // Inspect token dialog
+    InspectTokenCached,
+    InspectTokenHighlighted,
+    InspectTokenPending,
+    InspectTokenUnavailable,
+
     // About dialog
     AboutDialogTitle,
     AboutDialogVersion,
@@ -85,6 +93,10 @@ pub enum LocId {
     IndentationTabs,
     IndentationSpaces,
 
+    LanguageAutoDetect,
+    HighlightingViewportOnly,
+    HighlightingOff,
+
     SaveAsDialogPathLabel,
     SaveAsDialogNameLabel,
 
@@ -527,6 +539,34 @@ const S_LANG_LUT: [[&str; LangId::Count as usize]; LocId::Count as usize] = [
         /* zh_hans */ "文档选择器",
         /* zh_hant */ "文件選擇器",
     ],
+    // ViewSyntaxTheme
+    [
+        /* en      */ "Syntax Theme: ",
+        /* de      */ "Syntaxschema: ",
+        /* es      */ "Tema de sintaxis: ",
+        /* fr      */ "Thème de syntaxe : ",
+        /* it      */ "Tema della sintassi: ",
+        /* ja      */ "構文テーマ: ",
+        /* ko      */ "구문 테마: ",
+        /* pt_br   */ "Tema de sintaxe: ",
+        /* ru      */ "Тема синтаксиса: ",
+        /* zh_hans */ "语法主题: ",
+        /* zh_hant */ "語法主題: ",
+    ],
+    // ViewInspectToken
+    [
+        /* en      */ "Inspect Token",
+        /* de      */ "Token untersuchen",
+        /* es      */ "Inspeccionar token",
+        /* fr      */ "Inspecter le jeton",
+        /* it      */ "Ispeziona token",
+        /* ja      */ "トークンの検査",
+        /* ko      */ "토큰 검사",
+        /* pt_br   */ "Inspecionar token",
+        /* ru      */ "Просмотр токена",
+        /* zh_hans */ "检查标记",
+        /* zh_hant */ "檢查標記",
+    ],
 
     // Help (a menu bar item)
     [
@@ -614,6 +654,63 @@ const S_LANG_LUT: [[&str; LangId::Count as usize]; LocId::Count as usize] = [
         /* zh_hant */ "不儲存",
     ],
 
+    // InspectTokenCached
+    [
+        /* en      */ "From the highlighting cache",
+        /* de      */ "Aus dem Hervorhebungscache",
+        /* es      */ "De la caché de resaltado",
+        /* fr      */ "Depuis le cache de coloration",
+        /* it      */ "Dalla cache di evidenziazione",
+        /* ja      */ "ハイライト キャッシュから",
+        /* ko      */ "강조 표시 캐시에서",
+        /* pt_br   */ "Do cache de realce",
+        /* ru      */ "Из кэша подсветки",
+        /* zh_hans */ "来自高亮缓存",
+        /* zh_hant */ "來自醒目提示快取",
+    ],
+    // InspectTokenHighlighted
+    [
+        /* en      */ "Freshly highlighted",
+        /* de      */ "Neu hervorgehoben",
+        /* es      */ "Resaltado de nuevo",
+        /* fr      */ "Coloré à l'instant",
+        /* it      */ "Appena evidenziato",
+        /* ja      */ "新たにハイライト",
+        /* ko      */ "새로 강조 표시됨",
+        /* pt_br   */ "Realçado agora",
+        /* ru      */ "Подсвечено заново",
+        /* zh_hans */ "刚刚高亮",
+        /* zh_hant */ "剛剛醒目提示",
+    ],
+    // InspectTokenPending
+    [
+        /* en      */ "Waiting for the highlighter",
+        /* de      */ "Wartet auf die Hervorhebung",
+        /* es      */ "Esperando al resaltado",
+        /* fr      */ "En attente de la coloration",
+        /* it      */ "In attesa dell'evidenziazione",
+        /* ja      */ "ハイライト待ち",
+        /* ko      */ "강조 표시 대기 중",
+        /* pt_br   */ "Aguardando o realce",
+        /* ru      */ "Ожидает подсветки",
+        /* zh_hans */ "等待高亮",
+        /* zh_hant */ "等待醒目提示",
+    ],
+    // InspectTokenUnavailable
+    [
+        /* en      */ "Syntax highlighting is off for this document.",
+        /* de      */ "Die Syntaxhervorhebung ist für dieses Dokument deaktiviert.",
+        /* es      */ "El resaltado de sintaxis está desactivado para este documento.",
+        /* fr      */ "La coloration syntaxique est désactivée pour ce document.",
+        /* it      */ "L'evidenziazione della sintassi è disattivata per questo documento.",
+        /* ja      */ "このドキュメントでは構文ハイライトがオフです。",
+        /* ko      */ "이 문서에는 구문 강조 표시가 꺼져 있습니다.",
+        /* pt_br   */ "O realce de sintaxe está desativado para este documento.",
+        /* ru      */ "Подсветка синтаксиса для этого документа отключена.",
+        /* zh_hans */ "此文档的语法高亮已关闭。",
+        /* zh_hant */ "此文件的語法醒目提示已關閉。",
+    ],
+
     // AboutDialogTitle
     [
         /* en      */ "About",
@@ -902,6 +999,51 @@ const S_LANG_LUT: [[&str; LangId::Count as usize]; LocId::Count as usize] = [
         /* zh_hant */ "空格",
     ],
 
+    // LanguageAutoDetect
+    [
+        /* en      */ "Auto Detect",
+        /* de      */ "Automatisch erkennen",
+        /* es      */ "Detección automática",
+        /* fr      */ "Détection automatique",
+        /* it      */ "Rilevamento automatico",
+        /* ja      */ "自動検出",
+        /* ko      */ "자동 감지",
+        /* pt_br   */ "Detecção automática",
+        /* ru      */ "Автоопределение",
+        /* zh_hans */ "自动检测",
+        /* zh_hant */ "自動偵測",
+    ],
+
+    // HighlightingViewportOnly
+    [
+        /* en      */ "Partial Highlighting",
+        /* de      */ "Teilweise Hervorhebung",
+        /* es      */ "Resaltado parcial",
+        /* fr      */ "Coloration partielle",
+        /* it      */ "Evidenziazione parziale",
+        /* ja      */ "部分ハイライト",
+        /* ko      */ "부분 강조 표시",
+        /* pt_br   */ "Realce parcial",
+        /* ru      */ "Частичная подсветка",
+        /* zh_hans */ "部分高亮",
+        /* zh_hant */ "部分醒目提示",
+    ],
+
+    // HighlightingOff
+    [
+        /* en      */ "No Highlighting",
+        /* de      */ "Keine Hervorhebung",
+        /* es      */ "Sin resaltado",
+        /* fr      */ "Sans coloration",
+        /* it      */ "Nessuna evidenziazione",
+        /* ja      */ "ハイライトなし",
+        /* ko      */ "강조 표시 없음",
+        /* pt_br   */ "Sem realce",
+        /* ru      */ "Без подсветки",
+        /* zh_hans */ "无高亮",
+        /* zh_hant */ "無醒目提示",
+    ],
+
     // SaveAsDialogPathLabel
     [
         /* en      */ "Folder:",
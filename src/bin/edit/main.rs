@@ -21,6 +21,7 @@ use std::borrow::Cow;
 #[cfg(feature = "debug-latency")]
 use std::fmt::Write;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{env, process};
 
 use draw_editor::*;
@@ -32,7 +33,10 @@ use edit::framebuffer::{self, IndexedColor};
 use edit::helpers::{KIBI, MEBI, MetricFormatter, Rect, Size};
 use edit::input::{self, kbmod, vk};
 use edit::oklab::oklab_blend;
-use edit::syntax::process_background_highlighting;
+use edit::syntax::{
+    apply_highlighting_results, global_color_mapper_mut, has_pending_highlighting,
+    process_background_highlighting, user_languages,
+};
 use edit::tui::*;
 use edit::vt::{self, Token};
 use edit::{apperr, arena_format, base64, path, sys};
@@ -44,6 +48,9 @@ const SCRATCH_ARENA_CAPACITY: usize = 128 * MEBI;
 #[cfg(target_pointer_width = "64")]
 const SCRATCH_ARENA_CAPACITY: usize = 512 * MEBI;
 
+/// How often to check on the highlighting workers while they're busy.
+const HIGHLIGHTING_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 fn main() -> process::ExitCode {
     if cfg!(debug_assertions) {
         let hook = std::panic::take_hook();
@@ -72,6 +79,17 @@ fn run() -> apperr::Result<()> {
     localization::init();
 
     let mut state = State::new()?;
+    // User syntax definitions must be registered before the first document's language is detected.
+    for err in user_languages::load_from_config_dir() {
+        error_log_push(&mut state, err);
+    }
+    {
+        let mut color_mapper = global_color_mapper_mut();
+        color_mapper.set_truecolor(detect_truecolor());
+        for err in color_mapper.load_from_config_dir() {
+            error_log_push(&mut state, err);
+        }
+    }
     if handle_args(&mut state)? {
         return Ok(());
     }
@@ -87,6 +105,7 @@ fn run() -> apperr::Result<()> {
     let mut tui = Tui::new()?;
 
     let _restore = setup_terminal(&mut tui, &mut vt_parser);
+    global_color_mapper_mut().select_for_background(tui.indexed(IndexedColor::Background));
 
     state.menubar_color_bg = oklab_blend(
         tui.indexed(IndexedColor::Background),
@@ -122,7 +141,11 @@ fn run() -> apperr::Result<()> {
         // Process a batch of input.
         {
             let scratch = scratch_arena(None);
-            let read_timeout = vt_parser.read_timeout().min(tui.read_timeout());
+            let mut read_timeout = vt_parser.read_timeout().min(tui.read_timeout());
+            if has_pending_highlighting() {
+                // Come back for the lines the highlighting workers are still busy with.
+                read_timeout = read_timeout.min(HIGHLIGHTING_POLL_INTERVAL);
+            }
             let Some(input) = sys::read_stdin(&scratch, read_timeout) else {
                 break;
             };
@@ -133,6 +156,9 @@ fn run() -> apperr::Result<()> {
                 passes = 0usize;
             }
 
+            // Cache what the highlighting workers finished, so that this frame shows it.
+            apply_highlighting_results();
+
             let vt_iter = vt_parser.parse(&input);
             let mut input_iter = input_parser.parse(vt_iter);
 
@@ -249,34 +275,7 @@ fn process_background_highlighting_for_state(state: &mut State) {
         
         // Create a closure to get line content for background highlighting
         let get_line_content = |line_number: usize| -> Option<String> {
-            if line_number < buffer.logical_line_count() as usize {
-                // Extract line content from the buffer using the new public API
-                let logical_pos = edit::helpers::Point { x: 0, y: line_number as edit::helpers::CoordType };
-                let line_end_pos = edit::helpers::Point { 
-                    x: edit::helpers::CoordType::MAX, 
-                    y: line_number as edit::helpers::CoordType 
-                };
-                
-                // Get offsets without moving the cursor
-                let line_start_offset = buffer.get_offset_at_logical_pos(logical_pos);
-                let line_end_offset = buffer.get_offset_at_logical_pos(line_end_pos);
-                
-                if line_start_offset != line_end_offset {
-                    let chunk = buffer.read_forward(line_start_offset);
-                    let chunk_len = (line_end_offset - line_start_offset).min(chunk.len());
-                    let line_bytes = &chunk[..chunk_len];
-                    
-                    // Convert to string, handling invalid UTF-8
-                    match String::from_utf8(line_bytes.to_vec()) {
-                        Ok(s) => Some(s),
-                        Err(_) => Some(String::from_utf8_lossy(line_bytes).to_string())
-                    }
-                } else {
-                    Some(String::new()) // Empty line
-                }
-            } else {
-                None
-            }
+            buffer.logical_line_text(line_number as edit::helpers::CoordType)
         };
         
         // Process background highlighting
@@ -324,9 +323,12 @@ fn handle_args(state: &mut State) -> apperr::Result<bool> {
 
     if let Some(mut file) = sys::open_stdin_if_redirected() {
         let doc = state.documents.add_untitled()?;
-        let mut tb = doc.buffer.borrow_mut();
-        tb.read_file(&mut file, None)?;
-        tb.mark_as_dirty();
+        {
+            let mut tb = doc.buffer.borrow_mut();
+            tb.read_file(&mut file, None)?;
+            tb.mark_as_dirty();
+        }
+        doc.update_language();
     } else if paths.is_empty() {
         // No files were passed, and stdin is not redirected.
         state.documents.add_untitled()?;
@@ -382,6 +384,9 @@ fn draw(ctx: &mut Context, state: &mut State) {
     if state.wants_about {
         draw_dialog_about(ctx, state);
     }
+    if state.wants_token_inspector {
+        draw_token_inspector(ctx, state);
+    }
     if state.osc_clipboard_seen_generation != ctx.clipboard_generation() {
         draw_handle_clipboard_change(ctx, state);
     }
@@ -408,6 +413,12 @@ fn draw(ctx: &mut Context, state: &mut State) {
             state.wants_exit = true;
         } else if key == kbmod::CTRL | vk::G {
             state.wants_goto = true;
+        } else if key == kbmod::ALT | vk::I {
+            state.token_inspection = state
+                .documents
+                .active()
+                .and_then(|doc| doc.buffer.borrow().inspect_syntax_at_cursor());
+            state.wants_token_inspector = true;
         } else if key == kbmod::CTRL | vk::F && state.wants_search.kind != StateSearchKind::Disabled
         {
             state.wants_search.kind = StateSearchKind::Search;
@@ -667,6 +678,13 @@ fn setup_terminal(tui: &mut Tui, vt_parser: &mut vt::Parser) -> RestoreModes {
     RestoreModes
 }
 
+/// Guesses whether the terminal can display 24-bit colors. Most terminals advertise it
+/// via `COLORTERM`, while all Windows terminals since Windows 10 support it.
+fn detect_truecolor() -> bool {
+    cfg!(windows)
+        || std::env::var_os("COLORTERM").is_some_and(|v| v == "truecolor" || v == "24bit")
+}
+
 /// Strips all C0 control characters from the string and replaces them with "_".
 ///
 /// Jury is still out on whether this should also strip C1 control characters.
@@ -147,11 +147,16 @@ pub struct State {
     pub wants_encoding_picker: bool,
     pub wants_encoding_change: StateEncodingChange,
     pub wants_indentation_picker: bool,
+    pub wants_language_picker: bool,
+    pub language_picker_filter: String,
     pub wants_document_picker: bool,
     pub wants_about: bool,
     pub wants_close: bool,
     pub wants_exit: bool,
     pub wants_goto: bool,
+    pub wants_token_inspector: bool,
+    /// Taken when the token inspector opens, so that it shows how the line was last drawn.
+    pub token_inspection: Option<buffer::TokenInspection>,
     pub goto_target: String,
     pub goto_invalid: bool,
 
@@ -192,11 +197,15 @@ impl State {
             wants_encoding_picker: false,
             wants_encoding_change: StateEncodingChange::None,
             wants_indentation_picker: false,
+            wants_language_picker: false,
+            language_picker_filter: Default::default(),
             wants_document_picker: false,
             wants_about: false,
             wants_close: false,
             wants_exit: false,
             wants_goto: false,
+            wants_token_inspector: false,
+            token_inspection: None,
             goto_target: Default::default(),
             goto_invalid: false,
 
@@ -218,13 +227,19 @@ pub fn draw_add_untitled_document(ctx: &mut Context, state: &mut State) {
 pub fn error_log_add(ctx: &mut Context, state: &mut State, err: apperr::Error) {
     let msg = format!("{}", FormatApperr::from(err));
     if !msg.is_empty() {
-        state.error_log[state.error_log_index] = msg;
-        state.error_log_index = (state.error_log_index + 1) % state.error_log.len();
-        state.error_log_count = state.error_log.len().min(state.error_log_count + 1);
+        error_log_push(state, msg);
         ctx.needs_rerender();
     }
 }
 
+/// Like `error_log_add`, but for messages that don't originate from an `apperr::Error`,
+/// and usable before the first frame is drawn.
+pub fn error_log_push(state: &mut State, msg: String) {
+    state.error_log[state.error_log_index] = msg;
+    state.error_log_index = (state.error_log_index + 1) % state.error_log.len();
+    state.error_log_count = state.error_log.len().min(state.error_log_count + 1);
+}
+
 pub fn draw_error_log(ctx: &mut Context, state: &mut State) {
     ctx.modal_begin("error", loc(LocId::ErrorDialogTitle));
     ctx.attr_background_rgba(ctx.indexed(IndexedColor::Red));
@@ -3,95 +3,288 @@
 
 //! Syntax highlighting integration for text buffer rendering.
 
-use crate::framebuffer::Framebuffer;
-use crate::helpers::{CoordType, Rect};
-use crate::syntax::{get_line_tokens, get_line_tokens_with_viewport, global_color_mapper, TokenInfo};
+use std::ops::Range;
+
 use crate::buffer::TextBuffer;
+use crate::framebuffer::{Attributes, Framebuffer};
+use crate::helpers::{CoordType, Rect};
+use crate::syntax::render_bridge::get_buffer_highlighting;
+use crate::syntax::{
+    get_line_tokens, global_color_mapper, inspect_line_tokens, update_viewport_tracking, Language, TokenInfo,
+    TokenSource,
+};
+use crate::unicode::{Cursor, MeasurementConfig};
 
-/// Renders a line of text with syntax highlighting to the framebuffer.
-/// 
-/// This function replaces the standard `fb.replace_text` call with multiple
-/// calls that apply appropriate colors to each syntax token.
-pub fn render_highlighted_line(
-    fb: &mut Framebuffer,
-    buffer: &TextBuffer,
-    line_content: &str,
-    line_number: usize,
-    y: CoordType,
-    left: CoordType,
-    right: CoordType,
-) {
-    render_highlighted_line_with_viewport(fb, buffer, line_content, line_number, y, left, right, None, None);
+/// What the highlighter produced for the line with the cursor, as shown by the token inspector.
+#[derive(Debug, Clone)]
+pub struct TokenInspection {
+    pub language: Language,
+    /// The zero-based logical line number.
+    pub line: CoordType,
+    /// The cursor's byte offset within the line.
+    pub offset: usize,
+    pub tokens: Vec<TokenInfo>,
+    /// Where the tokens came from when the line was last drawn.
+    pub source: TokenSource,
+}
+
+impl TokenInspection {
+    /// Returns the token right of the cursor, or at the end of the line, the last one.
+    pub fn token_at_cursor(&self) -> Option<&TokenInfo> {
+        let mut tokens = self.tokens.iter();
+        tokens
+            .clone()
+            .find(|token| (token.start_offset..token.end_offset).contains(&self.offset))
+            .or_else(|| tokens.rfind(|token| token.end_offset == self.offset))
+    }
 }
 
-/// Renders a line of text with syntax highlighting to the framebuffer with viewport information.
-/// 
-/// This function replaces the standard `fb.replace_text` call with multiple
-/// calls that apply appropriate colors to each syntax token.
-pub fn render_highlighted_line_with_viewport(
+/// Colors the visual line between `cursor_beg` and `cursor_end` according to its syntax.
+///
+/// The text must have already been written to the framebuffer; this only blends in
+/// the token colors. `area` is the part of the framebuffer row that shows the text
+/// (i.e. without the line number margin) and `origin_x` is the horizontal scroll offset.
+///
+/// The entire logical line is tokenized, with the lines above it providing the lexer
+/// state it starts in, so that a word-wrapped or horizontally scrolled segment is
+/// colored the same way as if the whole line was visible.
+pub fn render_line_highlighting(
     fb: &mut Framebuffer,
     buffer: &TextBuffer,
-    line_content: &str,
-    line_number: usize,
-    y: CoordType,
-    left: CoordType,
-    right: CoordType,
-    viewport_start: Option<usize>,
-    viewport_end: Option<usize>,
+    cursor_beg: Cursor,
+    cursor_end: Cursor,
+    origin_x: CoordType,
+    area: Rect,
 ) {
-    // Try to get syntax tokens for this line with viewport information
-    let tokens = if let (Some(start), Some(end)) = (viewport_start, viewport_end) {
-        get_line_tokens_with_viewport(buffer, line_content, line_number, start, end)
-    } else {
-        // Fallback to basic highlighting without viewport tracking
-        get_line_tokens(buffer, line_content, line_number)
+    let Some(language) = get_buffer_highlighting(buffer).and_then(|state| {
+        let state = state.borrow();
+        state.is_highlighting().then_some(state.language)
+    }) else {
+        return;
     };
-    
-    if let Some(tokens) = tokens {
-        if !tokens.is_empty() {
-            render_with_tokens(fb, &tokens, y, left, right);
-            return;
+
+    let line_beg = buffer.goto_line_start(cursor_beg, cursor_beg.logical_pos.y);
+    let text = buffer.logical_line_text_at(line_beg);
+
+    let Some(tokens) =
+        get_line_tokens(buffer, &text, line_beg.logical_pos.y as usize, line_texts(buffer, line_beg))
+    else {
+        return;
+    };
+
+    // Measure columns exactly like the cursor does, so that tabs, wide glyphs and
+    // grapheme clusters line up with the text that `TextBuffer::render` wrote.
+    let mut measurement = buffer.measurement_config().with_cursor(cursor_beg);
+    let columns = token_columns(&mut measurement, &tokens, line_beg.offset, cursor_end);
+    render_with_tokens(fb, &columns, language, origin_x, area);
+}
+
+/// Tells the highlighter which lines are visible, starting at `cursor_beg`, so that it can
+/// send them to its workers in a single request. `height` is the number of rows, which
+/// is at least the number of visible logical lines.
+pub fn track_viewport(buffer: &TextBuffer, cursor_beg: Cursor, height: CoordType) {
+    let first = cursor_beg.logical_pos.y.max(0) as usize;
+    update_viewport_tracking(buffer, first, first + height.max(0) as usize);
+}
+
+/// Looks up the tokens of the line with the cursor the same way [`render_line_highlighting`] does
+/// and reports them along with where they came from when the line was last drawn.
+pub fn inspect_cursor_line(buffer: &TextBuffer) -> Option<TokenInspection> {
+    let language = get_buffer_highlighting(buffer)?.borrow().language;
+    let line_beg = buffer.goto_line_start(buffer.cursor, buffer.cursor.logical_pos.y);
+    let text = buffer.logical_line_text_at(line_beg);
+    let line = line_beg.logical_pos.y;
+
+    let (tokens, source) = inspect_line_tokens(buffer, &text, line as usize, line_texts(buffer, line_beg))?;
+    let offset = (buffer.cursor.offset - line_beg.offset).min(text.len());
+    Some(TokenInspection { language, line, offset, tokens, source })
+}
+
+/// Returns a closure that provides the text of the lines around `line_beg`, for the highlighter
+/// to determine the lexer state the line starts in and to batch the lines below it.
+fn line_texts(buffer: &TextBuffer, line_beg: Cursor) -> impl FnMut(usize) -> Option<String> + '_ {
+    // Lines are mostly requested in ascending order, so we keep seeking from the last one.
+    let mut hint = line_beg;
+    move |line_number: usize| {
+        let beg = buffer.goto_line_start(hint, line_number as CoordType);
+        if beg.logical_pos.y != line_number as CoordType {
+            return None;
         }
+        hint = beg;
+        Some(buffer.logical_line_text_at(beg))
     }
-    
-    // Fallback to normal rendering without highlighting
-    fb.replace_text(y, left, right, line_content);
 }
 
-/// Renders a line using syntax highlighting tokens.
+/// Blends the colors of the tokens, as measured by [`token_columns`], into the framebuffer.
+///
+/// The theme's overrides for `language` apply.
 fn render_with_tokens(
     fb: &mut Framebuffer,
-    tokens: &[TokenInfo],
-    y: CoordType,
-    left: CoordType,
-    right: CoordType,
+    tokens: &[(Range<CoordType>, &str)],
+    language: Language,
+    origin_x: CoordType,
+    area: Rect,
 ) {
     let color_mapper = global_color_mapper();
-    let mut current_x = left;
-    
-    for token in tokens {
-        if current_x >= right {
+
+    for (columns, kind) in tokens {
+        let rect = Rect {
+            left: (area.left + columns.start - origin_x).max(area.left),
+            top: area.top,
+            right: (area.left + columns.end - origin_x).min(area.right),
+            bottom: area.bottom,
+        };
+        if rect.left >= area.right {
             break;
         }
-        
-        let token_right = (current_x + token.text.chars().count() as CoordType).min(right);
-        
-        // Apply the token's text
-        fb.replace_text(y, current_x, token_right, &token.text);
-        
-        // Apply the token's color if it has a type
-        if let Some(ref kind) = token.kind {
-            let color = color_mapper.get_color(kind);
-            let color_rgba = fb.indexed(color);
-            fb.blend_fg(Rect { left: current_x, top: y, right: token_right, bottom: y + 1 }, color_rgba);
+        if rect.left >= rect.right {
+            continue;
+        }
+
+        let Some(style) = color_mapper.resolve_in(language, kind, fb.indexed_colors()) else {
+            continue;
+        };
+        if let Some(color) = style.foreground {
+            fb.blend_fg(rect, color);
+        }
+        if let Some(color) = style.background {
+            fb.blend_bg(rect, color);
+        }
+        if style.attributes != Attributes::None {
+            fb.replace_attr(rect, style.attributes, style.attributes);
         }
-        
-        current_x = token_right;
     }
-    
-    // Clear any remaining space on the line
-    if current_x < right {
-        fb.replace_text(y, current_x, right, "");
+}
+
+/// Computes the visual columns covered by each highlighted token within the visible segment.
+///
+/// Token offsets are relative to `line_offset`. `measurement` must start at the beginning
+/// of the visible segment, which ends at `cursor_end`. Parts of tokens outside of it are skipped.
+fn token_columns<'a>(
+    measurement: &mut MeasurementConfig,
+    tokens: &'a [TokenInfo],
+    line_offset: usize,
+    cursor_end: Cursor,
+) -> Vec<(Range<CoordType>, &'a str)> {
+    let segment_beg = measurement.cursor().offset;
+    let segment_end = cursor_end.offset;
+    let mut columns = Vec::new();
+
+    for token in tokens {
+        let Some(kind) = &token.kind else {
+            continue;
+        };
+
+        let beg = (line_offset + token.start_offset).clamp(segment_beg, segment_end);
+        let end = (line_offset + token.end_offset).clamp(segment_beg, segment_end);
+        if beg >= segment_end {
+            break;
+        }
+        if beg >= end {
+            continue;
+        }
+
+        // Tokens are sorted, so we can keep measuring forward. With word wrap enabled, the
+        // end of the segment may measure as the start of the next row, so we use `cursor_end`.
+        let left = measurement.goto_offset(beg).visual_pos.x;
+        let right = if end == segment_end {
+            cursor_end.visual_pos.x
+        } else {
+            measurement.goto_offset(end).visual_pos.x
+        };
+
+        columns.push((left..right, kind.as_str()));
     }
+
+    columns
 }
 
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::helpers::Point;
+    use crate::syntax::render_bridge::{register_buffer_highlighting, unregister_buffer_highlighting};
+    use crate::syntax::{HighlightingState, SyntaxHighlighter, global_highlighting_service};
+
+    #[test]
+    fn test_token_columns_match_cursor_measurement() {
+        // A tab, two wide glyphs, and a combining mark all precede highlighted tokens.
+        let text = "\t日本 fn e\u{301}x fn";
+        let mut buffer = TextBuffer::new(false).unwrap();
+        buffer.set_tab_size(4);
+        buffer.copy_from_str(&text.to_string());
+
+        let tokens = SyntaxHighlighter::new(Language::Rust).highlight_line(text, 0).unwrap();
+        let cursor_end = buffer.cursor_move_to_offset_internal(Cursor::default(), text.len());
+        let mut measurement = buffer.measurement_config();
+        let columns = token_columns(&mut measurement, &tokens, 0, cursor_end);
+
+        assert_eq!(columns, vec![(9..11, "keyword"), (15..17, "keyword")]);
+    }
+
+    #[test]
+    fn test_token_columns_clip_to_segment() {
+        let text = "fn main() { let x = 1; }";
+        let mut buffer = TextBuffer::new(false).unwrap();
+        buffer.copy_from_str(&text.to_string());
+
+        let tokens = SyntaxHighlighter::new(Language::Rust).highlight_line(text, 0).unwrap();
+        let cursor_beg = buffer.cursor_move_to_offset_internal(Cursor::default(), 13);
+        let cursor_end = buffer.cursor_move_to_offset_internal(cursor_beg, 20);
+        let mut measurement = buffer.measurement_config().with_cursor(cursor_beg);
+        let columns = token_columns(&mut measurement, &tokens, 0, cursor_end);
+
+        // "let" is cut off at the left edge and "1" is past the right edge.
+        assert_eq!(columns, vec![(13..15, "keyword")]);
+    }
+
+    #[test]
+    fn test_inspect_cursor_line() {
+        crate::arena::init(16 * 1024 * 1024).unwrap();
+
+        let mut buffer = TextBuffer::new(false).unwrap();
+        buffer.write(b"/* a\nb */ fn main() {}", true);
+        assert!(buffer.inspect_syntax_at_cursor().is_none());
+
+        let state = Rc::new(RefCell::new(HighlightingState::new(Language::Rust)));
+        register_buffer_highlighting(&buffer, state);
+
+        // Looks up the line's tokens like drawing it does.
+        let draw = |buffer: &TextBuffer| {
+            let line_beg = buffer.goto_line_start(Cursor::default(), 1);
+            let text = buffer.logical_line_text_at(line_beg);
+            get_line_tokens(buffer, &text, 1, line_texts(buffer, line_beg)).unwrap();
+        };
+
+        draw(&buffer);
+        assert_eq!(buffer.inspect_syntax_at_cursor().unwrap().source, TokenSource::Pending);
+        assert!(global_highlighting_service().wait_for_async_results(Duration::from_secs(10)));
+
+        // The line starts inside the comment that the line above opened.
+        draw(&buffer);
+        buffer.cursor_move_to_logical(Point { x: 6, y: 1 });
+        let inspection = buffer.inspect_syntax_at_cursor().unwrap();
+        assert_eq!((inspection.language, inspection.line, inspection.offset), (Language::Rust, 1, 6));
+        assert_eq!(inspection.source, TokenSource::Highlighted);
+        let token = inspection.token_at_cursor().unwrap();
+        assert_eq!((token.kind.as_deref(), token.text.as_str()), (Some("keyword"), "fn"));
+        assert_eq!(inspection.tokens[0].kind.as_deref(), Some("comment"));
+
+        // At the end of the line, the last token is the one at the cursor.
+        // Inspecting doesn't count as drawing the line, but drawing it again does.
+        buffer.cursor_move_to_logical(Point { x: CoordType::MAX, y: 1 });
+        assert_eq!(buffer.inspect_syntax_at_cursor().unwrap().source, TokenSource::Highlighted);
+        draw(&buffer);
+        let inspection = buffer.inspect_syntax_at_cursor().unwrap();
+        assert_eq!(inspection.source, TokenSource::Cache);
+        assert_eq!(inspection.offset, 17);
+        let token = inspection.token_at_cursor().unwrap();
+        assert_eq!((token.kind.as_deref(), token.text.as_str()), (None, " main() {}"));
+
+        unregister_buffer_highlighting(&buffer);
+    }
+}
@@ -37,6 +37,7 @@ use std::rc::Rc;
 use std::str;
 
 use gap_buffer::GapBuffer;
+pub use highlighting_render::TokenInspection;
 
 use crate::arena::{ArenaString, scratch_arena};
 use crate::cell::SemiRefCell;
@@ -327,6 +328,8 @@ impl TextBuffer {
         let mut cursor_for_rendering_offset =
             self.cursor_for_rendering.map_or(cursor_offset, |c| c.offset);
 
+        let mut replaced_newlines = false;
+
         #[cfg(debug_assertions)]
         let mut adjusted_newlines = 0;
 
@@ -370,6 +373,7 @@ impl TextBuffer {
                 off -= chunk_newline_len;
                 self.buffer.replace(off..off + chunk_newline_len, newline);
                 off += newline.len();
+                replaced_newlines = true;
             }
         }
 
@@ -383,6 +387,11 @@ impl TextBuffer {
         }
 
         self.newlines_are_crlf = crlf;
+
+        if replaced_newlines {
+            let last_line = self.stats.logical_lines.max(1) as usize - 1;
+            text_change_notifier::notify_replace_operation(self, 0, last_line, 0);
+        }
     }
 
     /// If enabled, automatically insert a final newline
@@ -1455,6 +1464,40 @@ impl TextBuffer {
         cursor.offset
     }
 
+    /// Returns the text of the given logical line without its trailing newline,
+    /// or `None` if the line doesn't exist.
+    pub fn logical_line_text(&self, line: CoordType) -> Option<String> {
+        let beg = self.goto_line_start(self.cursor, line);
+        (beg.logical_pos.y == line).then(|| self.logical_line_text_at(beg))
+    }
+
+    /// Returns what the syntax highlighter produced for the line with the cursor,
+    /// or `None` if highlighting is disabled for this buffer.
+    pub fn inspect_syntax_at_cursor(&self) -> Option<TokenInspection> {
+        highlighting_render::inspect_cursor_line(self)
+    }
+
+    /// Returns the text of the logical line that `beg` is the start of,
+    /// without its trailing newline. Invalid UTF-8 is replaced with U+FFFD.
+    fn logical_line_text_at(&self, beg: Cursor) -> String {
+        let next = self.goto_line_start(beg, beg.logical_pos.y + 1);
+        let end = if next.logical_pos.y > beg.logical_pos.y { next.offset } else { self.text_length() };
+
+        let mut text = Vec::new();
+        self.buffer.extract_raw(beg.offset, end, &mut text, 0);
+        if text.last() == Some(&b'\n') {
+            text.pop();
+            if text.last() == Some(&b'\r') {
+                text.pop();
+            }
+        }
+
+        match String::from_utf8(text) {
+            Ok(text) => text,
+            Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+        }
+    }
+
     /// Sets the cursor to the given position, and clears the selection.
     ///
     /// # Safety
@@ -1547,6 +1590,7 @@ impl TextBuffer {
             // Accelerate the next render pass by remembering where we started off.
             if y == 0 {
                 self.cursor_for_rendering = Some(cursor_beg);
+                highlighting_render::track_viewport(self, cursor_beg, height);
             }
 
             if line_number_width != 0 {
@@ -1672,16 +1716,25 @@ impl TextBuffer {
                 visual_pos_x_max = visual_pos_x_max.max(cursor_end.visual_pos.x);
             }
 
-            // Use syntax highlighting if available
-            highlighting_render::render_highlighted_line(
-                fb,
-                self,
-                &line,
-                visual_line as usize,
-                destination.top + y,
-                destination.left,
-                destination.right,
-            );
+            fb.replace_text(destination.top + y, destination.left, destination.right, &line);
+
+            // Color the text according to its syntax, if highlighting is available.
+            if cursor_beg.visual_pos.y == visual_line && cursor_beg.offset != cursor_end.offset {
+                let top = destination.top + y;
+                highlighting_render::render_line_highlighting(
+                    fb,
+                    self,
+                    cursor_beg,
+                    cursor_end,
+                    origin.x,
+                    Rect {
+                        left: destination.left + self.margin_width,
+                        top,
+                        right: destination.right,
+                        bottom: top + 1,
+                    },
+                );
+            }
 
             // Draw the selection on this line, if any.
             // FYI: `cursor_beg.visual_pos.y == visual_line` is necessary as the `visual_line`
@@ -2222,8 +2275,16 @@ impl TextBuffer {
         // Move self.cursor to the end of the newly written text. Can't use `self.set_cursor_internal`,
         // because we're still in the progress of recalculating the line stats.
         self.active_edit_off += text.len();
+        let cursor_before = self.cursor.logical_pos;
         self.cursor = self.cursor_move_to_offset_internal(self.cursor, self.active_edit_off);
         self.stats.logical_lines += self.cursor.logical_pos.y - logical_y_before;
+
+        text_change_notifier::notify_edit_operation(
+            self,
+            cursor_before,
+            self.cursor.logical_pos,
+            false,
+        );
     }
 
     /// Deletes the text between the current cursor position and `to`.
@@ -2250,6 +2311,14 @@ impl TextBuffer {
         self.buffer.allocate_gap(off, 0, count);
 
         self.stats.logical_lines += logical_y_before - to.logical_pos.y;
+
+        drop(undo);
+        text_change_notifier::notify_edit_operation(
+            self,
+            to.logical_pos,
+            self.cursor.logical_pos,
+            true,
+        );
     }
 
     /// Finalizes the current edit operation
@@ -2261,27 +2330,11 @@ impl TextBuffer {
             return;
         }
 
-        // Notify highlighting system about the text change
-        // Extract data needed for notification before calling it
-        let cursor_before;
-        let was_deletion;
+        #[cfg(debug_assertions)]
         {
-            let entry = self.undo_stack.back().unwrap().borrow();
-            #[cfg(debug_assertions)]
+            let entry = self.undo_stack.back_mut().unwrap().borrow_mut();
             debug_assert!(!entry.deleted.is_empty() || !entry.added.is_empty());
-            
-            cursor_before = entry.cursor;
-            was_deletion = !entry.deleted.is_empty();
-        } // Ensure borrow is definitely dropped here
-        
-        let cursor_after = self.cursor.logical_pos;
-        
-        text_change_notifier::notify_edit_operation(
-            self,
-            cursor_before,
-            cursor_after,
-            was_deletion,
-        );
+        }
 
         if let Some(info) = self.active_edit_line_info.take() {
             let deleted_count = self.undo_stack.back_mut().unwrap().borrow_mut().deleted.len();
@@ -2361,6 +2414,9 @@ impl TextBuffer {
             cursor
         };
 
+        let lines_removed;
+        let lines_added;
+
         {
             let buffer_generation = self.buffer.generation();
             let mut change = change.borrow_mut();
@@ -2369,6 +2425,9 @@ impl TextBuffer {
             // Undo: Whatever was deleted is now added and vice versa.
             mem::swap(&mut change.deleted, &mut change.added);
 
+            lines_removed = unicode::newlines_forward(&change.deleted, 0, 0, CoordType::MAX).1;
+            lines_added = unicode::newlines_forward(&change.added, 0, 0, CoordType::MAX).1;
+
             // Delete the inserted portion.
             self.buffer.allocate_gap(cursor.offset, 0, change.deleted.len());
 
@@ -2431,15 +2490,12 @@ impl TextBuffer {
         }
 
         // Notify highlighting system about the undo/redo operation
-        {
-            let change = if undo { self.redo_stack.back().unwrap() } else { self.undo_stack.back().unwrap() };
-            let change = change.borrow();
-            let start_line = change.cursor.y.min(change.cursor_before.y) as usize;
-            let end_line = change.cursor.y.max(change.cursor_before.y) as usize;
-            let line_delta = (self.stats.logical_lines as isize) - (change.stats_before.logical_lines as isize);
-            
-            text_change_notifier::notify_undo_redo(self, start_line, end_line, line_delta);
-        }
+        text_change_notifier::notify_undo_redo(
+            self,
+            cursor.logical_pos.y as usize,
+            lines_removed as usize,
+            lines_added as usize,
+        );
 
         // Also takes care of clearing `cursor_for_rendering`.
         self.reflow(false);
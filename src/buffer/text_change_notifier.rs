@@ -32,8 +32,6 @@ pub enum TextChangeType {
     Delete,
     /// Text was replaced
     Replace,
-    /// Multiple changes occurred (e.g., undo/redo)
-    Multiple,
 }
 
 impl TextChangeNotification {
@@ -99,15 +97,21 @@ pub fn notify_text_change(
         
         match notification.change_type {
             TextChangeType::Insert => {
+                // The line the insertion started on has changed, and the lines after it
+                // moved down by the number of inserted newlines.
                 let lines_added = notification.line_delta.max(0) as usize;
-                state.handle_text_insert(notification.start_line, lines_added);
+                state.handle_text_insert(notification.start_line + 1, lines_added);
+                state.mark_line_dirty(notification.start_line);
             }
             TextChangeType::Delete => {
+                // The line the deletion started on has been joined with the line the deletion
+                // ended on, and the lines in between are gone.
                 let lines_deleted = (-notification.line_delta).max(0) as usize;
-                state.handle_text_delete(notification.start_line, lines_deleted);
+                state.handle_text_delete(notification.start_line + 1, lines_deleted);
+                state.mark_line_dirty(notification.start_line);
             }
-            TextChangeType::Replace | TextChangeType::Multiple => {
-                // For replace or multiple changes, invalidate the affected range
+            TextChangeType::Replace => {
+                // For replacements, invalidate the affected range
                 state.mark_lines_dirty(notification.start_line, notification.end_line);
                 
                 // If lines were added or removed, we need to shift the cache
@@ -123,11 +127,15 @@ pub fn notify_text_change(
 
 /// Calculates the line delta between two cursor positions.
 pub fn calculate_line_delta(before: Point, after: Point) -> isize {
-    after.y as isize - before.y as isize
+    after.y - before.y
 }
 
 /// Notifies about a text edit operation.
 /// This should be called after any text modification in the buffer.
+///
+/// For insertions `cursor_before` is where the text was inserted and `cursor_after`
+/// is the end of the inserted text. For deletions `cursor_before` is the end of the
+/// deleted range and `cursor_after` is its start, which is where the cursor ends up.
 pub fn notify_edit_operation(
     buffer: &TextBuffer,
     cursor_before: Point,
@@ -140,40 +148,32 @@ pub fn notify_edit_operation(
         if line_delta < 0 {
             TextChangeNotification::delete(cursor_after.y as usize, (-line_delta) as usize)
         } else {
-            TextChangeNotification::single_line(cursor_before.y as usize, TextChangeType::Delete)
+            TextChangeNotification::single_line(cursor_after.y as usize, TextChangeType::Delete)
         }
+    } else if line_delta > 0 {
+        TextChangeNotification::insert(cursor_before.y as usize, line_delta as usize)
     } else {
-        if line_delta > 0 {
-            TextChangeNotification::insert(cursor_before.y as usize, line_delta as usize)
-        } else {
-            TextChangeNotification::single_line(cursor_after.y as usize, TextChangeType::Insert)
-        }
+        TextChangeNotification::single_line(cursor_before.y as usize, TextChangeType::Insert)
     };
     
     notify_text_change(buffer, &notification);
 }
 
 /// Notifies about an undo/redo operation.
-/// This marks a wider range as dirty since undo/redo can affect multiple lines.
+/// An undo/redo step removes some text at `line` and inserts other text in its place,
+/// so this is reported as a deletion followed by an insertion.
 pub fn notify_undo_redo(
     buffer: &TextBuffer,
-    start_line: usize,
-    end_line: usize,
-    line_delta: isize,
+    line: usize,
+    lines_removed: usize,
+    lines_added: usize,
 ) {
-    let notification = TextChangeNotification::new(
-        start_line,
-        end_line,
-        line_delta,
-        TextChangeType::Multiple,
-    );
-    
-    notify_text_change(buffer, &notification);
+    notify_text_change(buffer, &TextChangeNotification::delete(line, lines_removed));
+    notify_text_change(buffer, &TextChangeNotification::insert(line, lines_added));
 }
 
-/// Notifies about a find-and-replace operation.
-/// This should be called when text is replaced via find/replace functionality.
-#[allow(dead_code)]
+/// Notifies about a replacement of the lines `start_line..=end_line`.
+/// This should be called when text is modified without going through the regular edit path.
 pub fn notify_replace_operation(
     buffer: &TextBuffer,
     start_line: usize,
@@ -186,7 +186,12 @@ pub fn notify_replace_operation(
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
     use super::*;
+    use crate::buffer::CursorMovement;
+    use crate::syntax::{HighlightingState, Language, TokenInfo};
 
     #[test]
     fn test_text_change_notification_creation() {
@@ -229,4 +234,61 @@ mod tests {
         let after = Point { x: 10, y: 5 };
         assert_eq!(calculate_line_delta(before, after), 0);
     }
-}
\ No newline at end of file
+
+    fn cache_lines(state: &mut HighlightingState, count: usize) {
+        for line in 0..count {
+            let tokens = vec![TokenInfo::plain_text(format!("line {line}"), 0, 6)];
+            state.cache_tokens(line, line as u64, &tokens);
+        }
+    }
+
+    /// Returns the text of the line that was originally cached at `line`.
+    fn cached_text(state: &HighlightingState, line: usize) -> Option<String> {
+        let original = state.token_cache().content_hash(line)?;
+        state.get_cached_tokens(line, &format!("line {original}")).map(|tokens| tokens[0].text.clone())
+    }
+
+    #[test]
+    fn test_buffer_edits_shift_cache() {
+        crate::arena::init(16 * 1024 * 1024).unwrap();
+
+        let mut buffer = TextBuffer::new(false).unwrap();
+        buffer.write(b"a\nb\nc\nd\ne", true);
+
+        let state = Rc::new(RefCell::new(HighlightingState::new(Language::Rust)));
+        render_bridge::register_buffer_highlighting(&buffer, state.clone());
+        cache_lines(&mut state.borrow_mut(), 5);
+
+        // Splitting line 1 invalidates it and moves the lines below it down.
+        buffer.cursor_move_to_logical(Point { x: 1, y: 1 });
+        buffer.write(b"\n\n", false);
+        {
+            let state = state.borrow();
+            assert_eq!(cached_text(&state, 0).as_deref(), Some("line 0"));
+            assert_eq!(cached_text(&state, 1), None);
+            assert!(state.is_line_dirty(1) && state.is_line_dirty(2) && state.is_line_dirty(3));
+            assert_eq!(cached_text(&state, 4).as_deref(), Some("line 2"));
+            assert_eq!(cached_text(&state, 6).as_deref(), Some("line 4"));
+        }
+
+        // Undoing it moves them back up.
+        buffer.undo();
+        {
+            let state = state.borrow();
+            assert_eq!(cached_text(&state, 2).as_deref(), Some("line 2"));
+            assert_eq!(cached_text(&state, 4).as_deref(), Some("line 4"));
+            assert_eq!(cached_text(&state, 5), None);
+        }
+
+        // Deleting the newline at the end of line 2 joins it with line 3.
+        buffer.cursor_move_to_logical(Point { x: 1, y: 2 });
+        buffer.delete(CursorMovement::Grapheme, 1);
+        {
+            let state = state.borrow();
+            assert_eq!(cached_text(&state, 2), None);
+            assert_eq!(cached_text(&state, 3).as_deref(), Some("line 4"));
+        }
+
+        render_bridge::unregister_buffer_highlighting(&buffer);
+    }
+}
@@ -291,6 +291,11 @@ impl Framebuffer {
         ((thumb_height + 4) / 8) as CoordType
     }
 
+    /// Returns the color palette.
+    pub fn indexed_colors(&self) -> &[u32; INDEXED_COLORS_COUNT] {
+        &self.indexed_colors
+    }
+
     #[inline]
     pub fn indexed(&self, index: IndexedColor) -> u32 {
         self.indexed_colors[index as usize]
@@ -472,6 +477,13 @@ impl Framebuffer {
 
                 if last_attr != attr {
                     let diff = last_attr ^ attr;
+                    if diff.is(Attributes::Bold) {
+                        if attr.is(Attributes::Bold) {
+                            result.push_str("\x1b[1m");
+                        } else {
+                            result.push_str("\x1b[22m");
+                        }
+                    }
                     if diff.is(Attributes::Italic) {
                         if attr.is(Attributes::Italic) {
                             result.push_str("\x1b[3m");
@@ -788,7 +800,7 @@ impl Bitmap {
 ///
 /// It being a bitfield allows for simple diffing.
 #[repr(transparent)]
-#[derive(Default, Clone, Copy, PartialEq, Eq)]
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Attributes(u8);
 
 #[allow(non_upper_case_globals)]
@@ -796,7 +808,8 @@ impl Attributes {
     pub const None: Self = Self(0);
     pub const Italic: Self = Self(0b1);
     pub const Underlined: Self = Self(0b10);
-    pub const All: Self = Self(0b11);
+    pub const Bold: Self = Self(0b100);
+    pub const All: Self = Self(0b111);
 
     pub const fn is(self, attr: Self) -> bool {
         (self.0 & attr.0) == attr.0
@@ -0,0 +1,468 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A small JSON parser for configuration files.
+//!
+//! Since configuration files are written by humans, the parser is lenient in the
+//! same way VS Code's is: `//` and `/* */` comments as well as trailing commas are allowed.
+//! Objects preserve the order of their keys, which matters for things like rule lists.
+//!
+//! Values can also be written back out as (strict) JSON via their `Display` implementation.
+
+use std::fmt::{self, Write as _};
+
+/// A parsed JSON value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Returns the value for the given key if this is an object that contains it.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_object()?.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Value)]> {
+        match self {
+            Value::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
+    /// Returns a short, human readable name of the value's type for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "a boolean",
+            Value::Number(_) => "a number",
+            Value::String(_) => "a string",
+            Value::Array(_) => "an array",
+            Value::Object(_) => "an object",
+        }
+    }
+}
+
+/// Writes the value as JSON. The alternate form (`{:#}`) is indented with 2 spaces per level.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, f.alternate().then_some(0))
+    }
+}
+
+impl Value {
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: Option<usize>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{b}"),
+            // JSON can't represent NaN or infinities.
+            Value::Number(n) if !n.is_finite() => f.write_str("null"),
+            Value::Number(n) => write!(f, "{n}"),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) => {
+                write_container(f, indent, ['[', ']'], items.iter().map(|v| (None, v)))
+            }
+            Value::Object(entries) => write_container(
+                f,
+                indent,
+                ['{', '}'],
+                entries.iter().map(|(k, v)| (Some(k.as_str()), v)),
+            ),
+        }
+    }
+}
+
+fn write_container<'a>(
+    f: &mut fmt::Formatter<'_>,
+    indent: Option<usize>,
+    [open, close]: [char; 2],
+    items: impl Iterator<Item = (Option<&'a str>, &'a Value)>,
+) -> fmt::Result {
+    let inner = indent.map(|level| level + 1);
+    let mut empty = true;
+
+    f.write_char(open)?;
+    for (key, value) in items {
+        if !empty {
+            f.write_char(',')?;
+        }
+        empty = false;
+        if let Some(level) = inner {
+            write!(f, "\n{:1$}", "", level * 2)?;
+        }
+        if let Some(key) = key {
+            write_string(f, key)?;
+            f.write_str(if inner.is_some() { ": " } else { ":" })?;
+        }
+        value.write(f, inner)?;
+    }
+    if let Some(level) = indent
+        && !empty
+    {
+        write!(f, "\n{:1$}", "", level * 2)?;
+    }
+    f.write_char(close)
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for ch in s.chars() {
+        match ch {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            '\0'..='\x1f' => write!(f, "\\u{:04x}", ch as u32)?,
+            _ => f.write_char(ch)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// A syntax error, with the 1-based line and column it occurred at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub column: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at line {}, column {}", self.message, self.line, self.column)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parses a JSON document.
+pub fn parse(text: &str) -> Result<Value, Error> {
+    let mut parser = Parser { text, pos: 0, depth: 0 };
+    let value = parser.parse_value()?;
+    parser.skip_trivia()?;
+    if parser.pos != text.len() {
+        return Err(parser.error("Unexpected trailing characters"));
+    }
+    Ok(value)
+}
+
+/// Guards against stack overflows caused by deeply nested documents.
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &'static str) -> Error {
+        let before = &self.text[..self.pos.min(self.text.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rfind('\n').map_or(before, |i| &before[i + 1..]).chars().count() + 1;
+        Error { line, column, message }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_trivia(&mut self) -> Result<(), Error> {
+        loop {
+            let rest = &self.text[self.pos..];
+            let trimmed = rest.trim_start_matches([' ', '\t', '\r', '\n', '\u{feff}']);
+            self.pos += rest.len() - trimmed.len();
+
+            if trimmed.starts_with("//") {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if let Some(rest) = trimmed.strip_prefix("/*") {
+                match rest.find("*/") {
+                    Some(end) => self.pos += end + 4,
+                    None => return Err(self.error("Unterminated comment")),
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, Error> {
+        self.skip_trivia()?;
+        match self.peek() {
+            Some(b'{') => self.parse_container(b'}'),
+            Some(b'[') => self.parse_container(b']'),
+            Some(b'"') => self.parse_string().map(Value::String),
+            Some(b't') => self.parse_literal("true", Value::Bool(true)),
+            Some(b'f') => self.parse_literal("false", Value::Bool(false)),
+            Some(b'n') => self.parse_literal("null", Value::Null),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    /// Parses an object (if `close` is `}`) or an array (if `close` is `]`).
+    fn parse_container(&mut self, close: u8) -> Result<Value, Error> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error("Nesting too deep"));
+        }
+        self.pos += 1;
+
+        let mut array = Vec::new();
+        let mut object = Vec::new();
+
+        loop {
+            self.skip_trivia()?;
+            if self.peek() == Some(close) {
+                self.pos += 1;
+                break;
+            }
+
+            if close == b'}' {
+                if self.peek() != Some(b'"') {
+                    return Err(self.error("Expected a string key"));
+                }
+                let key = self.parse_string()?;
+                self.skip_trivia()?;
+                if self.peek() != Some(b':') {
+                    return Err(self.error("Expected ':'"));
+                }
+                self.pos += 1;
+                object.push((key, self.parse_value()?));
+            } else {
+                array.push(self.parse_value()?);
+            }
+
+            self.skip_trivia()?;
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(c) if c == close => {}
+                Some(_) => return Err(self.error("Expected ',' or a closing bracket")),
+                None => return Err(self.error("Unexpected end of input")),
+            }
+        }
+
+        self.depth -= 1;
+        Ok(if close == b'}' { Value::Object(object) } else { Value::Array(array) })
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: Value) -> Result<Value, Error> {
+        if self.text[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            Err(self.error("Unexpected character"))
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value, Error> {
+        let rest = &self.text[self.pos..];
+        let len = rest
+            .find(|c: char| !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+            .unwrap_or(rest.len());
+        match rest[..len].parse() {
+            Ok(n) => {
+                self.pos += len;
+                Ok(Value::Number(n))
+            }
+            Err(_) => Err(self.error("Invalid number")),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, Error> {
+        // Skip the opening quote.
+        self.pos += 1;
+        let mut str = String::new();
+
+        loop {
+            let rest = &self.text[self.pos..];
+            let Some(i) = rest.find(['"', '\\', '\n']) else {
+                return Err(self.error("Unterminated string"));
+            };
+            str.push_str(&rest[..i]);
+            self.pos += i;
+
+            match rest.as_bytes()[i] {
+                b'"' => {
+                    self.pos += 1;
+                    return Ok(str);
+                }
+                b'\\' => {
+                    self.pos += 1;
+                    let Some(c) = self.peek() else {
+                        return Err(self.error("Unterminated string"));
+                    };
+                    self.pos += 1;
+                    match c {
+                        b'"' => str.push('"'),
+                        b'\\' => str.push('\\'),
+                        b'/' => str.push('/'),
+                        b'b' => str.push('\x08'),
+                        b'f' => str.push('\x0c'),
+                        b'n' => str.push('\n'),
+                        b'r' => str.push('\r'),
+                        b't' => str.push('\t'),
+                        b'u' => str.push(self.parse_unicode_escape()?),
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.error("Invalid escape sequence"));
+                        }
+                    }
+                }
+                _ => return Err(self.error("Unterminated string")),
+            }
+        }
+    }
+
+    /// Parses the hex digits of a `\u` escape, including surrogate pairs.
+    fn parse_unicode_escape(&mut self) -> Result<char, Error> {
+        let hi = self.parse_hex4()?;
+        let code = if (0xD800..0xDC00).contains(&hi) {
+            if !self.text[self.pos..].starts_with("\\u") {
+                return Err(self.error("Unpaired surrogate"));
+            }
+            self.pos += 2;
+            let lo = self.parse_hex4()?;
+            if !(0xDC00..0xE000).contains(&lo) {
+                return Err(self.error("Unpaired surrogate"));
+            }
+            0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00)
+        } else {
+            hi
+        };
+        char::from_u32(code).ok_or_else(|| self.error("Invalid unicode escape"))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, Error> {
+        // `from_str_radix` would also accept a sign, as in `\u+abc`.
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .filter(|d| d.bytes().all(|b| b.is_ascii_hexdigit()));
+        match digits.and_then(|d| u32::from_str_radix(d, 16).ok()) {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => Err(self.error("Invalid unicode escape")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(parse("null"), Ok(Value::Null));
+        assert_eq!(parse(" true "), Ok(Value::Bool(true)));
+        assert_eq!(parse("-1.5e2"), Ok(Value::Number(-150.0)));
+        assert_eq!(parse(r#""a\"bé😀""#), Ok(Value::String("a\"bé😀".to_string())));
+        assert_eq!(
+            parse(r#"[1, "x", [], {}]"#),
+            Ok(Value::Array(vec![
+                Value::Number(1.0),
+                Value::String("x".to_string()),
+                Value::Array(vec![]),
+                Value::Object(vec![]),
+            ]))
+        );
+
+        let value = parse(r#"{"b": 1, "a": {"c": [true]}}"#).unwrap();
+        let keys: Vec<_> = value.as_object().unwrap().iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["b", "a"]);
+        assert_eq!(
+            value.get("a").and_then(|a| a.get("c")).and_then(|c| c.as_array()).map(|c| c.len()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_comments_and_trailing_commas() {
+        let text =
+            "// Header\n{\n  /* inline */ \"a\": [1, 2,],\n  \"b\": \"//not a comment\",\n}\n";
+        let value = parse(text).unwrap();
+        assert_eq!(value.get("a").and_then(Value::as_array).map(<[Value]>::len), Some(2));
+        assert_eq!(value.get("b").and_then(Value::as_str), Some("//not a comment"));
+    }
+
+    #[test]
+    fn test_write() {
+        let value =
+            parse(r#"{"a": [1, 2.5, -0.125, true, null], "b\n\"": "\u0001é", "c": {}, "d": []}"#)
+                .unwrap();
+        assert_eq!(
+            value.to_string(),
+            r#"{"a":[1,2.5,-0.125,true,null],"b\n\"":"\u0001é","c":{},"d":[]}"#
+        );
+        let pretty = r#"{
+  "a": [
+    1,
+    2.5,
+    -0.125,
+    true,
+    null
+  ],
+  "b\n\"": "\u0001é",
+  "c": {},
+  "d": []
+}"#;
+        assert_eq!(format!("{value:#}"), pretty);
+        assert_eq!(parse(&format!("{value:#}")), Ok(value));
+
+        assert_eq!(Value::Number(f64::NAN).to_string(), "null");
+        assert_eq!(Value::Number(1e21).to_string(), "1000000000000000000000");
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = parse("{\n  \"a\": tru\n}").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 8));
+        assert_eq!(err.to_string(), "Unexpected character at line 2, column 8");
+
+        assert!(parse("").is_err());
+        assert!(parse("[1 2]").is_err());
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("\"abc").is_err());
+        assert!(parse("/* open").is_err());
+        assert!(parse("1 1").is_err());
+        assert!(parse(&"[".repeat(1000)).is_err());
+        assert!(parse(r#""\u+abc""#).is_err());
+    }
+}
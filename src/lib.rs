@@ -23,9 +23,11 @@ pub mod buffer;
 pub mod cell;
 pub mod document;
 pub mod framebuffer;
+pub mod fuzzy;
 pub mod hash;
 pub mod helpers;
 pub mod icu;
+pub mod json;
 pub mod input;
 pub mod oklab;
 pub mod path;
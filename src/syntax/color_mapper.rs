@@ -3,20 +3,460 @@
 
 //! Maps syntax highlighting tokens to terminal colors.
 //!
-//! This module provides the mapping from Synoptic token types to Edit's 
-//! IndexedColor system, with support for different color themes and 
-//! terminal capabilities.
+//! Colors are organized in [`Theme`]s that assign a [`TokenStyle`] to each token kind.
+//! Light and dark themes are built in, one of which is picked based on the terminal's
+//! background color, as well as a theme that uses the terminal's 16 indexed colors,
+//! so that it matches the user's palette. Theme files can specify their own colors:
+//!
+//! ```json
+//! {
+//!     "name": "Solarized Dark",
+//!     "colors": {
+//!         "keyword": "#859900",
+//!         "comment": { "foreground": "#586e75", "italic": true },
+//!         "error": { "foreground": "brightRed", "background": "#dc322f40", "underline": true }
+//!     }
+//! }
+//! ```
+//!
+//! Colors are `#rrggbb` or `#rrggbbaa` hex strings, or the name of an indexed color
+//! like `brightBlack`. If the terminal doesn't support truecolor, RGBA colors are
+//! replaced by the closest indexed color, as measured in the Oklab color space.
+//!
+//! Token kinds are dotted scopes like `string.escape`. A kind without a style of its own
+//! uses the one of its longest prefix that has one, so `comment.doc` falls back to `comment`.
+//! Prefixing a kind with a language name styles it in that language only, as in
+//! `"rust:keyword.unsafe"` or `"py:string.doc"`. A theme can't style the same kind under
+//! two names of a language, like `rs:` and `rust:`.
 
-use crate::framebuffer::IndexedColor;
 use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::framebuffer::{Attributes, INDEXED_COLORS_COUNT, IndexedColor};
+use crate::json::{self, Value};
+use crate::oklab::srgb_to_oklab;
+use crate::syntax::language::Language;
+use crate::sys;
+
+/// The names of the built-in themes.
+pub const DARK_THEME_NAME: &str = "Dark";
+pub const LIGHT_THEME_NAME: &str = "Light";
+pub const TERMINAL_THEME_NAME: &str = "Terminal";
+
+/// The names theme files use for [`IndexedColor`]s.
+const INDEXED_COLOR_NAMES: [(&str, IndexedColor); INDEXED_COLORS_COUNT] = [
+    ("black", IndexedColor::Black),
+    ("red", IndexedColor::Red),
+    ("green", IndexedColor::Green),
+    ("yellow", IndexedColor::Yellow),
+    ("blue", IndexedColor::Blue),
+    ("magenta", IndexedColor::Magenta),
+    ("cyan", IndexedColor::Cyan),
+    ("white", IndexedColor::White),
+    ("brightBlack", IndexedColor::BrightBlack),
+    ("brightRed", IndexedColor::BrightRed),
+    ("brightGreen", IndexedColor::BrightGreen),
+    ("brightYellow", IndexedColor::BrightYellow),
+    ("brightBlue", IndexedColor::BrightBlue),
+    ("brightMagenta", IndexedColor::BrightMagenta),
+    ("brightCyan", IndexedColor::BrightCyan),
+    ("brightWhite", IndexedColor::BrightWhite),
+    ("background", IndexedColor::Background),
+    ("foreground", IndexedColor::Foreground),
+];
+
+/// A color in a theme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThemeColor {
+    /// A color from the terminal's palette.
+    Indexed(IndexedColor),
+    /// An sRGB color with alpha, in the same `0xAABBGGRR` layout the framebuffer uses.
+    Rgba(u32),
+}
+
+impl ThemeColor {
+    /// Parses a `#rrggbb` or `#rrggbbaa` hex string or the name of an indexed color.
+    pub fn parse(text: &str) -> Option<Self> {
+        if let Some(hex) = text.strip_prefix('#') {
+            if !matches!(hex.len(), 6 | 8) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            let rgba = u32::from_str_radix(hex, 16).ok()?;
+            let rgba = if hex.len() == 6 { rgba << 8 | 0xff } else { rgba };
+            // RRGGBBAA -> AABBGGRR
+            return Some(Self::Rgba(rgba.swap_bytes()));
+        }
+
+        INDEXED_COLOR_NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(text))
+            .map(|&(_, color)| Self::Indexed(color))
+    }
+
+    /// Converts the color to sRGB, given the terminal's palette.
+    /// Without `truecolor`, RGBA colors snap to the closest of the 16 base colors.
+    pub fn resolve(self, palette: &[u32; INDEXED_COLORS_COUNT], truecolor: bool) -> u32 {
+        match self {
+            Self::Indexed(index) => palette[index as usize],
+            Self::Rgba(color) if truecolor => color,
+            Self::Rgba(color) => {
+                let index = nearest_indexed_color(color, palette);
+                // Keep the alpha, so that translucent backgrounds stay translucent.
+                palette[index as usize] & 0x00ffffff | color & 0xff000000
+            }
+        }
+    }
+}
+
+impl fmt::Display for ThemeColor {
+    /// Formats the color the way theme files spell it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Indexed(index) => {
+                let name = INDEXED_COLOR_NAMES.iter().find(|&&(_, i)| i == index).unwrap().0;
+                f.write_str(name)
+            }
+            Self::Rgba(color) => {
+                // AABBGGRR -> RRGGBB(AA)
+                let rgba = color.swap_bytes();
+                if rgba & 0xff == 0xff {
+                    write!(f, "#{:06x}", rgba >> 8)
+                } else {
+                    write!(f, "#{rgba:08x}")
+                }
+            }
+        }
+    }
+}
+
+/// Returns the one of the 16 base colors in `palette` that is perceptually closest to `color`.
+pub fn nearest_indexed_color(color: u32, palette: &[u32; INDEXED_COLORS_COUNT]) -> IndexedColor {
+    let target = srgb_to_oklab(color | 0xff000000);
+    let mut best = (f32::MAX, IndexedColor::Black);
+
+    for &(_, index) in &INDEXED_COLOR_NAMES[..16] {
+        let c = srgb_to_oklab(palette[index as usize] | 0xff000000);
+        let (dl, da, db) = (c.l - target.l, c.a - target.a, c.b - target.b);
+        let distance = dl * dl + da * da + db * db;
+        if distance < best.0 {
+            best = (distance, index);
+        }
+    }
+
+    best.1
+}
+
+/// How tokens of one kind are drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenStyle {
+    pub foreground: Option<ThemeColor>,
+    pub background: Option<ThemeColor>,
+    pub attributes: Attributes,
+}
+
+impl TokenStyle {
+    /// A style that only sets the foreground color.
+    pub const fn fg(color: ThemeColor) -> Self {
+        Self { foreground: Some(color), background: None, attributes: Attributes::None }
+    }
+}
+
+/// A [`TokenStyle`] with its colors converted to sRGB, ready for the framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolvedStyle {
+    pub foreground: Option<u32>,
+    pub background: Option<u32>,
+    pub attributes: Attributes,
+}
+
+/// A named set of token styles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Theme {
+    pub name: String,
+    /// Styles keyed by token kind, e.g. "keyword", or by language and kind, e.g. "rust:keyword".
+    /// Use [`Theme::set_style`] to change them, so that language-specific ones take effect.
+    pub styles: HashMap<String, TokenStyle>,
+    /// The language-specific styles by language and kind, with the key they were given under.
+    /// Resolved once, since a language may go by several names and they're looked up per token.
+    language_styles: HashMap<Language, HashMap<String, (String, TokenStyle)>>,
+}
+
+impl Theme {
+    /// Returns the themes that ship with the editor, starting with the default one.
+    pub fn builtin() -> Vec<Theme> {
+        vec![Self::dark(), Self::light(), Self::terminal()]
+    }
+
+    /// A truecolor theme for dark backgrounds.
+    pub fn dark() -> Self {
+        Self::from_hex(
+            DARK_THEME_NAME,
+            &[
+                ("keyword", "#569cd6"),
+                ("type", "#4ec9b0"),
+                ("string", "#ce9178"),
+                ("string.escape", "#d7ba7d"),
+                ("string.placeholder", "#9cdcfe"),
+                ("comment", "#6a9955"),
+                ("comment.doc", "#7ca668"),
+                ("comment.doc.tag", "#569cd6"),
+                ("comment.todo", "#ffaf00"),
+                ("string.doc", "#7ca668"),
+                ("number", "#b5cea8"),
+                ("boolean", "#569cd6"),
+                ("attribute", "#9cdcfe"),
+                ("builtin", "#4fc1ff"),
+                ("decorator", "#dcdcaa"),
+                ("regex", "#d16969"),
+                ("operator", "#d4d4d4"),
+                ("punctuation", "#a0a0a0"),
+                ("function", "#dcdcaa"),
+                ("variable", "#9cdcfe"),
+                ("constant", "#4fc1ff"),
+                ("error", "#f44747"),
+                ("invalid", "#f44747"),
+                ("inserted", "#81b88b"),
+                ("deleted", "#e06c75"),
+            ],
+        )
+    }
+
+    /// A truecolor theme for light backgrounds.
+    pub fn light() -> Self {
+        Self::from_hex(
+            LIGHT_THEME_NAME,
+            &[
+                ("keyword", "#0000ff"),
+                ("type", "#267f99"),
+                ("string", "#a31515"),
+                ("string.escape", "#ee0000"),
+                ("string.placeholder", "#001080"),
+                ("comment", "#008000"),
+                ("comment.doc", "#3b7a2a"),
+                ("comment.doc.tag", "#0000ff"),
+                ("comment.todo", "#b35900"),
+                ("string.doc", "#3b7a2a"),
+                ("number", "#098658"),
+                ("boolean", "#0000ff"),
+                ("attribute", "#e50000"),
+                ("builtin", "#267f99"),
+                ("decorator", "#795e26"),
+                ("regex", "#811f3f"),
+                ("operator", "#383838"),
+                ("punctuation", "#5a5a5a"),
+                ("function", "#795e26"),
+                ("variable", "#001080"),
+                ("constant", "#0070c1"),
+                ("error", "#cd3131"),
+                ("invalid", "#cd3131"),
+                ("inserted", "#587c0c"),
+                ("deleted", "#ad0707"),
+            ],
+        )
+    }
+
+    /// A theme that only uses the terminal's 16 indexed colors,
+    /// so that it follows the user's palette.
+    pub fn terminal() -> Self {
+        let mut styles = HashMap::new();
+        for (kind, color) in [
+            ("keyword", IndexedColor::Blue),
+            ("type", IndexedColor::Cyan),
+            ("string", IndexedColor::Green),
+            ("string.escape", IndexedColor::BrightYellow),
+            ("string.placeholder", IndexedColor::BrightCyan),
+            ("comment", IndexedColor::BrightBlack),
+            ("comment.doc", IndexedColor::Green),
+            ("comment.doc.tag", IndexedColor::Blue),
+            ("comment.todo", IndexedColor::BrightYellow),
+            ("string.doc", IndexedColor::Green),
+            ("number", IndexedColor::Magenta),
+            ("boolean", IndexedColor::Magenta),
+            ("attribute", IndexedColor::Yellow),
+            ("builtin", IndexedColor::BrightCyan),
+            ("decorator", IndexedColor::BrightYellow),
+            ("regex", IndexedColor::Red),
+            ("operator", IndexedColor::White),
+            ("punctuation", IndexedColor::BrightBlack),
+            ("function", IndexedColor::BrightBlue),
+            ("variable", IndexedColor::White),
+            ("constant", IndexedColor::BrightMagenta),
+            ("error", IndexedColor::BrightRed),
+            ("invalid", IndexedColor::BrightRed),
+            ("inserted", IndexedColor::Green),
+            ("deleted", IndexedColor::Red),
+        ] {
+            styles.insert(kind.to_string(), TokenStyle::fg(ThemeColor::Indexed(color)));
+        }
+        Self { name: TERMINAL_THEME_NAME.to_string(), styles, language_styles: HashMap::new() }
+    }
+
+    /// Builds one of the truecolor themes. Comments and docs are italic, markers like `TODO` bold
+    /// and errors underlined.
+    fn from_hex(name: &str, colors: &[(&str, &str)]) -> Self {
+        let mut styles = HashMap::new();
+        for &(kind, hex) in colors {
+            let mut style = TokenStyle::fg(ThemeColor::parse(hex).unwrap());
+            style.attributes = match kind {
+                "comment" | "comment.doc" | "comment.doc.tag" | "string.doc" => Attributes::Italic,
+                "comment.todo" => Attributes::Bold,
+                "error" | "invalid" => Attributes::Underlined,
+                _ => Attributes::None,
+            };
+            styles.insert(kind.to_string(), style);
+        }
+        Self { name: name.to_string(), styles, language_styles: HashMap::new() }
+    }
+
+    /// Returns the style for a token kind in the given language, or in any language if `None`.
+    ///
+    /// If there's none for `kind` itself, the longest prefix of it with a style applies,
+    /// e.g. `keyword` for `keyword.control`. At each length, a style for the language,
+    /// like `rust:keyword`, takes precedence over the one for all languages.
+    pub fn style(&self, language: Option<Language>, kind: &str) -> Option<&TokenStyle> {
+        self.lookup(language, kind).map(|(_, style)| style)
+    }
+
+    /// Like [`Theme::style`], but also returns the key the style was found under.
+    pub fn lookup(&self, language: Option<Language>, kind: &str) -> Option<(&str, &TokenStyle)> {
+        let mut scope = kind;
+        loop {
+            if let Some(language) = language
+                && let Some(entry) = self.language_style(language, scope)
+            {
+                return Some(entry);
+            }
+            if let Some((key, style)) = self.styles.get_key_value(scope) {
+                return Some((key, style));
+            }
+            scope = &scope[..scope.rfind('.')?];
+        }
+    }
+
+    /// Returns the style of a key like `rust:keyword`, where the language may go by any of its names.
+    fn language_style(&self, language: Language, scope: &str) -> Option<(&str, &TokenStyle)> {
+        let (key, style) = self.language_styles.get(&language)?.get(scope)?;
+        Some((key, style))
+    }
+
+    /// Sets the style of a token kind. A kind prefixed with a language name, like `rust:keyword`,
+    /// only applies to that language. It replaces a style set for the same kind under another
+    /// name of the language, like `rs:keyword`, whose key is returned. Prefixes that don't name
+    /// a known language are kept, but never apply.
+    pub fn set_style(&mut self, key: String, style: TokenStyle) -> Option<String> {
+        let mut replaced = None;
+        if let Some((name, kind)) = key.split_once(':')
+            && let Some(language) = Language::from_name(name)
+        {
+            let entry = (key.clone(), style);
+            let styles = self.language_styles.entry(language).or_default();
+            if let Some((previous, _)) = styles.insert(kind.to_string(), entry)
+                && previous != key
+            {
+                self.styles.remove(&previous);
+                replaced = Some(previous);
+            }
+        }
+        self.styles.insert(key, style);
+        replaced
+    }
+
+    /// Parses a theme from its JSON representation.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let root = json::parse(text).map_err(|err| err.to_string())?;
+        if root.as_object().is_none() {
+            return Err(format!("Expected an object, found {}", root.type_name()));
+        }
+
+        let name = match root.get("name").map(|v| (v, v.as_str())) {
+            Some((_, Some(name))) if !name.trim().is_empty() => name.trim().to_string(),
+            Some((_, Some(_))) => return Err("\"name\" must not be empty".to_string()),
+            Some((v, None)) => {
+                return Err(format!("Expected \"name\" to be a string, found {}", v.type_name()));
+            }
+            None => return Err("Missing \"name\"".to_string()),
+        };
+
+        let mut theme = Self { name, styles: HashMap::new(), language_styles: HashMap::new() };
+        if let Some(colors) = root.get("colors") {
+            let Some(entries) = colors.as_object() else {
+                return Err(format!(
+                    "Expected \"colors\" to be an object, found {}",
+                    colors.type_name()
+                ));
+            };
+            for (kind, value) in entries {
+                let style = parse_style(value, &format!("colors.{kind}"))?;
+                if let Some(previous) = theme.set_style(kind.clone(), style) {
+                    return Err(format!(
+                        "\"colors.{kind}\" styles the same as \"colors.{previous}\""
+                    ));
+                }
+            }
+        }
+
+        Ok(theme)
+    }
+}
+
+/// Parses either a color string or an object with colors and attributes.
+fn parse_style(value: &Value, key: &str) -> Result<TokenStyle, String> {
+    if value.as_str().is_some() {
+        return Ok(TokenStyle::fg(parse_color(value, key)?));
+    }
+    if value.as_object().is_none() {
+        return Err(format!(
+            "Expected \"{key}\" to be a string or an object, found {}",
+            value.type_name()
+        ));
+    }
+
+    let mut style = TokenStyle { foreground: None, background: None, attributes: Attributes::None };
+    if let Some(v) = value.get("foreground") {
+        style.foreground = Some(parse_color(v, &format!("{key}.foreground"))?);
+    }
+    if let Some(v) = value.get("background") {
+        style.background = Some(parse_color(v, &format!("{key}.background"))?);
+    }
+    for (name, attr) in [
+        ("bold", Attributes::Bold),
+        ("italic", Attributes::Italic),
+        ("underline", Attributes::Underlined),
+    ] {
+        let Some(v) = value.get(name) else {
+            continue;
+        };
+        match v.as_bool() {
+            Some(true) => style.attributes = style.attributes | attr,
+            Some(false) => {}
+            None => {
+                return Err(format!(
+                    "Expected \"{key}.{name}\" to be a boolean, found {}",
+                    v.type_name()
+                ));
+            }
+        }
+    }
+    Ok(style)
+}
+
+fn parse_color(value: &Value, key: &str) -> Result<ThemeColor, String> {
+    let Some(text) = value.as_str() else {
+        return Err(format!("Expected \"{key}\" to be a string, found {}", value.type_name()));
+    };
+    ThemeColor::parse(text).ok_or_else(|| format!("Invalid color \"{text}\" in \"{key}\""))
+}
 
 /// Maps token types to colors for syntax highlighting.
 #[derive(Debug, Clone)]
 pub struct ColorMapper {
-    /// Mapping from token type to color
-    token_colors: HashMap<String, IndexedColor>,
-    /// Whether to use 256-color mode (vs 16-color mode)
-    use_256_colors: bool,
+    /// All available themes. The built-in ones come first.
+    themes: Vec<Theme>,
+    /// Index of the active theme in `themes`.
+    active: usize,
+    /// Whether the terminal can display 24-bit colors.
+    truecolor: bool,
 }
 
 impl Default for ColorMapper {
@@ -26,112 +466,177 @@ impl Default for ColorMapper {
 }
 
 impl ColorMapper {
-    /// Creates a new color mapper with the default theme.
-    pub fn new(use_256_colors: bool) -> Self {
-        let mut mapper = Self {
-            token_colors: HashMap::new(),
-            use_256_colors,
-        };
-        mapper.load_default_theme();
-        mapper
-    }
-
-    /// Loads the default color theme.
-    fn load_default_theme(&mut self) {
-        if self.use_256_colors {
-            // 256-color theme with rich colors
-            self.token_colors.insert("keyword".to_string(), IndexedColor::Blue);
-            self.token_colors.insert("type".to_string(), IndexedColor::Cyan);
-            self.token_colors.insert("string".to_string(), IndexedColor::Green);
-            self.token_colors.insert("comment".to_string(), IndexedColor::BrightBlack);
-            self.token_colors.insert("number".to_string(), IndexedColor::Magenta);
-            self.token_colors.insert("boolean".to_string(), IndexedColor::Magenta);
-            self.token_colors.insert("attribute".to_string(), IndexedColor::Yellow);
-            self.token_colors.insert("builtin".to_string(), IndexedColor::BrightCyan);
-            self.token_colors.insert("decorator".to_string(), IndexedColor::BrightYellow);
-            self.token_colors.insert("regex".to_string(), IndexedColor::Red);
-            self.token_colors.insert("operator".to_string(), IndexedColor::White);
-            self.token_colors.insert("punctuation".to_string(), IndexedColor::BrightBlack);
-            self.token_colors.insert("function".to_string(), IndexedColor::BrightBlue);
-            self.token_colors.insert("variable".to_string(), IndexedColor::White);
-            self.token_colors.insert("constant".to_string(), IndexedColor::BrightMagenta);
-            self.token_colors.insert("error".to_string(), IndexedColor::BrightRed);
-        } else {
-            // 16-color theme for basic terminals
-            self.token_colors.insert("keyword".to_string(), IndexedColor::Blue);
-            self.token_colors.insert("type".to_string(), IndexedColor::Cyan);
-            self.token_colors.insert("string".to_string(), IndexedColor::Green);
-            self.token_colors.insert("comment".to_string(), IndexedColor::BrightBlack);
-            self.token_colors.insert("number".to_string(), IndexedColor::Yellow);
-            self.token_colors.insert("boolean".to_string(), IndexedColor::Yellow);
-            self.token_colors.insert("attribute".to_string(), IndexedColor::Yellow);
-            self.token_colors.insert("builtin".to_string(), IndexedColor::Cyan);
-            self.token_colors.insert("decorator".to_string(), IndexedColor::Yellow);
-            self.token_colors.insert("regex".to_string(), IndexedColor::Red);
-            self.token_colors.insert("operator".to_string(), IndexedColor::White);
-            self.token_colors.insert("punctuation".to_string(), IndexedColor::White);
-            self.token_colors.insert("function".to_string(), IndexedColor::Blue);
-            self.token_colors.insert("variable".to_string(), IndexedColor::White);
-            self.token_colors.insert("constant".to_string(), IndexedColor::Yellow);
-            self.token_colors.insert("error".to_string(), IndexedColor::Red);
-        }
-    }
-
-    /// Gets the color for a given token type.
-    pub fn get_color(&self, token_type: &str) -> IndexedColor {
-        self.token_colors
-            .get(token_type)
-            .copied()
-            .unwrap_or(IndexedColor::White)
-    }
-
-    /// Sets a custom color for a token type.
-    pub fn set_color(&mut self, token_type: String, color: IndexedColor) {
-        self.token_colors.insert(token_type, color);
-    }
-
-    /// Resets the color mapping to the default theme.
-    pub fn reset_to_default(&mut self) {
-        self.token_colors.clear();
-        self.load_default_theme();
+    /// Creates a new color mapper with the built-in themes and the dark one selected.
+    pub fn new(truecolor: bool) -> Self {
+        Self { themes: Theme::builtin(), active: 0, truecolor }
     }
 
-    /// Returns whether 256-color mode is enabled.
-    pub fn is_256_color_mode(&self) -> bool {
-        self.use_256_colors
+    /// Returns the active theme.
+    pub fn theme(&self) -> &Theme {
+        &self.themes[self.active]
     }
 
-    /// Sets whether to use 256-color mode.
-    pub fn set_256_color_mode(&mut self, use_256_colors: bool) {
-        if self.use_256_colors != use_256_colors {
-            self.use_256_colors = use_256_colors;
-            self.reset_to_default();
+    /// Gets the style for a given token type, if the active theme defines one
+    /// for it or one of its prefixes (see [`Theme::style`]).
+    pub fn get_style(&self, token_type: &str) -> Option<&TokenStyle> {
+        self.theme().style(None, token_type)
+    }
+
+    /// Like [`ColorMapper::get_style`], but takes the active theme's overrides for `language` into account.
+    pub fn get_style_in(&self, language: Language, token_type: &str) -> Option<&TokenStyle> {
+        self.theme().style(Some(language), token_type)
+    }
+
+    /// Gets the style for a given token type with its colors converted to sRGB.
+    pub fn resolve(
+        &self,
+        token_type: &str,
+        palette: &[u32; INDEXED_COLORS_COUNT],
+    ) -> Option<ResolvedStyle> {
+        self.get_style(token_type).map(|style| self.resolve_style(style, palette))
+    }
+
+    /// Like [`ColorMapper::resolve`], but takes the active theme's overrides for `language` into account.
+    pub fn resolve_in(
+        &self,
+        language: Language,
+        token_type: &str,
+        palette: &[u32; INDEXED_COLORS_COUNT],
+    ) -> Option<ResolvedStyle> {
+        self.get_style_in(language, token_type).map(|style| self.resolve_style(style, palette))
+    }
+
+    fn resolve_style(
+        &self,
+        style: &TokenStyle,
+        palette: &[u32; INDEXED_COLORS_COUNT],
+    ) -> ResolvedStyle {
+        ResolvedStyle {
+            foreground: style.foreground.map(|c| c.resolve(palette, self.truecolor)),
+            background: style.background.map(|c| c.resolve(palette, self.truecolor)),
+            attributes: style.attributes,
+        }
+    }
+
+    /// Sets a custom style for a token type in the active theme.
+    pub fn set_style(&mut self, token_type: String, style: TokenStyle) {
+        self.themes[self.active].set_style(token_type, style);
+    }
+
+    /// Resets the built-in themes and makes the default one active.
+    pub fn reset_to_default(&mut self) {
+        for theme in Theme::builtin() {
+            self.load_theme(theme);
         }
+        self.active = 0;
     }
 
-    /// Gets all configured token types.
+    /// Selects the light or the dark built-in theme, whichever suits the
+    /// given terminal background color better.
+    pub fn select_for_background(&mut self, background: u32) {
+        let is_dark = srgb_to_oklab(background | 0xff000000).l < 0.5;
+        self.select_theme(if is_dark { DARK_THEME_NAME } else { LIGHT_THEME_NAME });
+    }
+
+    /// Returns whether RGBA colors are used as is.
+    pub fn is_truecolor(&self) -> bool {
+        self.truecolor
+    }
+
+    /// Sets whether the terminal supports truecolor.
+    /// If not, RGBA colors fall back to the closest indexed color.
+    pub fn set_truecolor(&mut self, truecolor: bool) {
+        self.truecolor = truecolor;
+    }
+
+    /// Gets all token types configured in the active theme.
     pub fn token_types(&self) -> Vec<&String> {
-        self.token_colors.keys().collect()
+        self.theme().styles.keys().collect()
     }
 
-    /// Loads a custom theme from a configuration.
-    pub fn load_theme(&mut self, theme: HashMap<String, IndexedColor>) {
-        self.token_colors = theme;
+    /// Adds a theme, replacing an existing one with the same name.
+    /// The active theme stays selected, even if it got replaced.
+    pub fn load_theme(&mut self, theme: Theme) {
+        match self.themes.iter().position(|t| t.name.eq_ignore_ascii_case(&theme.name)) {
+            Some(i) => self.themes[i] = theme,
+            None => self.themes.push(theme),
+        }
     }
 
-    /// Exports the current theme as a configuration.
-    pub fn export_theme(&self) -> HashMap<String, IndexedColor> {
-        self.token_colors.clone()
+    /// Returns the names of all available themes.
+    pub fn theme_names(&self) -> impl Iterator<Item = &str> {
+        self.themes.iter().map(|t| t.name.as_str())
+    }
+
+    /// Makes the theme with the given name the active one.
+    /// Returns false if there's no such theme.
+    pub fn select_theme(&mut self, name: &str) -> bool {
+        match self.themes.iter().position(|t| t.name.eq_ignore_ascii_case(name)) {
+            Some(i) => {
+                self.active = i;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the theme after the active one active, wrapping around at the end.
+    pub fn select_next_theme(&mut self) {
+        self.active = (self.active + 1) % self.themes.len();
+    }
+
+    /// Exports the active theme.
+    pub fn export_theme(&self) -> Theme {
+        self.theme().clone()
+    }
+
+    /// Loads all `*.json` theme files in the given directory.
+    /// Returns a message for every file that failed to load.
+    pub fn load_dir(&mut self, dir: &Path) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        let mut paths: Vec<_> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return errors,
+            Err(err) => {
+                errors.push(format!("{}: {err}", dir.display()));
+                return errors;
+            }
+        };
+        paths.sort();
+
+        for path in paths {
+            let result = std::fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| Theme::parse(&text));
+            match result {
+                Ok(theme) => self.load_theme(theme),
+                Err(err) => errors.push(format!("{}: {err}", path.display())),
+            }
+        }
+
+        errors
+    }
+
+    /// Loads the themes from the `themes` folder in the user's config directory.
+    /// Returns a message for every file that failed to load.
+    pub fn load_from_config_dir(&mut self) -> Vec<String> {
+        match sys::config_dir() {
+            Some(dir) => self.load_dir(&dir.join("themes")),
+            None => Vec::new(),
+        }
     }
 }
 
-use once_cell::sync::Lazy;
 use std::sync::Mutex;
 
+use once_cell::sync::Lazy;
+
 /// Global color mapper instance for syntax highlighting.
-static COLOR_MAPPER: Lazy<Mutex<ColorMapper>> = Lazy::new(|| {
-    Mutex::new(ColorMapper::default())
-});
+static COLOR_MAPPER: Lazy<Mutex<ColorMapper>> = Lazy::new(|| Mutex::new(ColorMapper::default()));
 
 /// Gets the global color mapper instance.
 pub fn global_color_mapper() -> std::sync::MutexGuard<'static, ColorMapper> {
@@ -147,43 +652,227 @@ pub fn global_color_mapper_mut() -> std::sync::MutexGuard<'static, ColorMapper>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::framebuffer::DEFAULT_THEME;
+
+    const THEME: &str = r##"{
+        "name": "Test Theme",
+        "colors": {
+            "keyword": "#ff8000",
+            "comment": { "foreground": "brightBlack", "italic": true },
+            "error": { "foreground": "#ff0000", "background": "#ff000040", "bold": true, "underline": true },
+        },
+    }"##;
 
     #[test]
     fn test_default_colors() {
-        let mapper = ColorMapper::new(true);
-        
-        assert_eq!(mapper.get_color("keyword"), IndexedColor::Blue);
-        assert_eq!(mapper.get_color("string"), IndexedColor::Green);
-        assert_eq!(mapper.get_color("comment"), IndexedColor::BrightBlack);
-        assert_eq!(mapper.get_color("unknown"), IndexedColor::White);
+        let mut mapper = ColorMapper::new(true);
+        assert_eq!(mapper.theme().name, DARK_THEME_NAME);
+        assert!(mapper.select_theme(TERMINAL_THEME_NAME));
+
+        let fg = |kind| mapper.get_style(kind).and_then(|s| s.foreground);
+        assert_eq!(fg("keyword"), Some(ThemeColor::Indexed(IndexedColor::Blue)));
+        assert_eq!(fg("string"), Some(ThemeColor::Indexed(IndexedColor::Green)));
+        assert_eq!(fg("comment"), Some(ThemeColor::Indexed(IndexedColor::BrightBlack)));
+        assert_eq!(fg("invalid"), Some(ThemeColor::Indexed(IndexedColor::BrightRed)));
+        assert_eq!(fg("comment.todo"), Some(ThemeColor::Indexed(IndexedColor::BrightYellow)));
+        assert_eq!(mapper.get_style("unknown"), None);
+
+        // Escapes and invalid escapes stand out from the string around them in every theme.
+        for theme in Theme::builtin() {
+            let fg = |kind: &str| theme.styles.get(kind).and_then(|s| s.foreground);
+            assert!(fg("string.escape").is_some_and(|c| Some(c) != fg("string")), "{}", theme.name);
+            assert!(
+                fg("string.placeholder").is_some_and(|c| Some(c) != fg("string")),
+                "{}",
+                theme.name
+            );
+            assert!(fg("invalid").is_some_and(|c| Some(c) != fg("string")), "{}", theme.name);
+            assert!(fg("comment.todo").is_some_and(|c| Some(c) != fg("comment")), "{}", theme.name);
+            assert!(fg("comment.doc").is_some_and(|c| Some(c) != fg("comment")), "{}", theme.name);
+        }
     }
 
     #[test]
     fn test_custom_colors() {
         let mut mapper = ColorMapper::new(true);
-        
-        mapper.set_color("keyword".to_string(), IndexedColor::BrightRed);
-        assert_eq!(mapper.get_color("keyword"), IndexedColor::BrightRed);
-    }
 
-    #[test]
-    fn test_16_color_mode() {
-        let mapper = ColorMapper::new(false);
-        
-        // In 16-color mode, some colors should be simplified
-        assert_eq!(mapper.get_color("number"), IndexedColor::Yellow);
+        let style = TokenStyle::fg(ThemeColor::Indexed(IndexedColor::BrightRed));
+        mapper.set_style("keyword".to_string(), style);
+        assert_eq!(mapper.get_style("keyword"), Some(&style));
     }
 
     #[test]
     fn test_theme_export_import() {
         let mut mapper = ColorMapper::new(true);
-        mapper.set_color("custom".to_string(), IndexedColor::BrightBlue);
-        
-        let theme = mapper.export_theme();
-        
+        let style = TokenStyle::fg(ThemeColor::Rgba(0xff123456));
+        mapper.set_style("custom".to_string(), style);
+
+        let mut theme = mapper.export_theme();
+        theme.name = "Exported".to_string();
+
         let mut mapper2 = ColorMapper::new(true);
         mapper2.load_theme(theme);
-        
-        assert_eq!(mapper2.get_color("custom"), IndexedColor::BrightBlue);
+        assert_eq!(mapper2.get_style("custom"), None);
+        assert!(mapper2.select_theme("Exported"));
+        assert_eq!(mapper2.get_style("custom"), Some(&style));
+        assert!(!mapper2.select_theme("Missing"));
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(ThemeColor::parse("#112233"), Some(ThemeColor::Rgba(0xff332211)));
+        assert_eq!(ThemeColor::parse("#11223344"), Some(ThemeColor::Rgba(0x44332211)));
+        assert_eq!(
+            ThemeColor::parse("BrightBlue"),
+            Some(ThemeColor::Indexed(IndexedColor::BrightBlue))
+        );
+        assert_eq!(ThemeColor::parse("#1122"), None);
+        assert_eq!(ThemeColor::parse("#gg2233"), None);
+        assert_eq!(ThemeColor::parse("teal"), None);
+
+        for text in ["#112233", "#11223344", "brightBlue", "background"] {
+            assert_eq!(ThemeColor::parse(text).unwrap().to_string(), text);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_theme() {
+        let theme = Theme::parse(THEME).unwrap();
+        assert_eq!(theme.name, "Test Theme");
+
+        let error = theme.styles["error"];
+        assert_eq!(error.foreground, Some(ThemeColor::Rgba(0xff0000ff)));
+        assert_eq!(error.background, Some(ThemeColor::Rgba(0x400000ff)));
+        assert!(error.attributes.is(Attributes::Bold | Attributes::Underlined));
+        assert!(!error.attributes.is(Attributes::Italic));
+        assert!(theme.styles["comment"].attributes.is(Attributes::Italic));
+
+        let err = |text| Theme::parse(text).unwrap_err();
+        assert_eq!(err("{}"), "Missing \"name\"");
+        assert_eq!(
+            err(r#"{"name": "x", "colors": []}"#),
+            "Expected \"colors\" to be an object, found an array"
+        );
+        assert_eq!(
+            err(r##"{"name": "x", "colors": {"a": "#12"}}"##),
+            "Invalid color \"#12\" in \"colors.a\""
+        );
+        assert_eq!(
+            err(r#"{"name": "x", "colors": {"a": {"bold": 1}}}"#),
+            "Expected \"colors.a.bold\" to be a boolean, found a number"
+        );
+        assert_eq!(
+            err(r##"{"name": "x", "colors": {"rs:a": "#000000", "rust:a": "#ffffff"}}"##),
+            "\"colors.rust:a\" styles the same as \"colors.rs:a\""
+        );
+    }
+
+    #[test]
+    fn test_scope_fallback() {
+        let theme = Theme::parse(
+            r##"{
+                "name": "Scopes",
+                "colors": {
+                    "keyword": "#000001",
+                    "keyword.control": "#000002",
+                    "rust:keyword": "#000003",
+                    "rs:keyword.unsafe": "#000004",
+                    "python:keyword.control.flow": "#000005",
+                    "nope:keyword": "#000006",
+                },
+            }"##,
+        )
+        .unwrap();
+        let color = |language, kind| {
+            theme.style(language, kind).and_then(|style| style.foreground).map(
+                |color| match color {
+                    ThemeColor::Rgba(rgba) => rgba >> 16 & 0xff,
+                    ThemeColor::Indexed(_) => unreachable!(),
+                },
+            )
+        };
+
+        assert_eq!(color(None, "keyword"), Some(1));
+        assert_eq!(color(None, "keyword.control"), Some(2));
+        assert_eq!(color(None, "keyword.control.flow"), Some(2));
+        assert_eq!(color(None, "keyword.unsafe"), Some(1));
+        assert_eq!(color(None, "keywords"), None);
+        assert_eq!(color(None, "string.escape"), None);
+
+        // Language overrides win at the same depth, but not over a longer generic scope.
+        assert_eq!(color(Some(Language::Rust), "keyword"), Some(3));
+        assert_eq!(color(Some(Language::Rust), "keyword.unsafe"), Some(4));
+        assert_eq!(color(Some(Language::Rust), "keyword.control"), Some(2));
+        assert_eq!(color(Some(Language::Python), "keyword.control.flow"), Some(5));
+        assert_eq!(color(Some(Language::Python), "keyword"), Some(1));
+        assert_eq!(
+            theme.lookup(Some(Language::Rust), "keyword.unsafe").map(|(key, _)| key),
+            Some("rs:keyword.unsafe")
+        );
+        assert_eq!(theme.lookup(None, "keyword.other").map(|(key, _)| key), Some("keyword"));
+
+        let mut mapper = ColorMapper::new(true);
+        mapper.load_theme(theme.clone());
+        assert!(mapper.select_theme("Scopes"));
+        assert_eq!(
+            mapper.get_style_in(Language::Rust, "keyword.unsafe"),
+            theme.styles.get("rs:keyword.unsafe")
+        );
+        assert_eq!(mapper.get_style("keyword.unsafe"), theme.styles.get("keyword"));
+    }
+
+    #[test]
+    fn test_truecolor_fallback() {
+        let mut mapper = ColorMapper::new(true);
+        mapper.load_theme(Theme::parse(THEME).unwrap());
+        assert!(mapper.select_theme("test theme"));
+        assert_eq!(
+            mapper.theme_names().collect::<Vec<_>>(),
+            [DARK_THEME_NAME, LIGHT_THEME_NAME, TERMINAL_THEME_NAME, "Test Theme"]
+        );
+
+        let style = mapper.resolve("keyword", &DEFAULT_THEME).unwrap();
+        assert_eq!(style.foreground, Some(0xff0080ff));
+
+        // Orange is closest to the palette's yellow.
+        mapper.set_truecolor(false);
+        let style = mapper.resolve("keyword", &DEFAULT_THEME).unwrap();
+        assert_eq!(style.foreground, Some(DEFAULT_THEME[IndexedColor::Yellow as usize]));
+
+        // The alpha of translucent colors survives the fallback.
+        let style = mapper.resolve("error", &DEFAULT_THEME).unwrap();
+        assert_eq!(style.background.map(|c| c >> 24), Some(0x40));
+
+        // Indexed colors always come from the palette.
+        let style = mapper.resolve("comment", &DEFAULT_THEME).unwrap();
+        assert_eq!(style.foreground, Some(DEFAULT_THEME[IndexedColor::BrightBlack as usize]));
+
+        mapper.select_next_theme();
+        assert_eq!(mapper.theme().name, DARK_THEME_NAME);
+        mapper.select_theme("test theme");
+        mapper.reset_to_default();
+        assert_eq!(mapper.theme().name, DARK_THEME_NAME);
+    }
+
+    #[test]
+    fn test_builtin_themes() {
+        let themes = Theme::builtin();
+        let kinds = |theme: &Theme| {
+            let mut kinds: Vec<_> = theme.styles.keys().cloned().collect();
+            kinds.sort();
+            kinds
+        };
+        for theme in &themes[1..] {
+            assert_eq!(kinds(theme), kinds(&themes[0]), "{}", theme.name);
+        }
+
+        let mut mapper = ColorMapper::new(true);
+        mapper.select_for_background(0xfff0f0f0);
+        assert_eq!(mapper.theme().name, LIGHT_THEME_NAME);
+        mapper.select_for_background(0xff1e1e1e);
+        assert_eq!(mapper.theme().name, DARK_THEME_NAME);
+        // Solarized Light's background is light, despite its saturation.
+        mapper.select_for_background(0xffe3f6fd);
+        assert_eq!(mapper.theme().name, LIGHT_THEME_NAME);
+    }
+}
@@ -3,18 +3,35 @@
 
 //! Syntax highlighting service and infrastructure.
 //!
-//! This module provides the core syntax highlighting functionality using Synoptic,
-//! including lazy initialization, token processing, and performance monitoring.
+//! This module provides the core syntax highlighting functionality on top of the
+//! stateful [`Lexer`], including lazy initialization, token processing, per-line
+//! lexer state tracking, and performance monitoring.
 
 use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 use std::thread;
 use once_cell::sync::Lazy;
-use synoptic::Highlighter;
-use crossbeam_channel::{bounded, select, Receiver, Sender};
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use regex::Regex;
 
 use crate::syntax::language::{Language, LanguageConfig, LanguageDetector};
+use crate::syntax::lexer::{Lexer, LexerState};
+use crate::syntax::performance::{FileSizeCategory, LineLengthCategory};
+use crate::syntax::textmate::Grammar;
+use crate::syntax::token_cache::{TokenCache, DEFAULT_TOKEN_CACHE_LIMIT};
+use crate::syntax::user_languages;
+
+/// Maximum number of lines that are re-tokenized ahead of a requested line in order
+/// to establish its starting lexer state. Lines further away start in the default state.
+const MAX_SYNC_LINES: usize = 1000;
+
+/// Markers like `TODO(owner):` or `SAFETY:` in comments, which get a kind of their own in every language.
+static COMMENT_TAG: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b(?:TODO|FIXME|HACK|XXX|NOTE|SAFETY|BUG)\b(?:\([^()]*\))?:?").unwrap());
 
 /// Information about a highlighted token in the document.
 #[derive(Debug, Clone, PartialEq)]
@@ -83,6 +100,14 @@ pub struct HighlightingMetrics {
     pub cache_hits: usize,
     /// Number of highlighting cache misses
     pub cache_misses: usize,
+    /// Number of range requests the worker pool completed
+    pub requests_completed: usize,
+    /// Number of range requests that edits cancelled before they completed
+    pub requests_cancelled: usize,
+    /// Total time workers spent on completed range requests
+    pub total_request_time: Duration,
+    /// Longest time a worker spent on a single range request
+    pub max_request_time: Duration,
 }
 
 impl HighlightingMetrics {
@@ -111,6 +136,26 @@ impl HighlightingMetrics {
         self.cache_misses += 1;
     }
 
+    /// Records a range request that a worker completed in the given time.
+    pub fn record_request(&mut self, duration: Duration) {
+        self.requests_completed += 1;
+        self.total_request_time += duration;
+        self.max_request_time = self.max_request_time.max(duration);
+    }
+
+    /// Records a range request that was cancelled.
+    pub fn record_cancelled_request(&mut self) {
+        self.requests_cancelled += 1;
+    }
+
+    /// Returns the average time workers spent on a completed range request.
+    pub fn avg_request_time(&self) -> Duration {
+        match self.requests_completed {
+            0 => Duration::ZERO,
+            n => self.total_request_time / n as u32,
+        }
+    }
+
     /// Returns the cache hit ratio (0.0 to 1.0).
     pub fn cache_hit_ratio(&self) -> f64 {
         let total_requests = self.cache_hits + self.cache_misses;
@@ -127,6 +172,56 @@ impl HighlightingMetrics {
     }
 }
 
+/// How much of a document gets highlighted, to keep huge or minified files responsive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HighlightingPolicy {
+    /// Every line is highlighted, with the lines around the viewport done in the background.
+    #[default]
+    Full,
+    /// Only the visible lines are highlighted. The lines above the viewport aren't tokenized,
+    /// so constructs like block comments that start above it aren't recognized.
+    ViewportOnly,
+    /// Nothing is highlighted.
+    Off,
+}
+
+/// Where the tokens of a line came from when the renderer looked them up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenSource {
+    /// The tokens were cached from an earlier lookup.
+    Cache,
+    /// The line was tokenized for the lookup, or the workers finished it since the previous one.
+    Highlighted,
+    /// The line was sent to the workers, so its previous tokens or plain text were used.
+    Pending,
+}
+
+impl HighlightingPolicy {
+    /// Picks the policy for a document of `size` bytes with lines `average_line_length` bytes long.
+    /// Long average lines mean the document is minified or otherwise not meant to be read.
+    pub fn for_document(size: u64, average_line_length: usize) -> Self {
+        let long_lines = LineLengthCategory::from_length(average_line_length) == LineLengthCategory::ExtraLong;
+        match FileSizeCategory::from_bytes(size) {
+            FileSizeCategory::Small | FileSizeCategory::Medium => Self::Full,
+            FileSizeCategory::Large if long_lines => Self::ViewportOnly,
+            FileSizeCategory::Large => Self::Full,
+            FileSizeCategory::ExtraLarge if long_lines => Self::Off,
+            FileSizeCategory::ExtraLarge => Self::ViewportOnly,
+        }
+    }
+
+    /// Returns the next lower level of highlighting.
+    pub fn reduced(self) -> Self {
+        match self {
+            Self::Full => Self::ViewportOnly,
+            Self::ViewportOnly | Self::Off => Self::Off,
+        }
+    }
+}
+
+/// The number of lines over which the highlighting latency is averaged before comparing it to the budget.
+const LATENCY_WINDOW: usize = 64;
+
 /// State information for syntax highlighting of a document.
 #[derive(Debug, Clone)]
 pub struct HighlightingState {
@@ -138,10 +233,12 @@ pub struct HighlightingState {
     pub enabled: bool,
     /// Performance metrics for this document
     pub metrics: HighlightingMetrics,
-    /// Cache of highlighted tokens per line (line_number -> tokens)
-    token_cache: HashMap<usize, Vec<TokenInfo>>,
-    /// Cache validity tracking (line_number -> content_hash)
-    cache_validity: HashMap<usize, u64>,
+    /// Cache of highlighted tokens and the content hash they're valid for, per line
+    token_cache: TokenCache,
+    /// Lexer state at the start and end of each cached line (line_number -> (start, end))
+    line_states: HashMap<usize, (LexerState, LexerState)>,
+    /// Every line before this one has been tokenized from a verified start state
+    sync_frontier: usize,
     /// Track which lines need re-highlighting
     dirty_lines: HashSet<usize>,
     /// Track if entire document needs re-highlighting
@@ -156,6 +253,47 @@ pub struct HighlightingState {
     background_batch_size: usize,
     /// Distance from viewport to pre-highlight (lines above and below)
     background_lookahead: usize,
+    /// Range requests on the worker pool that haven't been applied yet
+    async_requests: AsyncRequests,
+    /// How much of the document gets highlighted
+    policy: HighlightingPolicy,
+    /// Whether the user asked for full highlighting, which is then never reduced
+    policy_forced: bool,
+    /// Lines highlighted and the time it took, since the latency was last compared to the budget
+    latency_window: (usize, Duration),
+    /// Where the tokens came from when the renderer last looked up each line in the viewport
+    line_sources: HashMap<usize, TokenSource>,
+}
+
+/// Source of the IDs that tell apart the documents whose results share the worker pool's channel.
+static NEXT_DOCUMENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Bookkeeping for the range requests a document has in flight on the worker pool.
+#[derive(Debug)]
+struct AsyncRequests {
+    /// Identifies the document's results among those of all documents.
+    document: u64,
+    /// Bumped by every edit. Workers abandon requests made for an older generation.
+    generation: Arc<AtomicU64>,
+    /// Request IDs and lines requested in the current generation whose results haven't arrived yet.
+    in_flight: Vec<(u64, Range<usize>)>,
+}
+
+impl Default for AsyncRequests {
+    fn default() -> Self {
+        Self {
+            document: NEXT_DOCUMENT_ID.fetch_add(1, Ordering::Relaxed),
+            generation: Arc::new(AtomicU64::new(0)),
+            in_flight: Vec::new(),
+        }
+    }
+}
+
+impl Clone for AsyncRequests {
+    /// A copy of a state is a different document as far as the worker pool is concerned.
+    fn clone(&self) -> Self {
+        Self::default()
+    }
 }
 
 impl HighlightingState {
@@ -166,8 +304,9 @@ impl HighlightingState {
             config: LanguageConfig::new(language),
             enabled: true,
             metrics: HighlightingMetrics::default(),
-            token_cache: HashMap::new(),
-            cache_validity: HashMap::new(),
+            token_cache: TokenCache::default(),
+            line_states: HashMap::new(),
+            sync_frontier: 0,
             dirty_lines: HashSet::new(),
             needs_full_rehighlight: false,
             viewport: None,
@@ -175,6 +314,11 @@ impl HighlightingState {
             background_in_progress: HashSet::new(),
             background_batch_size: 10, // Process 10 lines per background cycle
             background_lookahead: 50,  // Pre-highlight 50 lines ahead/behind viewport
+            async_requests: AsyncRequests::default(),
+            policy: HighlightingPolicy::Full,
+            policy_forced: false,
+            latency_window: (0, Duration::ZERO),
+            line_sources: HashMap::new(),
         }
     }
 
@@ -185,8 +329,9 @@ impl HighlightingState {
             config: LanguageConfig::disabled(language),
             enabled: false,
             metrics: HighlightingMetrics::default(),
-            token_cache: HashMap::new(),
-            cache_validity: HashMap::new(),
+            token_cache: TokenCache::default(),
+            line_states: HashMap::new(),
+            sync_frontier: 0,
             dirty_lines: HashSet::new(),
             needs_full_rehighlight: false,
             viewport: None,
@@ -194,30 +339,98 @@ impl HighlightingState {
             background_in_progress: HashSet::new(),
             background_batch_size: 10,
             background_lookahead: 50,
+            async_requests: AsyncRequests::default(),
+            policy: HighlightingPolicy::Full,
+            policy_forced: false,
+            latency_window: (0, Duration::ZERO),
+            line_sources: HashMap::new(),
         }
     }
 
     /// Checks if tokens are cached for the given line with the given content hash.
     pub fn has_cached_tokens(&self, line_number: usize, content_hash: u64) -> bool {
-        self.cache_validity.get(&line_number) == Some(&content_hash) &&
-        self.token_cache.contains_key(&line_number)
+        self.token_cache.contains(line_number, content_hash)
     }
 
-    /// Gets cached tokens for the given line.
-    pub fn get_cached_tokens(&self, line_number: usize) -> Option<&Vec<TokenInfo>> {
-        self.token_cache.get(&line_number)
+    /// Gets cached tokens for the given line, whose current content is `line`.
+    /// The cache only stores their offsets and kinds, so the caller must have checked
+    /// with [`HighlightingState::has_cached_tokens`] that the content is unchanged.
+    pub fn get_cached_tokens(&self, line_number: usize, line: &str) -> Option<Vec<TokenInfo>> {
+        self.token_cache.tokens(line_number, line)
     }
 
     /// Caches tokens for the given line with the given content hash.
-    pub fn cache_tokens(&mut self, line_number: usize, content_hash: u64, tokens: Vec<TokenInfo>) {
-        self.token_cache.insert(line_number, tokens);
-        self.cache_validity.insert(line_number, content_hash);
+    /// The line is assumed to start and end in the default lexer state.
+    pub fn cache_tokens(&mut self, line_number: usize, content_hash: u64, tokens: &[TokenInfo]) {
+        let state = LexerState::default();
+        self.cache_line(line_number, content_hash, state.clone(), state, tokens);
+    }
+
+    /// Caches tokens for the given line along with the lexer states it started and ended in.
+    ///
+    /// If the line's start state was verified and its end state changed compared to the
+    /// previously cached one, the lines below it have to be re-tokenized. Otherwise the
+    /// states have converged and the cached lines below remain valid.
+    pub fn cache_line(
+        &mut self,
+        line_number: usize,
+        content_hash: u64,
+        start_state: LexerState,
+        end_state: LexerState,
+        tokens: &[TokenInfo],
+    ) {
+        let end_changed = self.line_end_state(line_number) != Some(&end_state);
+
+        self.token_cache.insert(line_number, content_hash, tokens);
+        self.line_states.insert(line_number, (start_state, end_state));
+
+        if line_number == self.sync_frontier || (line_number < self.sync_frontier && end_changed) {
+            self.sync_frontier = line_number + 1;
+        }
+    }
+
+    /// Checks if tokens are cached for the given line with the given content hash,
+    /// and if they were produced starting from the given lexer state.
+    pub fn has_cached_line(&self, line_number: usize, content_hash: u64, start_state: &LexerState) -> bool {
+        self.has_cached_tokens(line_number, content_hash)
+            && self.line_states.get(&line_number).is_some_and(|(start, _)| start == start_state)
+    }
+
+    /// Records that the cached tokens of the given line were found to be valid.
+    /// This advances the sync frontier if the line is right at it.
+    pub fn mark_line_verified(&mut self, line_number: usize) {
+        if line_number == self.sync_frontier {
+            self.sync_frontier = line_number + 1;
+        }
+    }
+
+    /// Returns the lexer state at the end of the given line, if it is known.
+    pub fn line_end_state(&self, line_number: usize) -> Option<&LexerState> {
+        self.line_states.get(&line_number).map(|(_, end)| end)
+    }
+
+    /// Returns the lexer state the given line starts in, based on the previous line.
+    /// Unknown states fall back to the default state.
+    pub fn line_start_state(&self, line_number: usize) -> LexerState {
+        match line_number.checked_sub(1) {
+            Some(prev) => self.line_end_state(prev).cloned().unwrap_or_default(),
+            None => LexerState::default(),
+        }
+    }
+
+    /// Returns the first line whose start state hasn't been verified yet.
+    /// All lines before it were tokenized in order from the top of the document.
+    pub fn sync_frontier(&self) -> usize {
+        self.sync_frontier
     }
 
     /// Invalidates cache for the given line.
     pub fn invalidate_line_cache(&mut self, line_number: usize) {
-        self.token_cache.remove(&line_number);
-        self.cache_validity.remove(&line_number);
+        self.next_generation();
+        self.token_cache.remove(line_number);
+        self.line_sources.remove(&line_number);
+        // The end state is kept, so that re-tokenizing the line can tell whether it changed.
+        self.sync_frontier = self.sync_frontier.min(line_number);
     }
 
     /// Invalidates cache for a range of lines.
@@ -229,8 +442,113 @@ impl HighlightingState {
 
     /// Clears all cached tokens.
     pub fn clear_cache(&mut self) {
+        self.next_generation();
         self.token_cache.clear();
-        self.cache_validity.clear();
+        self.line_states.clear();
+        self.line_sources.clear();
+        self.sync_frontier = 0;
+    }
+
+    /// Returns where the tokens came from when the renderer last looked up the given line.
+    pub fn line_source(&self, line_number: usize) -> Option<TokenSource> {
+        self.line_sources.get(&line_number).copied()
+    }
+
+    /// Returns where the valid cached tokens of the given line came from. If the line was
+    /// pending when it was last looked up, the workers highlighted it in the meantime.
+    fn cached_line_source(&self, line_number: usize) -> TokenSource {
+        match self.line_source(line_number) {
+            Some(TokenSource::Pending) => TokenSource::Highlighted,
+            _ => TokenSource::Cache,
+        }
+    }
+
+    /// Returns the generation of the document's content, which changes with every edit.
+    pub fn generation(&self) -> u64 {
+        self.async_requests.generation.load(Ordering::Relaxed)
+    }
+
+    /// Moves on to the next generation, which cancels the requests in flight on the worker pool.
+    fn next_generation(&mut self) {
+        self.async_requests.generation.fetch_add(1, Ordering::Relaxed);
+        self.async_requests.in_flight.clear();
+    }
+
+    /// Returns whether the line is part of a range request that's still in flight.
+    pub fn is_line_requested(&self, line_number: usize) -> bool {
+        self.async_requests.in_flight.iter().any(|(_, range)| range.contains(&line_number))
+    }
+
+    /// Returns whether range requests for this document are in flight.
+    pub fn has_pending_requests(&self) -> bool {
+        !self.async_requests.in_flight.is_empty()
+    }
+
+    /// Returns whether lines get highlighted, which depends on the language and the policy.
+    pub fn is_highlighting(&self) -> bool {
+        self.enabled && self.policy != HighlightingPolicy::Off
+    }
+
+    /// Returns how much of the document gets highlighted.
+    pub fn policy(&self) -> HighlightingPolicy {
+        self.policy
+    }
+
+    /// Sets how much of the document gets highlighted, unless the user forced full highlighting.
+    pub fn set_policy(&mut self, policy: HighlightingPolicy) {
+        if self.policy_forced || self.policy == policy {
+            return;
+        }
+        self.policy = policy;
+        self.latency_window = (0, Duration::ZERO);
+        if policy != HighlightingPolicy::Full {
+            self.background_queue.clear();
+        }
+    }
+
+    /// Highlights the whole document from now on, no matter its size or how slow it is.
+    pub fn force_full_highlighting(&mut self) {
+        self.set_policy(HighlightingPolicy::Full);
+        self.policy_forced = true;
+        if self.viewport.is_some() {
+            self.rebuild_background_queue();
+        }
+    }
+
+    /// Returns whether the user forced full highlighting.
+    pub fn is_policy_forced(&self) -> bool {
+        self.policy_forced
+    }
+
+    /// Records how long a line took to highlight. If the lines took longer than `budget`
+    /// on average, the policy is reduced by one level.
+    pub fn record_latency(&mut self, duration: Duration, budget: Duration) {
+        if self.policy_forced || self.policy == HighlightingPolicy::Off {
+            return;
+        }
+
+        let (lines, total) = &mut self.latency_window;
+        *lines += 1;
+        *total += duration;
+        if *lines < LATENCY_WINDOW {
+            return;
+        }
+
+        let average = *total / *lines as u32;
+        self.latency_window = (0, Duration::ZERO);
+        if average > budget {
+            self.set_policy(self.policy.reduced());
+        }
+    }
+
+    /// Returns the first line to tokenize so that the state `line_number` starts in is known.
+    fn sync_start(&self, line_number: usize) -> usize {
+        let first = match self.policy {
+            HighlightingPolicy::Full => line_number.saturating_sub(MAX_SYNC_LINES),
+            // The first visible line starts in whatever state is known for it, or the default one
+            _ => self.viewport.map_or(line_number, |(start, _)| start.min(line_number)),
+        };
+        self.sync_frontier.max(first)
     }
 
     /// Returns the size of the token cache.
@@ -238,6 +556,16 @@ impl HighlightingState {
         self.token_cache.len()
     }
 
+    /// Returns the token cache, e.g. to report its memory usage.
+    pub fn token_cache(&self) -> &TokenCache {
+        &self.token_cache
+    }
+
+    /// Sets how many bytes the token cache may hold before it evicts the least recently used lines.
+    pub fn set_cache_limit(&mut self, limit: usize) {
+        self.token_cache.set_limit(limit);
+    }
+
     /// Mark a line as needing re-highlighting.
     pub fn mark_line_dirty(&mut self, line_number: usize) {
         self.dirty_lines.insert(line_number);
@@ -293,6 +621,7 @@ impl HighlightingState {
         }
         
         self.viewport = Some(new_viewport);
+        self.line_sources.retain(|line, _| (start_line..end_line).contains(line));
         self.rebuild_background_queue();
     }
 
@@ -301,8 +630,8 @@ impl HighlightingState {
         self.background_queue.clear();
         
         let (start_line, end_line) = match self.viewport {
-            Some(viewport) => viewport,
-            None => return,
+            Some(viewport) if self.policy == HighlightingPolicy::Full => viewport,
+            _ => return,
         };
 
         // Calculate the range to pre-highlight
@@ -337,7 +666,7 @@ impl HighlightingState {
 
     /// Checks if a line has valid cached tokens (without checking content hash).
     fn has_valid_cache(&self, line_number: usize) -> bool {
-        self.token_cache.contains_key(&line_number)
+        self.token_cache.contains_line(line_number)
     }
 
     /// Gets the next batch of lines to highlight in the background.
@@ -395,29 +724,12 @@ impl HighlightingState {
         if lines_added == 0 {
             return;
         }
+        self.next_generation();
 
         // Shift cached tokens for lines after the insertion point
-        let mut new_token_cache = HashMap::new();
-        let mut new_cache_validity = HashMap::new();
-        let mut new_dirty_lines = HashSet::new();
-
-        for (&line_num, tokens) in &self.token_cache {
-            if line_num >= start_line {
-                // Shift line numbers down by the number of lines added
-                new_token_cache.insert(line_num + lines_added, tokens.clone());
-            } else {
-                new_token_cache.insert(line_num, tokens.clone());
-            }
-        }
-
-        for (&line_num, &hash) in &self.cache_validity {
-            if line_num >= start_line {
-                new_cache_validity.insert(line_num + lines_added, hash);
-            } else {
-                new_cache_validity.insert(line_num, hash);
-            }
-        }
+        self.token_cache.insert_lines(start_line, lines_added);
 
+        let mut new_dirty_lines = HashSet::new();
         for &line_num in &self.dirty_lines {
             if line_num >= start_line {
                 new_dirty_lines.insert(line_num + lines_added);
@@ -426,9 +738,15 @@ impl HighlightingState {
             }
         }
 
-        self.token_cache = new_token_cache;
-        self.cache_validity = new_cache_validity;
+        self.line_states = self.line_states
+            .drain()
+            .map(|(line_num, states)| {
+                if line_num >= start_line { (line_num + lines_added, states) } else { (line_num, states) }
+            })
+            .collect();
+
         self.dirty_lines = new_dirty_lines;
+        self.sync_frontier = self.sync_frontier.min(start_line);
 
         // Mark the insertion area as dirty
         for line in start_line..(start_line + lines_added) {
@@ -447,31 +765,12 @@ impl HighlightingState {
         if lines_deleted == 0 {
             return;
         }
+        self.next_generation();
 
         // Remove cached tokens for deleted lines and shift remaining lines
-        let mut new_token_cache = HashMap::new();
-        let mut new_cache_validity = HashMap::new();
-        let mut new_dirty_lines = HashSet::new();
-
-        for (&line_num, tokens) in &self.token_cache {
-            if line_num < start_line {
-                // Lines before deletion remain unchanged
-                new_token_cache.insert(line_num, tokens.clone());
-            } else if line_num >= start_line + lines_deleted {
-                // Lines after deletion are shifted up
-                new_token_cache.insert(line_num - lines_deleted, tokens.clone());
-            }
-            // Lines within the deleted range are not copied (removed)
-        }
-
-        for (&line_num, &hash) in &self.cache_validity {
-            if line_num < start_line {
-                new_cache_validity.insert(line_num, hash);
-            } else if line_num >= start_line + lines_deleted {
-                new_cache_validity.insert(line_num - lines_deleted, hash);
-            }
-        }
+        self.token_cache.remove_lines(start_line, lines_deleted);
 
+        let mut new_dirty_lines = HashSet::new();
         for &line_num in &self.dirty_lines {
             if line_num < start_line {
                 new_dirty_lines.insert(line_num);
@@ -480,9 +779,21 @@ impl HighlightingState {
             }
         }
 
-        self.token_cache = new_token_cache;
-        self.cache_validity = new_cache_validity;
+        self.line_states = self.line_states
+            .drain()
+            .filter_map(|(line_num, states)| {
+                if line_num < start_line {
+                    Some((line_num, states))
+                } else if line_num >= start_line + lines_deleted {
+                    Some((line_num - lines_deleted, states))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
         self.dirty_lines = new_dirty_lines;
+        self.sync_frontier = self.sync_frontier.min(start_line);
 
         // Mark the deletion point as dirty
         self.dirty_lines.insert(start_line);
@@ -497,11 +808,171 @@ impl HighlightingState {
     }
 }
 
-/// A wrapper around Synoptic's Highlighter with additional functionality.
+/// The tokenizer behind a [`SyntaxHighlighter`].
+#[derive(Debug)]
+enum Backend {
+    /// Hand-written rules for the stateful [`Lexer`].
+    Lexer(Lexer),
+    /// A TextMate grammar, shared between all highlighters of the language.
+    TextMate(Arc<Grammar>),
+}
+
+/// Registers the rules for unified diffs, which git commit messages embed as well.
+fn diff_rules(lexer: &mut Lexer) {
+    // File headers, from `diff --git` to the `---` and `+++` lines, and the headers of mailed patches
+    lexer.keyword("keyword", r"^(?:diff|index|similarity index|dissimilarity index|rename from|rename to|copy from|copy to|new file mode|deleted file mode|old mode|new mode|Binary files|Only in)\b.*$");
+    lexer.keyword("keyword", r"^(?:---|\+\+\+|\*\*\*) .*$");
+    lexer.keyword("attribute", r"^(From|Date|Subject):\s");
+    
+    // Hunk headers and the function they're in, also of normal diffs like `3,4c3`
+    lexer.keyword("type", r"^@@@? [-+0-9, ]+ @@@?");
+    lexer.keyword("function", r"^@@@? [-+0-9, ]+ @@@?\s*(\S.*)$");
+    lexer.keyword("type", r"^(?:\*{15}|\d+(?:,\d+)?[acd]\d+(?:,\d+)?)$");
+    lexer.keyword("punctuation", r"^---$");
+    
+    // Added and removed lines, and notes like `\ No newline at end of file`
+    lexer.keyword("inserted", r"^[+>].*$");
+    lexer.keyword("deleted", r"^[-<].*$");
+    lexer.keyword("comment", r"^\\ .*$");
+}
+
+/// Splits the [`COMMENT_TAG`]s out of comments and docstrings into `comment.todo` tokens.
+fn split_comment_tags(tokens: Vec<TokenInfo>) -> Vec<TokenInfo> {
+    let has_tag = |token: &TokenInfo| {
+        token.kind.as_deref().is_some_and(|kind| {
+            (kind == "comment" || kind.starts_with("comment.") || kind == "string.doc")
+                && COMMENT_TAG.is_match(&token.text)
+        })
+    };
+    if !tokens.iter().any(has_tag) {
+        return tokens;
+    }
+    
+    let mut result = Vec::with_capacity(tokens.len() + 2);
+    for token in tokens {
+        if !has_tag(&token) {
+            result.push(token);
+            continue;
+        }
+        let mut push = |beg: usize, end: usize, kind: &Option<String>| {
+            if beg < end {
+                let offset = token.start_offset;
+                result.push(TokenInfo::new(token.text[beg..end].to_string(), kind.clone(), offset + beg, offset + end));
+            }
+        };
+        let tag = Some("comment.todo".to_string());
+        let mut pos = 0;
+        for m in COMMENT_TAG.find_iter(&token.text) {
+            push(pos, m.start(), &token.kind);
+            push(m.start(), m.end(), &tag);
+            pos = m.end();
+        }
+        push(pos, token.text.len(), &token.kind);
+    }
+    result
+}
+
+/// Registers the rules for JavaScript, or TypeScript, which adds its types on top.
+/// Their `.jsx` and `.tsx` variants add JSX elements, which would clash with TypeScript's `<T>value` casts.
+fn javascript_rules(lexer: &mut Lexer, language: Language) {
+    let typescript = matches!(language, Language::TypeScript | Language::Tsx);
+    
+    // Keywords
+    lexer.keyword("keyword", r"\b(async|await|break|case|catch|class|const|continue|debugger|default|delete|do|else|export|extends|finally|for|function|if|import|in|instanceof|let|new|of|return|super|switch|this|throw|try|typeof|var|void|while|with|yield)\b");
+    if typescript {
+        // `type` only where it declares an alias, since `node.type` is a common property
+        lexer.keyword("keyword", r"\b(abstract|as|declare|enum|implements|interface|keyof|namespace|override|private|protected|public|readonly|satisfies)\b");
+        lexer.keyword("keyword", r"\b(type)\s+[A-Za-z_$][\w$]*\s*[<=]");
+    }
+    
+    // Built-in objects
+    lexer.keyword("type", r"\b(Array|Boolean|Date|Error|Function|JSON|Map|Math|Number|Object|Promise|RegExp|Set|String|Symbol|console|document|window)\b");
+    
+    if typescript {
+        // Primitive types, declared names and the types after `:`, `extends`, `implements`, `as` and `satisfies`.
+        // Only capitalized names count after a `:`, which keeps the values of object literals plain.
+        lexer.keyword("type", r"\b(any|bigint|boolean|never|number|object|string|symbol|unknown)\b");
+        lexer.keyword("type", r"\b(?:class|enum|interface|namespace|type)\s+([A-Za-z_$][\w$]*)");
+        lexer.keyword("type", r"\b(?:as|extends|implements|keyof|satisfies)\s+([A-Z][\w$.]*)");
+        lexer.keyword("type", r":\s*([A-Z][\w$.]*)");
+        
+        // Generic arguments and parameters like `Map<string, User[]>`, whose `<` directly follows a name
+        lexer.keyword("type", r"\b(<(?:[^<>()=;]|<(?:[^<>()=;]|<[^<>()=;]*>)*>)*>)");
+        
+        // Decorators
+        lexer.keyword("decorator", r"@[A-Za-z_$][\w$]*");
+    }
+    
+    if matches!(language, Language::Jsx | Language::Tsx) {
+        jsx_rules(lexer, language);
+    }
+    
+    // Strings. Quotes end at the end of the line, like they do for the parser.
+    let escapes = |lexer: &mut Lexer| {
+        lexer.region_keyword("string.escape", r"\\(?:x[0-9a-fA-F]{2}|u[0-9a-fA-F]{4}|u\{[0-9a-fA-F]{1,6}\}|[^xu]|$)");
+        lexer.region_keyword("invalid", r"\\[xu]");
+    };
+    lexer.bounded("string", "\"", "\"|$", true);
+    escapes(lexer);
+    lexer.bounded("string", "'", "'|$", true);
+    escapes(lexer);
+    lexer.bounded_interp("string", "`", "`", r"\$\{", r"\}", true);
+    escapes(lexer);
+    
+    // Comments, including JSDoc ones and their tags like `@param` or `{@link}`
+    lexer.keyword("comment", r"//.*$");
+    lexer.bounded("comment.doc", r"/\*\*(?:[^*/]|$)", r"\*/", false);
+    lexer.region_keyword("comment.doc.tag", r"(?:^|[\s*{])(@[A-Za-z]+)");
+    lexer.bounded("comment", r"/\*", r"\*/", false);
+    
+    // Numbers
+    lexer.keyword("number", r"\b\d+(?:\.\d+)?(?:[eE][+-]?\d+)?\b");
+    
+    // Regex
+    lexer.keyword("regex", r"/[^/\n]+/[gimuy]*");
+}
+
+/// Registers the rules for JSX elements. An element is a region from the `<` of its opening tag
+/// through its closing tag, or the `/>` of a self-closing one, so that its content can be plain text.
+fn jsx_rules(lexer: &mut Lexer, language: Language) {
+    // The `<` of the closing tag ends the content, see `jsx_tag`, and the rest ends the element.
+    const END: &str = r"<(/[\w.:-]*\s*>)|(/>)";
+    
+    // Closing tags of elements that started above what the lexer has seen
+    lexer.keyword("keyword", r"\B(</(?:[A-Za-z][\w.:-]*)?\s*>)");
+    // A `<` only starts a tag if a name directly follows it, and it doesn't directly follow one itself,
+    // which rules out both `a < b` and the `<` of `Array<T>`. A TSX type parameter like `<T,>` isn't one either.
+    lexer.nested_balanced("keyword", r"\B(<[A-Za-z][\w.:-]*)(?:[\s/>]|$)", END, move || jsx_tag(language));
+    // Fragments
+    lexer.nested_balanced("keyword", r"\B(<)>", END, move || jsx_tag(language));
+}
+
+/// Builds the lexer for a JSX element after the name in its opening tag: the attributes, then the content.
+fn jsx_tag(language: Language) -> Lexer {
+    let expression = move || SyntaxHighlighter::build_lexer(language).unwrap_or_default();
+    
+    let mut tag = Lexer::new();
+    tag.bounded("string", "\"", "\"", false);
+    tag.bounded("string", "'", "'", false);
+    tag.nested_balanced("punctuation", r"\{", r"\}", expression);
+    tag.keyword("attribute", r"[A-Za-z_$][\w$.:-]*");
+    
+    // The element hands the content everything up to and including the `<` of its closing tag.
+    // Text runs until a `{` or a child element, and an apostrophe in it is just that.
+    tag.nested_balanced("keyword", "(>)", "(<)$", move || {
+        let mut content = Lexer::new();
+        content.nested_balanced("punctuation", r"\{", r"\}", expression);
+        jsx_rules(&mut content, language);
+        content
+    });
+    tag
+}
+
+/// A language-specific tokenizer, backed by either the stateful [`Lexer`] or a TextMate [`Grammar`].
 #[derive(Debug)]
 pub struct SyntaxHighlighter {
-    /// The underlying Synoptic highlighter
-    highlighter: Option<Highlighter>,
+    /// The underlying tokenizer with this language's rules
+    backend: Option<Backend>,
     /// The language this highlighter is configured for
     language: Language,
     /// Whether the highlighter has been initialized
@@ -513,7 +984,7 @@ impl SyntaxHighlighter {
     /// Note: The highlighter is not initialized until first use (lazy initialization).
     pub fn new(language: Language) -> Self {
         Self {
-            highlighter: None,
+            backend: None,
             language,
             initialized: false,
         }
@@ -526,231 +997,873 @@ impl SyntaxHighlighter {
             return Ok(());
         }
 
-        // Try to create a Synoptic highlighter from extension
-        let extension = self.language.primary_extension();
-        match synoptic::from_extension(extension, 4) { // 4-space tabs
-            Some(highlighter) => {
-                self.highlighter = Some(highlighter);
-                self.initialized = true;
-                Ok(())
+        // User-defined languages may come with a TextMate grammar instead of rules
+        if let Language::Custom(id) = self.language
+            && let Some(grammar) = user_languages::get(id).and_then(|def| def.grammar.clone())
+        {
+            self.backend = Some(Backend::TextMate(grammar));
+            self.initialized = true;
+            return Ok(());
+        }
+
+        self.backend = Some(Backend::Lexer(Self::build_lexer(self.language)?));
+        self.initialized = true;
+        Ok(())
+    }
+
+    /// Builds the [`Lexer`] with the rules of the given language.
+    fn build_lexer(language: Language) -> Result<Lexer, String> {
+        let mut lexer = Lexer::new();
+
+        // Rules are tried in definition order when two of them match at the same offset
+        match language {
+            Language::Rust => {
+                // Keywords
+                lexer.keyword("keyword", r"\b(as|async|await|break|const|continue|crate|dyn|else|enum|extern|false|fn|for|if|impl|in|let|loop|match|mod|move|mut|pub|ref|return|self|Self|static|struct|super|trait|true|type|use|where|while)\b");
+                // Themes can single it out; it's colored like any keyword otherwise
+                lexer.keyword("keyword.unsafe", r"\b(unsafe)\b");
+                
+                // Types
+                lexer.keyword("type", r"\b(bool|char|f32|f64|i8|i16|i32|i64|i128|isize|str|u8|u16|u32|u64|u128|usize|String|Vec|Option|Result|Box|Rc|Arc)\b");
+                
+                // Strings (raw strings may span lines, just like regular ones)
+                lexer.bounded("string", r###"\bb?r##""###, r###""##"###, false);
+                lexer.bounded("string", r##"\bb?r#""##, r##""#"##, false);
+                lexer.bounded("string", r#"\bb?r""#, "\"", false);
+                // Format strings like `{name:>8}`, with `{{` and `}}` escaped. A lexer can't tell which
+                // macros take one, so this assumes that any macro's string literal argument is one,
+                // if it's the first argument or follows others like `write!(f, "...")`.
+                const ESCAPE: &str = r#"\\(?:[nrt0\\'"]|x[0-7][0-9a-fA-F]|u\{[0-9a-fA-F]{1,6}\}|$)"#;
+                lexer.bounded("string", r#"\b[A-Za-z_]\w*!\s*[(\[{](?:[^"()\[\]{}]*,)?\s*(")"#, "\"", true);
+                lexer.region_keyword("string.escape", ESCAPE);
+                lexer.region_keyword("invalid", r"\\.");
+                lexer.region_keyword("string.escape", r"\{\{|\}\}");
+                lexer.region_keyword("string.placeholder", r#"\{[\w.]*(?::[^{}"]*)?\}"#);
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.region_keyword("string.escape", ESCAPE);
+                lexer.region_keyword("invalid", r"\\.");
+                // Character literals, but not lifetimes like `'a`: the quote only starts one
+                // if the rest of the literal follows
+                lexer.bounded("string", r"(')(?:[^'\\]|\\(?:u\{[0-9a-fA-F]{1,6}\}|x[0-9a-fA-F]{2}|.))'", "'", true);
+                lexer.region_keyword("string.escape", ESCAPE);
+                lexer.region_keyword("invalid", r"\\.");
+                
+                // Comments, including doc comments, but not `////` or `/**/`
+                lexer.keyword("comment.doc", r"///(?:[^/].*)?$|//!.*$");
+                lexer.keyword("comment", r"//.*$");
+                lexer.bounded("comment.doc", r"/\*(?:\*[^*/]|\*$|!)", r"\*/", false);
+                lexer.bounded("comment", r"/\*", r"\*/", false);
+                
+                // Numbers
+                lexer.keyword("number", r"\b\d+(?:\.\d+)?(?:[eE][+-]?\d+)?(?:f32|f64|i8|i16|i32|i64|i128|isize|u8|u16|u32|u64|u128|usize)?\b");
+                
+                // Attributes
+                lexer.keyword("attribute", r"#!?\[.*?\]");
             }
-            None => {
-                // Create a highlighter manually for languages not supported by Synoptic
-                let mut highlighter = Highlighter::new(4); // 4-space tabs
-                
-                // Add comprehensive highlighting rules for Phase 1 languages
-                match self.language {
-                    Language::Rust => {
-                        // Keywords
-                        highlighter.keyword("keyword", r"\b(as|async|await|break|const|continue|crate|dyn|else|enum|extern|false|fn|for|if|impl|in|let|loop|match|mod|move|mut|pub|ref|return|self|Self|static|struct|super|trait|true|type|unsafe|use|where|while)\b");
-                        
-                        // Types
-                        highlighter.keyword("type", r"\b(bool|char|f32|f64|i8|i16|i32|i64|i128|isize|str|u8|u16|u32|u64|u128|usize|String|Vec|Option|Result|Box|Rc|Arc)\b");
-                        
-                        // Strings
-                        highlighter.bounded_interp("string", "\"", "\"", "\\{", "\\}", true);
-                        highlighter.keyword("string", r"'[^']*'");
-                        highlighter.keyword("string", r##"r#*".*?"#*"##);
-                        
-                        // Comments
-                        highlighter.keyword("comment", r"//.*$");
-                        highlighter.bounded("comment", r"/\*", r"\*/", false);
-                        
-                        // Numbers
-                        highlighter.keyword("number", r"\b\d+(\.\d+)?([eE][+-]?\d+)?(f32|f64|i8|i16|i32|i64|i128|isize|u8|u16|u32|u64|u128|usize)?\b");
-                        
-                        // Attributes
-                        highlighter.keyword("attribute", r"#\[.*?\]");
-                    }
-                    Language::JavaScript | Language::TypeScript => {
-                        // Keywords
-                        highlighter.keyword("keyword", r"\b(async|await|break|case|catch|class|const|continue|debugger|default|delete|do|else|export|extends|finally|for|function|if|import|in|instanceof|let|new|of|return|super|switch|this|throw|try|typeof|var|void|while|with|yield)\b");
-                        
-                        // Built-in objects
-                        highlighter.keyword("type", r"\b(Array|Boolean|Date|Error|Function|JSON|Map|Math|Number|Object|Promise|RegExp|Set|String|Symbol|console|document|window)\b");
-                        
-                        // Strings
-                        highlighter.bounded("string", "\"", "\"", true);
-                        highlighter.bounded("string", "'", "'", true);
-                        highlighter.bounded_interp("string", "`", "`", "${", "}", true);
-                        
-                        // Comments
-                        highlighter.keyword("comment", r"//.*$");
-                        highlighter.bounded("comment", r"/\*", r"\*/", false);
-                        
-                        // Numbers
-                        highlighter.keyword("number", r"\b\d+(\.\d+)?([eE][+-]?\d+)?\b");
-                        
-                        // Regex
-                        highlighter.keyword("regex", r"/[^/\n]+/[gimuy]*");
-                    }
-                    Language::Python => {
-                        // Keywords
-                        highlighter.keyword("keyword", r"\b(and|as|assert|async|await|break|class|continue|def|del|elif|else|except|False|finally|for|from|global|if|import|in|is|lambda|None|nonlocal|not|or|pass|raise|return|True|try|while|with|yield)\b");
-                        
-                        // Built-in functions
-                        highlighter.keyword("builtin", r"\b(abs|all|any|ascii|bin|bool|breakpoint|bytearray|bytes|callable|chr|classmethod|compile|complex|delattr|dict|dir|divmod|enumerate|eval|exec|filter|float|format|frozenset|getattr|globals|hasattr|hash|help|hex|id|input|int|isinstance|issubclass|iter|len|list|locals|map|max|memoryview|min|next|object|oct|open|ord|pow|print|property|range|repr|reversed|round|set|setattr|slice|sorted|staticmethod|str|sum|super|tuple|type|vars|zip)\b");
-                        
-                        // Strings
-                        highlighter.bounded("string", "\"\"\"", "\"\"\"", false);
-                        highlighter.bounded("string", "'''", "'''", false);
-                        highlighter.bounded("string", "\"", "\"", true);
-                        highlighter.bounded("string", "'", "'", true);
-                        highlighter.keyword("string", r#"[rf]"[^"]*"|[rf]'[^']*'"#);
-                        
-                        // Comments
-                        highlighter.keyword("comment", r"#.*$");
-                        
-                        // Numbers
-                        highlighter.keyword("number", r"\b\d+(\.\d+)?([eE][+-]?\d+)?\b");
-                        highlighter.keyword("number", r"\b0[xX][0-9a-fA-F]+\b");
-                        highlighter.keyword("number", r"\b0[bB][01]+\b");
-                        highlighter.keyword("number", r"\b0[oO][0-7]+\b");
-                        
-                        // Decorators
-                        highlighter.keyword("decorator", r"@\w+");
-                    }
-                    Language::Json => {
-                        // Strings
-                        highlighter.bounded("string", "\"", "\"", true);
-                        
-                        // Numbers
-                        highlighter.keyword("number", r"-?\b\d+(\.\d+)?([eE][+-]?\d+)?\b");
-                        
-                        // Booleans and null
-                        highlighter.keyword("boolean", r"\b(true|false|null)\b");
-                    }
-                    _ => {
-                        // For other languages, add basic string and comment highlighting
-                        highlighter.bounded("string", "\"", "\"", true);
-                        highlighter.keyword("comment", r"//.*$|#.*$");
+            Language::JavaScript | Language::TypeScript | Language::Jsx | Language::Tsx => {
+                javascript_rules(&mut lexer, language)
+            }
+            Language::Python => {
+                // Keywords
+                lexer.keyword("keyword", r"\b(and|as|assert|async|await|break|class|continue|def|del|elif|else|except|False|finally|for|from|global|if|import|in|is|lambda|None|nonlocal|not|or|pass|raise|return|True|try|while|with|yield)\b");
+                
+                // Built-in functions
+                lexer.keyword("builtin", r"\b(abs|all|any|ascii|bin|bool|breakpoint|bytearray|bytes|callable|chr|classmethod|compile|complex|delattr|dict|dir|divmod|enumerate|eval|exec|filter|float|format|frozenset|getattr|globals|hasattr|hash|help|hex|id|input|int|isinstance|issubclass|iter|len|list|locals|map|max|memoryview|min|next|object|oct|open|ord|pow|print|property|range|repr|reversed|round|set|setattr|slice|sorted|staticmethod|str|sum|super|tuple|type|vars|zip)\b");
+                
+                // Strings, by prefix. Raw strings have no escapes, f-strings have `{expr}` placeholders,
+                // and other strings may be formatted with `%s` or `{}` ones.
+                let escapes = |lexer: &mut Lexer| {
+                    lexer.region_keyword("string.escape", r#"\\(?:[\\'"abfnrtv]|[0-7]{1,3}|x[0-9a-fA-F]{2}|u[0-9a-fA-F]{4}|U[0-9a-fA-F]{8}|N\{[^}]+\}|$)"#);
+                    lexer.region_keyword("invalid", r"\\.");
+                    lexer.region_keyword("string.escape", r"\{\{|\}\}");
+                };
+                // Docstrings: triple-quoted strings that are statements of their own
+                for quote in ["\"\"\"", "'''"] {
+                    lexer.bounded("string.doc", &format!(r"^\s*((?i:[ru])?{quote})"), quote, false);
+                    escapes(&mut lexer);
+                }
+                for quote in ["\"\"\"", "'''", "\"", "'"] {
+                    let escapable = quote.len() == 1;
+                    lexer.bounded("string", &format!(r"(?i)\b(?:r|rb|br){quote}"), quote, escapable);
+                    lexer.bounded("string", &format!(r"(?i)\b(?:rf|fr){quote}"), quote, escapable);
+                    lexer.region_keyword("string.escape", r"\{\{|\}\}");
+                    lexer.region_keyword("string.placeholder", r"\{[^{}]*\}");
+                    lexer.bounded("string", &format!(r"(?i)\bf{quote}"), quote, escapable);
+                    escapes(&mut lexer);
+                    lexer.region_keyword("string.placeholder", r"\{[^{}]*\}");
+                    lexer.bounded("string", &format!(r"(?i)(?:\b[bu])?{quote}"), quote, escapable);
+                    escapes(&mut lexer);
+                    if escapable {
+                        // Not in docstrings, whose prose is more likely to contain "50%" or "{}" than a placeholder
+                        lexer.region_keyword("string.escape", "%%");
+                        lexer.region_keyword("string.placeholder", r"%(?:\([^)]*\))?[#0+-]*(?:\*|\d+)?(?:\.(?:\*|\d+))?[diouxXeEfFgGcrsab]");
+                        lexer.region_keyword("string.placeholder", r"\{[\w.\[\]]*(?:![rsa])?(?::[^{}]*)?\}");
                     }
                 }
                 
-                self.highlighter = Some(highlighter);
-                self.initialized = true;
-                Ok(())
+                // Comments
+                lexer.keyword("comment", r"#.*$");
+                
+                // Numbers
+                lexer.keyword("number", r"\b\d+(?:\.\d+)?(?:[eE][+-]?\d+)?\b");
+                lexer.keyword("number", r"\b0[xX][0-9a-fA-F]+\b");
+                lexer.keyword("number", r"\b0[bB][01]+\b");
+                lexer.keyword("number", r"\b0[oO][0-7]+\b");
+                
+                // Decorators
+                lexer.keyword("decorator", r"@\w+");
+            }
+            Language::Json => {
+                // Strings
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.region_keyword("string.escape", r#"\\(?:["\\/bfnrt]|u[0-9a-fA-F]{4})"#);
+                lexer.region_keyword("invalid", r"\\.");
+                
+                // Numbers
+                lexer.keyword("number", r"-?\b\d+(?:\.\d+)?(?:[eE][+-]?\d+)?\b");
+                
+                // Booleans and null
+                lexer.keyword("boolean", r"\b(true|false|null)\b");
+            }
+            Language::Html => {
+                // Comments, doctype and CDATA
+                lexer.bounded("comment", "<!--", "-->", false);
+                lexer.keyword("keyword", r"(?i)<!DOCTYPE\b[^>]*>");
+                lexer.bounded("string", r"<!\[CDATA\[", r"\]\]>", false);
+                
+                // Scripts and style sheets, from the `>` of their start tag on. The tag's name and
+                // attributes match earlier, and are highlighted by the rules below.
+                lexer.nested("keyword", r"(?i)<script\b[^>]*(>)", r"(?i)</script\s*>", || {
+                    Self::build_lexer(Language::JavaScript).unwrap_or_default()
+                });
+                lexer.nested("keyword", r"(?i)<style\b[^>]*(>)", r"(?i)</style\s*>", || {
+                    Self::build_lexer(Language::Css).unwrap_or_default()
+                });
+                
+                // Tags
+                lexer.keyword("keyword", r"</?[A-Za-z][\w.:-]*|/?>");
+                
+                // Attributes and their values. Quotes in the text between tags are left alone.
+                lexer.keyword("attribute", r"(?:^|\s)([A-Za-z_:@][\w.:-]*)\s*=");
+                lexer.keyword("string", r#"=\s*("[^"]*"|'[^']*'|[^\s>"'=`]+)"#);
+                lexer.bounded("string", r#"=\s*(")"#, "\"", false);
+                
+                // Character references
+                lexer.keyword("constant", r"&(?:#[0-9]+|#[xX][0-9a-fA-F]+|[A-Za-z][A-Za-z0-9]*);");
             }
+            Language::Css => {
+                // Comments and strings
+                lexer.bounded("comment", r"/\*", r"\*/", false);
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.bounded("string", "'", "'", true);
+                
+                // At-rules and `!important`
+                lexer.keyword("keyword", r"@[\w-]+|!\s*important\b");
+                
+                // Custom properties, before the declarations they may be part of
+                lexer.keyword("variable", r"--[\w-]+");
+                
+                // Property names: indented, or following `{` or `;`
+                lexer.keyword("attribute", r"(?:^\s+|[{;(]\s*)(-?[A-Za-z][\w-]*)\s*:");
+                
+                // Colors, before id selectors, since `#fab` could be either
+                lexer.keyword("constant", r"#[0-9a-fA-F]{3,8}\b");
+                
+                // Selectors
+                lexer.keyword("type", r"[.#]-?[A-Za-z_][\w-]*");
+                lexer.keyword("keyword", r"::[\w-]+|:(?:active|any-link|checked|default|defined|disabled|empty|enabled|first|first-child|first-of-type|focus|focus-visible|focus-within|fullscreen|has|hover|in-range|indeterminate|invalid|is|lang|last-child|last-of-type|left|link|not|nth-child|nth-last-child|nth-last-of-type|nth-of-type|only-child|only-of-type|optional|out-of-range|placeholder-shown|read-only|read-write|required|right|root|scope|target|valid|visited|where|after|before|first-letter|first-line)\b");
+                lexer.keyword("attribute", r"\[([\w-]+)");
+                
+                // Functions like `rgb(` and `var(`
+                lexer.keyword("function", r"\b([A-Za-z][\w-]*)\(");
+                
+                // Numbers with their unit
+                lexer.keyword("number", r"(?:^|[^\w#.-])(-?(?:\d+(?:\.\d+)?|\.\d+)(?:%|[A-Za-z]+)?)");
+            }
+            Language::Markdown => {
+                // Fenced code blocks whose info string names a language, like ```rust, are highlighted
+                // as that language. The fence line is a string, and the code starts on the next line.
+                let mut all_names = Vec::new();
+                for &language in Language::ALL.iter().filter(|&&l| l != Language::PlainText) {
+                    let names: Vec<_> = language.names().into_iter().map(regex::escape).collect();
+                    let start = format!(r"(?i)^\s*(```+|~~~+)\s*\{{?\.?(?:{})(?:[\s,{{}}]|$).*?()$", names.join("|"));
+                    lexer.nested("string", &start, r"^\s*\1[`~]*\s*$", move || {
+                        Self::build_lexer(language).unwrap_or_default()
+                    });
+                    all_names.extend(names);
+                }
+                let all_names = all_names.join("|");
+                lexer.keyword("string", &format!(r"(?i)^\s*(?:```+|~~~+)\s*\{{?\.?(?:{all_names})(?:[\s,{{}}]|$).*$"));
+                
+                // Other fenced code blocks
+                lexer.bounded("string", r"^\s*```", r"^\s*```", false);
+                lexer.bounded("string", r"^\s*~~~", r"^\s*~~~", false);
+                
+                // HTML comments
+                lexer.bounded("comment", "<!--", "-->", false);
+                
+                // Thematic breaks, before setext underlines and list items, since `---` and `- - -` look alike
+                lexer.keyword("punctuation", r"^ {0,3}(?:(?:\*\s*){3,}|(?:-\s*){3,}|(?:_\s*){3,})$");
+                
+                // Headings, including setext underlines
+                lexer.keyword("keyword", r"^ {0,3}#{1,6}(?:\s.*)?$");
+                lexer.keyword("keyword", r"^ {0,3}(?:=+|-{2,})\s*$");
+                
+                // Block quotes and list items
+                lexer.keyword("comment", r"^\s*>.*$");
+                lexer.keyword("punctuation", r"^\s*([-*+]|\d{1,9}[.)])(?:\s|$)");
+                lexer.keyword("punctuation", r"^\s*[-*+]\s+(\[[ xX]\])\s");
+                
+                // Code spans
+                lexer.keyword("string", r"``(?:[^`]|`[^`])+``|`[^`]+`");
+                
+                // Links, images, reference definitions and autolinks
+                lexer.keyword("function", r"!?\[[^\]]*\]\([^)]*\)");
+                lexer.keyword("function", r"!?\[[^\]]+\]\[[^\]]*\]");
+                lexer.keyword("function", r"^ {0,3}\[[^\]]+\]:\s*\S+");
+                lexer.keyword("string", r"<(?:https?|ftp|mailto):[^>\s]+>");
+                
+                // Emphasis. Underscores inside of words don't count.
+                lexer.keyword("constant", r"\*\*[^*\s](?:[^*]*[^*\s])?\*\*|\b__[^_\s](?:[^_]*[^_\s])?__\b");
+                lexer.keyword("constant", r"\*[^*\s](?:[^*]*[^*\s])?\*");
+                lexer.keyword("constant", r"(?:^|[^\w])(_[^_\s](?:[^_]*[^_\s])?_)(?:[^\w]|$)");
+                lexer.keyword("constant", r"~~[^~]+~~");
+            }
+            Language::Yaml => {
+                // Comments need to be preceded by whitespace, so that `a#b` is a plain scalar
+                lexer.keyword("comment", r"(?:^|\s)(#.*)$");
+                
+                // Directives and document markers
+                lexer.keyword("keyword", r"^%.*$");
+                lexer.keyword("keyword", r"^(?:---|\.\.\.)(?:\s|$)");
+                
+                // Mapping keys, both in block and in flow style
+                lexer.keyword("attribute", r#"^\s*(?:[-?]\s+)*("[^"]*"|'[^']*'|[^\s#'"{}\[\],&*!|>%@`-][^#]*?|-[^\s#][^#]*?)\s*:(?:\s|$)"#);
+                lexer.keyword("attribute", r#"[{,]\s*("[^"]*"|'[^']*'|[\w.-]+)\s*:(?:\s|$)"#);
+                
+                // Strings
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.bounded("string", "'", "'", false);
+                
+                // Anchors, aliases and tags
+                lexer.keyword("variable", r"[&*][^\s,\[\]{}]+");
+                lexer.keyword("type", r"(?:^|\s)(!!?[\w/.:<>-]*)");
+                
+                // Block scalar indicators
+                lexer.keyword("operator", r"(?:^|\s)([|>][-+0-9]*)\s*(?:#.*)?$");
+                
+                // Plain scalars that are entirely a boolean, null or number
+                lexer.keyword("boolean", r"(?:^|:\s+|-\s+|[\[{,]\s*)((?i:true|false|yes|no|on|off|null)|~)\s*(?:$|[,\]}]|\s#)");
+                lexer.keyword("number", r"(?:^|:\s+|-\s+|[\[{,]\s*)([-+]?(?:0x[0-9a-fA-F]+|0o[0-7]+|(?:\d[\d_]*)?\.?\d+(?:[eE][-+]?\d+)?|\.(?:inf|Inf|INF|nan|NaN|NAN)))\s*(?:$|[,\]}]|\s#)");
+            }
+            Language::Toml => {
+                // Comments
+                lexer.keyword("comment", r"#.*$");
+                
+                // Table headers, but not arrays of arrays like `[1, 2],`
+                lexer.keyword("keyword", r#"^\s*(\[\[?\s*(?:[\w-]+|"[^"]*"|'[^']*')(?:\s*\.\s*(?:[\w-]+|"[^"]*"|'[^']*'))*\s*\]\]?)\s*(?:#.*)?$"#);
+                
+                // Keys, which may be dotted or quoted, including those in inline tables
+                lexer.keyword("attribute", r#"^\s*((?:[\w-]+|"[^"]*"|'[^']*')(?:\s*\.\s*(?:[\w-]+|"[^"]*"|'[^']*'))*)\s*="#);
+                lexer.keyword("attribute", r"[{,]\s*([\w-]+(?:\s*\.\s*[\w-]+)*)\s*=");
+                
+                // Strings, after the keys, so that quoted keys are keys
+                lexer.bounded("string", "\"\"\"", "\"\"\"", true);
+                lexer.bounded("string", "'''", "'''", false);
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.bounded("string", "'", "'", false);
+                
+                // Booleans
+                lexer.keyword("boolean", r"\b(true|false)\b");
+                
+                // Dates and times, before numbers
+                lexer.keyword("constant", r"\b\d{4}-\d{2}-\d{2}(?:[Tt ]\d{2}:\d{2}(?::\d{2}(?:\.\d+)?)?(?:[Zz]|[+-]\d{2}:\d{2})?)?\b|\b\d{2}:\d{2}:\d{2}(?:\.\d+)?\b");
+                
+                // Numbers
+                lexer.keyword("number", r"(?:^|[^\w.-])([+-]?(?:0x[0-9a-fA-F_]+|0o[0-7_]+|0b[01_]+|\d[\d_]*(?:\.[\d_]+)?(?:[eE][+-]?[\d_]+)?|inf|nan))\b");
+            }
+            Language::Sql => {
+                // Comments
+                lexer.keyword("comment", r"--.*$");
+                lexer.bounded("comment", r"/\*", r"\*/", false);
+                
+                // Strings. Quotes are escaped by doubling them, which needs no special handling.
+                lexer.bounded("string", r"(?i)\b[enx]?'", "'", false);
+                lexer.bounded("string", "'", "'", false);
+                lexer.bounded("string", r"\$\$", r"\$\$", false);
+                
+                // Quoted identifiers
+                lexer.bounded("variable", "\"", "\"", false);
+                lexer.keyword("variable", r"`[^`]*`|\[[^\]\s]+\]");
+                
+                // Keywords
+                lexer.keyword("keyword", r"(?i)\b(add|after|all|alter|analyze|and|any|as|asc|before|begin|between|by|cascade|case|check|collate|column|commit|conflict|constraint|create|cross|current|database|declare|default|deferrable|delete|desc|distinct|do|drop|each|else|end|escape|except|exclusive|execute|exists|explain|fetch|first|following|for|foreign|from|full|function|grant|group|having|if|ignore|ilike|immediate|in|index|inner|insert|instead|intersect|into|is|isnull|join|key|last|lateral|left|like|limit|natural|next|not|nothing|notnull|null|nulls|of|offset|on|only|or|order|outer|over|partition|preceding|primary|procedure|range|recursive|references|release|rename|replace|restrict|return|returning|returns|revoke|right|rollback|row|rows|savepoint|schema|select|sequence|set|similar|some|table|temp|temporary|then|ties|to|transaction|trigger|truncate|unbounded|union|unique|update|using|vacuum|values|view|when|where|window|with|without)\b");
+                
+                // Types
+                lexer.keyword("type", r"(?i)\b(bigint|bigserial|binary|bit|blob|bool|boolean|bytea|char|character|clob|date|datetime|datetime2|dec|decimal|double|enum|float|int|int2|int4|int8|integer|interval|json|jsonb|longtext|mediumint|money|nchar|numeric|nvarchar|precision|real|serial|smallint|smallserial|text|time|timestamp|timestamptz|tinyint|uuid|varbinary|varchar|varying|xml|year|zone)\b");
+                
+                // Built-in functions
+                lexer.keyword("builtin", r"(?i)\b(abs|avg|cast|ceil|ceiling|coalesce|concat|convert|count|current_date|current_time|current_timestamp|date_trunc|dense_rank|extract|first_value|floor|greatest|group_concat|ifnull|lag|last_value|lead|least|left|length|lower|ltrim|max|min|now|nullif|nvl|rank|replace|right|round|row_number|rtrim|string_agg|substr|substring|sum|trim|upper)\s*\(");
+                
+                // Booleans and numbers
+                lexer.keyword("boolean", r"(?i)\b(true|false|unknown)\b");
+                lexer.keyword("number", r"\b0[xX][0-9a-fA-F]+\b|(?:\b\d+(?:\.\d*)?|\.\d+)(?:[eE][+-]?\d+)?\b");
+                
+                // Parameters like `:name`, `@name`, `$1` and `?`, but not `::type` casts
+                lexer.keyword("variable", r"(?:^|[^:\w])([:@]\w+|\$\d+|\?)");
+            }
+            Language::C => {
+                // Comments
+                lexer.keyword("comment", r"//.*$");
+                lexer.bounded("comment", r"/\*", r"\*/", false);
+                
+                // Preprocessor directives, the header of `#include <...>` and the name of `#define`
+                lexer.keyword("keyword", r"^\s*(#\s*[A-Za-z_]\w*)");
+                lexer.keyword("string", r"^\s*#\s*(?:include|include_next|embed)\s*(<[^>]*>)");
+                lexer.keyword("constant", r"^\s*#\s*define\s+([A-Za-z_]\w*)");
+                
+                // Strings and character literals, with their encoding prefix
+                lexer.bounded("string", r#"(?:\b(?:u8|[LuU]))?""#, "\"", true);
+                lexer.keyword("string", r"(?:\b(?:u8|[LuU]))?'(?:[^'\\]|\\(?:x[0-9a-fA-F]+|u[0-9a-fA-F]{4}|U[0-9a-fA-F]{8}|[0-7]{1,3}|.))'");
+                
+                // Keywords
+                lexer.keyword("keyword", r"\b(alignas|alignof|auto|break|case|const|constexpr|continue|default|defined|do|else|enum|extern|for|goto|if|inline|register|restrict|return|sizeof|static|static_assert|struct|switch|thread_local|typedef|typeof|typeof_unqual|union|volatile|while|_Alignas|_Alignof|_Atomic|_Generic|_Noreturn|_Static_assert|_Thread_local)\b");
+                
+                // Types, including the `_t` typedefs of the standard library and tagged types
+                lexer.keyword("type", r"\b(bool|char|double|float|int|long|short|signed|unsigned|void|FILE|_Bool|_Complex|_Imaginary|[a-z_]\w*_t)\b");
+                lexer.keyword("type", r"\b(?:struct|enum|union)\s+([A-Za-z_]\w*)");
+                
+                // Booleans and constants, including macros spelled in upper case
+                lexer.keyword("boolean", r"\b(true|false)\b");
+                lexer.keyword("constant", r"\b(NULL|nullptr|[A-Z][A-Z0-9_]*[A-Z0-9])\b");
+                
+                // Function calls and definitions
+                lexer.keyword("function", r"\b([A-Za-z_]\w*)\s*\(");
+                
+                // Numbers, with their suffix like `10UL` or `1.5f`
+                lexer.keyword("number", r"\b(?:0[xX][0-9a-fA-F']+(?:\.[0-9a-fA-F']*)?(?:[pP][+-]?\d+)?|0[bB][01']+|\d[\d']*(?:\.[\d']*)?(?:[eE][+-]?\d+)?)\w*|\B\.\d[\d']*(?:[eE][+-]?\d+)?\w*");
+            }
+            Language::Cpp => {
+                // Comments
+                lexer.keyword("comment", r"//.*$");
+                lexer.bounded("comment", r"/\*", r"\*/", false);
+                
+                // Preprocessor directives, the header of `#include <...>` and the name of `#define`
+                lexer.keyword("keyword", r"^\s*(#\s*[A-Za-z_]\w*)");
+                lexer.keyword("string", r"^\s*#\s*(?:include|include_next|import|embed)\s*(<[^>]*>)");
+                lexer.keyword("constant", r"^\s*#\s*define\s+([A-Za-z_]\w*)");
+                
+                // Raw strings. Without backreferences, a custom delimiter ends at any `)delim"`.
+                lexer.bounded("string", r#"\b(?:u8|[LuU])?R"\("#, r#"\)""#, false);
+                lexer.bounded("string", r#"\b(?:u8|[LuU])?R"[^\s()\\"]{1,16}\("#, r#"\)[^\s()\\"]{1,16}""#, false);
+                
+                // Strings and character literals, with their encoding prefix
+                lexer.bounded("string", r#"(?:\b(?:u8|[LuU]))?""#, "\"", true);
+                lexer.keyword("string", r"(?:\b(?:u8|[LuU]))?'(?:[^'\\]|\\(?:x[0-9a-fA-F]+|u[0-9a-fA-F]{4}|U[0-9a-fA-F]{8}|[0-7]{1,3}|.))'");
+                
+                // Keywords
+                lexer.keyword("keyword", r"\b(alignas|alignof|and|and_eq|asm|auto|bitand|bitor|break|case|catch|class|co_await|co_return|co_yield|compl|concept|const|const_cast|consteval|constexpr|constinit|continue|decltype|default|defined|delete|do|dynamic_cast|else|enum|explicit|export|extern|final|for|friend|goto|if|import|inline|module|mutable|namespace|new|noexcept|not|not_eq|operator|or|or_eq|override|private|protected|public|register|reinterpret_cast|requires|return|sizeof|static|static_assert|static_cast|struct|switch|template|this|thread_local|throw|try|typedef|typeid|typename|union|using|virtual|volatile|while|xor|xor_eq)\b");
+                
+                // Attributes like `[[nodiscard]]`
+                lexer.keyword("attribute", r"\[\[.*?\]\]");
+                
+                // Types, including declared ones
+                lexer.keyword("type", r"\b(bool|char|char8_t|char16_t|char32_t|double|float|int|long|short|signed|unsigned|void|wchar_t|FILE|[a-z_]\w*_t)\b");
+                lexer.keyword("type", r"\b(?:class|concept|enum|struct|typename|union)\s+([A-Za-z_]\w*)");
+                
+                // Booleans and constants, including macros spelled in upper case
+                lexer.keyword("boolean", r"\b(true|false)\b");
+                lexer.keyword("constant", r"\b(NULL|nullptr|[A-Z][A-Z0-9_]*[A-Z0-9])\b");
+                
+                // Function calls and definitions, including those with template arguments,
+                // then other template names like `vector<int>`
+                lexer.keyword("function", r"\b([A-Za-z_]\w*)\s*(?:<[\w\s:,*&<>]*>)?\s*\(");
+                lexer.keyword("type", r"\b([A-Za-z_]\w*)<[\w\s:,*&<>]*>");
+                
+                // Numbers, with their suffix like `10UL`, `1.5f` or `100ms`
+                lexer.keyword("number", r"\b(?:0[xX][0-9a-fA-F']+(?:\.[0-9a-fA-F']*)?(?:[pP][+-]?\d+)?|0[bB][01']+|\d[\d']*(?:\.[\d']*)?(?:[eE][+-]?\d+)?)\w*|\B\.\d[\d']*(?:[eE][+-]?\d+)?\w*");
+            }
+            Language::CSharp => {
+                // Comments
+                lexer.keyword("comment", r"//.*$");
+                lexer.bounded("comment", r"/\*", r"\*/", false);
+                
+                // Preprocessor directives, which take up the line and the ones a trailing `\` continues it on.
+                // Region names are free text.
+                lexer.keyword("keyword", r"^\s*(#\s*(?:region|endregion)\b.*)$");
+                lexer.bounded("keyword", r"^\s*(#\s*(?:if|elif|else|endif|define|undef|warning|error|line|pragma|nullable)\b)", "$", true);
+                lexer.region_keyword("comment", r"//.*$");
+                
+                // Raw, interpolated and verbatim strings. In the latter, quotes are escaped by doubling them.
+                // Doubled braces in interpolated strings are literal ones.
+                lexer.bounded_interp("string", r#"\$+""""#, r#"""""#, r"\{", r"\}", false);
+                lexer.bounded("string", r#"""""#, r#"""""#, false);
+                lexer.bounded_interp("string", r#"\$@"|@\$""#, "\"", r"\{", r"\}", false);
+                lexer.region_keyword("string.escape", r"\{\{|\}\}");
+                lexer.bounded_interp("string", r#"\$""#, "\"", r"\{", r"\}", true);
+                lexer.region_keyword("string.escape", r"\{\{|\}\}");
+                lexer.keyword("string", r#"@"(?:[^"]|"")*""#);
+                lexer.bounded("string", r#"@""#, "\"", false);
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.keyword("string", r"'(?:[^'\\]|\\(?:x[0-9a-fA-F]{1,4}|u[0-9a-fA-F]{4}|U[0-9a-fA-F]{8}|.))'");
+                
+                // Attributes like `[Serializable]` or `[assembly: InternalsVisibleTo(...)]`
+                lexer.keyword("attribute", r"^\s*\[(?:\w+:\s*)?([A-Za-z_][\w.]*)");
+                
+                // Keywords, including the contextual ones that aren't common identifiers
+                lexer.keyword("keyword", r"\b(abstract|and|as|async|await|base|break|case|catch|checked|class|const|continue|default|delegate|do|else|enum|event|explicit|extern|finally|fixed|for|foreach|get|global|goto|if|implicit|in|init|interface|internal|is|lock|nameof|namespace|new|not|operator|or|out|override|params|partial|private|protected|public|readonly|record|ref|required|return|sealed|set|sizeof|stackalloc|static|struct|switch|this|throw|try|typeof|unchecked|unsafe|using|var|virtual|volatile|when|where|while|with|yield)\b");
+                
+                // Built-in types
+                lexer.keyword("type", r"\b(bool|byte|char|decimal|double|dynamic|float|int|long|nint|nuint|object|sbyte|short|string|uint|ulong|ushort|void)\b");
+                
+                // Booleans and null
+                lexer.keyword("boolean", r"\b(true|false)\b");
+                lexer.keyword("constant", r"\bnull\b");
+                
+                // Method calls and declarations, before type names, since both are in PascalCase.
+                // Other names in PascalCase are types if they are used like one, e.g. `List<T>`,
+                // `Task.Delay` or `Exception ex`, and not after a `.`, which makes them a member.
+                lexer.keyword("function", r"\b([A-Za-z_]\w*)\s*(?:<[\w\s,.?\[\]<>]*>)?\s*\(");
+                lexer.keyword("type", r"(?:^|[^.\w])([A-Z]\w*)(?:\s*[<>\[\]?.,)]|\s+[A-Za-z_@])");
+                
+                // Numbers, with their suffix like `10UL` or `1.5m`
+                lexer.keyword("number", r"\b(?:0[xX][0-9a-fA-F_]+|0[bB][01_]+|\d[\d_]*(?:\.\d[\d_]*)?(?:[eE][+-]?\d+)?)(?:[uU][lL]?|[lL][uU]?|[fFdDmM])?\b");
+            }
+            Language::Java => {
+                // Comments
+                lexer.keyword("comment", r"//.*$");
+                lexer.bounded("comment", r"/\*", r"\*/", false);
+                
+                // Text blocks, strings and character literals
+                lexer.bounded("string", r#"""""#, r#"""""#, true);
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.keyword("string", r"'(?:[^'\\]|\\(?:u+[0-9a-fA-F]{4}|[0-7]{1,3}|.))'");
+                
+                // Annotations, but `@interface` declares one
+                lexer.keyword("keyword", r"@interface\b");
+                lexer.keyword("decorator", r"@[A-Za-z_][\w.]*");
+                
+                // Keywords, including the contextual ones
+                lexer.keyword("keyword", r"\b(non-sealed|abstract|assert|break|case|catch|class|const|continue|default|do|else|enum|exports|extends|final|finally|for|goto|if|implements|import|instanceof|interface|module|native|new|open|opens|package|permits|private|protected|provides|public|record|requires|return|sealed|static|strictfp|super|switch|synchronized|this|throw|throws|to|transient|transitive|try|uses|var|volatile|when|while|with|yield)\b");
+                
+                // Primitive types
+                lexer.keyword("type", r"\b(boolean|byte|char|double|float|int|long|short|void)\b");
+                
+                // Booleans, null and constants
+                lexer.keyword("boolean", r"\b(true|false)\b");
+                lexer.keyword("constant", r"\b(null|[A-Z][A-Z0-9_]*[A-Z0-9])\b");
+                
+                // Method calls and declarations
+                lexer.keyword("function", r"\b([A-Za-z_]\w*)\s*\(");
+                
+                // Class names and type parameters, which are in PascalCase, unless they are qualified
+                lexer.keyword("type", r"(?:^|[^.\w])([A-Z]\w*)");
+                
+                // Numbers, with their suffix like `10L` or `1.5f`
+                lexer.keyword("number", r"\b(?:0[xX][0-9a-fA-F_]+|0[bB][01_]+|\d[\d_]*(?:\.[\d_]*)?(?:[eE][+-]?\d+)?)[lLfFdD]?\b|\B\.\d[\d_]*(?:[eE][+-]?\d+)?[fFdD]?\b");
+            }
+            Language::Go => {
+                // Comments
+                lexer.keyword("comment", r"//.*$");
+                lexer.bounded("comment", r"/\*", r"\*/", false);
+                
+                // Raw strings, which may span lines, interpreted strings and runes
+                lexer.bounded("string", "`", "`", false);
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.keyword("string", r"'(?:[^'\\]|\\(?:x[0-9a-fA-F]{2}|u[0-9a-fA-F]{4}|U[0-9a-fA-F]{8}|[0-7]{3}|.))'");
+                
+                // Keywords
+                lexer.keyword("keyword", r"\b(break|case|chan|const|continue|default|defer|else|fallthrough|for|func|go|goto|if|import|interface|map|package|range|return|select|struct|switch|type|var)\b");
+                
+                // Types: built-in ones, declared ones and type parameters like `[K comparable, V any]`
+                lexer.keyword("type", r"\b(any|bool|byte|comparable|complex64|complex128|error|float32|float64|int|int8|int16|int32|int64|rune|string|uint|uint8|uint16|uint32|uint64|uintptr)\b");
+                lexer.keyword("type", r"\btype\s+([A-Za-z_]\w*)");
+                lexer.keyword("type", r"[\[,]\s*([A-Za-z_]\w*)\s+(?:any\b|comparable\b|interface\b|~|[A-Za-z_]\w*\.[A-Z])");
+                
+                // Booleans and predeclared constants
+                lexer.keyword("boolean", r"\b(true|false)\b");
+                lexer.keyword("constant", r"\b(nil|iota)\b");
+                
+                // Built-in functions, then other function calls and declarations
+                lexer.keyword("builtin", r"\b(append|cap|clear|close|complex|copy|delete|imag|len|make|max|min|new|panic|print|println|real|recover)\s*\(");
+                lexer.keyword("function", r"\b([A-Za-z_]\w*)\s*(?:\[[\w\s,.*\[\]]*\])?\(");
+                
+                // Numbers, including imaginary ones like `1.5i`
+                lexer.keyword("number", r"\b(?:0[xX][0-9a-fA-F_]+(?:\.[0-9a-fA-F_]*)?(?:[pP][+-]?\d+)?|0[bB][01_]+|0[oO][0-7_]+|\d[\d_]*(?:\.[\d_]*)?(?:[eE][+-]?\d+)?)i?\b|\B\.\d[\d_]*(?:[eE][+-]?\d+)?i?\b");
+            }
+            Language::Shell => {
+                // Parameter expansions and special parameters, which also apply inside double quotes
+                // and unquoted heredocs. There, a backslash only escapes a few characters.
+                const EXPANSION: &str = r"\$\{[^}]*\}|\$(?:[A-Za-z_]\w*|[0-9@*#?$!-])";
+                const QUOTED_ESCAPE: &str = r"\\[$`\x22\\]|\\$";
+
+                // Arithmetic expansions, in which names are variables even without a `$`
+                fn arithmetic() -> Lexer {
+                    let mut lexer = Lexer::new();
+                    lexer.keyword("variable", EXPANSION);
+                    lexer.keyword("variable", r"\b[A-Za-z_]\w*\b");
+                    lexer.keyword("number", r"\b(?:0[xX][0-9a-fA-F]+|\d+(?:#[0-9A-Za-z@_]+)?)\b");
+                    lexer
+                }
+                fn shell() -> Lexer {
+                    SyntaxHighlighter::build_lexer(Language::Shell).unwrap_or_default()
+                }
+
+                // Comments need to start a word, so that `$#` and `${#array[@]}` aren't comments
+                lexer.keyword("comment", r"(?:^|[\s;&|(])(#.*)$");
+                
+                // Escaped characters are quoted, too, and must not start a string or an expansion
+                lexer.keyword("string.escape", r"\\.");
+                
+                // Heredocs start on the next line and run until a line with just their delimiter,
+                // which `<<-` may indent with tabs. Unless the delimiter is quoted, the body is
+                // expanded. Here-strings (`<<<`) are operators.
+                lexer.keyword("operator", r"<<<");
+                lexer.keyword("operator", r#"(?:^|[^<])(<<-?)\s*['"\\]?[A-Za-z_]"#);
+                lexer.keyword("string", r#"(?:^|[^<])<<-?\s*('[A-Za-z_][\w-]*'|"[A-Za-z_][\w-]*"|\\?[A-Za-z_][\w-]*)"#);
+                lexer.bounded("string", r#"(?:^|[^<])<<\s*['"\\]([A-Za-z_][\w-]*).*?()$"#, r"^\1$", false);
+                lexer.bounded("string", r#"(?:^|[^<])<<-\s*['"\\]([A-Za-z_][\w-]*).*?()$"#, r"^\t*\1$", false);
+                lexer.bounded("string", r"(?:^|[^<])<<\s*([A-Za-z_][\w-]*).*?()$", r"^\1$", false);
+                lexer.region_keyword("string.escape", QUOTED_ESCAPE);
+                lexer.region_keyword("variable", EXPANSION);
+                lexer.bounded("string", r"(?:^|[^<])<<-\s*([A-Za-z_][\w-]*).*?()$", r"^\t*\1$", false);
+                lexer.region_keyword("string.escape", QUOTED_ESCAPE);
+                lexer.region_keyword("variable", EXPANSION);
+                
+                // Strings. Double-quoted ones may contain expansions and substitutions, where
+                // `$((` is arithmetic rather than a command substitution.
+                lexer.bounded("string", r"\$'", "'", true);
+                lexer.bounded("string", "'", "'", false);
+                lexer.bounded_interp("string", "\"", "\"", r"\$\(", r"\)", true);
+                lexer.region_keyword("string.escape", QUOTED_ESCAPE);
+                lexer.region_keyword("variable", EXPANSION);
+                lexer.region_nested("operator", r"\$\(\(", r"\)\)", arithmetic);
+                lexer.region_nested("operator", "`", "`", shell);
+                
+                // Arithmetic expansions and backtick command substitutions
+                lexer.nested("operator", r"\$\(\(", r"\)\)", arithmetic);
+                lexer.nested("operator", "`", "`", shell);
+                
+                // Parameter expansions, special parameters and the start of substitutions
+                lexer.keyword("variable", EXPANSION);
+                lexer.keyword("operator", r"\$\(|[<>]\(");
+                
+                // Variable assignments, including `export` and `local` ones
+                lexer.keyword("variable", r"^\s*(?:(?:export|local|readonly|declare|typeset)\s+(?:-\w+\s+)*)?([A-Za-z_]\w*)(?:\[[^\]]*\])?\+?=");
+                
+                // Function definitions
+                lexer.keyword("function", r"^\s*(?:function\s+)?([A-Za-z_][\w:.-]*)\s*\(\s*\)");
+                lexer.keyword("function", r"\bfunction\s+([A-Za-z_][\w:.-]*)");
+                
+                // Keywords and builtins, as whole words only, since `-` is part of command names
+                lexer.keyword("keyword", r"(?:^|[\s;&|(`])(if|then|else|elif|fi|case|esac|for|select|while|until|do|done|in|function|time|coproc|export|local|readonly|declare|typeset|unset|return|exit|break|continue)(?:$|[\s;&|)`])");
+                lexer.keyword("builtin", r"(?:^|[\s;&|(`])(alias|bg|bind|builtin|caller|cd|command|compgen|complete|dirs|disown|echo|enable|eval|exec|false|fc|fg|getopts|hash|help|history|jobs|kill|let|logout|mapfile|popd|printf|pushd|pwd|read|readarray|set|shift|shopt|source|suspend|test|times|trap|true|type|ulimit|umask|unalias|wait)(?:$|[\s;&|)`])");
+                
+                // Test operators like `[[ $a -eq 1 ]]`
+                lexer.keyword("operator", r"(?:^|\s)(-(?:eq|ne|lt|le|gt|ge|nt|ot|ef))(?:$|\s)");
+                
+                // Numbers, as whole words
+                lexer.keyword("number", r"(?:^|[\s=(\[,:])(\d+)(?:$|[\s;)\],])");
+            }
+            Language::PowerShell => {
+                // Comments
+                lexer.bounded("comment", "<#", "#>", false);
+                lexer.keyword("comment", r"#.*$");
+                
+                // Here-strings end with a quote at the start of a line
+                lexer.bounded_interp("string", r#"@"$"#, r#"^"@"#, r"\$\(", r"\)", false);
+                lexer.bounded("string", r"@'$", r"^'@", false);
+                
+                // Strings. Quotes are escaped by doubling them, or with a backtick in expandable strings.
+                lexer.set_escape_char(b'`');
+                lexer.keyword("string", r"`.");
+                lexer.bounded_interp("string", "\"", "\"", r"\$\(", r"\)", true);
+                lexer.bounded("string", "'", "'", false);
+                
+                // Attributes and type literals like `[CmdletBinding()]` and `[string[]]`
+                lexer.keyword("attribute", r"(?i)\[(Alias|AllowEmptyCollection|AllowEmptyString|AllowNull|CmdletBinding|OutputType|Parameter|SupportsWildcards|Validate\w+)\b");
+                lexer.keyword("type", r"\[[A-Za-z_][\w.]*(?:\[\])?\]");
+                
+                // Automatic constants, variables and subexpressions
+                lexer.keyword("boolean", r"(?i)\$(?:true|false)\b");
+                lexer.keyword("constant", r"(?i)\$null\b");
+                lexer.keyword("variable", r"\$\{[^}]*\}|[$@](?:[A-Za-z_][\w]*(?::[A-Za-z_]\w*)?|[_?^$])");
+                lexer.keyword("operator", r"\$\(|@\(|@\{");
+                
+                // Keywords
+                lexer.keyword("keyword", r"(?i)\b(begin|break|catch|class|clean|continue|data|default|do|dynamicparam|else|elseif|end|enum|exit|filter|finally|for|foreach|function|hidden|if|in|param|process|return|static|switch|throw|trap|try|until|using|while|workflow)\b");
+                
+                // Operators like `-eq` and `-notmatch`, then parameters like `-Path`
+                lexer.keyword("operator", r"(?i)(?:^|[^\w-])(-(?:[ic]?(?:eq|ne|gt|ge|lt|le|like|notlike|match|notmatch|contains|notcontains|in|notin|replace|split)|split|join|is|isnot|as|and|or|xor|not|band|bor|bxor|bnot|shl|shr|f))\b");
+                lexer.keyword("attribute", r"(?:^|[\s(])(-[A-Za-z][\w]*)");
+                
+                // Cmdlets and functions named `Verb-Noun`
+                lexer.keyword("function", r"\b[A-Za-z]+-[A-Za-z]\w*\b");
+                
+                // Numbers, with their multiplier like `10MB`
+                lexer.keyword("number", r"(?i)\b(?:0x[0-9a-f]+|\d+(?:\.\d+)?(?:e[+-]?\d+)?)(?:[kmgtp]b)?\b");
+            }
+            Language::Batch => {
+                // Comments: `REM` and labels that start with another colon
+                lexer.keyword("comment", r"(?i)^\s*@?(rem(?:\s.*)?)$");
+                lexer.keyword("comment", r"^\s*(::.*)$");
+                
+                // Labels and their use with `goto` and `call`
+                lexer.keyword("function", r"^\s*(:[^\s:+][^\s+]*)");
+                lexer.keyword("function", r"(?i)\b(?:goto|call)\s+(:?[^\s:%][^\s]*)");
+                
+                // Variables, loop variables, arguments and delayed expansion
+                lexer.keyword("variable", r"%%~?[A-Za-z]|%~?[a-zA-Z$:]*[0-9*]|%[^%\s]+%|![^!\s]+!");
+                
+                // Strings
+                lexer.keyword("string", r#""[^"]*"?"#);
+                
+                // Keywords, comparison operators and commands
+                lexer.keyword("keyword", r"(?i)\b(call|defined|do|else|errorlevel|exist|exit|for|goto|if|in|not|setlocal|endlocal|shift)\b");
+                lexer.keyword("operator", r"(?i)\b(equ|neq|lss|leq|gtr|geq)\b|==|@");
+                lexer.keyword("builtin", r"(?i)\b(assoc|attrib|cd|chdir|choice|cls|color|copy|date|del|dir|echo|erase|find|findstr|ftype|md|mkdir|mklink|move|path|pause|popd|prompt|pushd|rd|ren|rename|rmdir|robocopy|set|start|time|timeout|title|type|ver|vol|where|xcopy)\b");
+                
+                // Numbers
+                lexer.keyword("number", r"\b\d+\b");
+            }
+            Language::Makefile => {
+                // Comments, unless the `#` is escaped
+                lexer.keyword("comment", r"(?:^|[^\\])(#.*)$");
+                
+                // Recipe lines must start with a tab. Their `@`, `-` and `+` prefixes are operators,
+                // and spaces in front of the tab are a common mistake that make rejects.
+                lexer.keyword("error", r"^( +)\t");
+                lexer.keyword("operator", r"^\t+\s*([@+-]+)");
+                
+                // Variable references, function calls and automatic variables. `$$` is a literal `$`.
+                lexer.keyword("operator", r"\$\$");
+                lexer.keyword("builtin", r"\$[({](?:abspath|addprefix|addsuffix|and|basename|call|dir|error|eval|file|filter|filter-out|findstring|firstword|flavor|foreach|guile|if|info|intcmp|join|lastword|let|notdir|or|origin|patsubst|realpath|shell|sort|strip|subst|suffix|value|warning|wildcard|word|wordlist|words)\s");
+                lexer.keyword("variable", r"\$\([^()$\s]+\)|\$\{[^{}$\s]+\}|\$[@<^?*+%|]");
+                
+                // Directives, which can't start recipe lines. `else` may be followed by another conditional.
+                lexer.keyword("keyword", r"^ *(else) ");
+                lexer.keyword("keyword", r"^ *(?:else +)?(-include|sinclude|include|ifeq|ifneq|ifdef|ifndef|else|endif|define|endef|export|unexport|override|private|undefine|vpath)(?:$|\s)");
+                
+                // Variable assignments and their operator
+                lexer.keyword("variable", r"^ *(?:(?:export|override|private) +)*([A-Za-z_][\w.-]*)\s*(?::{1,3}=|[?+!]?=)");
+                lexer.keyword("operator", r"^ *(?:(?:export|override|private) +)*[A-Za-z_][\w.-]*\s*(:{1,3}=|[?+!]?=)");
+                
+                // Special targets like `.PHONY`, then ordinary targets, but not `x := y` or `x ::= y`
+                lexer.keyword("keyword", r"^(\.[A-Z_]+)\s*:");
+                lexer.keyword("function", r"^([^\s:#=][^:#=]*?)\s*::?(?:[^:=]|$)");
+                
+                // Strings, as used by `ifeq` and in recipes
+                lexer.keyword("string", r#""[^"]*"|'[^']*'"#);
+                
+                // Line continuations
+                lexer.keyword("operator", r"\\$");
+            }
+            Language::Dockerfile => {
+                // Parser directives, which are comments at the very top of the file, and comments
+                lexer.keyword("keyword", r"(?i)^#\s*(?:syntax|escape|check)\s*=.*$");
+                lexer.keyword("comment", r"^\s*#.*$");
+                
+                // Instructions, including the one triggered by `ONBUILD`
+                lexer.keyword("keyword", r"(?i)^\s*(ADD|ARG|CMD|COPY|ENTRYPOINT|ENV|EXPOSE|FROM|HEALTHCHECK|LABEL|MAINTAINER|ONBUILD|RUN|SHELL|STOPSIGNAL|USER|VOLUME|WORKDIR)(?:$|\s)");
+                lexer.keyword("keyword", r"(?i)^\s*ONBUILD\s+([A-Z]+)\b");
+                
+                // The base image and the name of the build stage
+                lexer.keyword("type", r"(?i)^\s*FROM\s+(?:--[\w-]+=\S+\s+)*([^\s-]\S*)");
+                lexer.keyword("keyword", r"(?i)^\s*FROM\s+.*?\s(AS)\s+\S+\s*$");
+                lexer.keyword("variable", r"(?i)^\s*FROM\s+.*?\sAS\s+(\S+)\s*$");
+                
+                // Heredocs, which start on the next line
+                lexer.keyword("operator", r"<<-?");
+                lexer.bounded("string", r#"<<-?\s*['"]?([A-Za-z_]\w*)['"]?.*?()$"#, r"^\s*\1$", false);
+                
+                // Strings
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.bounded("string", "'", "'", false);
+                
+                // Flags like `--from=build`
+                lexer.keyword("attribute", r"(?:^|\s)(--[A-Za-z][\w-]*)");
+                
+                // Variables, and the keys of `ENV`, `ARG` and `LABEL`
+                lexer.keyword("variable", r"\$\{[^}]*\}|\$[A-Za-z_]\w*");
+                lexer.keyword("variable", r"(?i)^\s*(?:ARG|ENV|LABEL)\s+([A-Za-z_][\w.-]*)");
+                lexer.keyword("variable", r"\s([A-Za-z_][\w.-]*)=");
+                
+                // Ports and other numbers
+                lexer.keyword("number", r"(?:^|[\s=:])(\d+(?:/(?:tcp|udp))?)(?:$|\s)");
+                
+                // Line continuations
+                lexer.keyword("operator", r"\\$");
+            }
+            Language::Ini => {
+                // Comments, which start a line or follow whitespace
+                lexer.keyword("comment", r"(?:^|\s)([;#].*)$");
+                
+                // Sections like `[core]` and `[remote "origin"]`
+                lexer.keyword("keyword", r"^\s*(\[[^\]]*\])");
+                
+                // Keys
+                lexer.keyword("attribute", r"^\s*([^\s;#\[=:][^=:]*?)\s*[=:]");
+                
+                // Strings and interpolations like `%(home)s` and `${section:key}`
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.keyword("variable", r"%\([^)]*\)[sd]|\$\{[^}]*\}");
+                
+                // Values that are entirely a boolean or a number
+                lexer.keyword("boolean", r"(?i)[=:]\s*(true|false|yes|no|on|off)\s*$");
+                lexer.keyword("number", r"[=:]\s*([+-]?(?:0x[0-9a-fA-F]+|\d+(?:\.\d+)?))\s*$");
+            }
+            Language::DotEnv => {
+                // Comments, which must follow whitespace inside of values
+                lexer.keyword("comment", r"(?:^|\s)(#.*)$");
+                
+                // Keys, optionally exported
+                lexer.keyword("keyword", r"^\s*(export)\s");
+                lexer.keyword("variable", r"^\s*(?:export\s+)?([A-Za-z_][\w.]*)\s*=");
+                
+                // Strings. Double-quoted ones may span lines.
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.bounded("string", "'", "'", false);
+                lexer.bounded("string", "`", "`", true);
+                
+                // Interpolations
+                lexer.keyword("variable", r"\$\{[^}]*\}|\$[A-Za-z_]\w*");
+                
+                // Values that are entirely a boolean or a number
+                lexer.keyword("boolean", r"(?i)=\s*(true|false)\s*$");
+                lexer.keyword("number", r"=\s*([+-]?\d+(?:\.\d+)?)\s*$");
+            }
+            Language::Xml => {
+                // Comments, CDATA sections, the doctype and processing instructions like `<?xml`
+                lexer.bounded("comment", "<!--", "-->", false);
+                lexer.bounded("string", r"<!\[CDATA\[", r"\]\]>", false);
+                lexer.keyword("keyword", r"<!DOCTYPE\b[^>]*>");
+                lexer.keyword("keyword", r"<\?[A-Za-z_][\w.:-]*|\?>");
+                
+                // Tags, with their namespace prefix
+                lexer.keyword("keyword", r"</?[A-Za-z_][\w.:-]*|/?>");
+                
+                // Attributes and their values, which may span lines
+                lexer.keyword("attribute", r"(?:^|\s)([A-Za-z_:][\w.:-]*)\s*=");
+                lexer.keyword("string", r#"=\s*("[^"]*"|'[^']*')"#);
+                lexer.bounded("string", r#"=\s*(")"#, "\"", false);
+                lexer.bounded("string", r"=\s*(')", "'", false);
+                
+                // Character and entity references
+                lexer.keyword("constant", r"&(?:#[0-9]+|#x[0-9a-fA-F]+|[A-Za-z_][\w.-]*);");
+            }
+            Language::Nginx => {
+                // Comments
+                lexer.keyword("comment", r"(?:^|[\s;{}])(#.*)$");
+                
+                // Strings
+                lexer.bounded("string", "\"", "\"", true);
+                lexer.bounded("string", "'", "'", true);
+                
+                // Variables like `$host` and `${request_uri}`
+                lexer.keyword("variable", r"\$\{[A-Za-z_]\w*\}|\$(?:[A-Za-z_]\w*|\d)");
+                
+                // Modifiers and regular expressions of `location`, and the pattern of `rewrite`
+                lexer.keyword("operator", r"\blocation\s+(=|~\*?|\^~)\s");
+                lexer.keyword("regex", r"\blocation\s+~\*?\s+([^\s{]+)");
+                lexer.keyword("regex", r"(?:^|[;{])\s*rewrite\s+(\S+)");
+                
+                // Directives and block names, which start a statement
+                lexer.keyword("keyword", r"(?:^|[;{}])\s*([A-Za-z_][\w.-]*)");
+                
+                // Flags, sizes and durations like `10m` or `1h30m`, and numbers
+                lexer.keyword("boolean", r"\s(on|off)\s*;");
+                lexer.keyword("number", r"(?:^|\s)(\d+(?:\.\d+)?(?:(?:ms|[kKmMgGshdwy])\d*)*)(?:$|[\s;])");
+            }
+            Language::Diff => diff_rules(&mut lexer),
+            Language::GitCommit => {
+                // Comments, which git strips from the message
+                lexer.keyword("comment", r"^#.*$");
+                
+                // The subject line, with the characters beyond the 50th flagged
+                lexer.keyword("keyword", r"^([^#\s].{0,49})");
+                lexer.keyword("error", r"^[^#\s].{49}(.+)$");
+                
+                // The body starts after the subject. It ends with the diff that `git commit -v`
+                // appends below the scissors line, which git cuts off.
+                lexer.nested("keyword", r"^[^#\s].*?()$", r"\b\B", || {
+                    let mut body = Lexer::new();
+                    body.keyword("comment", r"^#.*$");
+                    body.keyword("attribute", r"^([A-Z][a-z]*(?:-[a-z]+)*-by|Fixes|Closes|Refs|Change-Id):\s");
+                    body.nested("comment", r"^# -+ >8 -+()$", r"\b\B", || {
+                        let mut diff = Lexer::new();
+                        diff.keyword("comment", r"^#.*$");
+                        diff_rules(&mut diff);
+                        diff
+                    });
+                    body
+                });
+            }
+            Language::GitRebase => {
+                // Comments
+                lexer.keyword("comment", r"^\s*#.*$");
+                
+                // Commands, in their long and short form, and their options
+                lexer.keyword("keyword", r"^\s*(pick|p|reword|r|edit|e|squash|s|fixup|f|exec|x|break|b|drop|d|label|l|reset|t|merge|m|update-ref|u)(?:$|\s)");
+                lexer.keyword("attribute", r"^\s*(?:fixup|f|merge|m)\s+(-[cC])\s");
+                
+                // Commits, labels and refs
+                lexer.keyword("constant", r"^\s*(?:pick|p|reword|r|edit|e|squash|s|fixup|f|drop|d|merge|m)\s+(?:-[cC]\s+)?([0-9a-fA-F]{4,64})\b");
+                lexer.keyword("variable", r"^\s*(?:label|l|reset|t|update-ref|u)\s+(\S+)");
+                lexer.keyword("variable", r"^\s*(?:merge|m)\s+(?:-[cC]\s+[0-9a-fA-F]+\s+)?([^\s#]+)");
+                
+                // The original subject of a merge, after its label
+                lexer.keyword("comment", r"^\s*(?:merge|m)\s.*?\s(#.*)$");
+            }
+            Language::Custom(id) => {
+                let def = user_languages::get(id).ok_or("Unknown user-defined language")?;
+                lexer = def.build_lexer()?;
+            }
+            Language::PlainText => {}
         }
+
+        Ok(lexer)
     }
 
-    /// Highlights a single line of text and returns the tokens.
-    /// Note: For proper context-aware highlighting (e.g., multiline comments),
-    /// the entire document should be processed through `highlight_document` first.
+    /// Highlights a single line of text, assuming it starts outside of any
+    /// multi-line construct, and returns the tokens.
+    /// Use `highlight_line_with_state` to continue from the previous line.
     pub fn highlight_line(&mut self, line: &str, _line_number: usize) -> Result<Vec<TokenInfo>, String> {
+        self.highlight_line_with_state(line, &LexerState::default()).map(|(tokens, _)| tokens)
+    }
+
+    /// Highlights a single line of text starting in the given lexer state.
+    /// Returns the tokens and the lexer state at the end of the line.
+    pub fn highlight_line_with_state(
+        &mut self,
+        line: &str,
+        state: &LexerState,
+    ) -> Result<(Vec<TokenInfo>, LexerState), String> {
         // Ensure highlighter is initialized
         self.initialize()?;
 
-        if line.is_empty() {
-            return Ok(Vec::new());
-        }
-
-        // Get the highlighter
-        let highlighter = self.highlighter.as_mut().ok_or("Highlighter not initialized")?;
-
-        // For single-line highlighting, we run the highlighter on just this line
-        // This won't handle multiline tokens properly, but is fast for incremental updates
-        let lines = vec![line.to_string()];
-        highlighter.run(&lines);
-
-        // Get tokens for line 0 (since we're only processing one line)
-        let mut tokens = Vec::new();
-        let mut current_offset = 0;
-
-        // Process tokens from Synoptic
-        for token in highlighter.line(0, line) {
-            match token {
-                synoptic::TokOpt::Some(text, kind) => {
-                    let start = current_offset;
-                    let end = start + text.len();
-                    
-                    tokens.push(TokenInfo::highlighted(
-                        text.to_string(),
-                        kind.to_string(),
-                        start,
-                        end,
-                    ));
-                    
-                    current_offset = end;
-                }
-                synoptic::TokOpt::None(text) => {
-                    let start = current_offset;
-                    let end = start + text.len();
-                    
-                    tokens.push(TokenInfo::plain_text(
-                        text.to_string(),
-                        start,
-                        end,
-                    ));
-                    
-                    current_offset = end;
-                }
-            }
-        }
-
-        Ok(tokens)
+        let (tokens, state) = match self.backend.as_ref().ok_or("Highlighter not initialized")? {
+            Backend::Lexer(lexer) => lexer.tokenize_line(line, state),
+            Backend::TextMate(grammar) => grammar.tokenize_line(line, state),
+        };
+        Ok((split_comment_tags(tokens), state))
     }
     
     /// Highlights an entire document and returns tokens for a specific line.
     /// This method provides proper context-aware highlighting for multiline tokens.
     pub fn highlight_document(&mut self, document: &str, line_number: usize) -> Result<Vec<TokenInfo>, String> {
-        // Ensure highlighter is initialized
-        self.initialize()?;
-
-        // Get the highlighter
-        let highlighter = self.highlighter.as_mut().ok_or("Highlighter not initialized")?;
+        let mut state = LexerState::default();
 
-        // Run the highlighter on the entire document
-        let lines: Vec<String> = document.lines().map(String::from).collect();
-        highlighter.run(&lines);
-
-        // Get the specific line from the document
-        let lines: Vec<&str> = document.lines().collect();
-        if line_number >= lines.len() {
-            return Ok(Vec::new());
-        }
-        
-        let line = lines[line_number];
-        
-        // Get tokens for the specific line
-        let mut tokens = Vec::new();
-        let mut current_offset = 0;
-
-        // Process tokens from Synoptic
-        for token in highlighter.line(line_number, line) {
-            match token {
-                synoptic::TokOpt::Some(text, kind) => {
-                    let start = current_offset;
-                    let end = start + text.len();
-                    
-                    tokens.push(TokenInfo::highlighted(
-                        text.to_string(),
-                        kind.to_string(),
-                        start,
-                        end,
-                    ));
-                    
-                    current_offset = end;
-                }
-                synoptic::TokOpt::None(text) => {
-                    let start = current_offset;
-                    let end = start + text.len();
-                    
-                    tokens.push(TokenInfo::plain_text(
-                        text.to_string(),
-                        start,
-                        end,
-                    ));
-                    
-                    current_offset = end;
-                }
+        for (i, line) in document.lines().enumerate() {
+            let (tokens, next) = self.highlight_line_with_state(line, &state)?;
+            if i == line_number {
+                return Ok(tokens);
             }
+            state = next;
         }
 
-        Ok(tokens)
+        Ok(Vec::new())
     }
 
-
     /// Returns the language this highlighter is configured for.
     pub fn language(&self) -> Language {
         self.language
@@ -769,8 +1882,10 @@ pub struct HighlightingService {
     language_detector: LanguageDetector,
     /// Cache of syntax highlighters per language (legacy, fallback mode)
     highlighters: HashMap<Language, SyntaxHighlighter>,
-    /// Multi-threaded highlighter for timeout interruption
+    /// Worker pool for timeout interruption and asynchronous range requests
     threaded_highlighter: Option<ThreadedHighlighter>,
+    /// Range results collected from the worker pool, by document, that haven't been applied yet
+    completed: HashMap<u64, Vec<HighlightingResponse>>,
     /// Global highlighting configuration
     enabled: bool,
     /// Whether to use multi-threaded highlighting
@@ -781,6 +1896,10 @@ pub struct HighlightingService {
     line_timeout: Duration,
     /// Maximum line length before skipping highlighting
     max_line_length: usize,
+    /// Memory limit of the token cache of new highlighting states
+    cache_limit: usize,
+    /// Average time per line above which a document's highlighting policy is reduced
+    latency_budget: Duration,
 }
 
 impl Default for HighlightingService {
@@ -796,23 +1915,46 @@ impl HighlightingService {
             language_detector: LanguageDetector::new(),
             highlighters: HashMap::new(),
             threaded_highlighter: None, // Will be initialized on first use
+            completed: HashMap::new(),
             enabled: true,
             use_threaded_highlighting: true, // Enable by default for production
             global_metrics: HighlightingMetrics::default(),
             line_timeout: Duration::from_millis(50), // 50ms per line timeout
             max_line_length: 10_000, // Skip highlighting for lines longer than 10k characters
+            cache_limit: DEFAULT_TOKEN_CACHE_LIMIT,
+            latency_budget: Duration::from_millis(2), // Keeps a screenful of lines well within a frame
         }
     }
 
     /// Creates a new highlighting state for a file.
     pub fn create_highlighting_state<P: AsRef<Path>>(&mut self, file_path: P) -> HighlightingState {
         let language = self.language_detector.detect_language(&file_path);
-        
-        if self.enabled && (language.is_tier_1() || language.is_tier_2()) {
+        self.create_highlighting_state_for_language(language)
+    }
+
+    /// Creates a new highlighting state for a document, falling back to its
+    /// content (shebang, modelines) if the path doesn't identify the language.
+    ///
+    /// `head` and `tail` are the first and last few lines of the document.
+    pub fn create_highlighting_state_with_content(
+        &mut self,
+        file_path: Option<&Path>,
+        head: &[&str],
+        tail: &[&str],
+    ) -> HighlightingState {
+        let language = self.language_detector.detect_language_with_content(file_path, head, tail);
+        self.create_highlighting_state_for_language(language)
+    }
+
+    /// Creates a new highlighting state for the given language.
+    pub fn create_highlighting_state_for_language(&self, language: Language) -> HighlightingState {
+        let mut state = if self.enabled && (language.is_tier_1() || language.is_tier_2() || language.is_tier_3() || language.is_tier_4() || language.is_custom()) {
             HighlightingState::new(language)
         } else {
             HighlightingState::disabled(language)
-        }
+        };
+        state.set_cache_limit(self.cache_limit);
+        state
     }
 
     /// Highlights a single line of text.
@@ -822,7 +1964,7 @@ impl HighlightingService {
         line: &str, 
         line_number: usize
     ) -> Result<Vec<TokenInfo>, String> {
-        if !state.enabled || !self.enabled {
+        if !state.is_highlighting() || !self.enabled {
             // Return the entire line as plain text if highlighting is disabled
             return Ok(vec![TokenInfo::plain_text(
                 line.to_string(),
@@ -831,44 +1973,316 @@ impl HighlightingService {
             )]);
         }
 
-        // Skip highlighting for extremely long lines
-        if line.len() > self.max_line_length {
-            return Ok(vec![TokenInfo::plain_text(
-                line.to_string(),
-                0,
-                line.len(),
-            )]);
+        // Skip highlighting for extremely long lines
+        if line.len() > self.max_line_length {
+            return Ok(vec![TokenInfo::plain_text(
+                line.to_string(),
+                0,
+                line.len(),
+            )]);
+        }
+
+        // Calculate content hash for caching
+        let content_hash = self.calculate_line_hash(line);
+        let start_state = state.line_start_state(line_number);
+        
+        // Check cache first
+        if state.has_cached_line(line_number, content_hash, &start_state) {
+            state.metrics.record_cache_hit();
+            state.mark_line_verified(line_number);
+            return Ok(state.get_cached_tokens(line_number, line).unwrap());
+        }
+
+        state.metrics.record_cache_miss();
+
+        // Choose highlighting strategy based on configuration
+        let start_time = Instant::now();
+        let (tokens, end_state) = if self.use_threaded_highlighting {
+            self.highlight_line_threaded(state.language, line, line_number, &start_state)?
+        } else {
+            self.highlight_line_legacy(state, line, line_number, &start_state)?
+        };
+        let duration = start_time.elapsed();
+
+        // Update metrics
+        state.metrics.record_line_highlight(duration, tokens.len());
+        self.global_metrics.record_line_highlight(duration, tokens.len());
+        state.record_latency(duration, self.latency_budget);
+
+        // Cache the result
+        state.cache_line(line_number, content_hash, start_state, end_state, &tokens);
+
+        Ok(tokens)
+    }
+
+    /// Highlights a single line of text, taking multi-line constructs above it into account.
+    ///
+    /// Lines between the last verified line and `line_number` are tokenized first, so that
+    /// the line starts in the correct lexer state. Lines whose cached tokens are still valid
+    /// are cheap to walk over, which means that after an edit re-tokenization only proceeds
+    /// until the lexer state converges with what was cached before.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The highlighting state for the document
+    /// * `line` - The content of the line to highlight
+    /// * `line_number` - The zero-based line number
+    /// * `get_line_content` - A closure that returns the content for a given line number
+    pub fn highlight_line_with_context<F>(
+        &mut self,
+        state: &mut HighlightingState,
+        line: &str,
+        line_number: usize,
+        mut get_line_content: F,
+    ) -> Result<Vec<TokenInfo>, String>
+    where
+        F: FnMut(usize) -> Option<String>,
+    {
+        self.sync_line_states(state, line_number, &mut get_line_content)?;
+        self.highlight_line(state, line, line_number)
+    }
+
+    /// Returns the tokens of a line without waiting for the worker pool.
+    ///
+    /// Cached tokens that are still valid are returned right away. Otherwise the lines from the
+    /// first one that needs tokenizing through the end of the viewport go to the workers in a
+    /// single request. Until [`HighlightingService::apply_async_results`] caches its results,
+    /// the line's previous tokens are returned if its content didn't change, or plain text.
+    ///
+    /// Without threaded highlighting, this is [`HighlightingService::highlight_line_with_context`].
+    ///
+    /// Where the tokens came from is recorded for [`HighlightingState::line_source`].
+    pub fn highlight_line_async<F>(
+        &mut self,
+        state: &mut HighlightingState,
+        line: &str,
+        line_number: usize,
+        get_line_content: F,
+    ) -> Result<Vec<TokenInfo>, String>
+    where
+        F: FnMut(usize) -> Option<String>,
+    {
+        let (tokens, source) = self.lookup_line_async(state, line, line_number, get_line_content)?;
+        if let Some(source) = source {
+            state.line_sources.insert(line_number, source);
+        }
+        Ok(tokens)
+    }
+
+    /// Like [`HighlightingService::highlight_line_async`], but returns where the tokens came from
+    /// instead of recording it. That's None if the line isn't highlighted at all.
+    pub fn lookup_line_async<F>(
+        &mut self,
+        state: &mut HighlightingState,
+        line: &str,
+        line_number: usize,
+        mut get_line_content: F,
+    ) -> Result<(Vec<TokenInfo>, Option<TokenSource>), String>
+    where
+        F: FnMut(usize) -> Option<String>,
+    {
+        let highlighted = state.is_highlighting() && self.enabled && line.len() <= self.max_line_length;
+        if !self.use_threaded_highlighting || !highlighted {
+            self.sync_line_states(state, line_number, &mut get_line_content)?;
+            let source = highlighted.then(|| {
+                if self.is_line_cached(state, line, line_number) {
+                    state.cached_line_source(line_number)
+                } else {
+                    TokenSource::Highlighted
+                }
+            });
+            return Ok((self.highlight_line(state, line, line_number)?, source));
+        }
+        if self.threaded_highlighter.is_none() {
+            // Requests sent to a previous pool will never be answered
+            state.async_requests.in_flight.clear();
+        }
+
+        self.apply_async_results(state);
+
+        // Lines whose cached tokens are still valid are cheap to walk over, without the workers
+        let mut first = state.sync_start(line_number).min(line_number);
+        while first < line_number {
+            match get_line_content(first) {
+                Some(content) if self.is_line_cached(state, &content, first) => {
+                    state.mark_line_verified(first);
+                    first += 1;
+                }
+                _ => break,
+            }
         }
 
-        // Calculate content hash for caching
         let content_hash = self.calculate_line_hash(line);
-        
-        // Check cache first
-        if state.has_cached_tokens(line_number, content_hash) {
+        if first == line_number && state.has_cached_line(line_number, content_hash, &state.line_start_state(line_number)) {
             state.metrics.record_cache_hit();
-            return Ok(state.get_cached_tokens(line_number).unwrap().clone());
+            state.mark_line_verified(line_number);
+            let tokens = state.get_cached_tokens(line_number, line).unwrap();
+            return Ok((tokens, Some(state.cached_line_source(line_number))));
         }
 
         state.metrics.record_cache_miss();
 
-        // Choose highlighting strategy based on configuration
-        let tokens = if self.use_threaded_highlighting {
-            self.highlight_line_threaded(state.language, line, line_number)?
+        if !state.is_line_requested(first) && !state.is_line_requested(line_number) {
+            // Request everything down to the end of the viewport at once
+            let (viewport_start, viewport_end) = state.get_viewport().unwrap_or((line_number, line_number + 1));
+            let end = if line_number < viewport_end {
+                viewport_end
+            } else {
+                line_number + 1 + viewport_end.saturating_sub(viewport_start)
+            };
+
+            let mut lines = Vec::with_capacity(end - first);
+            for n in first..end {
+                let Some(content) = get_line_content(n) else {
+                    break;
+                };
+                lines.push(content);
+            }
+
+            if !lines.is_empty() {
+                let range = first..first + lines.len();
+                let request_id = self.worker_pool().request_range(
+                    state.async_requests.document,
+                    &state.async_requests.generation,
+                    state.language,
+                    first,
+                    lines,
+                    state.line_start_state(first),
+                );
+                state.async_requests.in_flight.push((request_id, range));
+            }
+        }
+
+        // In the meantime, the previous tokens are better than nothing if the content is unchanged
+        let tokens = if state.has_cached_tokens(line_number, content_hash) {
+            state.get_cached_tokens(line_number, line).unwrap()
         } else {
-            self.highlight_line_legacy(state, line, line_number)?
+            vec![TokenInfo::plain_text(line.to_string(), 0, line.len())]
         };
+        Ok((tokens, Some(TokenSource::Pending)))
+    }
 
-        // Calculate duration for metrics (approximate for threaded mode)
-        let duration = Duration::from_millis(1); // Threaded mode returns immediately
+    /// Caches the lines the workers finished for the document since the last call and records
+    /// the timings of its requests. Results for an older generation of the document are dropped,
+    /// and their requests count as cancelled. Returns the number of lines cached.
+    pub fn apply_async_results(&mut self, state: &mut HighlightingState) -> usize {
+        self.collect_async_results();
 
-        // Update metrics
-        state.metrics.record_line_highlight(duration, tokens.len());
-        self.global_metrics.record_line_highlight(duration, tokens.len());
+        let Some(responses) = self.completed.remove(&state.async_requests.document) else {
+            return 0;
+        };
 
-        // Cache the result
-        state.cache_tokens(line_number, content_hash, tokens.clone());
+        let mut applied = 0;
+        for response in responses {
+            match response {
+                HighlightingResponse::Range(range) => {
+                    // The workers finished it before noticing an edit superseded it
+                    if range.generation != state.generation() {
+                        state.metrics.record_cancelled_request();
+                        self.global_metrics.record_cancelled_request();
+                        continue;
+                    }
 
-        Ok(tokens)
+                    state.metrics.record_request(range.duration);
+                    self.global_metrics.record_request(range.duration);
+                    state.async_requests.in_flight.retain(|&(id, _)| id != range.request_id);
+
+                    for (i, line) in range.lines.into_iter().enumerate() {
+                        state.metrics.record_line_highlight(line.duration, line.tokens.len());
+                        self.global_metrics.record_line_highlight(line.duration, line.tokens.len());
+                        state.record_latency(line.duration, self.latency_budget);
+                        state.cache_line(range.first_line + i, line.content_hash, line.start_state, line.end_state, &line.tokens);
+                        applied += 1;
+                    }
+                }
+                HighlightingResponse::Cancelled { .. } => {
+                    state.metrics.record_cancelled_request();
+                    self.global_metrics.record_cancelled_request();
+                }
+                _ => {}
+            }
+        }
+
+        applied
+    }
+
+    /// Moves the responses that arrived from the worker pool to the per-document queues.
+    fn collect_async_results(&mut self) {
+        let Some(pool) = &mut self.threaded_highlighter else {
+            return;
+        };
+
+        for response in pool.poll() {
+            self.queue_async_result(response);
+        }
+    }
+
+    fn queue_async_result(&mut self, response: HighlightingResponse) {
+        let document = match &response {
+            HighlightingResponse::Range(range) => range.document,
+            HighlightingResponse::Cancelled { document, .. } => *document,
+            _ => return,
+        };
+        self.completed.entry(document).or_default().push(response);
+    }
+
+    /// Drops the results that no document picked up, e.g. those of closed documents.
+    pub fn discard_async_results(&mut self) {
+        self.collect_async_results();
+        self.completed.clear();
+    }
+
+    /// Returns whether the worker pool is still busy with range requests.
+    pub fn has_pending_async_work(&self) -> bool {
+        self.threaded_highlighter.as_ref().is_some_and(|pool| pool.outstanding_requests() > 0)
+    }
+
+    /// Blocks until the worker pool answered all range requests or the timeout elapsed.
+    /// Returns whether all of them were answered. Meant for tools and tests, not the UI.
+    pub fn wait_for_async_results(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        while self.has_pending_async_work() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(response) = self.threaded_highlighter.as_mut().and_then(|pool| pool.wait(remaining)) else {
+                return false;
+            };
+            self.queue_async_result(response);
+        }
+
+        true
+    }
+
+    /// Returns whether [`HighlightingService::highlight_line`] would answer from the cache.
+    pub fn is_line_cached(&self, state: &HighlightingState, line: &str, line_number: usize) -> bool {
+        let content_hash = self.calculate_line_hash(line);
+        state.has_cached_line(line_number, content_hash, &state.line_start_state(line_number))
+    }
+
+    /// Tokenizes the lines from the sync frontier up to (excluding) `line_number`,
+    /// so that the lexer state at the start of `line_number` is known.
+    fn sync_line_states<F>(
+        &mut self,
+        state: &mut HighlightingState,
+        line_number: usize,
+        get_line_content: &mut F,
+    ) -> Result<(), String>
+    where
+        F: FnMut(usize) -> Option<String>,
+    {
+        if !state.is_highlighting() || !self.enabled || state.sync_frontier() >= line_number {
+            return Ok(());
+        }
+
+        let first = state.sync_start(line_number);
+        for n in first..line_number {
+            let Some(content) = get_line_content(n) else {
+                break;
+            };
+            self.highlight_line(state, &content, n)?;
+        }
+
+        Ok(())
     }
 
     /// Sets a language override for a specific file.
@@ -931,6 +2345,27 @@ impl HighlightingService {
         self.max_line_length
     }
 
+    /// Sets how many bytes the token cache of each highlighting state created from now on may hold.
+    pub fn set_cache_limit(&mut self, limit: usize) {
+        self.cache_limit = limit;
+    }
+
+    /// Gets the memory limit of the token cache of new highlighting states.
+    pub fn cache_limit(&self) -> usize {
+        self.cache_limit
+    }
+
+    /// Sets the average time per line above which a document gets less highlighting.
+    /// See [`HighlightingState::record_latency`].
+    pub fn set_latency_budget(&mut self, budget: Duration) {
+        self.latency_budget = budget;
+    }
+
+    /// Gets the average time per line above which a document gets less highlighting.
+    pub fn latency_budget(&self) -> Duration {
+        self.latency_budget
+    }
+
     /// Enables or disables multi-threaded highlighting.
     pub fn set_threaded_highlighting(&mut self, enabled: bool) {
         self.use_threaded_highlighting = enabled;
@@ -954,14 +2389,17 @@ impl HighlightingService {
         }
     }
 
+    /// Returns the worker pool, starting it on first use.
+    fn worker_pool(&mut self) -> &mut ThreadedHighlighter {
+        let line_timeout = self.line_timeout;
+        let pool = self.threaded_highlighter.get_or_insert_with(ThreadedHighlighter::new);
+        pool.set_line_timeout(line_timeout);
+        pool
+    }
+
     /// Calculates a simple hash for line content caching.
     fn calculate_line_hash(&self, line: &str) -> u64 {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        
-        let mut hasher = DefaultHasher::new();
-        line.hash(&mut hasher);
-        hasher.finish()
+        line_hash(line)
     }
 
     /// Highlights a line using the threaded highlighter with timeout interruption.
@@ -970,36 +2408,28 @@ impl HighlightingService {
         language: Language,
         line: &str,
         line_number: usize,
-    ) -> Result<Vec<TokenInfo>, String> {
+        start_state: &LexerState,
+    ) -> Result<(Vec<TokenInfo>, LexerState), String> {
         // Initialize threaded highlighter if not already done
-        if self.threaded_highlighter.is_none() {
-            self.threaded_highlighter = Some(ThreadedHighlighter::new());
-        }
+        self.worker_pool();
+
+        // On any error, fall back to plain text and assume the state doesn't change
+        let fallback = || (vec![TokenInfo::plain_text(line.to_string(), 0, line.len())], start_state.clone());
 
         if let Some(ref mut threaded_highlighter) = self.threaded_highlighter {
             match threaded_highlighter.highlight_line_with_timeout(
                 language,
                 line,
                 line_number,
+                start_state,
                 self.line_timeout,
             ) {
-                Ok(tokens) => Ok(tokens),
-                Err(_) => {
-                    // Fallback to plain text on any error
-                    Ok(vec![TokenInfo::plain_text(
-                        line.to_string(),
-                        0,
-                        line.len(),
-                    )])
-                }
+                Ok(result) => Ok(result),
+                Err(_) => Ok(fallback()),
             }
         } else {
             // This shouldn't happen, but fallback to plain text
-            Ok(vec![TokenInfo::plain_text(
-                line.to_string(),
-                0,
-                line.len(),
-            )])
+            Ok(fallback())
         }
     }
 
@@ -1009,7 +2439,8 @@ impl HighlightingService {
         state: &HighlightingState,
         line: &str,
         line_number: usize,
-    ) -> Result<Vec<TokenInfo>, String> {
+        start_state: &LexerState,
+    ) -> Result<(Vec<TokenInfo>, LexerState), String> {
         // Get or create highlighter for this language
         let highlighter = self.highlighters
             .entry(state.language)
@@ -1017,20 +2448,19 @@ impl HighlightingService {
 
         // Perform highlighting with timeout protection
         let start_time = Instant::now();
-        let tokens = highlighter.highlight_line(line, line_number)?;
+        let result = highlighter.highlight_line_with_state(line, start_state)?;
         let duration = start_time.elapsed();
 
         // If highlighting took too long, return plain text
         if duration > self.line_timeout {
             eprintln!("Syntax highlighting timeout for line {} ({}ms)", line_number, duration.as_millis());
-            return Ok(vec![TokenInfo::plain_text(
-                line.to_string(),
-                0,
-                line.len(),
-            )]);
+            return Ok((
+                vec![TokenInfo::plain_text(line.to_string(), 0, line.len())],
+                start_state.clone(),
+            ));
         }
 
-        Ok(tokens)
+        Ok(result)
     }
 
     /// Performs background highlighting for a batch of lines.
@@ -1053,7 +2483,7 @@ impl HighlightingService {
     where
         F: FnMut(usize) -> Option<String>,
     {
-        if !state.enabled || !self.enabled {
+        if !state.is_highlighting() || !self.enabled {
             return 0;
         }
 
@@ -1065,11 +2495,15 @@ impl HighlightingService {
             if let Some(line_content) = get_line_content(line_number) {
                 // Skip extremely long lines to avoid blocking
                 if line_content.len() <= self.max_line_length {
+                    // Make sure the lines above are tokenized, so that the start state is right
+                    let _ = self.sync_line_states(state, line_number, &mut get_line_content);
+
                     // Calculate content hash
                     let content_hash = self.calculate_line_hash(&line_content);
+                    let start_state = state.line_start_state(line_number);
                     
                     // Skip if already cached with current content
-                    if !state.has_cached_tokens(line_number, content_hash) {
+                    if !state.has_cached_line(line_number, content_hash, &start_state) {
                         // Get or create highlighter for this language
                         let highlighter = self.highlighters
                             .entry(state.language)
@@ -1078,7 +2512,7 @@ impl HighlightingService {
                         // Perform highlighting with a shorter timeout for background work
                         let start_time = Instant::now();
                         
-                        if let Ok(tokens) = highlighter.highlight_line(&line_content, line_number) {
+                        if let Ok((tokens, end_state)) = highlighter.highlight_line_with_state(&line_content, &start_state) {
                             let duration = start_time.elapsed();
                             
                             // Use a shorter timeout for background highlighting (half of normal timeout)
@@ -1086,7 +2520,7 @@ impl HighlightingService {
                             
                             if duration <= background_timeout {
                                 // Cache the result
-                                state.cache_tokens(line_number, content_hash, tokens);
+                                state.cache_line(line_number, content_hash, start_state, end_state, &tokens);
                                 highlighted_count += 1;
                                 
                                 // Update metrics (but don't count towards main metrics to avoid skewing)
@@ -1151,6 +2585,30 @@ pub enum HighlightingRequest {
         line: String,
         /// The line number (for caching and metrics)
         line_number: usize,
+        /// The lexer state at the start of the line
+        start_state: LexerState,
+    },
+    /// Request to highlight consecutive lines of a document, each starting in the state
+    /// the previous one ended in. Answered with [`HighlightingResponse::Range`] once done.
+    HighlightRange {
+        /// Unique request ID for matching responses
+        request_id: u64,
+        /// The document the lines belong to
+        document: u64,
+        /// The document's generation when the request was made
+        generation: u64,
+        /// The document's current generation. Once it moves on, the request is cancelled.
+        current_generation: Arc<AtomicU64>,
+        /// The language to use for highlighting
+        language: Language,
+        /// The line number of the first line
+        first_line: usize,
+        /// The content of the lines
+        lines: Vec<String>,
+        /// The lexer state at the start of the first line
+        start_state: LexerState,
+        /// Lines that take longer than this are left as plain text
+        line_timeout: Duration,
     },
     /// Request to shutdown the worker thread
     Shutdown,
@@ -1165,6 +2623,8 @@ pub enum HighlightingResponse {
         request_id: u64,
         /// The highlighted tokens
         tokens: Vec<TokenInfo>,
+        /// The lexer state at the end of the line
+        end_state: LexerState,
         /// Time taken to highlight
         duration: Duration,
     },
@@ -1175,52 +2635,153 @@ pub enum HighlightingResponse {
         /// Error description
         error: String,
     },
+    /// The result of a [`HighlightingRequest::HighlightRange`]
+    Range(HighlightedRange),
+    /// A range request was abandoned, because the document changed
+    Cancelled {
+        /// The request ID this response corresponds to
+        request_id: u64,
+        /// The document the request was for
+        document: u64,
+    },
+}
+
+/// The lines of a range request, tokenized by a worker.
+#[derive(Debug, Clone)]
+pub struct HighlightedRange {
+    /// The request ID this result corresponds to
+    pub request_id: u64,
+    /// The document the lines belong to
+    pub document: u64,
+    /// The document's generation the lines were tokenized for
+    pub generation: u64,
+    /// The line number of the first line
+    pub first_line: usize,
+    /// The tokenized lines, in order
+    pub lines: Vec<HighlightedLine>,
+    /// Time the worker spent on the request
+    pub duration: Duration,
+}
+
+/// A line tokenized by a worker, ready to be cached.
+#[derive(Debug, Clone)]
+pub struct HighlightedLine {
+    /// Hash of the line content the tokens are for
+    pub content_hash: u64,
+    /// The highlighted tokens
+    pub tokens: Vec<TokenInfo>,
+    /// The lexer state at the start of the line
+    pub start_state: LexerState,
+    /// The lexer state at the end of the line
+    pub end_state: LexerState,
+    /// Time taken to highlight the line
+    pub duration: Duration,
+}
+
+/// Calculates the hash that identifies a line's content in the token cache.
+fn line_hash(line: &str) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    line.hash(&mut hasher);
+    hasher.finish()
 }
 
-/// A multi-threaded highlighting worker that can be interrupted with timeouts.
+/// A pool of highlighting workers.
+///
+/// Single lines can be highlighted with a timeout, for which the caller waits.
+/// Ranges of lines, like a whole viewport, are highlighted asynchronously instead:
+/// [`ThreadedHighlighter::request_range`] returns right away and the results are collected
+/// later with [`ThreadedHighlighter::poll`]. Each range is tokenized by a single worker,
+/// since every line depends on the state the previous one ended in, while separate ranges
+/// are spread over the pool.
 pub struct ThreadedHighlighter {
-    /// Channel to send highlighting requests to worker
+    /// Channel to send highlighting requests to the workers
     request_sender: Sender<HighlightingRequest>,
-    /// Channel to receive responses from worker
+    /// Channel to receive responses to single-line requests
     response_receiver: Receiver<HighlightingResponse>,
-    /// Handle to the worker thread
-    worker_handle: Option<thread::JoinHandle<()>>,
+    /// Channel to receive the results of range requests
+    range_receiver: Receiver<HighlightingResponse>,
+    /// Handles to the worker threads
+    worker_handles: Vec<thread::JoinHandle<()>>,
     /// Counter for generating unique request IDs
     next_request_id: u64,
+    /// Number of range requests that haven't been answered yet
+    outstanding: usize,
+    /// Lines of range requests that take longer than this are left as plain text
+    line_timeout: Duration,
 }
 
 impl std::fmt::Debug for ThreadedHighlighter {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ThreadedHighlighter")
             .field("next_request_id", &self.next_request_id)
-            .field("worker_active", &self.worker_handle.is_some())
+            .field("workers", &self.worker_handles.len())
+            .field("outstanding", &self.outstanding)
             .finish()
     }
 }
 
+impl Default for ThreadedHighlighter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ThreadedHighlighter {
-    /// Creates a new threaded highlighter with a dedicated worker thread.
+    /// Creates a new threaded highlighter with one worker per available core, up to 4.
     pub fn new() -> Self {
-        let (request_sender, request_receiver) = bounded(100); // Buffer up to 100 requests
-        let (response_sender, response_receiver) = bounded(100);
+        let workers = thread::available_parallelism().map_or(2, |n| n.get()).clamp(1, 4);
+        Self::with_workers(workers)
+    }
+
+    /// Creates a new threaded highlighter with the given number of worker threads.
+    pub fn with_workers(workers: usize) -> Self {
+        let (request_sender, request_receiver) = unbounded();
+        let (response_sender, response_receiver) = unbounded();
+        let (range_sender, range_receiver) = unbounded();
 
-        // Spawn worker thread
-        let worker_handle = thread::spawn(move || {
-            Self::worker_thread(request_receiver, response_sender);
-        });
+        let worker_handles = (0..workers.max(1))
+            .map(|_| {
+                let request_receiver = request_receiver.clone();
+                let response_sender = response_sender.clone();
+                let range_sender = range_sender.clone();
+                thread::spawn(move || Self::worker_thread(request_receiver, response_sender, range_sender))
+            })
+            .collect();
 
         Self {
             request_sender,
             response_receiver,
-            worker_handle: Some(worker_handle),
+            range_receiver,
+            worker_handles,
             next_request_id: 1,
+            outstanding: 0,
+            line_timeout: Duration::from_millis(50),
         }
     }
 
+    /// Returns the number of worker threads.
+    pub fn worker_count(&self) -> usize {
+        self.worker_handles.len()
+    }
+
+    /// Sets the time after which a line of a range request is left as plain text.
+    pub fn set_line_timeout(&mut self, timeout: Duration) {
+        self.line_timeout = timeout;
+    }
+
+    /// Returns the number of range requests that haven't been answered yet.
+    pub fn outstanding_requests(&self) -> usize {
+        self.outstanding
+    }
+
     /// Worker thread function that processes highlighting requests.
     fn worker_thread(
         request_receiver: Receiver<HighlightingRequest>,
         response_sender: Sender<HighlightingResponse>,
+        range_sender: Sender<HighlightingResponse>,
     ) {
         // Initialize highlighters for different languages
         let mut highlighters: HashMap<Language, SyntaxHighlighter> = HashMap::new();
@@ -1231,7 +2792,8 @@ impl ThreadedHighlighter {
                     request_id,
                     language,
                     line,
-                    line_number,
+                    line_number: _,
+                    start_state,
                 } => {
                     let start_time = Instant::now();
 
@@ -1241,12 +2803,13 @@ impl ThreadedHighlighter {
                         .or_insert_with(|| SyntaxHighlighter::new(language));
 
                     // Perform the highlighting
-                    match highlighter.highlight_line(&line, line_number) {
-                        Ok(tokens) => {
+                    match highlighter.highlight_line_with_state(&line, &start_state) {
+                        Ok((tokens, end_state)) => {
                             let duration = start_time.elapsed();
                             let _ = response_sender.send(HighlightingResponse::Success {
                                 request_id,
                                 tokens,
+                                end_state,
                                 duration,
                             });
                         }
@@ -1258,6 +2821,66 @@ impl ThreadedHighlighter {
                         }
                     }
                 }
+                HighlightingRequest::HighlightRange {
+                    request_id,
+                    document,
+                    generation,
+                    current_generation,
+                    language,
+                    first_line,
+                    lines,
+                    start_state,
+                    line_timeout,
+                } => {
+                    let start_time = Instant::now();
+                    let highlighter = highlighters
+                        .entry(language)
+                        .or_insert_with(|| SyntaxHighlighter::new(language));
+
+                    let mut state = start_state;
+                    let mut highlighted = Vec::with_capacity(lines.len());
+                    let mut cancelled = false;
+
+                    for line in &lines {
+                        // An edit makes the rest of the request pointless
+                        if current_generation.load(Ordering::Relaxed) != generation {
+                            cancelled = true;
+                            break;
+                        }
+
+                        let line_start = Instant::now();
+                        let result = highlighter.highlight_line_with_state(line, &state);
+                        let duration = line_start.elapsed();
+
+                        // Like the other paths, fall back to plain text if a line is too slow or fails
+                        let (tokens, end_state) = match result {
+                            Ok(result) if duration <= line_timeout => result,
+                            _ => (vec![TokenInfo::plain_text(line.clone(), 0, line.len())], state.clone()),
+                        };
+
+                        highlighted.push(HighlightedLine {
+                            content_hash: line_hash(line),
+                            tokens,
+                            start_state: std::mem::replace(&mut state, end_state.clone()),
+                            end_state,
+                            duration,
+                        });
+                    }
+
+                    let response = if cancelled {
+                        HighlightingResponse::Cancelled { request_id, document }
+                    } else {
+                        HighlightingResponse::Range(HighlightedRange {
+                            request_id,
+                            document,
+                            generation,
+                            first_line,
+                            lines: highlighted,
+                            duration: start_time.elapsed(),
+                        })
+                    };
+                    let _ = range_sender.send(response);
+                }
                 HighlightingRequest::Shutdown => {
                     // Clean shutdown
                     break;
@@ -1267,14 +2890,16 @@ impl ThreadedHighlighter {
     }
 
     /// Highlights a line with timeout interruption.
-    /// Returns Ok(tokens) if successful, or falls back to plain text on timeout/error.
+    /// Returns Ok((tokens, end_state)) if successful, or falls back to plain text on timeout/error,
+    /// in which case the end state is assumed to be the same as the start state.
     pub fn highlight_line_with_timeout(
         &mut self,
         language: Language,
         line: &str,
         line_number: usize,
+        start_state: &LexerState,
         timeout: Duration,
-    ) -> Result<Vec<TokenInfo>, Box<dyn std::error::Error + Send + Sync>> {
+    ) -> Result<(Vec<TokenInfo>, LexerState), Box<dyn std::error::Error + Send + Sync>> {
         let request_id = self.next_request_id;
         self.next_request_id += 1;
 
@@ -1284,65 +2909,93 @@ impl ThreadedHighlighter {
             language,
             line: line.to_string(),
             line_number,
+            start_state: start_state.clone(),
         };
 
         self.request_sender.send(request)?;
 
-        // Wait for response with timeout
-        select! {
-            recv(self.response_receiver) -> response => {
-                match response? {
-                    HighlightingResponse::Success { request_id: resp_id, tokens, duration: _ } => {
-                        if resp_id == request_id {
-                            Ok(tokens)
-                        } else {
-                            // Got response for different request, return plain text
-                            Ok(vec![TokenInfo::plain_text(
-                                line.to_string(),
-                                0,
-                                line.len(),
-                            )])
+        let plain_text = || (vec![TokenInfo::plain_text(line.to_string(), 0, line.len())], start_state.clone());
+        let deadline = Instant::now() + timeout;
+
+        // Wait for the response, skipping those to earlier requests that timed out
+        loop {
+            select! {
+                recv(self.response_receiver) -> response => {
+                    match response? {
+                        HighlightingResponse::Success { request_id: resp_id, tokens, end_state, .. } if resp_id == request_id => {
+                            return Ok((tokens, end_state));
                         }
-                    }
-                    HighlightingResponse::Error { request_id: resp_id, error: _ } => {
-                        if resp_id == request_id {
+                        HighlightingResponse::Error { request_id: resp_id, .. } if resp_id == request_id => {
                             // Return plain text on error
-                            Ok(vec![TokenInfo::plain_text(
-                                line.to_string(),
-                                0,
-                                line.len(),
-                            )])
-                        } else {
-                            // Got error for different request, return plain text
-                            Ok(vec![TokenInfo::plain_text(
-                                line.to_string(),
-                                0,
-                                line.len(),
-                            )])
+                            return Ok(plain_text());
                         }
+                        _ => {}
                     }
                 }
+                default(deadline.saturating_duration_since(Instant::now())) => {
+                    // Timeout occurred - return plain text immediately
+                    // The worker will eventually process the request but we don't wait
+                    return Ok(plain_text());
+                }
             }
-            default(timeout) => {
-                // Timeout occurred - return plain text immediately
-                // The worker thread will eventually process the request but we don't wait
-                Ok(vec![TokenInfo::plain_text(
-                    line.to_string(),
-                    0,
-                    line.len(),
-                )])
-            }
         }
     }
+
+    /// Sends consecutive lines to the pool to be highlighted asynchronously.
+    /// Returns the request ID, which the result returned by [`ThreadedHighlighter::poll`] carries.
+    pub fn request_range(
+        &mut self,
+        document: u64,
+        generation: &Arc<AtomicU64>,
+        language: Language,
+        first_line: usize,
+        lines: Vec<String>,
+        start_state: LexerState,
+    ) -> u64 {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        let request = HighlightingRequest::HighlightRange {
+            request_id,
+            document,
+            generation: generation.load(Ordering::Relaxed),
+            current_generation: generation.clone(),
+            language,
+            first_line,
+            lines,
+            start_state,
+            line_timeout: self.line_timeout,
+        };
+        if self.request_sender.send(request).is_ok() {
+            self.outstanding += 1;
+        }
+        request_id
+    }
+
+    /// Returns the range results and cancellations that arrived since the last call, without blocking.
+    pub fn poll(&mut self) -> Vec<HighlightingResponse> {
+        let responses: Vec<_> = self.range_receiver.try_iter().collect();
+        self.outstanding -= responses.len().min(self.outstanding);
+        responses
+    }
+
+    /// Waits until a range result or cancellation arrives, or the timeout elapses.
+    pub fn wait(&mut self, timeout: Duration) -> Option<HighlightingResponse> {
+        let response = self.range_receiver.recv_timeout(timeout).ok()?;
+        self.outstanding = self.outstanding.saturating_sub(1);
+        Some(response)
+    }
 }
 
 impl Drop for ThreadedHighlighter {
     fn drop(&mut self) {
-        // Send shutdown signal to worker thread
-        let _ = self.request_sender.send(HighlightingRequest::Shutdown);
-        
-        // Wait for worker thread to finish
-        if let Some(handle) = self.worker_handle.take() {
+        // Send a shutdown signal to every worker thread
+        for _ in &self.worker_handles {
+            let _ = self.request_sender.send(HighlightingRequest::Shutdown);
+        }
+
+        // Wait for worker threads to finish
+        for handle in self.worker_handles.drain(..) {
             let _ = handle.join();
         }
     }
@@ -1389,10 +3042,10 @@ mod tests {
         assert!(state.enabled);
         
         let tokens = vec![TokenInfo::plain_text("test".to_string(), 0, 4)];
-        state.cache_tokens(0, 12345, tokens.clone());
+        state.cache_tokens(0, 12345, &tokens);
         
         assert!(state.has_cached_tokens(0, 12345));
-        assert_eq!(state.get_cached_tokens(0).unwrap(), &tokens);
+        assert_eq!(state.get_cached_tokens(0, "test").unwrap(), tokens);
         
         state.invalidate_line_cache(0);
         assert!(!state.has_cached_tokens(0, 12345));
@@ -1458,6 +3111,76 @@ mod tests {
         assert_eq!(state.metrics.cache_hits, 1);
     }
 
+    #[test]
+    fn test_multiline_state_across_lines() {
+        let mut service = HighlightingService::new();
+        service.set_threaded_highlighting(false);
+        let mut state = service.create_highlighting_state("test.rs");
+        let lines = ["let a = 1; /* open", "fn inside()", "close */ fn after()"];
+        let get_line = |n: usize| lines.get(n).map(|l| l.to_string());
+
+        let tokens = service.highlight_line_with_context(&mut state, lines[1], 1, get_line).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].kind.as_deref(), Some("comment"));
+
+        let tokens = service.highlight_line_with_context(&mut state, lines[2], 2, get_line).unwrap();
+        assert_eq!(tokens[0].text, "close */");
+        assert_eq!(tokens[0].kind.as_deref(), Some("comment"));
+        assert!(tokens.iter().any(|t| t.text == "fn" && t.kind.as_deref() == Some("keyword")));
+    }
+
+    #[test]
+    fn test_retokenization_stops_when_state_converges() {
+        let mut service = HighlightingService::new();
+        service.set_threaded_highlighting(false);
+        let mut state = service.create_highlighting_state("test.rs");
+        let mut lines: Vec<String> = (0..20).map(|i| format!("let x{i} = {i};")).collect();
+
+        for n in 0..lines.len() {
+            service.highlight_line_with_context(&mut state, &lines[n], n, |i| lines.get(i).cloned()).unwrap();
+        }
+        assert_eq!(state.metrics.cache_misses, 20);
+
+        // An edit that doesn't change the end state only re-tokenizes the edited line.
+        lines[0] = "let changed = 0;".to_string();
+        state.mark_line_dirty(0);
+        service.highlight_line_with_context(&mut state, &lines[19], 19, |i| lines.get(i).cloned()).unwrap();
+        assert_eq!(state.metrics.cache_misses, 21);
+
+        // Opening a block comment changes the end state, so the lines below get re-tokenized.
+        lines[0] = "/* let changed = 0;".to_string();
+        state.mark_line_dirty(0);
+        let tokens = service
+            .highlight_line_with_context(&mut state, &lines[19], 19, |i| lines.get(i).cloned())
+            .unwrap();
+        assert_eq!(state.metrics.cache_misses, 41);
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].kind.as_deref(), Some("comment"));
+    }
+
+    #[test]
+    fn test_embedded_languages() {
+        let kind_of = |tokens: &[TokenInfo], text: &str| {
+            tokens.iter().find(|t| t.text == text).and_then(|t| t.kind.clone())
+        };
+
+        let mut highlighter = SyntaxHighlighter::new(Language::Html);
+        let (tokens, state) = highlighter.highlight_line_with_state("<script>/* a", &LexerState::default()).unwrap();
+        assert_eq!(kind_of(&tokens, "/* a").as_deref(), Some("comment"));
+        let (tokens, _) = highlighter.highlight_line_with_state("*/ let x; </script><b>", &state).unwrap();
+        assert_eq!(kind_of(&tokens, "*/").as_deref(), Some("comment"));
+        assert_eq!(kind_of(&tokens, "let").as_deref(), Some("keyword"));
+        assert_eq!(kind_of(&tokens, "<b").as_deref(), Some("keyword"));
+
+        let mut highlighter = SyntaxHighlighter::new(Language::Markdown);
+        let (_, state) = highlighter.highlight_line_with_state("``` py", &LexerState::default()).unwrap();
+        let (tokens, state) = highlighter.highlight_line_with_state("def f(): pass", &state).unwrap();
+        assert_eq!(kind_of(&tokens, "def").as_deref(), Some("keyword"));
+        let (_, state) = highlighter.highlight_line_with_state("```", &state).unwrap();
+        let (tokens, _) = highlighter.highlight_line_with_state("def", &state).unwrap();
+        assert_eq!(kind_of(&tokens, "def"), None);
+    }
+
     #[test]
     fn test_threaded_highlighting_configuration() {
         let mut service = HighlightingService::new();
@@ -1506,6 +3229,184 @@ mod tests {
         assert!(threaded_has_keywords);
     }
 
+    #[test]
+    fn test_async_viewport_request() {
+        let mut service = HighlightingService::new();
+        let mut state = service.create_highlighting_state("test.rs");
+        let lines = ["/* a", "b */ fn x() {}", "let y = 1;"];
+        let get_line = |n: usize| lines.get(n).map(|l| l.to_string());
+        state.update_viewport(0, lines.len());
+
+        // The first miss sends the whole viewport to the workers and returns plain text
+        let tokens = service.highlight_line_async(&mut state, lines[1], 1, get_line).unwrap();
+        assert!(tokens.iter().all(|t| !t.is_highlighted()));
+        assert!(state.is_line_requested(2));
+
+        // The lines below are already part of that request
+        service.highlight_line_async(&mut state, lines[2], 2, get_line).unwrap();
+        assert_eq!(service.threaded_highlighter.as_ref().unwrap().next_request_id, 2);
+
+        assert!(service.wait_for_async_results(Duration::from_secs(10)));
+        service.apply_async_results(&mut state);
+        assert!(!state.has_pending_requests());
+
+        let tokens = service.highlight_line_async(&mut state, lines[1], 1, get_line).unwrap();
+        let mut sync_state = service.create_highlighting_state("test.rs");
+        let expected = service.highlight_line_with_context(&mut sync_state, lines[1], 1, get_line).unwrap();
+        assert_eq!(tokens, expected);
+        assert_eq!(tokens[0].kind.as_deref(), Some("comment"));
+
+        assert_eq!(state.metrics.requests_completed, 1);
+        assert_eq!(state.metrics.lines_highlighted, 3);
+        assert!(state.metrics.max_request_time > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_cache_limit_evicts_lines() {
+        let mut service = HighlightingService::new();
+        service.set_threaded_highlighting(false);
+        service.set_cache_limit(4096);
+        let mut state = service.create_highlighting_state("test.rs");
+
+        let mut lines = vec!["let x = 1; // comment".to_string(); 200];
+        lines[0] = "/* start".to_string();
+        lines[199] = "end */ fn f() {}".to_string();
+        for (n, line) in lines.iter().enumerate() {
+            service.highlight_line(&mut state, line, n).unwrap();
+        }
+
+        let cache = state.token_cache();
+        assert!(cache.memory_usage() <= 4096);
+        assert!(cache.evictions() > 0);
+        assert!(!cache.contains_line(10));
+
+        // The lexer states outlive the tokens, so an evicted line is still inside the comment.
+        let tokens = service.highlight_line(&mut state, &lines[10], 10).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].kind.as_deref(), Some("comment"));
+    }
+
+    #[test]
+    fn test_policy_for_document() {
+        use HighlightingPolicy::*;
+
+        assert_eq!(HighlightingPolicy::for_document(50_000, 40), Full);
+        assert_eq!(HighlightingPolicy::for_document(50_000, 50_000), Full);
+        assert_eq!(HighlightingPolicy::for_document(500_000, 40), Full);
+        // A minified bundle
+        assert_eq!(HighlightingPolicy::for_document(500_000, 500_000), ViewportOnly);
+        // A huge log
+        assert_eq!(HighlightingPolicy::for_document(500_000_000, 120), ViewportOnly);
+        assert_eq!(HighlightingPolicy::for_document(500_000_000, 5_000), Off);
+    }
+
+    #[test]
+    fn test_latency_budget_reduces_policy() {
+        let budget = Duration::from_millis(1);
+        let mut state = HighlightingState::new(Language::Rust);
+        state.update_viewport(0, 10);
+        assert!(state.has_background_work());
+
+        for _ in 0..LATENCY_WINDOW {
+            state.record_latency(Duration::from_micros(100), budget);
+        }
+        assert_eq!(state.policy(), HighlightingPolicy::Full);
+
+        for _ in 0..LATENCY_WINDOW {
+            state.record_latency(Duration::from_millis(5), budget);
+        }
+        assert_eq!(state.policy(), HighlightingPolicy::ViewportOnly);
+        assert!(!state.has_background_work());
+
+        for _ in 0..LATENCY_WINDOW {
+            state.record_latency(Duration::from_millis(5), budget);
+        }
+        assert_eq!(state.policy(), HighlightingPolicy::Off);
+        assert!(!state.is_highlighting());
+
+        // Once the user forces it back on, it stays on.
+        state.force_full_highlighting();
+        assert!(state.is_highlighting() && state.is_policy_forced());
+        assert!(state.has_background_work());
+        for _ in 0..LATENCY_WINDOW {
+            state.record_latency(Duration::from_millis(5), budget);
+        }
+        state.set_policy(HighlightingPolicy::Off);
+        assert_eq!(state.policy(), HighlightingPolicy::Full);
+    }
+
+    #[test]
+    fn test_viewport_only_skips_lines_above() {
+        let mut service = HighlightingService::new();
+        service.set_threaded_highlighting(false);
+        let mut lines = vec!["let x = 1;".to_string(); 100];
+        lines[0] = "/* start".to_string();
+        let get_line = |n: usize| lines.get(n).cloned();
+
+        let mut state = service.create_highlighting_state("test.rs");
+        state.update_viewport(50, 60);
+        let tokens = service.highlight_line_with_context(&mut state, &lines[55], 55, get_line).unwrap();
+        assert_eq!(tokens[0].kind.as_deref(), Some("comment"));
+
+        let mut state = service.create_highlighting_state("test.rs");
+        state.set_policy(HighlightingPolicy::ViewportOnly);
+        state.update_viewport(50, 60);
+        let tokens = service.highlight_line_with_context(&mut state, &lines[55], 55, get_line).unwrap();
+        assert_eq!(tokens[0].kind.as_deref(), Some("keyword"));
+        assert_eq!(state.cache_size(), 6);
+        assert!(!state.has_background_work());
+    }
+
+    #[test]
+    fn test_edit_drops_async_results() {
+        let mut service = HighlightingService::new();
+        let mut state = service.create_highlighting_state("test.rs");
+        let lines = vec!["fn a() {}"; 100];
+        let get_line = |n: usize| lines.get(n).map(|l| l.to_string());
+        state.update_viewport(0, lines.len());
+
+        service.highlight_line_async(&mut state, lines[0], 0, get_line).unwrap();
+        let generation = state.generation();
+        state.mark_line_dirty(0);
+        assert_ne!(state.generation(), generation);
+        assert!(!state.has_pending_requests());
+
+        // Whether the workers finished or abandoned the request, nothing gets cached,
+        // and it counts as cancelled
+        assert!(service.wait_for_async_results(Duration::from_secs(10)));
+        assert_eq!(service.apply_async_results(&mut state), 0);
+        assert_eq!((state.metrics.requests_completed, state.metrics.requests_cancelled), (0, 1));
+        assert_eq!(state.metrics.total_request_time, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_worker_pool_cancels_stale_requests() {
+        let mut pool = ThreadedHighlighter::with_workers(1);
+        assert_eq!(pool.worker_count(), 1);
+
+        // Keep the only worker busy, so that the second request is still queued when it goes stale
+        let busy = Arc::new(AtomicU64::new(0));
+        pool.request_range(1, &busy, Language::Rust, 0, vec!["let x = 1;".to_string(); 20_000], LexerState::default());
+        let generation = Arc::new(AtomicU64::new(0));
+        let request_id =
+            pool.request_range(2, &generation, Language::Rust, 5, vec!["let y = 2;".to_string()], LexerState::default());
+        generation.fetch_add(1, Ordering::Relaxed);
+
+        let mut responses = Vec::new();
+        while pool.outstanding_requests() > 0 {
+            responses.push(pool.wait(Duration::from_secs(10)).unwrap());
+        }
+
+        match &responses[..] {
+            [HighlightingResponse::Range(range), HighlightingResponse::Cancelled { request_id: id, document }] => {
+                assert_eq!((range.document, range.first_line, range.lines.len()), (1, 0, 20_000));
+                assert!(range.lines[0].tokens.iter().any(|t| t.is_highlighted()));
+                assert_eq!((*id, *document), (request_id, 2));
+            }
+            _ => panic!("unexpected responses: {responses:?}"),
+        }
+    }
+
     #[test]
     fn test_timeout_behavior() {
         use std::time::Duration;
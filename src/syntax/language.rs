@@ -5,11 +5,15 @@
 //! Language detection and configuration for syntax highlighting.
 //!
 //! This module provides functionality to detect programming languages based on
-//! file extensions and manage language-specific configuration for syntax highlighting.
+//! file names, extensions and content (shebangs and modelines), and manage
+//! language-specific configuration for syntax highlighting.
 
 use std::collections::HashMap;
 use std::path::Path;
 use once_cell::sync::Lazy;
+use regex::Regex;
+
+use super::user_languages;
 
 /// Supported programming languages for syntax highlighting.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -20,6 +24,10 @@ pub enum Language {
     JavaScript,
     /// TypeScript programming language
     TypeScript,
+    /// JavaScript with JSX elements (`.jsx`)
+    Jsx,
+    /// TypeScript with JSX elements (`.tsx`)
+    Tsx,
     /// Python programming language
     Python,
     /// JSON data format
@@ -36,17 +44,90 @@ pub enum Language {
     Toml,
     /// SQL query language
     Sql,
+    /// C programming language
+    C,
+    /// C++ programming language
+    Cpp,
+    /// C# programming language
+    CSharp,
+    /// Java programming language
+    Java,
+    /// Go programming language
+    Go,
+    /// Shell scripts (sh, bash, zsh)
+    Shell,
+    /// PowerShell scripts and modules
+    PowerShell,
+    /// Windows batch files
+    Batch,
+    /// Makefiles
+    Makefile,
+    /// Dockerfiles
+    Dockerfile,
+    /// INI and similar configuration files
+    Ini,
+    /// Environment files (`.env`)
+    DotEnv,
+    /// XML documents, including project files and SVG images
+    Xml,
+    /// nginx-style configuration files
+    Nginx,
+    /// Unified diffs and patches
+    Diff,
+    /// Git commit, merge and tag messages
+    GitCommit,
+    /// Git's interactive rebase instructions (`git-rebase-todo`)
+    GitRebase,
     /// Plain text (no highlighting)
     PlainText,
+    /// A language loaded from the user's config directory, see [`user_languages`]
+    Custom(u16),
 }
 
 impl Language {
+    /// All languages, in the order they are presented to the user.
+    pub const ALL: &[Language] = &[
+        Language::PlainText,
+        Language::Batch,
+        Language::C,
+        Language::CSharp,
+        Language::Cpp,
+        Language::Css,
+        Language::Diff,
+        Language::Dockerfile,
+        Language::DotEnv,
+        Language::GitCommit,
+        Language::GitRebase,
+        Language::Go,
+        Language::Html,
+        Language::Ini,
+        Language::Java,
+        Language::JavaScript,
+        Language::Json,
+        Language::Jsx,
+        Language::Makefile,
+        Language::Markdown,
+        Language::Nginx,
+        Language::PowerShell,
+        Language::Python,
+        Language::Rust,
+        Language::Shell,
+        Language::Sql,
+        Language::Toml,
+        Language::Tsx,
+        Language::TypeScript,
+        Language::Xml,
+        Language::Yaml,
+    ];
+
     /// Returns the display name of the language.
     pub fn display_name(self) -> &'static str {
         match self {
             Language::Rust => "Rust",
             Language::JavaScript => "JavaScript",
             Language::TypeScript => "TypeScript",
+            Language::Jsx => "JSX",
+            Language::Tsx => "TSX",
             Language::Python => "Python",
             Language::Json => "JSON",
             Language::Html => "HTML",
@@ -55,7 +136,25 @@ impl Language {
             Language::Yaml => "YAML",
             Language::Toml => "TOML",
             Language::Sql => "SQL",
+            Language::C => "C",
+            Language::Cpp => "C++",
+            Language::CSharp => "C#",
+            Language::Java => "Java",
+            Language::Go => "Go",
+            Language::Shell => "Shell",
+            Language::PowerShell => "PowerShell",
+            Language::Batch => "Batch",
+            Language::Makefile => "Makefile",
+            Language::Dockerfile => "Dockerfile",
+            Language::Ini => "INI",
+            Language::DotEnv => "dotenv",
+            Language::Xml => "XML",
+            Language::Nginx => "nginx",
+            Language::Diff => "Diff",
+            Language::GitCommit => "Git Commit Message",
+            Language::GitRebase => "Git Rebase Todo",
             Language::PlainText => "Plain Text",
+            Language::Custom(id) => user_languages::get(id).map_or("Unknown", |l| l.name.as_str()),
         }
     }
 
@@ -65,6 +164,8 @@ impl Language {
             Language::Rust => "rs",
             Language::JavaScript => "js",
             Language::TypeScript => "ts",
+            Language::Jsx => "jsx",
+            Language::Tsx => "tsx",
             Language::Python => "py",
             Language::Json => "json",
             Language::Html => "html",
@@ -73,22 +174,96 @@ impl Language {
             Language::Yaml => "yaml",
             Language::Toml => "toml",
             Language::Sql => "sql",
+            Language::C => "c",
+            Language::Cpp => "cpp",
+            Language::CSharp => "cs",
+            Language::Java => "java",
+            Language::Go => "go",
+            Language::Shell => "sh",
+            Language::PowerShell => "ps1",
+            Language::Batch => "bat",
+            Language::Makefile => "mk",
+            Language::Dockerfile => "dockerfile",
+            Language::Ini => "ini",
+            Language::DotEnv => "env",
+            Language::Xml => "xml",
+            Language::Nginx => "conf",
+            Language::Diff => "diff",
+            Language::GitCommit => "gitcommit",
+            Language::GitRebase => "gitrebase",
             Language::PlainText => "txt",
+            Language::Custom(id) => user_languages::get(id).and_then(|l| l.primary_extension()).unwrap_or("txt"),
+        }
+    }
+
+    /// Looks up a language by a name as it appears in modelines, shebangs and
+    /// similar places, e.g. "python", "bash" or "js". The comparison is case-insensitive
+    /// and falls back to treating the name as a file extension.
+    pub fn from_name(name: &str) -> Option<Language> {
+        let name = name.to_ascii_lowercase();
+        if let Some(&(_, language)) = LANGUAGE_NAMES.iter().find(|&&(n, _)| n == name) {
+            return Some(language);
         }
+        user_languages::from_name(&name).or_else(|| EXTENSION_MAP.get(name.as_str()).copied())
+    }
+
+    /// Returns the names and file extensions of a built-in language that
+    /// [`Language::from_name`] recognizes, e.g. for the info string of a Markdown code fence.
+    pub fn names(self) -> Vec<&'static str> {
+        let names = LANGUAGE_NAMES.iter().filter(|&&(_, l)| l == self).map(|&(n, _)| n);
+        let extensions = EXTENSION_MAP.iter().filter(|&(_, &l)| l == self).map(|(&ext, _)| ext);
+        let mut names: Vec<_> = names.chain(extensions).collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// Returns the built-in languages followed by the ones defined by the user.
+    pub fn all() -> impl Iterator<Item = Language> {
+        Language::ALL.iter().copied().chain(user_languages::languages())
+    }
+
+    /// Returns whether this language was defined by the user.
+    pub fn is_custom(self) -> bool {
+        matches!(self, Language::Custom(_))
     }
 
     /// Returns whether this language is supported in the current phase.
     /// Phase 0: Infrastructure only
     /// Phase 1: Tier 1 languages (Rust, JavaScript, Python, JSON)
     /// Phase 2: Tier 2 languages (HTML, CSS, Markdown, YAML, TOML, SQL)
+    /// Phase 3: Tier 3 languages (C, C++, C#, Java, Go, shell scripts)
+    /// Phase 4: Tier 4 languages (build, container and configuration files)
     pub fn is_tier_1(self) -> bool {
-        matches!(self, Language::Rust | Language::JavaScript | Language::TypeScript | Language::Python | Language::Json)
+        matches!(
+            self,
+            Language::Rust | Language::JavaScript | Language::TypeScript | Language::Jsx | Language::Tsx
+                | Language::Python | Language::Json
+        )
     }
 
     /// Returns whether this language is supported in Phase 2.
     pub fn is_tier_2(self) -> bool {
         matches!(self, Language::Html | Language::Css | Language::Markdown | Language::Yaml | Language::Toml | Language::Sql)
     }
+
+    /// Returns whether this language is supported in Phase 3.
+    pub fn is_tier_3(self) -> bool {
+        matches!(
+            self,
+            Language::C | Language::Cpp | Language::CSharp | Language::Java | Language::Go
+                | Language::Shell | Language::PowerShell | Language::Batch
+        )
+    }
+
+    /// Returns whether this language is supported in Phase 4.
+    pub fn is_tier_4(self) -> bool {
+        matches!(
+            self,
+            Language::Makefile | Language::Dockerfile | Language::Ini | Language::DotEnv | Language::Xml
+                | Language::Nginx | Language::Diff | Language::GitCommit | Language::GitRebase
+        )
+    }
 }
 
 /// Configuration for a specific language's syntax highlighting.
@@ -126,6 +301,46 @@ impl LanguageConfig {
     }
 }
 
+/// Names of the built-in languages, other than their file extensions, as used in modelines,
+/// shebangs and Markdown code fences.
+static LANGUAGE_NAMES: &[(&str, Language)] = &[
+    ("rust", Language::Rust),
+    ("javascript", Language::JavaScript), ("node", Language::JavaScript), ("nodejs", Language::JavaScript),
+    ("deno", Language::JavaScript), ("bun", Language::JavaScript),
+    ("typescript", Language::TypeScript), ("ts-node", Language::TypeScript),
+    ("javascriptreact", Language::Jsx), ("typescriptreact", Language::Tsx),
+    ("python", Language::Python), ("pypy", Language::Python),
+    ("json", Language::Json),
+    ("html", Language::Html),
+    ("css", Language::Css),
+    ("markdown", Language::Markdown),
+    ("yaml", Language::Yaml),
+    ("toml", Language::Toml),
+    ("sql", Language::Sql),
+    ("c", Language::C),
+    ("cpp", Language::Cpp), ("c++", Language::Cpp), ("cxx", Language::Cpp),
+    ("csharp", Language::CSharp), ("c#", Language::CSharp),
+    ("java", Language::Java),
+    ("go", Language::Go), ("golang", Language::Go),
+    ("sh", Language::Shell), ("bash", Language::Shell), ("zsh", Language::Shell), ("dash", Language::Shell),
+    ("ksh", Language::Shell), ("ash", Language::Shell), ("shell", Language::Shell),
+    ("shell-script", Language::Shell),
+    ("powershell", Language::PowerShell), ("pwsh", Language::PowerShell),
+    ("batch", Language::Batch), ("dosbatch", Language::Batch), ("bat", Language::Batch),
+    ("cmd", Language::Batch),
+    ("make", Language::Makefile), ("makefile", Language::Makefile),
+    ("dockerfile", Language::Dockerfile), ("docker", Language::Dockerfile),
+    ("ini", Language::Ini), ("dosini", Language::Ini), ("cfg", Language::Ini), ("gitconfig", Language::Ini),
+    ("editorconfig", Language::Ini),
+    ("dotenv", Language::DotEnv), ("env", Language::DotEnv),
+    ("xml", Language::Xml), ("svg", Language::Xml), ("xsd", Language::Xml), ("xslt", Language::Xml),
+    ("nginx", Language::Nginx), ("conf", Language::Nginx),
+    ("diff", Language::Diff), ("patch", Language::Diff),
+    ("gitcommit", Language::GitCommit),
+    ("gitrebase", Language::GitRebase),
+    ("text", Language::PlainText), ("plain", Language::PlainText), ("plaintext", Language::PlainText),
+];
+
 /// Global mapping of file extensions to programming languages.
 static EXTENSION_MAP: Lazy<HashMap<&'static str, Language>> = Lazy::new(|| {
     let mut map = HashMap::new();
@@ -135,9 +350,9 @@ static EXTENSION_MAP: Lazy<HashMap<&'static str, Language>> = Lazy::new(|| {
     map.insert("js", Language::JavaScript);
     map.insert("mjs", Language::JavaScript);
     map.insert("cjs", Language::JavaScript);
-    map.insert("jsx", Language::JavaScript);
+    map.insert("jsx", Language::Jsx);
     map.insert("ts", Language::TypeScript);
-    map.insert("tsx", Language::TypeScript);
+    map.insert("tsx", Language::Tsx);
     map.insert("py", Language::Python);
     map.insert("pyw", Language::Python);
     map.insert("pyi", Language::Python);
@@ -165,6 +380,69 @@ static EXTENSION_MAP: Lazy<HashMap<&'static str, Language>> = Lazy::new(|| {
     map.insert("mysql", Language::Sql);
     map.insert("pgsql", Language::Sql);
     
+    // Tier 3 languages (Phase 3)
+    map.insert("c", Language::C);
+    map.insert("h", Language::C);
+    map.insert("cpp", Language::Cpp);
+    map.insert("cc", Language::Cpp);
+    map.insert("cxx", Language::Cpp);
+    map.insert("c++", Language::Cpp);
+    map.insert("hpp", Language::Cpp);
+    map.insert("hh", Language::Cpp);
+    map.insert("hxx", Language::Cpp);
+    map.insert("h++", Language::Cpp);
+    map.insert("inl", Language::Cpp);
+    map.insert("ipp", Language::Cpp);
+    map.insert("tpp", Language::Cpp);
+    map.insert("ixx", Language::Cpp);
+    map.insert("cppm", Language::Cpp);
+    map.insert("cs", Language::CSharp);
+    map.insert("csx", Language::CSharp);
+    map.insert("java", Language::Java);
+    map.insert("go", Language::Go);
+    map.insert("ps1", Language::PowerShell);
+    map.insert("psm1", Language::PowerShell);
+    map.insert("psd1", Language::PowerShell);
+    map.insert("bat", Language::Batch);
+    map.insert("cmd", Language::Batch);
+    
+    // Tier 4 languages (Phase 4)
+    map.insert("ini", Language::Ini);
+    map.insert("cfg", Language::Ini);
+    map.insert("inf", Language::Ini);
+    map.insert("desktop", Language::Ini);
+    map.insert("properties", Language::Ini);
+    map.insert("gitconfig", Language::Ini);
+    map.insert("env", Language::DotEnv);
+    map.insert("xml", Language::Xml);
+    map.insert("xsd", Language::Xml);
+    map.insert("xsl", Language::Xml);
+    map.insert("xslt", Language::Xml);
+    map.insert("svg", Language::Xml);
+    map.insert("plist", Language::Xml);
+    map.insert("xaml", Language::Xml);
+    map.insert("resx", Language::Xml);
+    map.insert("nuspec", Language::Xml);
+    map.insert("csproj", Language::Xml);
+    map.insert("vbproj", Language::Xml);
+    map.insert("fsproj", Language::Xml);
+    map.insert("vcxproj", Language::Xml);
+    map.insert("props", Language::Xml);
+    map.insert("targets", Language::Xml);
+    map.insert("conf", Language::Nginx);
+    map.insert("diff", Language::Diff);
+    map.insert("patch", Language::Diff);
+    map.insert("rej", Language::Diff);
+    
+    // Languages that are mostly detected by file name or content
+    map.insert("sh", Language::Shell);
+    map.insert("bash", Language::Shell);
+    map.insert("zsh", Language::Shell);
+    map.insert("ksh", Language::Shell);
+    map.insert("mk", Language::Makefile);
+    map.insert("mak", Language::Makefile);
+    map.insert("dockerfile", Language::Dockerfile);
+    
     // Common text file extensions
     map.insert("txt", Language::PlainText);
     map.insert("text", Language::PlainText);
@@ -172,6 +450,61 @@ static EXTENSION_MAP: Lazy<HashMap<&'static str, Language>> = Lazy::new(|| {
     map
 });
 
+/// Mapping of well-known file names without a (meaningful) extension to languages.
+static FILENAME_MAP: Lazy<HashMap<&'static str, Language>> = Lazy::new(|| {
+    let mut map = HashMap::new();
+
+    map.insert("Makefile", Language::Makefile);
+    map.insert("makefile", Language::Makefile);
+    map.insert("GNUmakefile", Language::Makefile);
+    map.insert("Dockerfile", Language::Dockerfile);
+    map.insert("Containerfile", Language::Dockerfile);
+    map.insert(".env", Language::DotEnv);
+    map.insert(".envrc", Language::Shell);
+    map.insert(".gitconfig", Language::Ini);
+    map.insert(".gitmodules", Language::Ini);
+    map.insert(".editorconfig", Language::Ini);
+    map.insert(".npmrc", Language::Ini);
+    map.insert(".pylintrc", Language::Ini);
+    map.insert("nginx.conf", Language::Nginx);
+    map.insert("COMMIT_EDITMSG", Language::GitCommit);
+    map.insert("MERGE_MSG", Language::GitCommit);
+    map.insert("TAG_EDITMSG", Language::GitCommit);
+    map.insert("SQUASH_MSG", Language::GitCommit);
+    map.insert("EDIT_DESCRIPTION", Language::GitCommit);
+    map.insert("git-rebase-todo", Language::GitRebase);
+    map.insert(".bashrc", Language::Shell);
+    map.insert(".bash_profile", Language::Shell);
+    map.insert(".bash_logout", Language::Shell);
+    map.insert(".bash_aliases", Language::Shell);
+    map.insert(".profile", Language::Shell);
+    map.insert(".zshrc", Language::Shell);
+    map.insert(".zshenv", Language::Shell);
+    map.insert(".zprofile", Language::Shell);
+    map.insert("PKGBUILD", Language::Shell);
+    map.insert("Cargo.lock", Language::Toml);
+    map.insert("poetry.lock", Language::Toml);
+    map.insert("Pipfile", Language::Toml);
+    map.insert(".babelrc", Language::Json);
+    map.insert(".eslintrc", Language::Json);
+    map.insert(".prettierrc", Language::Json);
+
+    map
+});
+
+/// Number of lines at the start and at the end of a document that are searched for modelines.
+pub const MODELINE_SEARCH_LINES: usize = 5;
+
+/// Matches Vim modelines such as `vim: ft=python` or `vim: set filetype=sh:`.
+static VIM_MODELINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:^|\s)(?:vi|vim|ex):.*?\b(?:ft|filetype|syntax)=([\w+-]+)").unwrap()
+});
+
+/// Matches Emacs modelines such as `-*- mode: python -*-` or `-*- python -*-`.
+static EMACS_MODELINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"-\*-\s*(?:.*?\bmode:\s*([\w+-]+)[^-]*|([\w+-]+)\s*)-\*-").unwrap()
+});
+
 /// Language detector that can identify programming languages from file paths.
 #[derive(Debug, Default)]
 pub struct LanguageDetector {
@@ -189,8 +522,10 @@ impl LanguageDetector {
     /// 
     /// This function uses the following detection strategy:
     /// 1. Check for manual override
-    /// 2. Extract file extension and look up in extension map
-    /// 3. Fall back to PlainText if no match found
+    /// 2. Match the file name against user-defined languages, then look up
+    ///    well-known file names (e.g. `Makefile`, `.bashrc`, `Cargo.lock`)
+    /// 3. Extract file extension and look up in extension map
+    /// 4. Fall back to PlainText if no match found
     /// 
     /// # Arguments
     /// 
@@ -200,26 +535,108 @@ impl LanguageDetector {
     /// 
     /// The detected language, or `Language::PlainText` if detection fails.
     pub fn detect_language<P: AsRef<Path>>(&self, path: P) -> Language {
-        let path = path.as_ref();
+        self.detect_language_from_path(path.as_ref()).unwrap_or(Language::PlainText)
+    }
+
+    /// Detects the programming language from a file path and the document's content.
+    ///
+    /// The path takes precedence, as in [`LanguageDetector::detect_language`]. If it doesn't
+    /// identify the language, the content is checked as described in
+    /// [`LanguageDetector::detect_language_from_content`].
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file path to analyze, if the document has one
+    /// * `head` - The first few lines of the document
+    /// * `tail` - The last few lines of the document (may overlap with `head`)
+    pub fn detect_language_with_content(&self, path: Option<&Path>, head: &[&str], tail: &[&str]) -> Language {
+        path.and_then(|path| self.detect_language_from_path(path))
+            .or_else(|| Self::detect_language_from_content(head, tail))
+            .unwrap_or(Language::PlainText)
+    }
+
+    /// Detects the programming language from the content of a document.
+    ///
+    /// 1. A shebang in the first line, e.g. `#!/usr/bin/env python3`
+    /// 2. A Vim or Emacs modeline in the first or last [`MODELINE_SEARCH_LINES`] lines,
+    ///    e.g. `vim: ft=sh` or `-*- mode: python -*-`
+    pub fn detect_language_from_content(head: &[&str], tail: &[&str]) -> Option<Language> {
+        if let Some(language) = head.first().and_then(|line| Self::detect_shebang(line)) {
+            return Some(language);
+        }
+
+        head.iter()
+            .take(MODELINE_SEARCH_LINES)
+            .chain(tail.iter().rev().take(MODELINE_SEARCH_LINES))
+            .find_map(|line| Self::detect_modeline(line))
+    }
+
+    /// Parses a shebang line like `#!/bin/bash` or `#!/usr/bin/env -S python3 -u`.
+    pub fn detect_shebang(line: &str) -> Option<Language> {
+        let mut args = line.strip_prefix("#!")?.split_whitespace();
+        let mut interpreter = args.next()?.rsplit('/').next()?;
+
+        if interpreter == "env" {
+            // Skip options and variable assignments passed to env.
+            interpreter = args.find(|arg| !arg.starts_with('-') && !arg.contains('='))?;
+        }
+
+        // "python3.12" -> "python"
+        let name = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+        Language::from_name(name)
+    }
+
+    /// Parses a Vim or Emacs modeline anywhere in the given line.
+    pub fn detect_modeline(line: &str) -> Option<Language> {
+        if let Some(caps) = VIM_MODELINE.captures(line) {
+            return Language::from_name(&caps[1]);
+        }
+        if let Some(caps) = EMACS_MODELINE.captures(line) {
+            let name = caps.get(1).or_else(|| caps.get(2))?.as_str();
+            return Language::from_name(name);
+        }
+        None
+    }
+
+    /// Detects the language from the override, file name and extension, in that order.
+    fn detect_language_from_path(&self, path: &Path) -> Option<Language> {
         let path_str = path.to_string_lossy();
 
         // Check for manual override first
         if let Some(&language) = self.overrides.get(path_str.as_ref()) {
-            return language;
+            return Some(language);
+        }
+
+        // User-defined file patterns take precedence over the built-in ones
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        if let Some(language) = user_languages::detect_file_name(file_name) {
+            return Some(language);
+        }
+
+        // Well-known file names
+        if let Some(&language) = FILENAME_MAP.get(file_name) {
+            return Some(language);
+        }
+        if file_name.starts_with("Dockerfile.") || file_name.starts_with("Containerfile.") {
+            return Some(Language::Dockerfile);
+        }
+        // `.env.local`, `.env.production` and the like
+        if file_name.starts_with(".env.") {
+            return Some(Language::DotEnv);
         }
 
         // Extract file extension
-        if let Some(extension) = path.extension() {
-            if let Some(ext_str) = extension.to_str() {
-                let ext_lower = ext_str.to_lowercase();
-                if let Some(&language) = EXTENSION_MAP.get(ext_lower.as_str()) {
-                    return language;
-                }
-            }
+        let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase);
+        if let Some(&language) = extension.and_then(|ext| EXTENSION_MAP.get(ext.as_str())) {
+            return Some(language);
+        }
+
+        // Files in nginx's configuration directory, like `sites-available/default`
+        if path.parent().is_some_and(|dir| dir.components().any(|c| c.as_os_str() == "nginx")) {
+            return Some(Language::Nginx);
         }
 
-        // Fallback to plain text
-        Language::PlainText
+        None
     }
 
     /// Sets a manual language override for a specific file path.
@@ -325,11 +742,81 @@ mod tests {
     fn test_language_detection_no_extension() {
         let detector = LanguageDetector::new();
         
-        assert_eq!(detector.detect_language("Makefile"), Language::PlainText);
         assert_eq!(detector.detect_language("README"), Language::PlainText);
         assert_eq!(detector.detect_language("noext"), Language::PlainText);
     }
 
+    #[test]
+    fn test_language_detection_filenames() {
+        let detector = LanguageDetector::new();
+
+        assert_eq!(detector.detect_language("Makefile"), Language::Makefile);
+        assert_eq!(detector.detect_language("/src/GNUmakefile"), Language::Makefile);
+        assert_eq!(detector.detect_language("Dockerfile"), Language::Dockerfile);
+        assert_eq!(detector.detect_language("Dockerfile.dev"), Language::Dockerfile);
+        assert_eq!(detector.detect_language("/home/user/.bashrc"), Language::Shell);
+        assert_eq!(detector.detect_language("Cargo.lock"), Language::Toml);
+        assert_eq!(detector.detect_language("other.lock"), Language::PlainText);
+        assert_eq!(detector.detect_language("/app/.env"), Language::DotEnv);
+        assert_eq!(detector.detect_language(".env.production"), Language::DotEnv);
+        assert_eq!(detector.detect_language("/home/user/.gitconfig"), Language::Ini);
+        assert_eq!(detector.detect_language("/etc/nginx/sites-available/default"), Language::Nginx);
+        assert_eq!(detector.detect_language("/etc/nginx/mime.types"), Language::Nginx);
+        assert_eq!(detector.detect_language("/etc/nginx/conf.d/app.json"), Language::Json);
+        assert_eq!(detector.detect_language("/repo/.git/COMMIT_EDITMSG"), Language::GitCommit);
+        assert_eq!(detector.detect_language("/repo/.git/MERGE_MSG"), Language::GitCommit);
+        assert_eq!(detector.detect_language("/repo/.git/TAG_EDITMSG"), Language::GitCommit);
+        assert_eq!(detector.detect_language("/repo/.git/rebase-merge/git-rebase-todo"), Language::GitRebase);
+    }
+
+    #[test]
+    fn test_language_detection_shebang() {
+        assert_eq!(LanguageDetector::detect_shebang("#!/usr/bin/env python3"), Some(Language::Python));
+        assert_eq!(LanguageDetector::detect_shebang("#!/usr/bin/python3.12 -u"), Some(Language::Python));
+        assert_eq!(LanguageDetector::detect_shebang("#!/bin/bash"), Some(Language::Shell));
+        assert_eq!(LanguageDetector::detect_shebang("#! /bin/sh -e"), Some(Language::Shell));
+        assert_eq!(LanguageDetector::detect_shebang("#!/usr/bin/env -S node --harmony"), Some(Language::JavaScript));
+        assert_eq!(LanguageDetector::detect_shebang("#!/usr/bin/make -f"), Some(Language::Makefile));
+        assert_eq!(LanguageDetector::detect_shebang("#!/usr/bin/env pwsh"), Some(Language::PowerShell));
+        assert_eq!(LanguageDetector::detect_shebang("#!/usr/bin/env perl"), None);
+        assert_eq!(LanguageDetector::detect_shebang("# not a shebang"), None);
+    }
+
+    #[test]
+    fn test_language_detection_modeline() {
+        assert_eq!(LanguageDetector::detect_modeline("# vim: ft=python"), Some(Language::Python));
+        assert_eq!(LanguageDetector::detect_modeline("/* vim: set filetype=javascript: */"), Some(Language::JavaScript));
+        assert_eq!(LanguageDetector::detect_modeline("// vi: sw=4 syntax=rust"), Some(Language::Rust));
+        assert_eq!(LanguageDetector::detect_modeline("# -*- mode: yaml -*-"), Some(Language::Yaml));
+        assert_eq!(LanguageDetector::detect_modeline("# -*- coding: utf-8; mode: sh -*-"), Some(Language::Shell));
+        assert_eq!(LanguageDetector::detect_modeline(";; -*- markdown -*-"), Some(Language::Markdown));
+        assert_eq!(LanguageDetector::detect_modeline("let ft = 1;"), None);
+    }
+
+    #[test]
+    fn test_language_detection_precedence() {
+        let detector = LanguageDetector::new();
+        let shebang = ["#!/usr/bin/env python3", "print(1)"];
+        let modeline = ["echo hi", "# vim: ft=toml"];
+
+        // File names and extensions win over the content.
+        let path = Path::new("Makefile");
+        assert_eq!(detector.detect_language_with_content(Some(path), &shebang, &shebang), Language::Makefile);
+        let path = Path::new("script.rs");
+        assert_eq!(detector.detect_language_with_content(Some(path), &shebang, &shebang), Language::Rust);
+
+        // The content is used if the path doesn't tell.
+        let path = Path::new("script");
+        assert_eq!(detector.detect_language_with_content(Some(path), &shebang, &shebang), Language::Python);
+        assert_eq!(detector.detect_language_with_content(None, &shebang, &shebang), Language::Python);
+
+        // The shebang wins over modelines, which are also found at the end of the document.
+        let head = ["#!/bin/sh", "# vim: ft=python"];
+        assert_eq!(detector.detect_language_with_content(None, &head, &head), Language::Shell);
+        assert_eq!(detector.detect_language_with_content(None, &modeline[..1], &modeline), Language::Toml);
+        assert_eq!(detector.detect_language_with_content(None, &["plain"], &["plain"]), Language::PlainText);
+    }
+
     #[test]
     fn test_language_overrides() {
         let mut detector = LanguageDetector::new();
@@ -367,10 +854,26 @@ mod tests {
         
         // Test some specific mappings
         let detector = LanguageDetector::new();
-        assert_eq!(detector.detect_language("app.jsx"), Language::JavaScript);
-        assert_eq!(detector.detect_language("types.tsx"), Language::TypeScript);
+        assert_eq!(detector.detect_language("app.jsx"), Language::Jsx);
+        assert_eq!(detector.detect_language("types.tsx"), Language::Tsx);
         assert_eq!(detector.detect_language("config.toml"), Language::Toml);
         assert_eq!(detector.detect_language("data.yaml"), Language::Yaml);
+        assert_eq!(detector.detect_language("stdio.h"), Language::C);
+        assert_eq!(detector.detect_language("vector.hpp"), Language::Cpp);
+        assert_eq!(detector.detect_language("main.cc"), Language::Cpp);
+        assert_eq!(detector.detect_language("Program.cs"), Language::CSharp);
+        assert_eq!(detector.detect_language("Main.java"), Language::Java);
+        assert_eq!(detector.detect_language("main.go"), Language::Go);
+        assert_eq!(detector.detect_language("install.bash"), Language::Shell);
+        assert_eq!(detector.detect_language("Build.psm1"), Language::PowerShell);
+        assert_eq!(detector.detect_language("setup.cmd"), Language::Batch);
+        assert_eq!(detector.detect_language("rules.mk"), Language::Makefile);
+        assert_eq!(detector.detect_language("setup.cfg"), Language::Ini);
+        assert_eq!(detector.detect_language("tox.ini"), Language::Ini);
+        assert_eq!(detector.detect_language("App.csproj"), Language::Xml);
+        assert_eq!(detector.detect_language("logo.svg"), Language::Xml);
+        assert_eq!(detector.detect_language("site.conf"), Language::Nginx);
+        assert_eq!(detector.detect_language("fix.patch"), Language::Diff);
     }
 
     #[test]
@@ -385,8 +888,20 @@ mod tests {
         assert!(Language::Markdown.is_tier_2());
         assert!(Language::Yaml.is_tier_2());
         
+        assert!(Language::C.is_tier_3());
+        assert!(Language::Cpp.is_tier_3());
+        assert!(Language::Go.is_tier_3());
+        assert!(Language::Shell.is_tier_3());
+        
+        assert!(Language::Makefile.is_tier_4());
+        assert!(Language::Dockerfile.is_tier_4());
+        assert!(Language::Xml.is_tier_4());
+        assert!(Language::Nginx.is_tier_4());
+        
         assert!(!Language::PlainText.is_tier_1());
         assert!(!Language::PlainText.is_tier_2());
+        assert!(!Language::PlainText.is_tier_3());
+        assert!(!Language::PlainText.is_tier_4());
     }
 
     #[test]
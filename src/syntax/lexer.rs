@@ -0,0 +1,1030 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Line-oriented, regex-based tokenizer with explicit state between lines.
+//!
+//! The rule vocabulary mirrors Synoptic's (`keyword`, `bounded`, `bounded_interp`),
+//! but instead of tokenizing the whole document at once, a [`Lexer`] tokenizes
+//! one line at a time and hands back the [`LexerState`] at the end of that line.
+//! Feeding that state into the next line is what makes block comments,
+//! triple-quoted strings and template literals work across line boundaries,
+//! and comparing states is how callers know when re-tokenization can stop.
+//!
+//! Unlike Synoptic, the `end` of a bounded rule may refer back to groups of its `start`
+//! (`\1`), which is what heredocs like `<<EOF` ... `EOF` need. The resolved `end` pattern
+//! is part of the [`LexerState`], so it survives line boundaries like the region itself.
+//!
+//! A region can also hand its contents to another [`Lexer`] (see [`Lexer::nested`]),
+//! whose state is then carried along in the outer [`LexerState`].
+
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use regex::{Captures, Regex};
+
+use crate::syntax::highlighter::TokenInfo;
+use crate::syntax::textmate;
+
+/// Maximum nesting depth of regions and interpolations that a [`LexerState`] tracks.
+/// Deeper nesting is treated as plain text. This keeps the state small and comparisons cheap.
+const MAX_STATE_DEPTH: usize = 16;
+
+/// A keyword rule: every match of `regex` is a token of the given `kind`.
+#[derive(Debug, Clone)]
+struct KeywordRule {
+    kind: String,
+    regex: Regex,
+}
+
+/// The `end` of a [`RegionRule`].
+#[derive(Debug, Clone)]
+enum EndPattern {
+    Static(Regex),
+    /// Contains back-references to the `start` match and is compiled once those are known.
+    Dynamic(String),
+}
+
+/// A bounded rule: a region from a `start` match to an `end` match, which may span lines.
+#[derive(Debug, Clone)]
+struct RegionRule {
+    kind: String,
+    start: Regex,
+    end: EndPattern,
+    /// If true, delimiters preceded by an odd number of escape characters are ignored.
+    escapable: bool,
+    /// Optional interpolation markers. Code between them is tokenized with the regular rules.
+    interp: Option<(Regex, Regex)>,
+    /// If set, the contents are tokenized by this lexer, and only the delimiters are of `kind`.
+    inner: Option<InnerLexer>,
+    /// Keyword rules that apply inside the region, e.g. escape sequences, as indices into [`Lexer::keywords`].
+    keywords: Vec<usize>,
+    /// Regions that can start inside the region, as indices into [`Lexer::regions`].
+    regions: Vec<usize>,
+}
+
+/// The lexer for the contents of a nested region. It's built when the region is first entered,
+/// since a Markdown document, for instance, can embed any language, but rarely does.
+#[derive(Clone)]
+struct InnerLexer {
+    lexer: Arc<OnceLock<Lexer>>,
+    build: Arc<dyn Fn() -> Lexer + Send + Sync>,
+    /// If true, the region only ends where the inner lexer isn't inside a region of its own.
+    balanced: bool,
+}
+
+impl InnerLexer {
+    fn get(&self) -> &Lexer {
+        self.lexer.get_or_init(|| (self.build)())
+    }
+}
+
+impl fmt::Debug for InnerLexer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InnerLexer")
+            .field("lexer", &self.lexer.get())
+            .field("balanced", &self.balanced)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A single entry on the [`LexerState`] stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Frame {
+    /// Inside the region of the given [`RegionRule`] index.
+    Region(u16),
+    /// Inside an interpolation of the given [`RegionRule`] index.
+    Interp(u16),
+}
+
+/// The tokenizer state at a line boundary.
+///
+/// The default state means "not inside any region".
+/// Two states compare equal if and only if tokenizing the next line
+/// would produce identical results, which is what makes them useful
+/// for detecting when an edit stopped affecting the lines below it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct LexerState {
+    stack: Vec<Frame>,
+    /// The resolved `end` patterns of the open regions with a [`EndPattern::Dynamic`] end,
+    /// innermost last.
+    ends: Vec<Arc<str>>,
+    /// The state of the inner lexer, if the innermost region is a nested one.
+    inner: Option<Box<LexerState>>,
+    /// The rule stack of the TextMate backend, see [`crate::syntax::textmate`].
+    pub(super) grammar: Vec<textmate::Frame>,
+}
+
+impl LexerState {
+    pub(super) fn from_grammar_stack(grammar: Vec<textmate::Frame>) -> Self {
+        Self { stack: Vec::new(), ends: Vec::new(), inner: None, grammar }
+    }
+
+    /// Returns true if the line ended outside of any region.
+    pub fn is_default(&self) -> bool {
+        self.stack.is_empty() && self.grammar.is_empty()
+    }
+
+    fn top(&self) -> Option<Frame> {
+        self.stack.last().copied()
+    }
+
+    fn push(&mut self, frame: Frame) -> bool {
+        if self.stack.len() >= MAX_STATE_DEPTH {
+            return false;
+        }
+        self.stack.push(frame);
+        true
+    }
+
+    fn pop(&mut self) {
+        self.stack.pop();
+    }
+}
+
+/// What a candidate match found on the current line will do once it's consumed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Keyword(usize),
+    /// A keyword rule inside a region, see [`Lexer::region_keyword`].
+    RegionKeyword(usize),
+    RegionStart(usize),
+    RegionEnd(usize),
+    InterpStart(usize),
+    InterpEnd(usize),
+}
+
+/// A set of compiled tokenization rules for one language.
+#[derive(Debug, Clone, Default)]
+pub struct Lexer {
+    keywords: Vec<KeywordRule>,
+    regions: Vec<RegionRule>,
+    /// Definition order of all rules, used to break ties between matches at the same offset.
+    order: Vec<Action>,
+    /// The escape character of escapable regions, if it isn't a backslash.
+    escape: Option<u8>,
+}
+
+impl Lexer {
+    /// Creates an empty lexer. Without rules, every line is a single plain text token.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a keyword rule.
+    ///
+    /// If the regex has capture groups, the last participating group is the token,
+    /// so `"(//.*)$"` and `"fn\\s+(\\w+)"` work as they do in Synoptic.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `exp` is not a valid regex. Use [`Lexer::try_keyword`] for user input.
+    pub fn keyword(&mut self, kind: &str, exp: &str) {
+        self.try_keyword(kind, exp).expect("Invalid keyword regex");
+    }
+
+    /// Registers a keyword rule that only applies inside the regions of the last bounded rule,
+    /// e.g. the escape sequences of a string. The region's kind covers the text in between.
+    /// Where it matches at the same offset as an interpolation, it wins, so that `{{` can escape a `{`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `exp` is not a valid regex, or if no bounded rule has been registered yet.
+    pub fn region_keyword(&mut self, kind: &str, exp: &str) {
+        self.try_region_keyword(kind, exp).expect("Invalid keyword regex");
+    }
+
+    /// Registers a bounded rule, e.g. `/*` ... `*/`.
+    ///
+    /// `end` may contain back-references to the groups of `start`, e.g. `<<(\w+)` ... `^\1$`.
+    /// If the last group of `start` is empty and at the end of the line, as in `<<(\w+).*()$`,
+    /// the region starts on the next line.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` or `end` is not a valid regex.
+    pub fn bounded(&mut self, kind: &str, start: &str, end: &str, escapable: bool) {
+        self.try_bounded(kind, start, end, escapable).expect("Invalid bounded regex");
+    }
+
+    /// Registers a bounded rule with interpolation, e.g. `` ` `` ... `` ` `` with `${` ... `}`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the regexes is invalid.
+    pub fn bounded_interp(
+        &mut self,
+        kind: &str,
+        start: &str,
+        end: &str,
+        i_start: &str,
+        i_end: &str,
+        escapable: bool,
+    ) {
+        self.try_bounded_interp(kind, start, end, i_start, i_end, escapable)
+            .expect("Invalid bounded regex");
+    }
+
+    /// Registers a region whose contents are tokenized by another lexer, e.g. a `<script>`
+    /// element or the diff at the end of a commit message. `inner` builds that lexer
+    /// when the region is first entered. The last groups of `start` and `end` are tokens
+    /// of the given `kind`. `end` may contain back-references like in [`Lexer::bounded`],
+    /// and a region that starts with an empty group at the end of a line starts on the next one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` or `end` is not a valid regex.
+    pub fn nested(
+        &mut self,
+        kind: &str,
+        start: &str,
+        end: &str,
+        inner: impl Fn() -> Lexer + Send + Sync + 'static,
+    ) {
+        self.try_nested(kind, start, end, inner).expect("Invalid nested regex");
+    }
+
+    /// Registers a nested region like [`Lexer::nested`] that only starts inside the regions of the last
+    /// bounded rule, e.g. an arithmetic expansion in a string. The outer region resumes after it.
+    /// Where it starts at the same offset as an interpolation, it wins.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` or `end` is not a valid regex, or if no bounded rule has been registered yet.
+    pub fn region_nested(
+        &mut self,
+        kind: &str,
+        start: &str,
+        end: &str,
+        inner: impl Fn() -> Lexer + Send + Sync + 'static,
+    ) {
+        self.try_region_nested(kind, start, end, inner).expect("Invalid nested regex");
+    }
+
+    /// Like [`Lexer::nested`], but `end` is ignored where the inner lexer is inside a region of its own.
+    /// A JSX tag uses this, so that the `>` of an arrow function in one of its attributes doesn't end it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` or `end` is not a valid regex.
+    pub fn nested_balanced(
+        &mut self,
+        kind: &str,
+        start: &str,
+        end: &str,
+        inner: impl Fn() -> Lexer + Send + Sync + 'static,
+    ) {
+        self.try_nested(kind, start, end, inner).expect("Invalid nested regex");
+        self.regions.last_mut().unwrap().inner.as_mut().unwrap().balanced = true;
+    }
+
+    /// Fallible version of [`Lexer::keyword`].
+    pub fn try_keyword(&mut self, kind: &str, exp: &str) -> Result<(), regex::Error> {
+        let regex = Regex::new(exp)?;
+        self.order.push(Action::Keyword(self.keywords.len()));
+        self.keywords.push(KeywordRule { kind: kind.to_string(), regex });
+        Ok(())
+    }
+
+    /// Fallible version of [`Lexer::region_keyword`].
+    pub fn try_region_keyword(&mut self, kind: &str, exp: &str) -> Result<(), regex::Error> {
+        let regex = Regex::new(exp)?;
+        let keyword = self.keywords.len();
+        self.last_bounded().expect("A region keyword needs a bounded rule to apply to").keywords.push(keyword);
+        self.keywords.push(KeywordRule { kind: kind.to_string(), regex });
+        Ok(())
+    }
+
+    /// Fallible version of [`Lexer::bounded`].
+    pub fn try_bounded(
+        &mut self,
+        kind: &str,
+        start: &str,
+        end: &str,
+        escapable: bool,
+    ) -> Result<(), regex::Error> {
+        self.push_region(kind, start, end, escapable, None)
+    }
+
+    /// Fallible version of [`Lexer::bounded_interp`].
+    pub fn try_bounded_interp(
+        &mut self,
+        kind: &str,
+        start: &str,
+        end: &str,
+        i_start: &str,
+        i_end: &str,
+        escapable: bool,
+    ) -> Result<(), regex::Error> {
+        let interp = (Regex::new(i_start)?, Regex::new(i_end)?);
+        self.push_region(kind, start, end, escapable, Some(interp))
+    }
+
+    /// Fallible version of [`Lexer::region_nested`].
+    pub fn try_region_nested(
+        &mut self,
+        kind: &str,
+        start: &str,
+        end: &str,
+        inner: impl Fn() -> Lexer + Send + Sync + 'static,
+    ) -> Result<(), regex::Error> {
+        assert!(self.last_bounded().is_some(), "A region rule needs a bounded rule to apply to");
+        self.try_nested(kind, start, end, inner)?;
+        // It doesn't start outside of the region, so it's left out of the definition order.
+        self.order.pop();
+        let region = self.regions.len() - 1;
+        self.last_bounded().unwrap().regions.push(region);
+        Ok(())
+    }
+
+    /// Returns the last bounded rule that starts anywhere, i.e. not only inside another region.
+    fn last_bounded(&mut self) -> Option<&mut RegionRule> {
+        let r = self.order.iter().rev().find_map(|action| match *action {
+            Action::RegionStart(r) => Some(r),
+            _ => None,
+        })?;
+        Some(&mut self.regions[r])
+    }
+
+    /// Fallible version of [`Lexer::nested`].
+    pub fn try_nested(
+        &mut self,
+        kind: &str,
+        start: &str,
+        end: &str,
+        inner: impl Fn() -> Lexer + Send + Sync + 'static,
+    ) -> Result<(), regex::Error> {
+        self.push_region(kind, start, end, false, None)?;
+        self.regions.last_mut().unwrap().inner =
+            Some(InnerLexer { lexer: Arc::default(), build: Arc::new(inner), balanced: false });
+        Ok(())
+    }
+
+    fn push_region(
+        &mut self,
+        kind: &str,
+        start: &str,
+        end: &str,
+        escapable: bool,
+        interp: Option<(Regex, Regex)>,
+    ) -> Result<(), regex::Error> {
+        let start = Regex::new(start)?;
+        let end = if textmate::has_back_references(end) {
+            // Validate it with empty groups, to fail here rather than on first use.
+            Regex::new(&Self::strip_back_references(end))?;
+            EndPattern::Dynamic(end.to_string())
+        } else {
+            EndPattern::Static(Regex::new(end)?)
+        };
+        self.order.push(Action::RegionStart(self.regions.len()));
+        self.regions.push(RegionRule {
+            kind: kind.to_string(),
+            start,
+            end,
+            escapable,
+            interp,
+            inner: None,
+            keywords: Vec::new(),
+            regions: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Removes the `\1`-style back-references from `pattern`.
+    fn strip_back_references(pattern: &str) -> String {
+        let mut out = String::with_capacity(pattern.len());
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some(d) if d.is_ascii_digit() => {}
+                next => {
+                    out.push(c);
+                    out.extend(next);
+                }
+            }
+        }
+        out
+    }
+
+    /// Sets the character that escapes delimiters in escapable regions, e.g. `` ` `` in PowerShell.
+    /// The default is a backslash.
+    pub fn set_escape_char(&mut self, escape: u8) {
+        self.escape = Some(escape);
+    }
+
+    /// Returns true if no rules have been registered.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Tokenizes a single line starting in the given state.
+    ///
+    /// Returns the tokens, which cover the entire line without gaps,
+    /// and the state at the end of the line.
+    pub fn tokenize_line(&self, line: &str, state: &LexerState) -> (Vec<TokenInfo>, LexerState) {
+        let mut state = state.clone();
+        let mut tokens = Vec::new();
+
+        if line.is_empty() {
+            return (tokens, state);
+        }
+
+        // Cached next match per rule, as `Some((start, end))`, or `None` if there's none left.
+        // Index layout: keywords, then region starts, then region ends, interp starts, interp ends.
+        let keyword_count = self.keywords.len();
+        let region_count = self.regions.len();
+        let mut cache: Vec<Option<Option<(usize, usize)>>> =
+            vec![None; keyword_count + region_count * 4];
+
+        let mut pos = 0;
+        // Where the currently open region token started on this line.
+        let mut region_beg = 0;
+        // The compiled end of the innermost region with back-references, if any.
+        let mut dynamic_end: Option<(Arc<str>, Option<Regex>)> = None;
+
+        // Once `pos` reaches the end of the line, there's one more pass for empty matches there.
+        let mut at_end = false;
+
+        while !at_end {
+            at_end = pos >= line.len();
+
+            // Inside a nested region, the inner lexer gets everything up to the region's end.
+            if let Some(Frame::Region(r)) = state.top()
+                && let Some(inner) = &self.regions[r as usize].inner
+            {
+                let r = r as usize;
+                let rule = &self.regions[r];
+                let end = match &rule.end {
+                    EndPattern::Static(regex) => Some(regex),
+                    EndPattern::Dynamic(_) => Self::dynamic_end(&state, &mut dynamic_end),
+                };
+                let slot = keyword_count + region_count + r;
+                let inner_state = state.inner.take().map(|s| *s).unwrap_or_default();
+                let mut search = pos;
+                let (m, inner_tokens, inner_state) = loop {
+                    let m = end
+                        .filter(|_| search <= line.len())
+                        .and_then(|end| self.next_match(&mut cache[slot], end, line, search, false));
+                    let stop = m.map_or(line.len(), |(beg, _)| beg);
+                    let (inner_tokens, next) = inner.get().tokenize_line(&line[pos..stop], &inner_state);
+                    if m.is_none() || !inner.balanced || next.is_default() {
+                        break (m, inner_tokens, next);
+                    }
+                    // The end is inside a string or the like of the inner lexer. Try the next one.
+                    search = textmate::next_char_boundary(line, stop);
+                };
+                for t in &inner_tokens {
+                    Self::push_token(&mut tokens, line, pos + t.start_offset, pos + t.end_offset, t.kind.as_deref());
+                }
+
+                let Some((beg, end)) = m else {
+                    state.inner = Some(Box::new(inner_state));
+                    pos = line.len();
+                    break;
+                };
+                Self::push_token(&mut tokens, line, beg, end, Some(&rule.kind));
+                state.pop();
+                if matches!(rule.end, EndPattern::Dynamic(_)) {
+                    state.ends.pop();
+                }
+                // The region this one started in, if any, resumes.
+                region_beg = end;
+                pos = end;
+                continue;
+            }
+
+            let in_region = matches!(state.top(), Some(Frame::Region(_)));
+            let mut best: Option<(usize, usize, Action)> = None;
+
+            let consider = |best: &mut Option<(usize, usize, Action)>, m, action| {
+                if let Some((beg, end)) = m
+                    && best.is_none_or(|(b, _, _)| beg < b)
+                {
+                    *best = Some((beg, end, action));
+                }
+            };
+
+            match state.top() {
+                Some(Frame::Region(r)) => {
+                    let r = r as usize;
+                    let rule = &self.regions[r];
+                    let slot = keyword_count + region_count + r;
+                    let end = match &rule.end {
+                        EndPattern::Static(regex) => Some(regex),
+                        EndPattern::Dynamic(_) => Self::dynamic_end(&state, &mut dynamic_end),
+                    };
+                    if let Some(end) = end {
+                        let m = self.next_match(&mut cache[slot], end, line, pos, rule.escapable);
+                        consider(&mut best, m, Action::RegionEnd(r));
+                    }
+                    for &k in &rule.keywords {
+                        let m = self.next_match(&mut cache[k], &self.keywords[k].regex, line, pos, false);
+                        consider(&mut best, m, Action::RegionKeyword(k));
+                    }
+                    for &sub in &rule.regions {
+                        let slot = keyword_count + sub;
+                        let m = self.next_match(&mut cache[slot], &self.regions[sub].start, line, pos, false);
+                        consider(&mut best, m, Action::RegionStart(sub));
+                    }
+                    if let Some((i_start, _)) = &rule.interp {
+                        let slot = keyword_count + region_count * 2 + r;
+                        let m = self.next_match(&mut cache[slot], i_start, line, pos, rule.escapable);
+                        consider(&mut best, m, Action::InterpStart(r));
+                    }
+                }
+                top => {
+                    if let Some(Frame::Interp(r)) = top {
+                        let r = r as usize;
+                        let (_, i_end) = self.regions[r].interp.as_ref().unwrap();
+                        let slot = keyword_count + region_count * 3 + r;
+                        let m = self.next_match(&mut cache[slot], i_end, line, pos, false);
+                        consider(&mut best, m, Action::InterpEnd(r));
+                    }
+                    // Rules are visited in definition order, and `consider` only replaces the
+                    // current best on a strictly earlier start. Earlier rules thus win ties.
+                    for &action in &self.order {
+                        let m = match action {
+                            Action::Keyword(k) => {
+                                self.next_match(&mut cache[k], &self.keywords[k].regex, line, pos, false)
+                            }
+                            Action::RegionStart(r) => {
+                                let slot = keyword_count + r;
+                                self.next_match(&mut cache[slot], &self.regions[r].start, line, pos, false)
+                            }
+                            _ => unreachable!(),
+                        };
+                        consider(&mut best, m, action);
+                    }
+                }
+            }
+
+            let Some((beg, end, action)) = best else {
+                // Nothing else matches on this line.
+                if in_region {
+                    Self::push_token(&mut tokens, line, region_beg, line.len(), self.region_kind(&state));
+                } else {
+                    Self::push_token(&mut tokens, line, pos, line.len(), None);
+                }
+                pos = line.len();
+                break;
+            };
+
+            match action {
+                Action::Keyword(k) => {
+                    Self::push_token(&mut tokens, line, pos, beg, None);
+                    Self::push_token(&mut tokens, line, beg, end, Some(&self.keywords[k].kind));
+                    pos = end;
+                }
+                Action::RegionKeyword(k) => {
+                    Self::push_token(&mut tokens, line, region_beg, beg, self.region_kind(&state));
+                    Self::push_token(&mut tokens, line, beg, end, Some(&self.keywords[k].kind));
+                    region_beg = end;
+                    pos = end;
+                }
+                Action::RegionStart(r) => {
+                    // Inside a region, this is one of its `regions`, which interrupts its token.
+                    let (text_beg, text_kind) =
+                        if in_region { (region_beg, self.region_kind(&state)) } else { (pos, None) };
+                    if state.push(Frame::Region(r as u16)) {
+                        if let EndPattern::Dynamic(pattern) = &self.regions[r].end {
+                            let caps = self.start_captures(r, line, beg, end);
+                            state.ends.push(textmate::resolve_back_references(pattern, &caps).into());
+                            // The cached end match belongs to the previous region of this rule, if any.
+                            cache[keyword_count + region_count + r] = None;
+                        }
+                        Self::push_token(&mut tokens, line, text_beg, beg, text_kind);
+                        region_beg = beg;
+                        if self.regions[r].inner.is_some() {
+                            // The start delimiter is a token of its own, the inner lexer starts afresh.
+                            Self::push_token(&mut tokens, line, beg, end, Some(&self.regions[r].kind));
+                            state.inner = Some(Box::default());
+                        }
+                    } else {
+                        Self::push_token(&mut tokens, line, text_beg, end, text_kind);
+                        region_beg = end;
+                    }
+                    pos = end;
+                }
+                Action::RegionEnd(r) => {
+                    Self::push_token(&mut tokens, line, region_beg, end, Some(&self.regions[r].kind));
+                    state.pop();
+                    if matches!(self.regions[r].end, EndPattern::Dynamic(_)) {
+                        state.ends.pop();
+                    }
+                    // The region this one started in resumes.
+                    region_beg = end;
+                    pos = end;
+                }
+                Action::InterpStart(r) => {
+                    // Like Synoptic, the interpolation opener still belongs to the string.
+                    Self::push_token(&mut tokens, line, region_beg, end, Some(&self.regions[r].kind));
+                    state.pop();
+                    state.push(Frame::Interp(r as u16));
+                    pos = end;
+                }
+                Action::InterpEnd(r) => {
+                    Self::push_token(&mut tokens, line, pos, beg, None);
+                    state.pop();
+                    state.push(Frame::Region(r as u16));
+                    // ...and the closer resumes the string.
+                    region_beg = beg;
+                    pos = end;
+                }
+            }
+        }
+
+        // A region that started on this line and is still open: its token runs to the end.
+        if pos >= line.len()
+            && matches!(state.top(), Some(Frame::Region(r)) if self.regions[r as usize].inner.is_none())
+            && tokens.last().is_none_or(|t| t.end_offset < line.len())
+        {
+            Self::push_token(&mut tokens, line, region_beg, line.len(), self.region_kind(&state));
+        }
+
+        (tokens, state)
+    }
+
+    fn region_kind(&self, state: &LexerState) -> Option<&str> {
+        match state.top() {
+            Some(Frame::Region(r)) => Some(&self.regions[r as usize].kind),
+            _ => None,
+        }
+    }
+
+    /// Returns the `end` regex of the innermost region with back-references,
+    /// compiling it unless `compiled` already holds it.
+    fn dynamic_end<'a>(
+        state: &LexerState,
+        compiled: &'a mut Option<(Arc<str>, Option<Regex>)>,
+    ) -> Option<&'a Regex> {
+        let pattern = state.ends.last()?;
+        if compiled.as_ref().is_none_or(|(p, _)| p != pattern) {
+            *compiled = Some((pattern.clone(), Regex::new(pattern).ok()));
+        }
+        compiled.as_ref()?.1.as_ref()
+    }
+
+    /// Recovers the captures of the start of region `r`, whose last group matched `line[beg..end]`.
+    fn start_captures<'a>(&self, r: usize, line: &'a str, beg: usize, end: usize) -> Captures<'a> {
+        let regex = &self.regions[r].start;
+        // The match itself may start before `beg`, in its leading context.
+        (0..=beg)
+            .rev()
+            .filter(|&i| line.is_char_boundary(i))
+            .filter_map(|i| regex.captures_at(line, i))
+            .find(|caps| Self::last_group(caps) == (beg, end))
+            .unwrap()
+    }
+
+    /// Finds the next match of `regex` at or after `pos`, reusing the cached result if possible.
+    /// Returns the byte range of the last participating capture group.
+    fn next_match(
+        &self,
+        cache: &mut Option<Option<(usize, usize)>>,
+        regex: &Regex,
+        line: &str,
+        pos: usize,
+        escapable: bool,
+    ) -> Option<(usize, usize)> {
+        if let Some(cached) = *cache {
+            match cached {
+                None => return None,
+                Some((beg, _)) if beg >= pos => return cached,
+                _ => {}
+            }
+        }
+
+        let mut search = pos;
+        let result = loop {
+            let Some(caps) = regex.captures_at(line, search) else {
+                break None;
+            };
+            let (beg, end) = Self::last_group(&caps);
+            if beg < pos || (beg == end && end < line.len()) || (escapable && self.is_escaped(line, beg)) {
+                // Skip empty matches, escaped delimiters and matches that
+                // only start before `pos` because of their leading context.
+                // Empty matches at the end of the line are fine, since they can't stall the
+                // tokenizer. They let a region start on the next line, as heredocs do.
+                search = textmate::next_char_boundary(line, caps.get(0).unwrap().start().max(search));
+                if search > line.len() {
+                    break None;
+                }
+                continue;
+            }
+            break Some((beg, end));
+        };
+
+        *cache = Some(result);
+        result
+    }
+
+    fn last_group(caps: &Captures) -> (usize, usize) {
+        let m = caps.iter().flatten().last().unwrap();
+        (m.start(), m.end())
+    }
+
+    fn is_escaped(&self, line: &str, offset: usize) -> bool {
+        let escape = self.escape.unwrap_or(b'\\');
+        let escapes = line.as_bytes()[..offset].iter().rev().take_while(|&&b| b == escape).count();
+        escapes % 2 != 0
+    }
+
+    /// Appends a token for `line[beg..end]`, merging adjacent plain text tokens.
+    fn push_token(tokens: &mut Vec<TokenInfo>, line: &str, beg: usize, end: usize, kind: Option<&str>) {
+        if beg >= end {
+            return;
+        }
+
+        let text = &line[beg..end];
+
+        if kind.is_none()
+            && let Some(last) = tokens.last_mut()
+            && last.kind.is_none()
+            && last.end_offset == beg
+        {
+            last.text.push_str(text);
+            last.end_offset = end;
+            return;
+        }
+
+        tokens.push(match kind {
+            Some(kind) => TokenInfo::highlighted(text.to_string(), kind.to_string(), beg, end),
+            None => TokenInfo::plain_text(text.to_string(), beg, end),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c_like() -> Lexer {
+        let mut lexer = Lexer::new();
+        lexer.keyword("comment", r"//.*$");
+        lexer.bounded("comment", r"/\*", r"\*/", false);
+        lexer.bounded("string", "\"", "\"", true);
+        lexer.bounded_interp("string", "`", "`", r"\$\{", r"\}", true);
+        lexer.keyword("keyword", r"\b(if|else|return)\b");
+        lexer
+    }
+
+    fn kinds(tokens: &[TokenInfo]) -> Vec<(&str, Option<&str>)> {
+        tokens.iter().map(|t| (t.text.as_str(), t.kind.as_deref())).collect()
+    }
+
+    #[test]
+    fn test_single_line() {
+        let lexer = c_like();
+        let (tokens, state) = lexer.tokenize_line("if x return \"a\" // c", &LexerState::default());
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                ("if", Some("keyword")),
+                (" x ", None),
+                ("return", Some("keyword")),
+                (" ", None),
+                ("\"a\"", Some("string")),
+                (" ", None),
+                ("// c", Some("comment")),
+            ]
+        );
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_tokens_cover_line() {
+        let lexer = c_like();
+        let line = "a /* b */ \"c\\\"d\" `e${if}f` ü";
+        let (tokens, _) = lexer.tokenize_line(line, &LexerState::default());
+        let mut offset = 0;
+        for token in &tokens {
+            assert_eq!(token.start_offset, offset);
+            assert_eq!(&line[token.start_offset..token.end_offset], token.text);
+            offset = token.end_offset;
+        }
+        assert_eq!(offset, line.len());
+    }
+
+    #[test]
+    fn test_block_comment_across_lines() {
+        let lexer = c_like();
+        let (tokens, state) = lexer.tokenize_line("x /* open", &LexerState::default());
+        assert_eq!(kinds(&tokens), vec![("x ", None), ("/* open", Some("comment"))]);
+        assert!(!state.is_default());
+
+        let (tokens, state) = lexer.tokenize_line("if \"still\" inside", &state);
+        assert_eq!(kinds(&tokens), vec![("if \"still\" inside", Some("comment"))]);
+
+        let (tokens, state) = lexer.tokenize_line("done */ if", &state);
+        assert_eq!(kinds(&tokens), vec![("done */", Some("comment")), (" ", None), ("if", Some("keyword"))]);
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_escaped_delimiter() {
+        let lexer = c_like();
+        let (tokens, state) = lexer.tokenize_line(r#""a\"b" if"#, &LexerState::default());
+        assert_eq!(kinds(&tokens), vec![(r#""a\"b""#, Some("string")), (" ", None), ("if", Some("keyword"))]);
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_interpolation_across_lines() {
+        let lexer = c_like();
+        let (tokens, state) = lexer.tokenize_line("`a ${", &LexerState::default());
+        assert_eq!(kinds(&tokens), vec![("`a ${", Some("string"))]);
+
+        let (tokens, state) = lexer.tokenize_line("if } b", &state);
+        assert_eq!(kinds(&tokens), vec![("if", Some("keyword")), (" ", None), ("} b", Some("string"))]);
+
+        let (_, state) = lexer.tokenize_line("`", &state);
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_back_reference_in_end() {
+        let mut lexer = c_like();
+        lexer.bounded("string", r"<<([A-Za-z_]\w*)", r"^\1$", false);
+
+        let (tokens, state) = lexer.tokenize_line("cat <<EOF", &LexerState::default());
+        assert_eq!(kinds(&tokens), vec![("cat <<", None), ("EOF", Some("string"))]);
+
+        // Only the delimiter of the open region ends it.
+        let (tokens, state) = lexer.tokenize_line("END", &state);
+        assert_eq!(kinds(&tokens), vec![("END", Some("string"))]);
+        let (_, other) = lexer.tokenize_line("cat <<END", &LexerState::default());
+        assert_ne!(state, other);
+
+        let (tokens, state) = lexer.tokenize_line("EOF", &state);
+        assert_eq!(kinds(&tokens), vec![("EOF", Some("string"))]);
+        assert!(state.is_default());
+
+        // With an empty group at the end, the rest of the start line is tokenized as usual.
+        let mut lexer = c_like();
+        lexer.bounded("string", r"<<([A-Za-z_]\w*).*?()$", r"^\1$", false);
+        let (tokens, state) = lexer.tokenize_line("cat <<EOF if", &LexerState::default());
+        assert_eq!(kinds(&tokens), vec![("cat <<EOF ", None), ("if", Some("keyword"))]);
+        let (tokens, state) = lexer.tokenize_line("if", &state);
+        assert_eq!(kinds(&tokens), vec![("if", Some("string"))]);
+        let (_, state) = lexer.tokenize_line("EOF", &state);
+        assert!(state.is_default());
+
+        assert!(Lexer::new().try_bounded("string", "<<(a)", r"\1(", false).is_err());
+    }
+
+    #[test]
+    fn test_nested_lexer() {
+        let mut inner = Lexer::new();
+        inner.keyword("number", r"\d+");
+        inner.bounded("comment", r"/\*", r"\*/", false);
+        let mut lexer = c_like();
+        lexer.nested("keyword", "<x>", "</x>", move || inner.clone());
+
+        let (tokens, state) = lexer.tokenize_line("if <x>1 /* a", &LexerState::default());
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                ("if", Some("keyword")),
+                (" ", None),
+                ("<x>", Some("keyword")),
+                ("1", Some("number")),
+                (" ", None),
+                ("/* a", Some("comment")),
+            ]
+        );
+
+        // The inner state is part of the outer one, and the end cuts the inner region short.
+        let (_, other) = lexer.tokenize_line("<x>1", &LexerState::default());
+        assert_ne!(state, other);
+        let (tokens, state) = lexer.tokenize_line("if */ 2 </x> if", &state);
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                ("if */", Some("comment")),
+                (" ", None),
+                ("2", Some("number")),
+                (" ", None),
+                ("</x>", Some("keyword")),
+                (" ", None),
+                ("if", Some("keyword")),
+            ]
+        );
+        assert!(state.is_default());
+
+        // A region that starts with an empty group at the end of the line starts on the next one.
+        let mut inner = Lexer::new();
+        inner.keyword("number", r"\d+");
+        let mut lexer = Lexer::new();
+        lexer.keyword("keyword", r"^if");
+        lexer.nested("keyword", r"^if.*?()$", r"\b\B", move || inner.clone());
+        let (tokens, state) = lexer.tokenize_line("if 1", &LexerState::default());
+        assert_eq!(kinds(&tokens), vec![("if", Some("keyword")), (" 1", None)]);
+        let (tokens, _) = lexer.tokenize_line("if 1", &state);
+        assert_eq!(kinds(&tokens), vec![("if ", None), ("1", Some("number"))]);
+    }
+
+    #[test]
+    fn test_region_keywords() {
+        let mut lexer = c_like();
+        lexer.bounded("string", "'", "'", true);
+        lexer.region_keyword("string.escape", r"\\.");
+
+        // They apply to the regions of their own rule only, and can't end it.
+        let (tokens, state) = lexer.tokenize_line(r#"'a\'b' "\n" '\"c"#, &LexerState::default());
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                ("'a", Some("string")),
+                ("\\'", Some("string.escape")),
+                ("b'", Some("string")),
+                (" ", None),
+                ("\"\\n\"", Some("string")),
+                (" ", None),
+                ("'", Some("string")),
+                ("\\\"", Some("string.escape")),
+                ("c", Some("string")),
+            ]
+        );
+        let (tokens, state) = lexer.tokenize_line(r"\t' \t", &state);
+        assert_eq!(
+            kinds(&tokens),
+            vec![("\\t", Some("string.escape")), ("'", Some("string")), (" \\t", None)]
+        );
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_region_nested_lexer() {
+        let mut inner = Lexer::new();
+        inner.keyword("number", r"\d+");
+        let mut lexer = c_like();
+        lexer.bounded_interp("string", "'", "'", r"\{", r"\}", true);
+        lexer.region_nested("keyword", r"\{\{", r"\}\}", move || inner.clone());
+        lexer.region_keyword("string.escape", r"\\.");
+
+        // It wins over the interpolation, the string resumes after it, and it doesn't start outside.
+        let (tokens, state) = lexer.tokenize_line(r"'a {{1}} \n {if}' {{2", &LexerState::default());
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                ("'a ", Some("string")),
+                ("{{", Some("keyword")),
+                ("1", Some("number")),
+                ("}}", Some("keyword")),
+                (" ", Some("string")),
+                ("\\n", Some("string.escape")),
+                (" {", Some("string")),
+                ("if", Some("keyword")),
+                ("}'", Some("string")),
+                (" {{2", None),
+            ]
+        );
+        assert!(state.is_default());
+
+        // Across lines, the string resumes on the line the nested region ends on.
+        let (_, state) = lexer.tokenize_line("'{{1", &LexerState::default());
+        let (tokens, state) = lexer.tokenize_line("2}} a' if", &state);
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                ("2", Some("number")),
+                ("}}", Some("keyword")),
+                (" a'", Some("string")),
+                (" ", None),
+                ("if", Some("keyword")),
+            ]
+        );
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_balanced_nested_lexer() {
+        let mut inner = Lexer::new();
+        inner.bounded("string", "\"", "\"", false);
+        let mut lexer = Lexer::new();
+        lexer.nested_balanced("keyword", "<", ">", move || inner.clone());
+
+        // A `>` inside the inner lexer's string doesn't end the region, even on the next line.
+        let (tokens, state) = lexer.tokenize_line(r#"<"a>b" "c>"#, &LexerState::default());
+        assert_eq!(
+            kinds(&tokens),
+            vec![("<", Some("keyword")), ("\"a>b\"", Some("string")), (" ", None), ("\"c>", Some("string"))]
+        );
+        let (tokens, state) = lexer.tokenize_line(r#"" > x"#, &state);
+        assert_eq!(kinds(&tokens), vec![("\"", Some("string")), (" ", None), (">", Some("keyword")), (" x", None)]);
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_state_equality() {
+        let lexer = c_like();
+        let (_, a) = lexer.tokenize_line("/* a", &LexerState::default());
+        let (_, b) = lexer.tokenize_line("b /* c", &LexerState::default());
+        let (_, c) = lexer.tokenize_line("\"d", &LexerState::default());
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+}
@@ -3,28 +3,40 @@
 
 //! Syntax highlighting infrastructure for the Edit text editor.
 //!
-//! This module provides syntax highlighting capabilities using the Synoptic library.
+//! This module provides syntax highlighting capabilities using a stateful, regex-based lexer.
 //! It includes language detection, highlighter management, and token processing.
 
 pub mod language;
 pub mod highlighter;
+pub mod lexer;
 pub mod performance;
 pub mod color_mapper;
 pub mod render_bridge;
+pub mod textmate;
+pub mod token_cache;
+pub mod user_languages;
 
 #[cfg(test)]
 mod performance_test;
 
 pub use language::{Language, LanguageConfig, LanguageDetector};
-pub use highlighter::{SyntaxHighlighter, HighlightingService, TokenInfo, HighlightingState, global_highlighting_service};
+pub use highlighter::{
+    SyntaxHighlighter, HighlightingService, TokenInfo, HighlightingState, HighlightingMetrics, HighlightingPolicy,
+    TokenSource, ThreadedHighlighter, global_highlighting_service
+};
+pub use lexer::{Lexer, LexerState};
+pub use token_cache::{TokenCache, DEFAULT_TOKEN_CACHE_LIMIT};
 pub use performance::{
     PerformanceBaseline, PerformanceMeasurement, FileSizeCategory, LineLengthCategory,
     FileLoadingMetrics, MemoryMetrics, HighlightingPerformanceMetrics, SystemResourceMetrics,
     create_test_session, run_baseline_test
 };
-pub use color_mapper::{ColorMapper, global_color_mapper, global_color_mapper_mut};
+pub use color_mapper::{
+    ColorMapper, Theme, ThemeColor, TokenStyle, ResolvedStyle, global_color_mapper,
+    global_color_mapper_mut
+};
 pub use render_bridge::{
     register_buffer_highlighting, unregister_buffer_highlighting, get_line_tokens,
-    get_line_tokens_with_viewport, process_background_highlighting, has_background_work,
-    update_viewport_tracking
+    inspect_line_tokens, get_line_tokens_with_viewport, process_background_highlighting, has_background_work,
+    update_viewport_tracking, apply_highlighting_results, has_pending_highlighting
 };
@@ -198,6 +198,397 @@ mod tests {
                     "        return transformed",
                 ],
             },
+            TestCase {
+                language: Language::C,
+                filename: "test.c",
+                lines: vec![
+                    "#include <stdio.h>",
+                    "#include <stdlib.h>",
+                    "#include \"list.h\"",
+                    "",
+                    "#define MAX_ITEMS 128",
+                    "#define SQUARE(x) ((x) * (x))",
+                    "",
+                    "/* A singly linked list of integers. */",
+                    "typedef struct list {",
+                    "    int32_t value;",
+                    "    struct list *next;",
+                    "} list_t;",
+                    "",
+                    "static size_t list_length(const list_t *head) {",
+                    "    size_t n = 0;",
+                    "    while (head != NULL) { n++; head = head->next; }",
+                    "    return n;",
+                    "}",
+                    "",
+                    "int main(int argc, char *argv[]) {",
+                    "    char sep = argc > 1 ? argv[1][0] : '\\n';",
+                    "    unsigned long mask = 0xFFFFul & ~(1UL << 3);",
+                    "    double ratio = 1.5e-3 * SQUARE(2.0f);",
+                    "#ifdef DEBUG",
+                    "    fprintf(stderr, \"mask=%lx ratio=%f%c\", mask, ratio, sep); // debug output",
+                    "#endif",
+                    "    return list_length(NULL) == 0 ? EXIT_SUCCESS : EXIT_FAILURE;",
+                    "}",
+                ],
+            },
+            TestCase {
+                language: Language::Cpp,
+                filename: "test.cpp",
+                lines: vec![
+                    "#include <memory>",
+                    "#include <string_view>",
+                    "#include <unordered_map>",
+                    "",
+                    "namespace cache {",
+                    "",
+                    "template <typename Key, typename Value, std::size_t Capacity = 64>",
+                    "class LruCache final {",
+                    "public:",
+                    "    [[nodiscard]] std::optional<Value> get(const Key& key) const noexcept {",
+                    "        if (auto it = entries_.find(key); it != entries_.end()) {",
+                    "            return it->second;",
+                    "        }",
+                    "        return std::nullopt;",
+                    "    }",
+                    "",
+                    "    template <typename... Args>",
+                    "    void emplace(const Key& key, Args&&... args) {",
+                    "        entries_.try_emplace(key, std::forward<Args>(args)...);",
+                    "    }",
+                    "",
+                    "private:",
+                    "    std::unordered_map<Key, Value> entries_;",
+                    "};",
+                    "",
+                    "}  // namespace cache",
+                    "",
+                    "constexpr auto kQuery = R\"sql(SELECT * FROM \"users\" WHERE id = ?)sql\";",
+                    "static_assert(sizeof(char32_t) == 4, \"unexpected size\");",
+                    "auto timeout = 1'500ms + std::chrono::seconds{2};",
+                ],
+            },
+            TestCase {
+                language: Language::CSharp,
+                filename: "test.cs",
+                lines: vec![
+                    "#nullable enable",
+                    "using System;",
+                    "using System.Collections.Generic;",
+                    "using System.Threading.Tasks;",
+                    "",
+                    "namespace Inventory.Services;",
+                    "",
+                    "[Serializable]",
+                    "public sealed record Item(string Name, decimal Price, int Quantity);",
+                    "",
+                    "public class InventoryService<TKey> where TKey : notnull",
+                    "{",
+                    "    private readonly Dictionary<TKey, Item> _items = new();",
+                    "",
+                    "    public async Task<Item?> FindAsync(TKey key)",
+                    "    {",
+                    "        await Task.Yield();",
+                    "        return _items.TryGetValue(key, out var item) ? item : null;",
+                    "    }",
+                    "",
+                    "    public string Describe(Item item)",
+                    "    {",
+                    "        var path = @\"C:\\Data\\\"\"inventory\"\".json\";",
+                    "        var summary = $\"{item.Name}: {item.Quantity} x {item.Price:C2}\";",
+                    "        var json = \"\"\"",
+                    "            { \"name\": \"widget\", \"price\": 9.99 }",
+                    "            \"\"\";",
+                    "        return item.Quantity > 0 ? summary : $@\"{path}\\missing\";",
+                    "    }",
+                    "}",
+                ],
+            },
+            TestCase {
+                language: Language::Java,
+                filename: "Test.java",
+                lines: vec![
+                    "package com.example.orders;",
+                    "",
+                    "import java.util.List;",
+                    "import java.util.Map;",
+                    "import java.util.stream.Collectors;",
+                    "",
+                    "/**",
+                    " * Groups orders by their customer.",
+                    " */",
+                    "@Service",
+                    "public class OrderService<T extends Order & Comparable<T>> {",
+                    "    private static final long TIMEOUT_MS = 30_000L;",
+                    "    private final Map<String, List<T>> byCustomer = new HashMap<>();",
+                    "",
+                    "    @Override",
+                    "    @SuppressWarnings(\"unchecked\")",
+                    "    public Map<String, Long> countByCustomer(List<T> orders, char separator) {",
+                    "        if (orders == null || separator == '\\t') {",
+                    "            throw new IllegalArgumentException(\"orders must not be null\");",
+                    "        }",
+                    "        String report = \"\"\"",
+                    "            Orders: %d",
+                    "            \"\"\".formatted(orders.size());",
+                    "        return orders.stream()",
+                    "            .filter(order -> order.total() > 0.5d)",
+                    "            .collect(Collectors.groupingBy(Order::customer, Collectors.counting()));",
+                    "    }",
+                    "}",
+                ],
+            },
+            TestCase {
+                language: Language::Go,
+                filename: "test.go",
+                lines: vec![
+                    "package main",
+                    "",
+                    "import (",
+                    "\t\"context\"",
+                    "\t\"fmt\"",
+                    "\t\"time\"",
+                    ")",
+                    "",
+                    "// Cache stores values of any comparable key type.",
+                    "type Cache[K comparable, V any] struct {",
+                    "\tentries map[K]V",
+                    "\tttl     time.Duration",
+                    "}",
+                    "",
+                    "func NewCache[K comparable, V any](ttl time.Duration) *Cache[K, V] {",
+                    "\treturn &Cache[K, V]{entries: make(map[K]V), ttl: ttl}",
+                    "}",
+                    "",
+                    "func (c *Cache[K, V]) Get(ctx context.Context, key K) (V, bool) {",
+                    "\tselect {",
+                    "\tcase <-ctx.Done():",
+                    "\t\tvar zero V",
+                    "\t\treturn zero, false",
+                    "\tdefault:",
+                    "\t\tv, ok := c.entries[key]",
+                    "\t\treturn v, ok",
+                    "\t}",
+                    "}",
+                    "",
+                    "func main() {",
+                    "\tquery := `SELECT name",
+                    "FROM \"users\"`",
+                    "\tc := NewCache[string, rune](5 * time.Second)",
+                    "\tfmt.Println(query, c.ttl, 'x', 0x1F, 1.5e3, nil)",
+                    "}",
+                ],
+            },
+            TestCase {
+                language: Language::Shell,
+                filename: "test.sh",
+                lines: vec![
+                    "#!/usr/bin/env bash",
+                    "set -euo pipefail",
+                    "",
+                    "readonly BACKUP_DIR=\"${BACKUP_DIR:-/var/backups}\"",
+                    "declare -A sizes=()",
+                    "",
+                    "usage() {",
+                    "    echo \"usage: $(basename \"$0\") [-v] <dir>...\" >&2",
+                    "    exit 1",
+                    "}",
+                    "",
+                    "[[ $# -ge 1 ]] || usage",
+                    "",
+                    "for dir in \"$@\"; do",
+                    "    if [[ ! -d $dir ]]; then",
+                    "        printf 'skipping %s\\n' \"$dir\"",
+                    "        continue",
+                    "    fi",
+                    "    sizes[$dir]=$(du -sb \"$dir\" | cut -f1)",
+                    "    total=$((total + ${sizes[$dir]}))",
+                    "done",
+                    "",
+                    "cat <<-EOF | tee \"$BACKUP_DIR/report.txt\"",
+                    "\tDirectories: ${#sizes[@]}",
+                    "\tTotal bytes: $total",
+                    "\tEOF",
+                    "",
+                    "case \"${1:-}\" in",
+                    "    -v|--verbose) set -x ;;",
+                    "    *) ;;",
+                    "esac",
+                ],
+            },
+            TestCase {
+                language: Language::PowerShell,
+                filename: "test.ps1",
+                lines: vec![
+                    "#Requires -Version 7",
+                    "<#",
+                    ".SYNOPSIS",
+                    "    Rotates old log files.",
+                    "#>",
+                    "[CmdletBinding(SupportsShouldProcess)]",
+                    "param(",
+                    "    [Parameter(Mandatory)]",
+                    "    [ValidateNotNullOrEmpty()]",
+                    "    [string]$LogDirectory,",
+                    "    [int]$KeepDays = 14",
+                    ")",
+                    "",
+                    "$cutoff = (Get-Date).AddDays(-$KeepDays)",
+                    "$old = Get-ChildItem -Path $LogDirectory -Filter '*.log' |",
+                    "    Where-Object { $_.LastWriteTime -lt $cutoff -and $_.Length -gt 1MB }",
+                    "",
+                    "foreach ($file in $old) {",
+                    "    if ($PSCmdlet.ShouldProcess($file.FullName, 'Compress')) {",
+                    "        Compress-Archive -Path $file.FullName -DestinationPath \"$($file.FullName).zip\"",
+                    "        Remove-Item $file -Force",
+                    "    }",
+                    "}",
+                    "",
+                    "$summary = @\"",
+                    "Rotated $($old.Count) files in $LogDirectory",
+                    "\"@",
+                    "Write-Output $summary",
+                ],
+            },
+            TestCase {
+                language: Language::Batch,
+                filename: "test.bat",
+                lines: vec![
+                    "@echo off",
+                    "rem Builds the project in the given configuration",
+                    "setlocal enabledelayedexpansion",
+                    "",
+                    "if \"%~1\"==\"\" (set CONFIG=Release) else (set CONFIG=%~1)",
+                    "set \"OUT=%~dp0out\\%CONFIG%\"",
+                    "",
+                    "if not exist \"%OUT%\" mkdir \"%OUT%\"",
+                    "",
+                    "for %%p in (core cli tests) do (",
+                    "    echo Building %%p...",
+                    "    call :build %%p || goto :failed",
+                    "    set /a built+=1",
+                    ")",
+                    "",
+                    "echo Built !built! projects into %OUT%",
+                    "exit /b 0",
+                    "",
+                    ":build",
+                    "msbuild \"%~1.csproj\" /p:Configuration=%CONFIG% /nologo",
+                    "exit /b %ERRORLEVEL%",
+                    "",
+                    ":failed",
+                    ":: Report and bail out",
+                    "echo Build failed 1>&2",
+                    "exit /b 1",
+                ],
+            },
+            TestCase {
+                language: Language::Makefile,
+                filename: "Makefile",
+                lines: vec![
+                    "CC ?= gcc",
+                    "CFLAGS := -O2 -Wall # warnings on",
+                    "OBJS := $(patsubst %.c,$(BUILD)/%.o,$(wildcard src/*.c))",
+                    "",
+                    ".PHONY: all clean",
+                    "",
+                    "all: $(BUILD)/app",
+                    "",
+                    "$(BUILD)/app: $(OBJS)",
+                    "\t@echo \"linking $@\"",
+                    "\t$(CC) $(LDFLAGS) -o $@ $^ $(LDLIBS)",
+                    "",
+                    "$(BUILD)/%.o: %.c",
+                    "\t-@mkdir -p $(@D)",
+                    "\t$(CC) $(CFLAGS) -c $< -o $@",
+                    "",
+                    "ifeq ($(OS),Windows_NT)",
+                    "    EXE := .exe",
+                    "endif",
+                    "",
+                    "clean:",
+                    "\trm -rf $(BUILD)",
+                ],
+            },
+            TestCase {
+                language: Language::Dockerfile,
+                filename: "Dockerfile",
+                lines: vec![
+                    "# syntax=docker/dockerfile:1",
+                    "ARG RUST_VERSION=1.82",
+                    "FROM rust:${RUST_VERSION}-slim AS build",
+                    "WORKDIR /src",
+                    "COPY Cargo.toml Cargo.lock ./",
+                    "RUN --mount=type=cache,target=/usr/local/cargo/registry \\",
+                    "    cargo build --release --locked",
+                    "",
+                    "FROM debian:bookworm-slim",
+                    "LABEL org.opencontainers.image.source=\"https://example.com/app\"",
+                    "ENV APP_ENV=production PORT=8080",
+                    "COPY --from=build /src/target/release/app /usr/local/bin/app",
+                    "EXPOSE 8080/tcp",
+                    "ENTRYPOINT [\"/usr/local/bin/app\"]",
+                ],
+            },
+            TestCase {
+                language: Language::Xml,
+                filename: "test.csproj",
+                lines: vec![
+                    "<?xml version=\"1.0\" encoding=\"utf-8\"?>",
+                    "<!-- Project file -->",
+                    "<Project Sdk=\"Microsoft.NET.Sdk\">",
+                    "  <PropertyGroup Condition=\"'$(Configuration)' == 'Release'\">",
+                    "    <TargetFramework>net8.0</TargetFramework>",
+                    "    <Description>Tom &amp; Jerry</Description>",
+                    "  </PropertyGroup>",
+                    "  <ItemGroup>",
+                    "    <PackageReference Include=\"Newtonsoft.Json\" Version=\"13.0.3\" />",
+                    "  </ItemGroup>",
+                    "  <Script><![CDATA[ if (a < b) { run(); } ]]></Script>",
+                    "</Project>",
+                ],
+            },
+            TestCase {
+                language: Language::Diff,
+                filename: "test.diff",
+                lines: vec![
+                    "diff --git a/src/old.rs b/src/new.rs",
+                    "similarity index 90%",
+                    "rename from src/old.rs",
+                    "rename to src/new.rs",
+                    "index 3b18e51..a1c9f02 100644",
+                    "--- a/src/old.rs",
+                    "+++ b/src/new.rs",
+                    "@@ -1,7 +1,8 @@ fn main() {",
+                    " fn main() {",
+                    "-    println!(\"old\");",
+                    "+    println!(\"new\");",
+                    "+    println!(\"and more\");",
+                    "     let x = 1;",
+                    " }",
+                    "\\ No newline at end of file",
+                ],
+            },
+            TestCase {
+                language: Language::GitCommit,
+                filename: "COMMIT_EDITMSG",
+                lines: vec![
+                    "Fix the highlighting of heredocs that start in the middle of a line",
+                    "",
+                    "The heredoc used to swallow the rest of its start line.",
+                    "",
+                    "Signed-off-by: Jane Doe <jane@example.com>",
+                    "# Please enter the commit message for your changes.",
+                    "# ------------------------ >8 ------------------------",
+                    "# Do not modify or remove the line above.",
+                    "diff --git a/src/lexer.rs b/src/lexer.rs",
+                    "--- a/src/lexer.rs",
+                    "+++ b/src/lexer.rs",
+                    "@@ -12,7 +12,8 @@ impl Lexer {",
+                    "-        let pos = 0;",
+                    "+        let mut pos = 0;",
+                ],
+            },
         ]
     }
 
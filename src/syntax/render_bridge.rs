@@ -11,7 +11,7 @@ use std::collections::HashMap;
 use std::rc::Rc;
 
 use crate::buffer::TextBuffer;
-use crate::syntax::{HighlightingState, TokenInfo, global_highlighting_service};
+use crate::syntax::{HighlightingState, TokenInfo, TokenSource, global_highlighting_service};
 
 // A registry that maps TextBuffer instances to their highlighting states.
 // This allows the rendering code to access highlighting information without
@@ -46,37 +46,77 @@ pub fn get_buffer_highlighting(buffer: &TextBuffer) -> Option<Rc<RefCell<Highlig
 }
 
 /// Gets syntax highlighting tokens for a specific line in a buffer.
+/// `get_line_content` provides the other lines of the buffer. The lines above may be needed to
+/// determine whether the line starts inside a multi-line construct like a block comment, and the
+/// lines below it are sent to the highlighting workers along with it.
+///
+/// This never waits for the workers: lines they haven't finished yet come back with their previous
+/// tokens or as plain text, until [`apply_highlighting_results`] picks up the results.
 /// Returns None if no highlighting is available for the buffer.
-pub fn get_line_tokens(buffer: &TextBuffer, line_content: &str, line_number: usize) -> Option<Vec<TokenInfo>> {
+pub fn get_line_tokens<F>(
+    buffer: &TextBuffer,
+    line_content: &str,
+    line_number: usize,
+    get_line_content: F,
+) -> Option<Vec<TokenInfo>>
+where
+    F: FnMut(usize) -> Option<String>,
+{
     let state_rc = get_buffer_highlighting(buffer)?;
     let mut state = state_rc.borrow_mut();
     
-    if !state.enabled {
+    if !state.is_highlighting() {
         return None;
     }
     
     // Get the highlighting service and highlight the line
     let mut service = global_highlighting_service();
-    match service.highlight_line(&mut state, line_content, line_number) {
-        Ok(tokens) => Some(tokens),
-        Err(_) => None,
+    service.highlight_line_async(&mut state, line_content, line_number, get_line_content).ok()
+}
+
+/// Like [`get_line_tokens`], but also returns where the tokens came from, which is what the
+/// token inspector shows. That's recorded when the line is drawn, so the lookup here doesn't
+/// change it. Lines that weren't drawn yet report where their tokens came from just now.
+/// Returns None if no highlighting is available for the buffer.
+pub fn inspect_line_tokens<F>(
+    buffer: &TextBuffer,
+    line_content: &str,
+    line_number: usize,
+    get_line_content: F,
+) -> Option<(Vec<TokenInfo>, TokenSource)>
+where
+    F: FnMut(usize) -> Option<String>,
+{
+    let state_rc = get_buffer_highlighting(buffer)?;
+    let mut state = state_rc.borrow_mut();
+
+    if !state.is_highlighting() {
+        return None;
     }
+
+    let mut service = global_highlighting_service();
+    let (tokens, source) = service.lookup_line_async(&mut state, line_content, line_number, get_line_content).ok()?;
+    Some((tokens, state.line_source(line_number).or(source)?))
 }
 
 /// Gets syntax highlighting tokens for a specific line in a buffer with viewport tracking.
 /// This version also updates the viewport information for background highlighting.
 /// Returns None if no highlighting is available for the buffer.
-pub fn get_line_tokens_with_viewport(
+pub fn get_line_tokens_with_viewport<F>(
     buffer: &TextBuffer, 
     line_content: &str, 
     line_number: usize,
     viewport_start: usize,
     viewport_end: usize,
-) -> Option<Vec<TokenInfo>> {
+    get_line_content: F,
+) -> Option<Vec<TokenInfo>>
+where
+    F: FnMut(usize) -> Option<String>,
+{
     let state_rc = get_buffer_highlighting(buffer)?;
     let mut state = state_rc.borrow_mut();
     
-    if !state.enabled {
+    if !state.is_highlighting() {
         return None;
     }
     
@@ -85,10 +125,30 @@ pub fn get_line_tokens_with_viewport(
     service.update_viewport(&mut state, viewport_start, viewport_end);
     
     // Get highlighting for the current line
-    match service.highlight_line(&mut state, line_content, line_number) {
-        Ok(tokens) => Some(tokens),
-        Err(_) => None,
-    }
+    service.highlight_line_async(&mut state, line_content, line_number, get_line_content).ok()
+}
+
+/// Caches the lines the highlighting workers finished since the last call in the buffers they
+/// belong to. Returns true if there were any, in which case the buffers need to be redrawn.
+pub fn apply_highlighting_results() -> bool {
+    let mut service = global_highlighting_service();
+    let mut applied = false;
+
+    BUFFER_HIGHLIGHTING_REGISTRY.with(|registry| {
+        for state in registry.borrow().values() {
+            applied |= service.apply_async_results(&mut state.borrow_mut()) != 0;
+        }
+    });
+
+    // Whatever is left belongs to buffers that are gone.
+    service.discard_async_results();
+    applied
+}
+
+/// Returns true if the highlighting workers are still busy with lines of some buffer,
+/// so that [`apply_highlighting_results`] should be called again soon.
+pub fn has_pending_highlighting() -> bool {
+    global_highlighting_service().has_pending_async_work()
 }
 
 /// Performs background highlighting for lines near the viewport.
@@ -109,7 +169,7 @@ where
     let state_rc = get_buffer_highlighting(buffer)?;
     let mut state = state_rc.borrow_mut();
     
-    if !state.enabled {
+    if !state.is_highlighting() {
         return Some(0);
     }
     
@@ -135,7 +195,7 @@ pub fn has_background_work(buffer: &TextBuffer) -> bool {
 pub fn update_viewport_tracking(buffer: &TextBuffer, viewport_start: usize, viewport_end: usize) {
     if let Some(state_rc) = get_buffer_highlighting(buffer) {
         let mut state = state_rc.borrow_mut();
-        if state.enabled {
+        if state.is_highlighting() {
             let mut service = global_highlighting_service();
             service.update_viewport(&mut state, viewport_start, viewport_end);
         }
@@ -0,0 +1,1200 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A highlighting backend that interprets TextMate grammars (`.tmLanguage.json`),
+//! the format used by VS Code and many other editors.
+//!
+//! Supported are `match` rules, `begin`/`end` and `begin`/`while` rules (including
+//! back-references from `end` to `begin`), `captures`, `patterns`, `include`s of the
+//! grammar itself and of `repository` entries, and `applyEndPatternLast`.
+//! Includes of other grammars are ignored.
+//!
+//! TextMate grammars are written for Oniguruma, whereas this module uses the `regex` crate.
+//! Patterns are translated where possible (`\h`, atomic groups, possessive quantifiers, ...).
+//! Lookaround assertions have no equivalent and are dropped, which makes the affected
+//! rules a little more permissive. Rules that still fail to compile are skipped;
+//! [`Grammar::skipped_rules`] reports how many.
+//!
+//! Scope names are mapped onto the token kinds of [`super::ColorMapper`] via a [`ScopeMap`].
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use regex::{Captures, Regex};
+
+use super::highlighter::TokenInfo;
+use super::lexer::LexerState;
+use crate::json::{self, Value};
+
+/// Maximum nesting depth of `begin` rules that a [`LexerState`] tracks.
+const MAX_STACK_DEPTH: usize = 64;
+
+/// How many matches may occur without any progress before the tokenizer forcibly moves on.
+/// Guards against `begin`/`end` pairs that both match the empty string.
+const MAX_STALLED_MATCHES: usize = 16;
+
+/// How many compiled dynamic `end` patterns a [`Grammar`] keeps around.
+/// Each distinct heredoc delimiter and the like needs one of its own.
+const MAX_DYNAMIC_ENDS: usize = 64;
+
+/// Maps TextMate scope names onto token kinds by their longest matching prefix.
+///
+/// A prefix matches whole dot-separated segments only: `string` matches
+/// `string.quoted.double`, but not `stringy`.
+#[derive(Debug, Clone)]
+pub struct ScopeMap {
+    prefixes: HashMap<String, String>,
+}
+
+impl Default for ScopeMap {
+    fn default() -> Self {
+        let mut map = Self { prefixes: HashMap::new() };
+        for (prefix, kind) in [
+            ("comment", "comment"),
+            ("comment.block.documentation", "comment.doc"),
+            ("comment.line.documentation", "comment.doc"),
+            ("comment.line.double-slash.documentation", "comment.doc"),
+            ("storage.type.class.jsdoc", "comment.doc.tag"),
+            ("string", "string"),
+            ("string.regexp", "regex"),
+            ("string.quoted.docstring", "string.doc"),
+            ("constant", "constant"),
+            ("constant.numeric", "number"),
+            ("constant.language.boolean", "boolean"),
+            ("constant.character.escape", "string.escape"),
+            ("constant.other.placeholder", "string.placeholder"),
+            ("keyword", "keyword"),
+            ("keyword.operator", "operator"),
+            ("storage", "keyword"),
+            ("storage.type", "type"),
+            ("support.type", "type"),
+            ("support.class", "type"),
+            ("entity.name.type", "type"),
+            ("entity.name.class", "type"),
+            ("entity.name.function", "function"),
+            ("support.function", "builtin"),
+            ("entity.name.tag", "keyword"),
+            ("entity.other.attribute-name", "attribute"),
+            ("entity.name.function.decorator", "decorator"),
+            ("meta.decorator", "decorator"),
+            ("variable", "variable"),
+            ("variable.language", "builtin"),
+            ("punctuation", "punctuation"),
+            ("punctuation.definition.comment", "comment"),
+            ("punctuation.definition.string", "string"),
+            ("invalid", "invalid"),
+            ("markup.heading", "keyword"),
+            ("markup.bold", "constant"),
+            ("markup.italic", "constant"),
+            ("markup.inline.raw", "string"),
+            ("markup.fenced_code", "string"),
+            ("markup.underline.link", "string"),
+            ("markup.quote", "comment"),
+            ("markup.list", "punctuation"),
+            ("markup.inserted", "inserted"),
+            ("markup.deleted", "deleted"),
+            ("meta.diff.range", "type"),
+            ("meta.diff.header", "keyword"),
+        ] {
+            map.insert(prefix, kind);
+        }
+        map
+    }
+}
+
+impl ScopeMap {
+    /// Creates an empty map. Use `ScopeMap::default()` for the built-in table.
+    pub fn empty() -> Self {
+        Self { prefixes: HashMap::new() }
+    }
+
+    /// Maps all scopes starting with `prefix` to `kind`, unless a longer prefix matches.
+    pub fn insert(&mut self, prefix: &str, kind: &str) {
+        self.prefixes.insert(prefix.to_string(), kind.to_string());
+    }
+
+    /// Adds the entries of a JSON object like `{ "markup.heading": "keyword" }`.
+    pub fn extend_from_json(&mut self, text: &str) -> Result<(), String> {
+        let root = json::parse(text).map_err(|err| err.to_string())?;
+        let Some(entries) = root.as_object() else {
+            return Err(format!("Expected an object, found {}", root.type_name()));
+        };
+        for (prefix, kind) in entries {
+            let Some(kind) = kind.as_str() else {
+                return Err(format!(
+                    "Expected \"{prefix}\" to be a string, found {}",
+                    kind.type_name()
+                ));
+            };
+            self.insert(prefix, kind);
+        }
+        Ok(())
+    }
+
+    /// Returns the token kind for a single scope name.
+    pub fn kind_for(&self, scope: &str) -> Option<&str> {
+        let mut prefix = scope;
+        loop {
+            if let Some(kind) = self.prefixes.get(prefix) {
+                return Some(kind);
+            }
+            prefix = &prefix[..prefix.rfind('.')?];
+        }
+    }
+}
+
+/// A compiled rule. Rules reference each other by their index in [`Grammar::rules`].
+#[derive(Debug)]
+struct Rule {
+    /// Token kind of the rule's `name`.
+    kind: Option<u16>,
+    /// Token kind of the text between `begin` and `end`, from `contentName`, falling back to `kind`.
+    content_kind: Option<u16>,
+    body: RuleBody,
+}
+
+#[derive(Debug)]
+enum RuleBody {
+    /// Only `patterns` or an `include`. Resolved into the rules it stands for.
+    Group(Vec<RuleRef>),
+    Match {
+        regex: Regex,
+        captures: Vec<(usize, u16)>,
+    },
+    Begin {
+        begin: Regex,
+        begin_captures: Vec<(usize, u16)>,
+        /// The `end` or `while` pattern, depending on `is_while`.
+        end: EndPattern,
+        end_captures: Vec<(usize, u16)>,
+        is_while: bool,
+        apply_end_pattern_last: bool,
+        /// The flattened list of rules to try inside the region.
+        patterns: Vec<u32>,
+    },
+    /// A rule that failed to compile.
+    Invalid,
+}
+
+#[derive(Debug)]
+enum RuleRef {
+    Rule(u32),
+    /// `$self` or `$base`.
+    Root,
+    /// A `#name` reference into the repository.
+    Repository(String),
+}
+
+#[derive(Debug)]
+enum EndPattern {
+    Static(Regex),
+    /// Contains back-references to the `begin` match and is compiled once those are known.
+    Dynamic(String),
+}
+
+/// A single entry on the TextMate rule stack, stored in [`LexerState`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Frame {
+    rule: u32,
+    /// The `end`/`while` pattern with its back-references resolved, if it had any.
+    end: Option<Arc<str>>,
+}
+
+/// A compiled TextMate grammar.
+#[derive(Debug)]
+pub struct Grammar {
+    /// The display name, e.g. "Rust".
+    pub name: String,
+    /// The root scope, e.g. `source.rust`.
+    pub scope_name: String,
+    /// File extensions or names, e.g. `rs`.
+    pub file_types: Vec<String>,
+    rules: Vec<Rule>,
+    /// The flattened root patterns.
+    root: Vec<u32>,
+    /// Interned token kinds.
+    kinds: Vec<String>,
+    skipped: usize,
+    /// Compiled dynamic `end` patterns, up to [`MAX_DYNAMIC_ENDS`] of them.
+    dynamic_cache: Mutex<HashMap<Arc<str>, Option<Regex>>>,
+}
+
+impl Grammar {
+    /// Parses and compiles a grammar from its JSON representation.
+    pub fn parse(text: &str, scopes: &ScopeMap) -> Result<Self, String> {
+        let root = json::parse(text).map_err(|err| err.to_string())?;
+        if root.as_object().is_none() {
+            return Err(format!("Expected an object, found {}", root.type_name()));
+        }
+        let Some(scope_name) = root.get("scopeName").and_then(Value::as_str) else {
+            return Err("Missing \"scopeName\"".to_string());
+        };
+        let Some(patterns) = root.get("patterns").and_then(Value::as_array) else {
+            return Err("Missing \"patterns\"".to_string());
+        };
+
+        let name = root.get("name").and_then(Value::as_str).map_or_else(
+            || scope_name.rsplit('.').next().unwrap_or(scope_name).to_string(),
+            str::to_string,
+        );
+        let file_types = root
+            .get("fileTypes")
+            .and_then(Value::as_array)
+            .map(|a| a.iter().filter_map(Value::as_str).map(str::to_string).collect())
+            .unwrap_or_default();
+
+        let mut compiler = Compiler {
+            scopes,
+            rules: Vec::new(),
+            kinds: Vec::new(),
+            repository: HashMap::new(),
+            skipped: 0,
+        };
+
+        if let Some(repository) = root.get("repository") {
+            compiler.compile_repository(repository);
+        }
+        let refs = patterns.iter().map(|p| compiler.compile_ref(p)).collect();
+        let root_group =
+            compiler.push(Rule { kind: None, content_kind: None, body: RuleBody::Group(refs) });
+
+        let mut grammar = Grammar {
+            name,
+            scope_name: scope_name.to_string(),
+            file_types,
+            rules: compiler.rules,
+            root: Vec::new(),
+            kinds: compiler.kinds,
+            skipped: compiler.skipped,
+            dynamic_cache: Mutex::new(HashMap::new()),
+        };
+        let repository = compiler.repository;
+        grammar.link(root_group, &repository);
+        Ok(grammar)
+    }
+
+    /// Returns how many rules were skipped because their patterns couldn't be compiled.
+    pub fn skipped_rules(&self) -> usize {
+        self.skipped
+    }
+
+    /// Flattens the `patterns` of the root and every `begin` rule into lists of
+    /// matchable rules, so that the tokenizer doesn't need to follow includes.
+    fn link(&mut self, root_group: u32, repository: &HashMap<String, u32>) {
+        self.root = self.flatten(&[RuleRef::Rule(root_group)], root_group, repository);
+
+        for i in 0..self.rules.len() {
+            if let RuleBody::Begin { .. } = self.rules[i].body {
+                let refs = match &mut self.rules[i].body {
+                    RuleBody::Begin { patterns, .. } => std::mem::take(patterns),
+                    _ => unreachable!(),
+                };
+                let refs: Vec<_> = refs.into_iter().map(RuleRef::Rule).collect();
+                let flat = self.flatten(&refs, root_group, repository);
+                if let RuleBody::Begin { patterns, .. } = &mut self.rules[i].body {
+                    *patterns = flat;
+                }
+            }
+        }
+    }
+
+    fn flatten(
+        &self,
+        refs: &[RuleRef],
+        root_group: u32,
+        repository: &HashMap<String, u32>,
+    ) -> Vec<u32> {
+        let mut out = Vec::new();
+        let mut visited = vec![false; self.rules.len()];
+        self.flatten_into(refs, root_group, repository, &mut visited, &mut out);
+        out
+    }
+
+    fn flatten_into(
+        &self,
+        refs: &[RuleRef],
+        root_group: u32,
+        repository: &HashMap<String, u32>,
+        visited: &mut [bool],
+        out: &mut Vec<u32>,
+    ) {
+        for r in refs {
+            let id = match r {
+                RuleRef::Rule(id) => *id,
+                RuleRef::Root => root_group,
+                RuleRef::Repository(name) => match repository.get(name) {
+                    Some(&id) => id,
+                    None => continue,
+                },
+            };
+            // Visiting a rule twice would only add duplicates (or recurse forever).
+            if std::mem::replace(&mut visited[id as usize], true) {
+                continue;
+            }
+            match &self.rules[id as usize].body {
+                RuleBody::Group(refs) => {
+                    self.flatten_into(refs, root_group, repository, visited, out)
+                }
+                RuleBody::Match { .. } | RuleBody::Begin { .. } => out.push(id),
+                RuleBody::Invalid => {}
+            }
+        }
+    }
+
+    /// Tokenizes a single line starting in the given state.
+    ///
+    /// Returns the tokens, which cover the entire line without gaps,
+    /// and the state at the end of the line.
+    pub fn tokenize_line(&self, line: &str, state: &LexerState) -> (Vec<TokenInfo>, LexerState) {
+        let mut stack = state.grammar.clone();
+        // Like in VS Code, every line is tokenized with its line ending, as many grammars expect it.
+        let text = format!("{line}\n");
+        let mut emitter = Emitter { text: &text, tokens: Vec::new() };
+        let mut pos = 0;
+
+        // `while` rules only continue if their pattern matches at the start of each line.
+        for depth in 0..stack.len() {
+            let frame = &stack[depth];
+            let RuleBody::Begin { is_while: true, end_captures, .. } =
+                &self.rules[frame.rule as usize].body
+            else {
+                continue;
+            };
+            let caps = self
+                .end_regex(frame)
+                .and_then(|re| re.captures_at(&text, pos))
+                .filter(|c| c.get(0).unwrap().start() == pos);
+            match caps {
+                Some(caps) => {
+                    let ambient = self.ambient_kind(&stack[..=depth]);
+                    emitter.paint_match(&caps, ambient, end_captures);
+                    pos = caps.get(0).unwrap().end();
+                }
+                None => {
+                    stack.truncate(depth);
+                    break;
+                }
+            }
+        }
+
+        let mut cache: Vec<Option<Option<(usize, usize)>>> = vec![None; self.rules.len()];
+        let mut stalled = 0;
+
+        while pos < text.len() {
+            let frame = stack.last();
+            let patterns = match frame {
+                Some(frame) => match &self.rules[frame.rule as usize].body {
+                    RuleBody::Begin { patterns, .. } => patterns.as_slice(),
+                    _ => &[],
+                },
+                None => self.root.as_slice(),
+            };
+
+            // Find the earliest match. On ties, the end pattern wins, unless
+            // `applyEndPatternLast` is set, and otherwise earlier rules win.
+            let mut best: Option<(usize, Option<u32>)> = None;
+            let mut end_match = None;
+            if let Some(frame) = frame
+                && let RuleBody::Begin { is_while: false, apply_end_pattern_last, .. } =
+                    self.rules[frame.rule as usize].body
+                && let Some(caps) = self.end_regex(frame).and_then(|re| re.captures_at(&text, pos))
+            {
+                let start = caps.get(0).unwrap().start();
+                if !apply_end_pattern_last {
+                    best = Some((start, None));
+                }
+                end_match = Some((start, caps));
+            }
+            for &id in patterns {
+                let Some((start, _)) = self.next_match(&mut cache[id as usize], id, &text, pos)
+                else {
+                    continue;
+                };
+                if best.is_none_or(|(b, _)| start < b) {
+                    best = Some((start, Some(id)));
+                }
+            }
+            if let Some((start, _)) = &end_match
+                && best.is_none_or(|(b, _)| *start < b)
+            {
+                best = Some((*start, None));
+            }
+
+            let Some((start, winner)) = best else {
+                break;
+            };
+
+            let ambient = self.ambient_kind(&stack);
+            emitter.paint(pos, start, ambient);
+
+            let end = match winner {
+                None => {
+                    // The end of the current region.
+                    let (_, caps) = end_match.unwrap();
+                    let frame = stack.pop().unwrap();
+                    let rule = &self.rules[frame.rule as usize];
+                    let base = rule.kind.or_else(|| self.ambient_kind(&stack));
+                    if let RuleBody::Begin { end_captures, .. } = &rule.body {
+                        emitter.paint_match(&caps, base, end_captures);
+                    }
+                    caps.get(0).unwrap().end()
+                }
+                Some(id) => {
+                    let rule = &self.rules[id as usize];
+                    match &rule.body {
+                        RuleBody::Match { regex, captures } => {
+                            let caps = regex.captures_at(&text, start).unwrap();
+                            emitter.paint_match(&caps, rule.kind.or(ambient), captures);
+                            caps.get(0).unwrap().end()
+                        }
+                        RuleBody::Begin { begin, begin_captures, end, .. } => {
+                            let caps = begin.captures_at(&text, start).unwrap();
+                            emitter.paint_match(&caps, rule.kind.or(ambient), begin_captures);
+                            let end = match end {
+                                EndPattern::Static(_) => None,
+                                EndPattern::Dynamic(pattern) => {
+                                    Some(resolve_back_references(pattern, &caps).into())
+                                }
+                            };
+                            let frame = Frame { rule: id, end };
+                            let whole = caps.get(0).unwrap();
+                            if whole.is_empty() && self.matches_empty_end(&frame, &text, start) {
+                                // The region would be empty, and pushing it would make no progress.
+                                let next = next_char_boundary(&text, start);
+                                emitter.paint(start, next, ambient);
+                                next
+                            } else {
+                                if stack.len() < MAX_STACK_DEPTH {
+                                    stack.push(frame);
+                                }
+                                whole.end()
+                            }
+                        }
+                        RuleBody::Group(_) | RuleBody::Invalid => unreachable!(),
+                    }
+                }
+            };
+
+            if end > pos {
+                pos = end;
+                stalled = 0;
+            } else {
+                stalled += 1;
+                if stalled >= MAX_STALLED_MATCHES {
+                    // Skip a character, so that the empty matches can't repeat forever.
+                    let next = next_char_boundary(&text, pos);
+                    emitter.paint(pos, next, self.ambient_kind(&stack));
+                    pos = next;
+                    stalled = 0;
+                }
+            }
+        }
+
+        emitter.paint(pos, text.len(), self.ambient_kind(&stack));
+
+        let tokens = emitter.finish(line.len(), &self.kinds);
+        (tokens, LexerState::from_grammar_stack(stack))
+    }
+
+    /// Returns the innermost token kind of the given stack of regions.
+    fn ambient_kind(&self, stack: &[Frame]) -> Option<u16> {
+        stack.iter().rev().find_map(|f| self.rules[f.rule as usize].content_kind)
+    }
+
+    /// Finds the next non-empty match of a `match` rule or the next match of a `begin` rule,
+    /// at or after `pos`, reusing the cached result if possible.
+    fn next_match(
+        &self,
+        cache: &mut Option<Option<(usize, usize)>>,
+        id: u32,
+        text: &str,
+        pos: usize,
+    ) -> Option<(usize, usize)> {
+        if let Some(cached) = *cache {
+            match cached {
+                None => return None,
+                Some((beg, _)) if beg >= pos => return cached,
+                _ => {}
+            }
+        }
+
+        let result = match &self.rules[id as usize].body {
+            RuleBody::Match { regex, .. } => {
+                let mut search = pos;
+                loop {
+                    match regex.find_at(text, search) {
+                        Some(m) if m.is_empty() => {
+                            search = next_char_boundary(text, m.start());
+                            if search > text.len() {
+                                break None;
+                            }
+                        }
+                        Some(m) => break Some((m.start(), m.end())),
+                        None => break None,
+                    }
+                }
+            }
+            RuleBody::Begin { begin, .. } => begin.find_at(text, pos).map(|m| (m.start(), m.end())),
+            RuleBody::Group(_) | RuleBody::Invalid => None,
+        };
+
+        *cache = Some(result);
+        result
+    }
+
+    /// Returns whether the `end` pattern of a frame matches the empty string at `pos`.
+    fn matches_empty_end(&self, frame: &Frame, text: &str, pos: usize) -> bool {
+        if let RuleBody::Begin { is_while: true, .. } = self.rules[frame.rule as usize].body {
+            return false;
+        }
+        self.end_regex(frame)
+            .and_then(|re| re.find_at(text, pos))
+            .is_some_and(|m| m.start() == pos && m.is_empty())
+    }
+
+    /// Returns the compiled `end`/`while` pattern of the given frame.
+    fn end_regex(&self, frame: &Frame) -> Option<Regex> {
+        let RuleBody::Begin { end, .. } = &self.rules[frame.rule as usize].body else {
+            return None;
+        };
+        match (end, &frame.end) {
+            (EndPattern::Static(regex), _) => Some(regex.clone()),
+            (EndPattern::Dynamic(_), Some(resolved)) => {
+                let mut cache = self.dynamic_cache.lock().unwrap();
+                if cache.len() >= MAX_DYNAMIC_ENDS && !cache.contains_key(resolved) {
+                    cache.clear();
+                }
+                cache.entry(resolved.clone()).or_insert_with(|| compile(resolved).ok()).clone()
+            }
+            (EndPattern::Dynamic(_), None) => None,
+        }
+    }
+}
+
+/// Builds the rule list of a [`Grammar`].
+struct Compiler<'a> {
+    scopes: &'a ScopeMap,
+    rules: Vec<Rule>,
+    kinds: Vec<String>,
+    repository: HashMap<String, u32>,
+    skipped: usize,
+}
+
+impl Compiler<'_> {
+    fn push(&mut self, rule: Rule) -> u32 {
+        self.rules.push(rule);
+        (self.rules.len() - 1) as u32
+    }
+
+    /// Compiles the entries of a `repository` object. Nested repositories
+    /// share one namespace, where the first definition of a name wins.
+    fn compile_repository(&mut self, repository: &Value) {
+        let Some(entries) = repository.as_object() else {
+            return;
+        };
+        // Reserve the IDs first, so that entries can reference each other.
+        let mut pending = Vec::new();
+        for (name, value) in entries {
+            if !self.repository.contains_key(name) {
+                let id =
+                    self.push(Rule { kind: None, content_kind: None, body: RuleBody::Invalid });
+                self.repository.insert(name.clone(), id);
+                pending.push((id, value));
+            }
+        }
+        for (id, value) in pending {
+            self.rules[id as usize] = self.compile_rule(value);
+        }
+    }
+
+    fn compile_ref(&mut self, value: &Value) -> RuleRef {
+        if value.get("begin").is_none()
+            && value.get("match").is_none()
+            && let Some(include) = value.get("include").and_then(Value::as_str)
+        {
+            return self.include_ref(include);
+        }
+        let rule = self.compile_rule(value);
+        RuleRef::Rule(self.push(rule))
+    }
+
+    fn include_ref(&mut self, include: &str) -> RuleRef {
+        match include {
+            "$self" | "$base" => RuleRef::Root,
+            _ => match include.strip_prefix('#') {
+                Some(name) => RuleRef::Repository(name.to_string()),
+                // Other grammars aren't supported. An empty group is a no-op.
+                None => RuleRef::Rule(self.push(Rule {
+                    kind: None,
+                    content_kind: None,
+                    body: RuleBody::Group(Vec::new()),
+                })),
+            },
+        }
+    }
+
+    fn compile_rule(&mut self, value: &Value) -> Rule {
+        if let Some(repository) = value.get("repository") {
+            self.compile_repository(repository);
+        }
+
+        let kind = value.get("name").and_then(Value::as_str).and_then(|n| self.intern_scopes(n));
+        let content_kind = value
+            .get("contentName")
+            .and_then(Value::as_str)
+            .and_then(|n| self.intern_scopes(n))
+            .or(kind);
+        let patterns: Vec<RuleRef> = value
+            .get("patterns")
+            .and_then(Value::as_array)
+            .map(|a| a.iter().map(|p| self.compile_ref(p)).collect())
+            .unwrap_or_default();
+
+        let body = if let Some(pattern) = value.get("match").and_then(Value::as_str) {
+            match compile(pattern) {
+                Ok(regex) => RuleBody::Match {
+                    regex,
+                    captures: self.compile_captures(value.get("captures")),
+                },
+                Err(_) => self.skip(),
+            }
+        } else if let Some(begin) = value.get("begin").and_then(Value::as_str) {
+            let (end, is_while) = match (value.get("end"), value.get("while")) {
+                (Some(end), _) => (end.as_str(), false),
+                (None, Some(w)) => (w.as_str(), true),
+                // TextMate treats a missing end pattern as one that never matches.
+                (None, None) => (Some(r"\z\A"), false),
+            };
+            let captures = value.get("captures");
+            let begin_captures = self.compile_captures(value.get("beginCaptures").or(captures));
+            let end_key = if is_while { "whileCaptures" } else { "endCaptures" };
+            let end_captures = self.compile_captures(value.get(end_key).or(captures));
+
+            let end = end.and_then(|end| {
+                if has_back_references(end) {
+                    Some(EndPattern::Dynamic(end.to_string()))
+                } else {
+                    compile(end).ok().map(EndPattern::Static)
+                }
+            });
+            // Without its lookarounds, such a pattern would match everywhere.
+            let begin = Some(begin).filter(|b| translate_pattern(b) != "(?m)");
+            match (begin.map(compile), end) {
+                (Some(Ok(begin)), Some(end)) => {
+                    // The patterns are linked later, once all rules exist.
+                    let mut ids = Vec::with_capacity(patterns.len());
+                    for p in patterns {
+                        ids.push(match p {
+                            RuleRef::Rule(id) => id,
+                            other => self.push(Rule {
+                                kind: None,
+                                content_kind: None,
+                                body: RuleBody::Group(vec![other]),
+                            }),
+                        });
+                    }
+                    RuleBody::Begin {
+                        begin,
+                        begin_captures,
+                        end,
+                        end_captures,
+                        is_while,
+                        apply_end_pattern_last: value.get("applyEndPatternLast").is_some_and(|v| {
+                            v.as_bool().unwrap_or(false) || v.as_f64().is_some_and(|n| n != 0.0)
+                        }),
+                        patterns: ids,
+                    }
+                }
+                _ => self.skip(),
+            }
+        } else if let Some(include) = value.get("include").and_then(Value::as_str) {
+            RuleBody::Group(vec![self.include_ref(include)])
+        } else {
+            RuleBody::Group(patterns)
+        };
+
+        Rule { kind, content_kind, body }
+    }
+
+    fn skip(&mut self) -> RuleBody {
+        self.skipped += 1;
+        RuleBody::Invalid
+    }
+
+    /// Compiles a `captures` object like `{ "1": { "name": "keyword" } }`.
+    fn compile_captures(&mut self, value: Option<&Value>) -> Vec<(usize, u16)> {
+        let mut captures: Vec<_> = value
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .filter_map(|(group, capture)| {
+                let group = group.parse().ok()?;
+                let kind = self.intern_scopes(capture.get("name")?.as_str()?)?;
+                Some((group, kind))
+            })
+            .collect();
+        // Outer groups have lower indices and are painted first, so that inner ones win.
+        captures.sort_by_key(|&(group, _)| group);
+        captures
+    }
+
+    /// Maps a space-separated list of scopes to the kind of the last one that has any.
+    fn intern_scopes(&mut self, scopes: &str) -> Option<u16> {
+        let kind = scopes.split_whitespace().rev().find_map(|scope| {
+            // Scopes may contain `$1`-style substitutions. Only the part before them is static.
+            let scope = scope.split('$').next().unwrap_or_default().trim_end_matches('.');
+            self.scopes.kind_for(scope)
+        })?;
+        let index = match self.kinds.iter().position(|k| k == kind) {
+            Some(index) => index,
+            None => {
+                self.kinds.push(kind.to_string());
+                self.kinds.len() - 1
+            }
+        };
+        Some(index as u16)
+    }
+}
+
+/// Collects the tokens of a line, merging adjacent ranges of the same kind.
+struct Emitter<'a> {
+    text: &'a str,
+    tokens: Vec<(usize, usize, Option<u16>)>,
+}
+
+impl Emitter<'_> {
+    fn paint(&mut self, beg: usize, end: usize, kind: Option<u16>) {
+        if beg >= end {
+            return;
+        }
+        if let Some(last) = self.tokens.last_mut()
+            && last.1 == beg
+            && last.2 == kind
+        {
+            last.1 = end;
+            return;
+        }
+        self.tokens.push((beg, end, kind));
+    }
+
+    /// Paints a match with the `base` kind, and its capture groups on top.
+    fn paint_match(&mut self, caps: &Captures, base: Option<u16>, captures: &[(usize, u16)]) {
+        let whole = caps.get(0).unwrap();
+        let (beg, end) = (whole.start(), whole.end());
+        if captures.is_empty() {
+            self.paint(beg, end, base);
+            return;
+        }
+
+        let mut kinds = vec![base; end - beg];
+        for &(group, kind) in captures {
+            if let Some(m) = caps.get(group) {
+                let (b, e) = (m.start().clamp(beg, end), m.end().clamp(beg, end));
+                kinds[b - beg..e - beg].fill(Some(kind));
+            }
+        }
+
+        let mut run = beg;
+        for i in beg + 1..=end {
+            if i == end || kinds[i - beg] != kinds[run - beg] {
+                // Runs may only be split at character boundaries.
+                if i == end || self.text.is_char_boundary(i) {
+                    self.paint(run, i, kinds[run - beg]);
+                    run = i;
+                }
+            }
+        }
+    }
+
+    /// Converts the ranges into tokens, cut off at `len` to drop the line ending.
+    fn finish(self, len: usize, kinds: &[String]) -> Vec<TokenInfo> {
+        self.tokens
+            .into_iter()
+            .filter(|&(beg, _, _)| beg < len)
+            .map(|(beg, end, kind)| {
+                let end = end.min(len);
+                let text = self.text[beg..end].to_string();
+                match kind {
+                    Some(kind) => {
+                        TokenInfo::highlighted(text, kinds[kind as usize].clone(), beg, end)
+                    }
+                    None => TokenInfo::plain_text(text, beg, end),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Returns the offset of the char boundary after `offset`, which may be `text.len() + 1` at the end.
+pub(super) fn next_char_boundary(text: &str, offset: usize) -> usize {
+    let mut next = offset + 1;
+    while next < text.len() && !text.is_char_boundary(next) {
+        next += 1;
+    }
+    next
+}
+
+/// Returns true if `pattern` contains `\1`-style back-references.
+pub(super) fn has_back_references(pattern: &str) -> bool {
+    let bytes = pattern.as_bytes();
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        if bytes[i] == b'\\' {
+            if bytes[i + 1].is_ascii_digit() {
+                return true;
+            }
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    false
+}
+
+/// Replaces `\1`-style back-references with the escaped text of the corresponding group.
+pub(super) fn resolve_back_references(pattern: &str, caps: &Captures) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(d) if d.is_ascii_digit() => {
+                let group = d.to_digit(10).unwrap() as usize;
+                out.push_str(&regex::escape(caps.get(group).map_or("", |m| m.as_str())));
+            }
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Compiles an Oniguruma pattern with the `regex` crate. See [`translate_pattern`].
+fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&translate_pattern(pattern))
+}
+
+/// Translates an Oniguruma pattern into the `regex` crate's syntax, as far as possible.
+pub fn translate_pattern(pattern: &str) -> String {
+    // Oniguruma's `^` and `$` always match at line boundaries.
+    let mut out = String::with_capacity(pattern.len() + 8);
+    out.push_str("(?m)");
+
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    let mut class_depth = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' if i + 1 < chars.len() => {
+                let n = chars[i + 1];
+                i += 2;
+                match n {
+                    'h' if class_depth > 0 => out.push_str("0-9A-Fa-f"),
+                    'h' => out.push_str("[0-9A-Fa-f]"),
+                    'H' if class_depth == 0 => out.push_str("[^0-9A-Fa-f]"),
+                    // `\G` anchors at the search position, which is where matching starts anyway.
+                    'G' => {}
+                    'Z' => out.push_str(r"(?:\n?\z)"),
+                    'e' => out.push_str(r"\x1B"),
+                    '<' | '>' => out.push(n),
+                    _ => {
+                        out.push('\\');
+                        out.push(n);
+                    }
+                }
+                continue;
+            }
+            '[' => {
+                // A `[` inside a class only opens a nested class in Oniguruma
+                // if it's a POSIX class like `[:alpha:]` or a set operation.
+                if class_depth > 0 && chars.get(i + 1) != Some(&':') && !out.ends_with("&&") {
+                    out.push_str(r"\[");
+                    i += 1;
+                    continue;
+                }
+                class_depth += 1;
+                out.push('[');
+                i += 1;
+                // A leading `]` (or `^]`) is a literal in both dialects, but must be escaped here.
+                if chars.get(i) == Some(&'^') {
+                    out.push('^');
+                    i += 1;
+                }
+                if chars.get(i) == Some(&']') {
+                    out.push_str(r"\]");
+                    i += 1;
+                }
+                continue;
+            }
+            ']' if class_depth > 0 => class_depth -= 1,
+            '(' if class_depth == 0 && chars.get(i + 1) == Some(&'?') => {
+                let rest: String = chars[i + 2..].iter().take(2).collect();
+                if rest.starts_with('=') || rest.starts_with('!') || rest == "<=" || rest == "<!" {
+                    // Lookarounds have no equivalent. Drop them, including any quantifier.
+                    i = skip_group(&chars, i);
+                    if matches!(chars.get(i), Some('?' | '*' | '+')) {
+                        i += 1;
+                    }
+                    continue;
+                }
+                if rest.starts_with('>') {
+                    // Atomic groups are treated as regular groups.
+                    out.push_str("(?:");
+                    i += 3;
+                    continue;
+                }
+            }
+            '+' if class_depth == 0
+                && i > 0
+                && matches!(chars[i - 1], '*' | '+' | '?')
+                && !is_escaped(&chars, i - 1) =>
+            {
+                // Possessive quantifiers are treated as greedy ones.
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        out.push(c);
+        i += 1;
+    }
+
+    out
+}
+
+/// Returns whether the character at `i` is escaped by a backslash.
+fn is_escaped(chars: &[char], i: usize) -> bool {
+    chars[..i].iter().rev().take_while(|&&c| c == '\\').count() % 2 != 0
+}
+
+/// Returns the index after the group that opens at `start`.
+fn skip_group(chars: &[char], start: usize) -> usize {
+    let mut depth = 0;
+    let mut in_class = false;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '(' if !in_class => depth += 1,
+            ')' if !in_class => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAMMAR: &str = r##"{
+        "name": "Test",
+        "scopeName": "source.test",
+        "fileTypes": ["tst"],
+        "patterns": [
+            { "include": "#comments" },
+            { "include": "#strings" },
+            { "match": "\\b(fn)\\s+(\\w+)", "captures": { "1": { "name": "storage.type.function" }, "2": { "name": "entity.name.function" } } },
+            { "match": "\\b(?:if|else)\\b", "name": "keyword.control" },
+            { "match": "\\b\\d+\\b", "name": "constant.numeric.integer" },
+            {
+                "begin": "<<(\\w+)", "end": "^\\1$",
+                "beginCaptures": { "0": { "name": "keyword.operator.heredoc" } },
+                "contentName": "string.unquoted.heredoc"
+            },
+            { "begin": "^>", "while": "^>", "name": "markup.quote" }
+        ],
+        "repository": {
+            "comments": {
+                "patterns": [
+                    { "match": "//.*$", "name": "comment.line.double-slash" },
+                    { "begin": "/\\*", "end": "\\*/", "name": "comment.block" }
+                ]
+            },
+            "strings": {
+                "begin": "\"", "end": "\"", "name": "string.quoted.double",
+                "patterns": [
+                    { "match": "\\\\.", "name": "constant.character.escape" },
+                    { "begin": "\\$\\{", "end": "\\}", "name": "meta.interpolation", "contentName": "source.test", "patterns": [{ "include": "$self" }] }
+                ]
+            }
+        }
+    }"##;
+
+    fn grammar() -> Grammar {
+        Grammar::parse(GRAMMAR, &ScopeMap::default()).unwrap()
+    }
+
+    fn kinds(tokens: &[TokenInfo]) -> Vec<(&str, Option<&str>)> {
+        tokens.iter().map(|t| (t.text.as_str(), t.kind.as_deref())).collect()
+    }
+
+    #[test]
+    fn test_scope_map_longest_prefix() {
+        let mut map = ScopeMap::empty();
+        map.insert("string", "string");
+        map.insert("string.regexp", "regex");
+        assert_eq!(map.kind_for("string.quoted.double.rust"), Some("string"));
+        assert_eq!(map.kind_for("string.regexp.js"), Some("regex"));
+        assert_eq!(map.kind_for("stringy"), None);
+        assert_eq!(map.kind_for("meta.block"), None);
+
+        map.extend_from_json(r#"{ "meta.block": "punctuation" }"#).unwrap();
+        assert_eq!(map.kind_for("meta.block.rust"), Some("punctuation"));
+        assert!(map.extend_from_json(r#"{ "meta": 1 }"#).is_err());
+    }
+
+    #[test]
+    fn test_translate_pattern() {
+        assert_eq!(translate_pattern(r"\h+"), r"(?m)[0-9A-Fa-f]+");
+        assert_eq!(translate_pattern(r"[\h_]"), r"(?m)[0-9A-Fa-f_]");
+        assert_eq!(translate_pattern(r"(?>a|b)++c*+"), r"(?m)(?:a|b)+c*");
+        assert_eq!(translate_pattern(r"\+\++"), r"(?m)\+\++");
+        assert_eq!(translate_pattern(r"(?<=\.)foo(?!\w)"), r"(?m)foo");
+        assert_eq!(translate_pattern(r"(?<name>a)\G"), r"(?m)(?<name>a)");
+        assert_eq!(translate_pattern(r"[]a[]"), r"(?m)[\]a\[]");
+        assert_eq!(translate_pattern(r"[[:alpha:]]"), r"(?m)[[:alpha:]]");
+        assert!(compile(r"\w+(?=\()").is_ok());
+    }
+
+    #[test]
+    fn test_match_and_captures() {
+        let g = grammar();
+        assert_eq!(g.name, "Test");
+        assert_eq!(g.skipped_rules(), 0);
+
+        let (tokens, state) = g.tokenize_line("fn main if 42 // c", &LexerState::default());
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                ("fn", Some("type")),
+                (" ", None),
+                ("main", Some("function")),
+                (" ", None),
+                ("if", Some("keyword")),
+                (" ", None),
+                ("42", Some("number")),
+                (" ", None),
+                ("// c", Some("comment")),
+            ]
+        );
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_nested_regions() {
+        let g = grammar();
+        let (tokens, _) = g.tokenize_line(r#"x "a\n${if "b"}c""#, &LexerState::default());
+        assert_eq!(
+            kinds(&tokens),
+            vec![
+                ("x ", None),
+                ("\"a", Some("string")),
+                ("\\n", Some("string.escape")),
+                ("${", Some("string")),
+                ("if", Some("keyword")),
+                // The interpolation has no kind of its own, so the string's applies.
+                (" \"b\"}c\"", Some("string")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiline_state() {
+        let g = grammar();
+        let (_, state) = g.tokenize_line("a /* open", &LexerState::default());
+        assert!(!state.is_default());
+        let (tokens, state) = g.tokenize_line("still */ 1", &state);
+        assert_eq!(
+            kinds(&tokens),
+            vec![("still */", Some("comment")), (" ", None), ("1", Some("number"))]
+        );
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_back_references_in_end() {
+        let g = grammar();
+        let (_, s1) = g.tokenize_line("cat <<EOF", &LexerState::default());
+        let (tokens, s2) = g.tokenize_line("if EOFx", &s1);
+        assert_eq!(kinds(&tokens), vec![("if EOFx", Some("string"))]);
+        assert_eq!(s1, s2);
+        let (_, s3) = g.tokenize_line("EOF", &s2);
+        assert!(s3.is_default());
+
+        // A different delimiter is a different state.
+        let (_, other) = g.tokenize_line("cat <<END", &LexerState::default());
+        assert_ne!(s1, other);
+
+        // Each delimiter compiles an `end` of its own, but they don't pile up.
+        for i in 0..MAX_DYNAMIC_ENDS * 2 {
+            let (_, state) = g.tokenize_line(&format!("cat <<EOF{i}"), &LexerState::default());
+            g.tokenize_line("if", &state);
+        }
+        assert!(g.dynamic_cache.lock().unwrap().len() <= MAX_DYNAMIC_ENDS);
+    }
+
+    #[test]
+    fn test_while_rule() {
+        let g = grammar();
+        let (tokens, state) = g.tokenize_line("> quoted", &LexerState::default());
+        assert_eq!(kinds(&tokens), vec![("> quoted", Some("comment"))]);
+        let (tokens, state) = g.tokenize_line("> more", &state);
+        assert_eq!(kinds(&tokens), vec![("> more", Some("comment"))]);
+        let (tokens, state) = g.tokenize_line("if", &state);
+        assert_eq!(kinds(&tokens), vec![("if", Some("keyword"))]);
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_invalid_rules_are_skipped() {
+        let text = r##"{ "scopeName": "source.x", "patterns": [
+            { "match": "(unclosed", "name": "keyword" },
+            { "match": "ok", "name": "keyword" },
+            { "include": "source.other" },
+            { "include": "#missing" }
+        ] }"##;
+        let g = Grammar::parse(text, &ScopeMap::default()).unwrap();
+        assert_eq!(g.name, "x");
+        assert_eq!(g.skipped_rules(), 1);
+        let (tokens, _) = g.tokenize_line("is ok", &LexerState::default());
+        assert_eq!(kinds(&tokens), vec![("is ", None), ("ok", Some("keyword"))]);
+
+        assert!(Grammar::parse("{}", &ScopeMap::default()).is_err());
+        assert!(Grammar::parse(r#"{ "scopeName": "x" }"#, &ScopeMap::default()).is_err());
+    }
+
+    #[test]
+    fn test_empty_matches_terminate() {
+        let text = r#"{ "scopeName": "source.x", "patterns": [
+            { "begin": "(?=y)", "end": "z", "name": "comment" },
+            { "begin": "x?", "end": "", "name": "keyword" },
+            { "match": "a*", "name": "number" }
+        ] }"#;
+        let g = Grammar::parse(text, &ScopeMap::default()).unwrap();
+        // A `begin` pattern that consists of nothing but lookarounds can't work.
+        assert_eq!(g.skipped_rules(), 1);
+
+        let (tokens, state) = g.tokenize_line("xbaax", &LexerState::default());
+        let text: String = tokens.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(text, "xbaax");
+        assert_eq!(tokens[0].kind.as_deref(), Some("keyword"));
+        assert!(state.is_default());
+    }
+}
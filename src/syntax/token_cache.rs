@@ -0,0 +1,371 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A memory-bounded cache of the tokens of highlighted lines.
+//!
+//! A cached line doesn't store [`TokenInfo`]s, which carry a copy of their text and kind.
+//! Instead, each token is a span into the line with a kind ID that's interned once for
+//! all documents. The tokens are rebuilt from the line's text when they're looked up.
+//! Once the cache exceeds its memory limit, the least recently used lines are evicted.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::mem;
+use std::num::NonZeroU32;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+
+use crate::syntax::highlighter::TokenInfo;
+
+/// The memory limit of a token cache, unless configured otherwise.
+pub const DEFAULT_TOKEN_CACHE_LIMIT: usize = 16 * 1024 * 1024;
+
+/// The token kinds of all documents, interned.
+static KINDS: Lazy<RwLock<KindTable>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct KindTable {
+    ids: HashMap<Box<str>, KindId>,
+    names: Vec<Box<str>>,
+}
+
+/// Identifies an interned token kind, like `keyword` or `string.escape`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KindId(NonZeroU32);
+
+/// Returns the ID of the given token kind, interning it if it's new.
+pub fn intern_kind(kind: &str) -> KindId {
+    if let Some(&id) = KINDS.read().unwrap().ids.get(kind) {
+        return id;
+    }
+
+    let mut kinds = KINDS.write().unwrap();
+    if let Some(&id) = kinds.ids.get(kind) {
+        return id;
+    }
+    let id = KindId(NonZeroU32::new(kinds.names.len() as u32 + 1).unwrap());
+    kinds.names.push(kind.into());
+    kinds.ids.insert(kind.into(), id);
+    id
+}
+
+/// Returns the name of an interned token kind.
+pub fn kind_name(id: KindId) -> String {
+    KINDS.read().unwrap().names[id.0.get() as usize - 1].to_string()
+}
+
+/// A token, relative to the start of its line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    offset: u32,
+    len: u32,
+    kind: Option<KindId>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedLine {
+    content_hash: u64,
+    spans: Box<[Span]>,
+    /// The cache's clock when the line was last cached or looked up.
+    last_used: Cell<u64>,
+}
+
+impl CachedLine {
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<(usize, CachedLine)>() + mem::size_of_val(&*self.spans)
+    }
+}
+
+/// The tokens of highlighted lines, by line number.
+#[derive(Debug, Clone)]
+pub struct TokenCache {
+    lines: HashMap<usize, CachedLine>,
+    /// Incremented on every access, to tell the least recently used lines.
+    clock: Cell<u64>,
+    /// The bytes held by the cached lines, see [`TokenCache::memory_usage`].
+    bytes: usize,
+    limit: usize,
+    evictions: usize,
+}
+
+impl Default for TokenCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_TOKEN_CACHE_LIMIT)
+    }
+}
+
+impl TokenCache {
+    /// Creates an empty cache that holds up to `limit` bytes.
+    pub fn new(limit: usize) -> Self {
+        Self { lines: HashMap::new(), clock: Cell::new(0), bytes: 0, limit, evictions: 0 }
+    }
+
+    /// Caches the tokens of a line with the given content hash.
+    ///
+    /// Returns false and caches nothing if the tokens don't fit the compact representation,
+    /// i.e. if they're out of order or the line is longer than 4 GiB.
+    pub fn insert(&mut self, line_number: usize, content_hash: u64, tokens: &[TokenInfo]) -> bool {
+        let mut end = 0;
+        let mut spans = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            if token.start_offset < end || token.end_offset < token.start_offset {
+                return false;
+            }
+            let (Ok(offset), Ok(len)) = (
+                u32::try_from(token.start_offset),
+                u32::try_from(token.end_offset - token.start_offset),
+            ) else {
+                return false;
+            };
+            spans.push(Span { offset, len, kind: token.kind.as_deref().map(intern_kind) });
+            end = token.end_offset;
+        }
+
+        self.remove(line_number);
+        let line =
+            CachedLine { content_hash, spans: spans.into(), last_used: Cell::new(self.tick()) };
+        self.bytes += line.memory_usage();
+        self.lines.insert(line_number, line);
+
+        if self.bytes > self.limit {
+            self.evict();
+        }
+        true
+    }
+
+    /// Returns whether tokens are cached for the line with the given content hash.
+    pub fn contains(&self, line_number: usize, content_hash: u64) -> bool {
+        self.content_hash(line_number) == Some(content_hash)
+    }
+
+    /// Returns whether any tokens are cached for the line.
+    pub fn contains_line(&self, line_number: usize) -> bool {
+        self.lines.contains_key(&line_number)
+    }
+
+    /// Returns the content hash the line's tokens were cached with.
+    pub fn content_hash(&self, line_number: usize) -> Option<u64> {
+        self.lines.get(&line_number).map(|line| line.content_hash)
+    }
+
+    /// Rebuilds the cached tokens of the line from its text and marks it as recently used.
+    ///
+    /// `text` must be the content the tokens were cached for. Returns `None` if nothing
+    /// is cached or if the tokens don't fit `text`.
+    pub fn tokens(&self, line_number: usize, text: &str) -> Option<Vec<TokenInfo>> {
+        let line = self.lines.get(&line_number)?;
+        line.last_used.set(self.tick());
+
+        let kinds = KINDS.read().unwrap();
+        line.spans
+            .iter()
+            .map(|span| {
+                let start = span.offset as usize;
+                let end = start + span.len as usize;
+                let kind = span.kind.map(|id| kinds.names[id.0.get() as usize - 1].to_string());
+                Some(TokenInfo::new(text.get(start..end)?.to_string(), kind, start, end))
+            })
+            .collect()
+    }
+
+    /// Removes the tokens of the line.
+    pub fn remove(&mut self, line_number: usize) {
+        if let Some(line) = self.lines.remove(&line_number) {
+            self.bytes -= line.memory_usage();
+        }
+    }
+
+    /// Removes all cached lines.
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.bytes = 0;
+    }
+
+    /// Moves the lines at and below `line_number` down by `count` lines.
+    pub fn insert_lines(&mut self, line_number: usize, count: usize) {
+        self.lines = mem::take(&mut self.lines)
+            .into_iter()
+            .map(|(n, line)| if n >= line_number { (n + count, line) } else { (n, line) })
+            .collect();
+    }
+
+    /// Removes the `count` lines starting at `line_number` and moves the lines below them up.
+    pub fn remove_lines(&mut self, line_number: usize, count: usize) {
+        let end = line_number + count;
+        let mut bytes = 0;
+        self.lines = mem::take(&mut self.lines)
+            .into_iter()
+            .filter_map(|(n, line)| {
+                let n = if n < line_number {
+                    n
+                } else if n >= end {
+                    n - count
+                } else {
+                    return None;
+                };
+                bytes += line.memory_usage();
+                Some((n, line))
+            })
+            .collect();
+        self.bytes = bytes;
+    }
+
+    /// Returns the number of cached lines.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Returns whether no lines are cached.
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Returns the bytes held by the cached lines, including their entries in the table
+    /// but not the table's spare capacity.
+    pub fn memory_usage(&self) -> usize {
+        self.bytes
+    }
+
+    /// Returns the number of bytes the cache may hold.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Sets the number of bytes the cache may hold, evicting lines if it now holds more.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        if self.bytes > self.limit {
+            self.evict();
+        }
+    }
+
+    /// Returns the number of lines evicted to stay within the limit so far.
+    pub fn evictions(&self) -> usize {
+        self.evictions
+    }
+
+    fn tick(&self) -> u64 {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        now
+    }
+
+    /// Evicts the least recently used lines until the cache is a quarter below its limit,
+    /// so that the lines cached next don't immediately cause another eviction.
+    fn evict(&mut self) {
+        let target = self.limit / 4 * 3;
+        let mut by_age: Vec<_> =
+            self.lines.iter().map(|(&n, line)| (line.last_used.get(), n)).collect();
+        by_age.sort_unstable();
+
+        for (_, line_number) in by_age {
+            if self.bytes <= target {
+                break;
+            }
+            self.remove(line_number);
+            self.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens_for(text: &str) -> Vec<TokenInfo> {
+        let split = text.find(' ').unwrap_or(text.len());
+        vec![
+            TokenInfo::highlighted(text[..split].to_string(), "keyword".to_string(), 0, split),
+            TokenInfo::plain_text(text[split..].to_string(), split, text.len()),
+        ]
+    }
+
+    #[test]
+    fn test_interning() {
+        let id = intern_kind("string.escape");
+        assert_eq!(intern_kind("string.escape"), id);
+        assert_ne!(intern_kind("string"), id);
+        assert_eq!(kind_name(id), "string.escape");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut cache = TokenCache::default();
+        let text = "fn main() {}";
+        assert!(cache.insert(3, 42, &tokens_for(text)));
+
+        assert!(cache.contains(3, 42));
+        assert!(!cache.contains(3, 43));
+        assert_eq!(cache.tokens(3, text), Some(tokens_for(text)));
+        assert_eq!(cache.tokens(4, text), None);
+
+        // Text that the spans don't fit is rejected rather than sliced wrongly.
+        assert_eq!(cache.tokens(3, "fn"), None);
+
+        cache.remove(3);
+        assert!(cache.is_empty());
+        assert_eq!(cache.memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut cache = TokenCache::default();
+        cache.insert(0, 0, &tokens_for("let x"));
+        let one_line = cache.memory_usage();
+        assert!(one_line >= 2 * mem::size_of::<Span>());
+
+        cache.insert(1, 0, &tokens_for("let y"));
+        assert_eq!(cache.memory_usage(), 2 * one_line);
+
+        // Replacing a line doesn't count it twice.
+        cache.insert(1, 1, &tokens_for("let z"));
+        assert_eq!(cache.memory_usage(), 2 * one_line);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let text = "let x";
+        let mut cache = TokenCache::default();
+        cache.insert(0, 0, &tokens_for(text));
+        let line_size = cache.memory_usage();
+        cache.set_limit(8 * line_size);
+
+        for line_number in 1..8 {
+            cache.insert(line_number, 0, &tokens_for(text));
+        }
+        assert_eq!(cache.len(), 8);
+
+        // Line 0 is the oldest, but looking it up makes it the most recently used.
+        assert!(cache.tokens(0, text).is_some());
+        cache.insert(8, 0, &tokens_for(text));
+
+        assert!(cache.memory_usage() <= cache.limit());
+        assert_eq!(cache.len(), 6);
+        assert_eq!(cache.evictions(), 3);
+        assert!(cache.contains_line(0) && cache.contains_line(8));
+        assert!((1..4).all(|n| !cache.contains_line(n)));
+    }
+
+    #[test]
+    fn test_shift_lines() {
+        let mut cache = TokenCache::default();
+        for line_number in 0..5 {
+            cache.insert(line_number, line_number as u64, &tokens_for("x"));
+        }
+        let bytes = cache.memory_usage();
+
+        cache.insert_lines(2, 3);
+        assert_eq!(cache.content_hash(1), Some(1));
+        assert_eq!(cache.content_hash(2), None);
+        assert_eq!(cache.content_hash(5), Some(2));
+        assert_eq!(cache.memory_usage(), bytes);
+
+        cache.remove_lines(1, 5);
+        assert_eq!(cache.content_hash(0), Some(0));
+        assert_eq!(cache.content_hash(1), Some(3));
+        assert_eq!(cache.content_hash(2), Some(4));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.memory_usage(), bytes / 5 * 3);
+    }
+}
@@ -0,0 +1,526 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Language definitions loaded from the user's config directory.
+//!
+//! Every `*.json` file in the `syntax` folder of [`crate::sys::config_dir`]
+//! defines one language. All keys except `name` are optional:
+//!
+//! ```json
+//! {
+//!     "name": "Acme Config",
+//!     "aliases": ["acme"],
+//!     "filePatterns": ["*.acme", "Acmefile"],
+//!     "lineComment": "#",
+//!     "blockComment": ["/*", "*/"],
+//!     "keywords": { "keyword": ["if", "else"], "constant": ["on", "off"] },
+//!     "regions": [{ "kind": "string", "start": "\"", "end": "\"", "escapable": true }],
+//!     "rules": [{ "kind": "number", "match": "\\b\\d+\\b" }]
+//! }
+//! ```
+//!
+//! Comment markers and keywords are literal text, whereas the `start`, `end` and `match`
+//! fields are regular expressions. Comments are tried first, then regions, keywords and rules,
+//! each in the order they are listed. The token kinds are the same ones the built-in
+//! grammars use, e.g. `keyword`, `type`, `string`, `comment` or `number`.
+//!
+//! Files ending in `.tmLanguage.json` are instead loaded as TextMate grammars, the format
+//! VS Code extensions ship, see [`super::textmate`]. Their scopes are mapped onto token kinds
+//! by scope prefix. The table can be extended with a `scopes.json` file in the same
+//! folder, e.g. `{ "entity.name.tag": "keyword" }`.
+//!
+//! Definitions are registered alongside the built-in languages as [`Language::Custom`]
+//! and live for the rest of the process.
+
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use super::language::Language;
+use super::lexer::Lexer;
+use super::textmate::{Grammar, ScopeMap};
+use crate::json::{self, Value};
+use crate::sys;
+
+/// A region delimited by a start and an end pattern, such as a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub kind: String,
+    pub start: String,
+    pub end: String,
+    /// Whether a backslash escapes the end pattern.
+    pub escapable: bool,
+}
+
+/// A single-line regex rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub kind: String,
+    pub pattern: String,
+}
+
+/// A language defined by the user.
+#[derive(Debug, Clone, Default)]
+pub struct UserLanguage {
+    /// The display name, e.g. "Acme Config".
+    pub name: String,
+    /// Additional names used in modelines and shebangs, e.g. "acme".
+    pub aliases: Vec<String>,
+    /// File name patterns with `*` and `?` wildcards, e.g. `*.acme`.
+    pub file_patterns: Vec<String>,
+    pub line_comment: Option<String>,
+    pub block_comment: Option<(String, String)>,
+    /// Lists of literal words, keyed by their token kind.
+    pub keywords: Vec<(String, Vec<String>)>,
+    pub regions: Vec<Region>,
+    pub rules: Vec<Rule>,
+    /// If set, the language is highlighted by this grammar instead of the rules above.
+    pub grammar: Option<Arc<Grammar>>,
+}
+
+impl UserLanguage {
+    /// Parses a definition from its JSON representation.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let root = json::parse(text).map_err(|err| err.to_string())?;
+        if root.as_object().is_none() {
+            return Err(format!("Expected an object, found {}", root.type_name()));
+        }
+
+        let name = match root.get("name") {
+            Some(v) => expect_str(v, "name")?.trim().to_string(),
+            None => return Err("Missing \"name\"".to_string()),
+        };
+        if name.is_empty() {
+            return Err("\"name\" must not be empty".to_string());
+        }
+
+        let mut def = UserLanguage { name, ..Default::default() };
+
+        if let Some(v) = root.get("aliases") {
+            def.aliases = expect_str_array(v, "aliases")?;
+        }
+        if let Some(v) = root.get("filePatterns") {
+            def.file_patterns = expect_str_array(v, "filePatterns")?;
+        }
+        if let Some(v) = root.get("lineComment") {
+            def.line_comment = Some(expect_str(v, "lineComment")?.to_string());
+        }
+        if let Some(v) = root.get("blockComment") {
+            match expect_str_array(v, "blockComment")?.as_slice() {
+                [start, end] => def.block_comment = Some((start.clone(), end.clone())),
+                _ => return Err("\"blockComment\" must be a [start, end] pair".to_string()),
+            }
+        }
+        if let Some(v) = root.get("keywords") {
+            let Some(kinds) = v.as_object() else {
+                return Err(format!(
+                    "Expected \"keywords\" to be an object, found {}",
+                    v.type_name()
+                ));
+            };
+            for (kind, words) in kinds {
+                let words = expect_str_array(words, &format!("keywords.{kind}"))?;
+                def.keywords.push((kind.clone(), words));
+            }
+        }
+        if let Some(v) = root.get("regions") {
+            for (i, region) in expect_array(v, "regions")?.iter().enumerate() {
+                let field = |key: &str| -> Result<String, String> {
+                    match region.get(key) {
+                        Some(v) => Ok(expect_str(v, &format!("regions[{i}].{key}"))?.to_string()),
+                        None => Err(format!("Missing \"{key}\" in regions[{i}]")),
+                    }
+                };
+                def.regions.push(Region {
+                    kind: field("kind")?,
+                    start: field("start")?,
+                    end: field("end")?,
+                    escapable: region.get("escapable").and_then(Value::as_bool).unwrap_or(false),
+                });
+            }
+        }
+        if let Some(v) = root.get("rules") {
+            for (i, rule) in expect_array(v, "rules")?.iter().enumerate() {
+                let field = |key: &str| -> Result<String, String> {
+                    match rule.get(key) {
+                        Some(v) => Ok(expect_str(v, &format!("rules[{i}].{key}"))?.to_string()),
+                        None => Err(format!("Missing \"{key}\" in rules[{i}]")),
+                    }
+                };
+                def.rules.push(Rule { kind: field("kind")?, pattern: field("match")? });
+            }
+        }
+
+        Ok(def)
+    }
+
+    /// Builds a lexer from the definition.
+    /// Fails if any of the regular expressions are invalid.
+    pub fn build_lexer(&self) -> Result<Lexer, String> {
+        let mut lexer = Lexer::new();
+
+        if let Some(marker) = &self.line_comment {
+            let exp = format!("{}.*$", regex::escape(marker));
+            lexer.try_keyword("comment", &exp).map_err(|err| format!("lineComment: {err}"))?;
+        }
+        if let Some((start, end)) = &self.block_comment {
+            lexer
+                .try_bounded("comment", &regex::escape(start), &regex::escape(end), false)
+                .map_err(|err| format!("blockComment: {err}"))?;
+        }
+        for (i, region) in self.regions.iter().enumerate() {
+            lexer
+                .try_bounded(&region.kind, &region.start, &region.end, region.escapable)
+                .map_err(|err| format!("regions[{i}]: {err}"))?;
+        }
+        for (kind, words) in &self.keywords {
+            if words.is_empty() {
+                continue;
+            }
+            let words: Vec<_> = words.iter().map(|w| regex::escape(w)).collect();
+            let exp = format!(r"\b(?:{})\b", words.join("|"));
+            lexer.try_keyword(kind, &exp).map_err(|err| format!("keywords.{kind}: {err}"))?;
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            lexer
+                .try_keyword(&rule.kind, &rule.pattern)
+                .map_err(|err| format!("rules[{i}]: {err}"))?;
+        }
+
+        Ok(lexer)
+    }
+
+    /// Wraps a TextMate grammar. The last segment of its scope name (`rust` in
+    /// `source.rust`) becomes an alias and its file types become file patterns.
+    pub fn from_grammar(grammar: Grammar) -> Self {
+        let alias = grammar.scope_name.rsplit('.').next().unwrap_or_default();
+        let mut file_patterns = Vec::new();
+        for file_type in &grammar.file_types {
+            // `fileTypes` mixes extensions (`rs`) and whole file names (`Makefile`).
+            file_patterns.push(format!("*.{file_type}"));
+            file_patterns.push(file_type.clone());
+        }
+        UserLanguage {
+            name: grammar.name.clone(),
+            aliases: if alias.is_empty() { Vec::new() } else { vec![alias.to_string()] },
+            file_patterns,
+            grammar: Some(Arc::new(grammar)),
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether one of the definition's file patterns matches the given file name.
+    pub fn matches_file_name(&self, file_name: &str) -> bool {
+        self.file_patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), file_name.as_bytes()))
+    }
+
+    /// Returns the extension of the first `*.ext` file pattern, if any.
+    pub fn primary_extension(&self) -> Option<&str> {
+        self.file_patterns
+            .iter()
+            .find_map(|p| p.strip_prefix("*."))
+            .filter(|ext| !ext.contains(['*', '?']))
+    }
+}
+
+fn expect_str<'a>(value: &'a Value, key: &str) -> Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("Expected \"{key}\" to be a string, found {}", value.type_name()))
+}
+
+fn expect_array<'a>(value: &'a Value, key: &str) -> Result<&'a [Value], String> {
+    value
+        .as_array()
+        .ok_or_else(|| format!("Expected \"{key}\" to be an array, found {}", value.type_name()))
+}
+
+fn expect_str_array(value: &Value, key: &str) -> Result<Vec<String>, String> {
+    expect_array(value, key)?
+        .iter()
+        .enumerate()
+        .map(|(i, v)| expect_str(v, &format!("{key}[{i}]")).map(str::to_string))
+        .collect()
+}
+
+/// Matches a file name against a pattern where `*` matches any number of
+/// characters and `?` matches exactly one.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match.
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    p = bp + 1;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p.min(pattern.len())..].iter().all(|&c| c == b'*')
+}
+
+/// All registered definitions. Indices are the IDs used in [`Language::Custom`].
+/// The definitions are leaked so that their names can be handed out as `&'static str`.
+static REGISTRY: RwLock<Vec<&'static UserLanguage>> = RwLock::new(Vec::new());
+
+/// Registers a definition and returns the language that refers to it.
+/// The definition is validated first, so that later lexer construction can't fail.
+pub fn register(def: UserLanguage) -> Result<Language, String> {
+    if def.grammar.is_none() {
+        def.build_lexer()?;
+    }
+
+    let mut registry = REGISTRY.write().unwrap();
+    if registry.iter().any(|l| l.name.eq_ignore_ascii_case(&def.name)) {
+        return Err(format!("\"{}\" is already defined", def.name));
+    }
+    let id = u16::try_from(registry.len()).map_err(|_| "Too many languages".to_string())?;
+    registry.push(Box::leak(Box::new(def)));
+    Ok(Language::Custom(id))
+}
+
+/// Returns the definition with the given ID.
+pub fn get(id: u16) -> Option<&'static UserLanguage> {
+    REGISTRY.read().unwrap().get(id as usize).copied()
+}
+
+/// Returns all registered languages.
+pub fn languages() -> Vec<Language> {
+    let len = REGISTRY.read().unwrap().len();
+    (0..len as u16).map(Language::Custom).collect()
+}
+
+/// Finds a language by its name or one of its aliases, ignoring case.
+pub fn from_name(name: &str) -> Option<Language> {
+    let registry = REGISTRY.read().unwrap();
+    let id = registry.iter().position(|l| {
+        l.name.eq_ignore_ascii_case(name) || l.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+    })?;
+    Some(Language::Custom(id as u16))
+}
+
+/// Finds a language whose file patterns match the given file name.
+/// If several do, the one registered last wins.
+pub fn detect_file_name(file_name: &str) -> Option<Language> {
+    let registry = REGISTRY.read().unwrap();
+    let id = registry.iter().rposition(|l| l.matches_file_name(file_name))?;
+    Some(Language::Custom(id as u16))
+}
+
+/// The optional file in the `syntax` folder that extends the scope to kind table.
+const SCOPES_FILE_NAME: &str = "scopes.json";
+
+/// Loads and registers all definitions in the given directory.
+/// Returns a message for every file that failed to load.
+pub fn load_dir(dir: &Path) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    let mut paths: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter(|path| !path.ends_with(SCOPES_FILE_NAME))
+            .collect(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return errors,
+        Err(err) => {
+            errors.push(format!("{}: {err}", dir.display()));
+            return errors;
+        }
+    };
+    // Make the registration order, and thus precedence, independent of the file system.
+    paths.sort();
+
+    let mut scopes = ScopeMap::default();
+    let scopes_path = dir.join(SCOPES_FILE_NAME);
+    match std::fs::read_to_string(&scopes_path) {
+        Ok(text) => {
+            if let Err(err) = scopes.extend_from_json(&text) {
+                errors.push(format!("{}: {err}", scopes_path.display()));
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => errors.push(format!("{}: {err}", scopes_path.display())),
+    }
+
+    for path in paths {
+        let is_grammar = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(".tmLanguage.json"));
+        let result = std::fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| {
+                if is_grammar {
+                    Grammar::parse(&text, &scopes).map(UserLanguage::from_grammar)
+                } else {
+                    UserLanguage::parse(&text)
+                }
+            })
+            .and_then(register);
+        if let Err(err) = result {
+            errors.push(format!("{}: {err}", path.display()));
+        }
+    }
+
+    errors
+}
+
+/// Loads the definitions from the `syntax` folder in the user's config directory.
+/// Returns a message for every file that failed to load.
+pub fn load_from_config_dir() -> Vec<String> {
+    match sys::config_dir() {
+        Some(dir) => load_dir(&dir.join("syntax")),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACME: &str = r##"{
+        // Comments are allowed.
+        "name": "Acme Test Config",
+        "aliases": ["acmetest"],
+        "filePatterns": ["*.acmetest", "Acmetestfile"],
+        "lineComment": "#",
+        "blockComment": ["(*", "*)"],
+        "keywords": { "keyword": ["if", "else"], "constant": ["on", "off"] },
+        "regions": [{ "kind": "string", "start": "\"", "end": "\"", "escapable": true }],
+        "rules": [{ "kind": "number", "match": "\\b\\d+\\b" }],
+    }"##;
+
+    #[test]
+    fn test_parse_definition() {
+        let def = UserLanguage::parse(ACME).unwrap();
+        assert_eq!(def.name, "Acme Test Config");
+        assert_eq!(def.aliases, ["acmetest"]);
+        assert_eq!(def.block_comment, Some(("(*".to_string(), "*)".to_string())));
+        assert_eq!(def.keywords.len(), 2);
+        assert!(def.regions[0].escapable);
+        assert_eq!(def.rules[0].pattern, r"\b\d+\b");
+        assert_eq!(def.primary_extension(), Some("acmetest"));
+    }
+
+    #[test]
+    fn test_definition_errors() {
+        let err = |text| UserLanguage::parse(text).unwrap_err();
+        assert_eq!(err("[]"), "Expected an object, found an array");
+        assert_eq!(err("{}"), "Missing \"name\"");
+        assert_eq!(err(r#"{"name": 1}"#), "Expected \"name\" to be a string, found a number");
+        assert_eq!(
+            err(r#"{"name": "x", "aliases": ["a", 2]}"#),
+            "Expected \"aliases[1]\" to be a string, found a number"
+        );
+        assert_eq!(
+            err(r#"{"name": "x", "rules": [{"kind": "k"}]}"#),
+            "Missing \"match\" in rules[0]"
+        );
+        assert!(err("{\"name\": \"x\",\n\"rules\": [}").contains("line 2"));
+
+        let def = UserLanguage::parse(r#"{"name": "x", "rules": [{"kind": "k", "match": "("}]}"#)
+            .unwrap();
+        assert!(def.build_lexer().unwrap_err().starts_with("rules[0]: "));
+        assert!(register(def).is_err());
+    }
+
+    #[test]
+    fn test_tokenize_with_definition() {
+        let lexer = UserLanguage::parse(ACME).unwrap().build_lexer().unwrap();
+        let (tokens, _) = lexer.tokenize_line(r#"if x "a\"b" 42 # on"#, &Default::default());
+        let kinds: Vec<_> =
+            tokens.iter().filter_map(|t| Some((t.kind.as_deref()?, t.text.as_str()))).collect();
+        assert_eq!(
+            kinds,
+            [("keyword", "if"), ("string", r#""a\"b""#), ("number", "42"), ("comment", "# on")]
+        );
+
+        // Block comments continue on the next line.
+        let (_, state) = lexer.tokenize_line("(* open", &Default::default());
+        let (tokens, state) = lexer.tokenize_line("still *) else", &state);
+        assert_eq!(tokens[0].kind.as_deref(), Some("comment"));
+        assert!(state.is_default());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let m = |p: &str, n: &str| glob_match(p.as_bytes(), n.as_bytes());
+        assert!(m("*.acme", "x.acme"));
+        assert!(m("*.acme", ".acme"));
+        assert!(!m("*.acme", "x.acme.bak"));
+        assert!(m("Acme*", "Acmefile"));
+        assert!(m("a?c", "abc"));
+        assert!(!m("a?c", "ac"));
+        assert!(m("*.d/*.conf", "x.d/y.conf"));
+        assert!(m("*", ""));
+    }
+
+    #[test]
+    fn test_register_and_detect() {
+        let language = register(UserLanguage::parse(ACME).unwrap()).unwrap();
+        assert!(language.is_custom());
+        assert_eq!(language.display_name(), "Acme Test Config");
+        assert_eq!(language.primary_extension(), "acmetest");
+        assert!(languages().contains(&language));
+
+        assert_eq!(Language::from_name("ACMETEST"), Some(language));
+        assert_eq!(detect_file_name("Acmetestfile"), Some(language));
+        let detector = super::super::LanguageDetector::new();
+        assert_eq!(detector.detect_language("/tmp/x.acmetest"), language);
+
+        // Names must be unique.
+        assert!(register(UserLanguage::parse(ACME).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_dir_with_grammar() {
+        let dir = std::env::temp_dir().join(format!("edit-syntax-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("scopes.json"), r#"{ "markup.zap": "keyword" }"#).unwrap();
+        std::fs::write(
+            dir.join("zap.tmLanguage.json"),
+            r#"{
+                "name": "Zap Test Grammar",
+                "scopeName": "source.zaptest",
+                "fileTypes": ["zaptest"],
+                "patterns": [{ "match": "\\bzap\\b", "name": "markup.zap.word" }]
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("broken.json"), "{").unwrap();
+
+        let errors = load_dir(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("broken.json"));
+
+        let Some(Language::Custom(id)) = detect_file_name("x.zaptest") else {
+            panic!("grammar not registered");
+        };
+        let def = get(id).unwrap();
+        assert_eq!(def.name, "Zap Test Grammar");
+        assert_eq!(def.aliases, ["zaptest"]);
+
+        let grammar = def.grammar.as_ref().unwrap();
+        let (tokens, _) = grammar.tokenize_line("a zap", &Default::default());
+        let kinds: Vec<_> =
+            tokens.iter().filter_map(|t| Some((t.kind.as_deref()?, t.text.as_str()))).collect();
+        assert_eq!(kinds, [("keyword", "zap")]);
+    }
+}
@@ -15,3 +15,12 @@ pub use std::fs::canonicalize;
 pub use unix::*;
 #[cfg(windows)]
 pub use windows::*;
+
+/// How much memory the process uses, in KiB.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The resident set size: the physical memory currently in use.
+    pub resident_kb: u64,
+    /// The highest resident set size so far.
+    pub peak_resident_kb: u64,
+}
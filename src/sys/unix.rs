@@ -10,12 +10,13 @@ use std::ffi::{CStr, c_int, c_void};
 use std::fs::{self, File};
 use std::mem::{self, ManuallyDrop, MaybeUninit};
 use std::os::fd::{AsRawFd as _, FromRawFd as _};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr::{self, NonNull, null_mut};
 use std::{thread, time};
 
 use crate::arena::{Arena, ArenaString, scratch_arena};
 use crate::helpers::*;
+use crate::sys::MemoryUsage;
 use crate::{apperr, arena_format};
 
 #[cfg(target_os = "netbsd")]
@@ -360,6 +361,48 @@ pub fn open_stdin_if_redirected() -> Option<File> {
     }
 }
 
+/// Returns the directory for user configuration files, following the XDG Base Directory
+/// specification: `$XDG_CONFIG_HOME/edit`, or `$HOME/.config/edit` as a fallback.
+pub fn config_dir() -> Option<PathBuf> {
+    let non_empty = |name| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    let base = non_empty("XDG_CONFIG_HOME").or_else(|| Some(non_empty("HOME")?.join(".config")))?;
+    Some(base.join("edit"))
+}
+
+/// Returns how much memory the process uses.
+///
+/// On Linux this is read from `/proc/self/status`. Elsewhere, or if that fails, only the peak
+/// is known from `getrusage`, which then stands in for the current usage as well.
+pub fn memory_usage() -> Option<MemoryUsage> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(usage) = fs::read_to_string("/proc/self/status").ok().and_then(|s| parse_proc_status(&s)) {
+        return Some(usage);
+    }
+
+    unsafe {
+        let mut usage: libc::rusage = mem::zeroed();
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0 {
+            return None;
+        }
+        // macOS reports bytes, everyone else KiB.
+        let peak = usage.ru_maxrss.max(0) as u64;
+        let peak_kb = if cfg!(target_vendor = "apple") { peak / 1024 } else { peak };
+        Some(MemoryUsage { resident_kb: peak_kb, peak_resident_kb: peak_kb })
+    }
+}
+
+/// Extracts the current (`VmRSS`) and peak (`VmHWM`) resident set size from `/proc/self/status`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_proc_status(status: &str) -> Option<MemoryUsage> {
+    let field = |name: &str| -> Option<u64> {
+        let line = status.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?;
+        line.trim().strip_suffix("kB")?.trim().parse().ok()
+    };
+    let resident_kb = field("VmRSS")?;
+    let peak_resident_kb = field("VmHWM").unwrap_or(resident_kb).max(resident_kb);
+    Some(MemoryUsage { resident_kb, peak_resident_kb })
+}
+
 #[derive(Clone, PartialEq, Eq)]
 pub struct FileId {
     st_dev: libc::dev_t,
@@ -12,13 +12,14 @@ use std::{mem, time};
 
 use windows_sys::Win32::Storage::FileSystem;
 use windows_sys::Win32::System::Diagnostics::Debug;
-use windows_sys::Win32::System::{Console, IO, LibraryLoader, Memory, Threading};
+use windows_sys::Win32::System::{Console, IO, LibraryLoader, Memory, ProcessStatus, Threading};
 use windows_sys::Win32::{Foundation, Globalization};
 use windows_sys::w;
 
 use crate::apperr;
 use crate::arena::{Arena, ArenaString, scratch_arena};
 use crate::helpers::*;
+use crate::sys::MemoryUsage;
 
 type ReadConsoleInputExW = unsafe extern "system" fn(
     h_console_input: Foundation::HANDLE,
@@ -397,6 +398,27 @@ pub fn open_stdin_if_redirected() -> Option<File> {
     }
 }
 
+/// Returns the directory for user configuration files: `%APPDATA%\edit`.
+pub fn config_dir() -> Option<PathBuf> {
+    let appdata = std::env::var_os("APPDATA").filter(|v| !v.is_empty())?;
+    Some(PathBuf::from(appdata).join("edit"))
+}
+
+/// Returns how much memory the process uses, i.e. its working set.
+pub fn memory_usage() -> Option<MemoryUsage> {
+    unsafe {
+        let mut counters: ProcessStatus::PROCESS_MEMORY_COUNTERS = mem::zeroed();
+        counters.cb = mem::size_of::<ProcessStatus::PROCESS_MEMORY_COUNTERS>() as u32;
+        if ProcessStatus::GetProcessMemoryInfo(Threading::GetCurrentProcess(), &mut counters, counters.cb) == 0 {
+            return None;
+        }
+        Some(MemoryUsage {
+            resident_kb: (counters.WorkingSetSize / 1024) as u64,
+            peak_resident_kb: (counters.PeakWorkingSetSize / 1024) as u64,
+        })
+    }
+}
+
 pub fn drives() -> impl Iterator<Item = char> {
     unsafe {
         let mut mask = FileSystem::GetLogicalDrives();
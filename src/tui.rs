@@ -1340,6 +1340,11 @@ impl<'a> Context<'a, '_> {
         self.tui.framebuffer.indexed(index)
     }
 
+    /// Returns the framebuffer's palette of indexed colors.
+    pub fn indexed_colors(&self) -> &[u32; INDEXED_COLORS_COUNT] {
+        self.tui.framebuffer.indexed_colors()
+    }
+
     /// Returns an indexed color from the framebuffer with the given alpha.
     /// See [`Framebuffer::indexed_alpha()`].
     #[inline]
@@ -0,0 +1,106 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Golden-file tests for the built-in grammars.
+//!
+//! Every file in `tests/golden/` is a sample whose language is detected from its name.
+//! Next to it, `<name>.golden` pins the token kinds of each line:
+//!
+//! ```text
+//!    2 | name = "edit"
+//!        attribute "name"
+//!        string "\"edit\""
+//! ```
+//!
+//! Run with `UPDATE_GOLDEN=1` to rewrite the `.golden` files after an intended change,
+//! then review the diff.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use edit::syntax::{Language, LanguageDetector, LexerState, SyntaxHighlighter};
+
+const GOLDEN_EXTENSION: &str = "golden";
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+/// Tokenizes `text` line by line, carrying the lexer state across lines,
+/// and renders the highlighted tokens in the golden file format.
+fn render(language: Language, text: &str) -> String {
+    let mut highlighter = SyntaxHighlighter::new(language);
+    let mut state = LexerState::default();
+    let mut out = String::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let (tokens, next) = highlighter.highlight_line_with_state(line, &state).unwrap();
+        state = next;
+
+        _ = writeln!(out, "{:4} | {line}", i + 1);
+        for token in &tokens {
+            if let Some(kind) = &token.kind {
+                _ = writeln!(out, "       {kind} {:?}", token.text);
+            }
+        }
+    }
+
+    // Lines without tokens shouldn't leave trailing whitespace behind.
+    out.lines().map(str::trim_end).fold(String::new(), |mut acc, line| {
+        acc.push_str(line);
+        acc.push('\n');
+        acc
+    })
+}
+
+#[test]
+fn test_golden_files() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let detector = LanguageDetector::new();
+
+    let mut samples: Vec<_> = fs::read_dir(golden_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_none_or(|ext| ext != GOLDEN_EXTENSION))
+        .collect();
+    samples.sort();
+    assert!(!samples.is_empty());
+
+    let mut failures = Vec::new();
+
+    for sample in &samples {
+        let language = detector.detect_language(sample);
+        assert_ne!(language, Language::PlainText, "{}: unknown language", sample.display());
+
+        let text = fs::read_to_string(sample).unwrap();
+        let actual = render(language, &text);
+
+        let mut golden = sample.clone().into_os_string();
+        golden.push(".");
+        golden.push(GOLDEN_EXTENSION);
+        let golden = PathBuf::from(golden);
+
+        if update {
+            fs::write(&golden, &actual).unwrap();
+            continue;
+        }
+
+        let expected = fs::read_to_string(&golden).unwrap_or_default();
+        if actual != expected.replace("\r\n", "\n") {
+            let line = actual.lines().zip(expected.lines()).position(|(a, e)| a != e);
+            failures.push(format!(
+                "{} differs from {} (first difference on golden line {})",
+                sample.display(),
+                golden.display(),
+                line.map_or(actual.lines().count().min(expected.lines().count()), |l| l) + 1,
+            ));
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{}\nRun with UPDATE_GOLDEN=1 to accept the new output.",
+        failures.join("\n")
+    );
+}
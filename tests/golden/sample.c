@@ -0,0 +1,29 @@
+/* Golden sample */
+#include <stdio.h>
+#include "config.h"
+#define MAX_LEN 256
+#if defined(DEBUG) && DEBUG > 1
+#  pragma once
+#endif
+
+typedef struct node {
+    uint32_t id;
+    const char *name;
+    struct node *next;
+} node_t;
+
+static int count_nodes(const node_t *head) {
+    int n = 0;
+    for (; head != NULL; head = head->next) n++;
+    return n;
+}
+
+int main(int argc, char **argv) {
+    char sep = '\n', quote = '\'', hex = '\x41';
+    wchar_t wide = L'w';
+    const char *msg = u8"h\u00e9llo \"world\"";
+    unsigned long big = 0xFFul + 10UL + 0b1010 + 1e-3f + .5;
+    bool ok = true;
+    printf("%s %d%c", msg, MAX_LEN, sep); // trailing comment
+    return ok ? EXIT_SUCCESS : EXIT_FAILURE;
+}
@@ -0,0 +1,24 @@
+@echo off
+rem Golden sample
+:: Another comment
+setlocal enabledelayedexpansion
+
+set "TARGET=%~dp0build"
+if not exist "%TARGET%" mkdir "%TARGET%"
+
+for %%f in (*.txt) do (
+    set /a count+=1
+    echo Processing %%f !count!
+)
+
+if %ERRORLEVEL% neq 0 goto :error
+call :build %1
+exit /b 0
+
+:build
+echo Building %~1 in %TARGET%
+goto :eof
+
+:error
+echo Failed with %ERRORLEVEL% 1>&2
+exit /b 1
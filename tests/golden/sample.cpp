@@ -0,0 +1,29 @@
+// Golden sample
+#include <vector>
+#include <memory>
+
+namespace app {
+
+template <typename T, std::size_t N = 4>
+class Buffer final : public Base<T> {
+public:
+    explicit Buffer(std::vector<T> items) noexcept : items_(std::move(items)) {}
+    [[nodiscard]] constexpr auto size() const -> std::size_t { return items_.size(); }
+private:
+    std::vector<T> items_;
+};
+
+}  // namespace app
+
+int main() {
+    auto buf = std::make_unique<app::Buffer<int>>(std::vector<int>{1'000'000, 2, 3});
+    auto raw = R"(C:\path\"quoted")";
+    auto tagged = R"sql(SELECT ")" FROM t)sql";
+    auto multi = R"(first line
+second line)";
+    char32_t c = U'x';
+    using namespace std::chrono_literals;
+    auto delay = 100ms;
+    if (buf != nullptr && delay > 0ms) { return 0; }
+    co_return;
+}
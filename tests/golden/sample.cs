@@ -0,0 +1,34 @@
+// Golden sample
+#nullable enable
+#define TRACE // Continued \
+    VERBOSE
+#region Usings
+using System;
+using System.Collections.Generic;
+#endregion
+
+namespace App.Services;
+
+[Serializable]
+[assembly: InternalsVisibleTo("App.Tests")]
+public sealed partial class UserService<TKey> : IService where TKey : notnull
+{
+    private readonly Dictionary<TKey, List<string>> _names = new();
+    public const int MaxUsers = 1_000;
+
+    public async Task<string?> GetNameAsync(TKey id, char sep = ',')
+    {
+        var path = @"C:\Users\""admin""";
+        var greeting = $"Hello {_names[id].Count} users\n";
+        var braces = $"a {{b}} c {sep}";
+        var json = """
+            { "id": 1 }
+            """;
+        var mixed = $@"{path}\log";
+        await Task.Delay(TimeSpan.FromSeconds(1.5));
+        return _names.TryGetValue(id, out var list) ? list.Count.ToString() : null;
+    }
+
+    public bool IsEmpty => _names.Count == 0 && true;
+    public decimal Price { get; init; } = 9.99m;
+}
@@ -0,0 +1,35 @@
+// Golden sample
+package main
+
+import (
+	"errors"
+	"fmt"
+)
+
+type Number interface {
+	~int | ~float64
+}
+
+type Pair[K comparable, V any] struct {
+	Key   K
+	Value V
+}
+
+/* Sum adds the values. */
+func Sum[T Number](values []T) (total T) {
+	for _, v := range values {
+		total += v
+	}
+	return
+}
+
+func main() {
+	query := `SELECT *
+FROM "users"`
+	r, nl := 'x', '\n'
+	nums := make([]float64, 0, 0x10)
+	nums = append(nums, 1.5, 1e3, 0o17, 2i)
+	if err := errors.New("bad \"input\""); err != nil {
+		fmt.Println(query, r, nl, Sum[float64](nums), iota, true)
+	}
+}
@@ -0,0 +1,31 @@
+// Golden sample
+package com.example.app;
+
+import java.util.List;
+import java.util.Map;
+
+/**
+ * A small service.
+ */
+@SuppressWarnings("unchecked")
+public final class Repository<K extends Comparable<K>, V> implements Store<K, V> {
+    private static final int MAX_SIZE = 1_024;
+    private final Map<K, List<V>> items = new HashMap<>();
+
+    @Override
+    public Optional<V> find(K key, char sep) {
+        if (key == null || sep == '\'' || sep == '\u0041') {
+            return Optional.empty();
+        }
+        String query = """
+            SELECT * FROM "items"
+            WHERE id = ?""";
+        long total = 0xFFL + 10L + 0b101 + (long) 1.5e3d;
+        return items.getOrDefault(key, List.of()).stream().findFirst();
+    }
+
+    public @interface Marker {}
+
+    sealed interface Shape permits Circle, Square {}
+    non-sealed class Circle implements Shape { boolean round = true; }
+}
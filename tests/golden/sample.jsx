@@ -0,0 +1,22 @@
+// JSX elements and attributes
+/**
+ * Renders the items, see {@link Item}.
+ * @param {Object} props - mail@example.com isn't a tag
+ */
+export default function App({ items, onSelect }) {
+  const title = 'Items';
+  if (items.length < 1) return null;
+  return (
+    <div className="app" data-count={items.length}>
+      <h1 id='title'>{title}</h1>
+      <List items={items} onClick={() => onSelect(items[0])}
+            hidden />
+      <p>Don't forget the apostrophe.</p>
+      <p>
+        Don't break
+        the <em>text</em> {title}
+      </p>
+      <>{items.map(item => <Item key={item.id} {...item} />)}</>
+    </div>
+  );
+}
@@ -0,0 +1,27 @@
+# Golden sample
+<#
+.SYNOPSIS
+    Copies the build output.
+#>
+[CmdletBinding()]
+param(
+    [Parameter(Mandatory = $true)]
+    [string]$Source,
+    [int]$Retries = 3
+)
+
+$ErrorActionPreference = 'Stop'
+$items = @(Get-ChildItem -Path $Source -Filter "*.dll" -Recurse)
+$summary = @{ Count = $items.Count; Size = 10MB }
+
+foreach ($item in $items) {
+    if ($item.Length -gt 1KB -and $item.Name -notmatch '^test') {
+        Write-Host "Copying $($item.Name) to $env:TEMP `"now`""
+        Copy-Item $item.FullName -Destination $env:TEMP -Force
+    }
+}
+
+$text = @"
+Total: $($summary.Count)
+"@
+if ($null -eq $text) { return $false }
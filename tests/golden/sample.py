@@ -0,0 +1,14 @@
+"""Strings with escapes, %-formatting, str.format and f-strings."""
+import re
+
+def greet(name: str, count: int = 1) -> str:
+    r'''Greets `name`.
+
+    TODO: greet in other languages.
+    '''
+    doc = """Not a docstring"""  # XXX: or is it?
+    label = "tab\there \N{BULLET} \x41 and \q"
+    print("%s has %(count)05d items, 100%% done" % (name, count))
+    print('{0!r:>10} and {name}, not {{this}}'.format(name, name=name))
+    pattern = r"\d+\.\d*{2}"
+    return f"Hello, {name.title()}! {{braces}} {count:>3}\n" + rf'\w+{name}'
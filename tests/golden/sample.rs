@@ -0,0 +1,26 @@
+//! Strings with escapes and format placeholders
+use std::fmt;
+
+/// A point, documented with `///`.
+//// Four slashes make a regular comment.
+#[derive(Debug)]
+struct Point { x: i32, y: i32 } // TODO(alice): add z
+
+/** A block doc comment */
+fn origin() -> Point {
+    // SAFETY: nothing unsafe here, but FIXME anyway. NOTES aren't tags.
+    /* HACK */ Point { x: 0, y: 0 }
+}
+
+fn main() {
+    let name = "tab\there, quote \" and \u{1F600}\x41";
+    let bad = "\q is no escape, \x80 is out of range";
+    let raw = r"C:\path\{not} a placeholder";
+    println!("{name:>8} {0:?} {{literal}} {:.2}", 1.5f64);
+    let c = '\n';
+    let q = '\'';
+    write!(out, "{q}\n").unwrap();
+    let s = "continued \
+             on the next line {}";
+    let p = unsafe { std::ptr::null::<u8>().read() };
+}
@@ -0,0 +1,44 @@
+#!/usr/bin/env bash
+# Golden sample
+set -euo pipefail
+
+readonly VERSION="1.2.3"
+export PREFIX=${PREFIX:-/usr/local}
+declare -a files=("$@")
+count=0
+
+log() {
+    printf '%s: %s\n' "$(date +%H:%M:%S)" "$*" >&2
+}
+
+function cleanup {
+    rm -rf "${tmpdir}" || true
+}
+trap cleanup EXIT
+
+for f in "${files[@]}"; do
+    if [[ -f $f && $count -lt 10 ]]; then
+        count=$((count + 1))
+        echo "next: $(( count + 1 )) of `echo $count`"
+    elif [ "$f" = "--help" ]; then
+        echo "usage: $0 [file...]" # not a \"string
+    fi
+done
+
+cat <<EOF > "$PREFIX/share/info.txt"
+version: $VERSION
+files: ${#files[@]}
+EOF
+
+grep -q x <<< "$VERSION" && echo \"escaped\" $'tab\there'
+case "$1" in
+    start|stop) exec "$1" ;;
+    *) exit 1 ;;
+esac
+cat <<'EOF'
+literal $VERSION
+  EOF
+EOF
+cat <<-END
+	tabbed "\$HOME" $HOME
+	END
@@ -0,0 +1,26 @@
+-- Golden sample
+/* A block comment
+   across lines */
+CREATE TABLE users (
+    id BIGSERIAL PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    "Display Name" TEXT DEFAULT 'n/a',
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT now(),
+    active BOOLEAN DEFAULT TRUE
+);
+
+SELECT u.id, COUNT(*) AS total, coalesce(u.name, 'it''s') AS label
+FROM users u
+LEFT JOIN orders o ON o.user_id = u.id
+WHERE u.created_at > :since AND o.amount >= 10.5e2
+GROUP BY u.id
+HAVING count(o.id) > 0
+ORDER BY total DESC
+LIMIT 10 OFFSET ?;
+
+UPDATE `orders` SET status = 'done', price = price::numeric WHERE id = $1;
+DELETE FROM [dbo].[logs] WHERE level = @level OR flag = 0x1F;
+INSERT INTO tags (name) VALUES (E'escaped\n'), ('multi
+line');
+SELECT $$dollar
+quoted$$ AS body;
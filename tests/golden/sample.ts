@@ -0,0 +1,27 @@
+// TypeScript types, interfaces and generics
+import * as path from "path";
+
+export interface User<T = unknown> extends Base {
+  readonly id: number;
+  name?: string;
+  meta: Map<string, Array<T>>;
+}
+
+type Handler = (event: Event) => void;
+declare namespace Config { const debug: boolean; }
+
+enum Color { Red, Green = "green" }
+
+abstract class Store<T> implements Iterable<T> {
+  private items: T[] = [];
+  @observable public count = 0;
+  constructor(protected readonly name: string) { super(); }
+}
+
+function first<T>(items: T[], fallback: T): T | undefined {
+  const key: keyof User = "name";
+  const node = { type: "leaf", value: Color.Red };
+  if (items.length < 2 && node.type !== 'tree') return items[0] as T;
+  const n = <number>fallback;
+  return fallback satisfies T;
+}
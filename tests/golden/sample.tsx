@@ -0,0 +1,8 @@
+import React from "react";
+
+interface Props { label: string; onPress?: () => void }
+
+export const Button = ({ label, onPress }: Props): JSX.Element => {
+  const [count, setCount] = React.useState<number>(0);
+  return <button title={`${label}: ${count}`} onClick={() => setCount(count + 1)}>{label}</button>;
+};